@@ -121,10 +121,10 @@ build_macos() {
     rustup target add x86_64-apple-darwin aarch64-apple-darwin
 
     # Собираем для Intel
-    cargo build --release --target x86_64-apple-darwin --features ffi,crypto --no-default-features
+    cargo build --release --target x86_64-apple-darwin --features ffi,crypto,store --no-default-features
 
     # Собираем для Apple Silicon
-    cargo build --release --target aarch64-apple-darwin --features ffi,crypto --no-default-features
+    cargo build --release --target aarch64-apple-darwin --features ffi,crypto,store --no-default-features
 
     # Создаём universal dylib
     mkdir -p target/macos
@@ -3,16 +3,19 @@ use chacha20poly1305::aead::Aead;
 use chacha20poly1305::KeyInit;
 use chacha20poly1305::{ChaCha20Poly1305, Nonce};
 use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use pbkdf2::pbkdf2_hmac;
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
 use zeroize::Zeroize;
 
 #[path = "mod.rs"]
 pub mod types;
 pub use types::{
-    AeadKey, Argon2Config, CryptoError, DecryptedFileWithMessage, EncryptedFile,
-    EncryptedFileWithMessage, EncryptedMessage, IdentityKeyPair, KeyPair, SignedPublicKey,
+    AeadKey, Argon2Config, CryptoError, DecryptedFileWithMessage, DeviceEnvelope, DeviceKey,
+    DeviceLinkEnvelope, EncryptedFile, EncryptedFileWithMessage, EncryptedMessage, IdentityKeyPair,
+    KdfParams, KeyPair, SignedPublicKey,
 };
 
 // Helpers for base64
@@ -100,12 +103,44 @@ pub fn import_private_key_b64(b64: &str) -> Result<StaticSecret, CryptoError> {
 /// Деривирует 32-байтный мастер-ключ по PBKDF2-HMAC-SHA256(100k) из пароля и соли (Base64-16).
 /// Замечание: используется для расшифровки приватного ключа с сервера, не для шифрования сообщений/файлов.
 pub fn derive_key_from_password(password: &str, salt_b64: &str) -> Result<AeadKey, CryptoError> {
-    let mut out = [0u8; 32];
-    let salt = b64_decode(salt_b64)?;
-    pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, 100_000, &mut out);
-    let key = AeadKey::from_bytes(&out);
-    out.zeroize();
-    key
+    derive_master_key(password, salt_b64, &KdfParams::legacy_pbkdf2())
+}
+
+/// Деривирует мастер-ключ по параметрам KDF пользователя (`UserAuthResponse.kdf_params`).
+/// PBKDF2 — для старых аккаунтов; Argon2id — для новых и после апгрейда при входе.
+pub fn derive_master_key(
+    password: &str,
+    salt_b64: &str,
+    params: &KdfParams,
+) -> Result<AeadKey, CryptoError> {
+    match params.algorithm.as_str() {
+        types::KDF_ALGORITHM_PBKDF2 => {
+            if params.iterations < 100_000 {
+                return Err(CryptoError::Kdf(
+                    "PBKDF2 iterations must be at least 100000".into(),
+                ));
+            }
+            let mut out = [0u8; 32];
+            let salt = b64_decode(salt_b64)?;
+            pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, params.iterations, &mut out);
+            let key = AeadKey::from_bytes(&out);
+            out.zeroize();
+            key
+        }
+        types::KDF_ALGORITHM_ARGON2ID => {
+            let defaults = Argon2Config::default();
+            let config = Argon2Config {
+                memory_kib: params.memory_kib.unwrap_or(defaults.memory_kib),
+                iterations: params.iterations,
+                parallelism: params.parallelism.unwrap_or(defaults.parallelism),
+            };
+            derive_recovery_key_argon2id_with_config(password, salt_b64, &config)
+        }
+        other => Err(CryptoError::Kdf(format!(
+            "unsupported algorithm: {}",
+            other
+        ))),
+    }
 }
 
 /// Деривирует 32-байтный ключ из произвольной строки: SHA-256(secret)[0..32].
@@ -530,6 +565,40 @@ pub fn unwrap_symmetric_key(
     AeadKey::from_bytes(&pt)
 }
 
+/// Оборачивает ключ сообщения под каждое устройство получателей.
+/// Результат — объект `envelopes` сообщения: `{device_id: DeviceEnvelope}`.
+pub fn wrap_symmetric_key_for_devices(
+    key_to_wrap: &AeadKey,
+    devices: &[DeviceKey],
+) -> Result<BTreeMap<String, DeviceEnvelope>, CryptoError> {
+    let mut envelopes = BTreeMap::new();
+    for device in devices {
+        let (key, ephem_pub_key, iv) = wrap_symmetric_key(key_to_wrap, &device.public_key)?;
+        envelopes.insert(
+            device.device_id.clone(),
+            DeviceEnvelope {
+                key,
+                ephem_pub_key,
+                iv,
+            },
+        );
+    }
+    Ok(envelopes)
+}
+
+/// Разворачивает конверт, адресованный этому устройству, его приватным ключом.
+pub fn unwrap_device_envelope(
+    envelope: &DeviceEnvelope,
+    device_private_key_b64: &str,
+) -> Result<AeadKey, CryptoError> {
+    unwrap_symmetric_key(
+        &envelope.key,
+        &envelope.ephem_pub_key,
+        &envelope.iv,
+        device_private_key_b64,
+    )
+}
+
 /// AEAD-шифрование короткого сообщения (возвращает Base64 ciphertext + nonce).
 pub fn encrypt_message(data: &str, key: &AeadKey) -> Result<EncryptedMessage, CryptoError> {
     let cipher = ChaCha20Poly1305::new(&key.0);
@@ -661,3 +730,380 @@ pub fn decrypt_file_with_message(
         mimetype: mimetype.to_string(),
     })
 }
+
+// ==============================
+// Searchable encryption (blind index)
+// ==============================
+
+/// Количество байт HMAC, оставляемых в токене слепого индекса (128 бит).
+const BLIND_INDEX_TOKEN_LEN: usize = 16;
+/// Слова короче этого порога не индексируются.
+const BLIND_INDEX_MIN_WORD_CHARS: usize = 2;
+/// Слова длиннее обрезаются до этого числа символов.
+const BLIND_INDEX_MAX_WORD_CHARS: usize = 64;
+
+/// Деривирует ключ слепого индекса для конкретного чата: HKDF-SHA256(secret, info = "ren-sdk-search:{chat_id}").
+/// `secret` — персональный секрет пользователя (например, мастер-ключ), поэтому токены разных
+/// пользователей и разных чатов не сопоставимы между собой на сервере.
+pub fn derive_chat_search_key(secret: &AeadKey, chat_id: i64) -> Result<AeadKey, CryptoError> {
+    let mut ikm = secret.to_bytes();
+    let hk = Hkdf::<Sha256>::new(Some(b"ren-sdk-search"), &ikm);
+    ikm.zeroize();
+    let info = format!("ren-sdk-search:{}", chat_id);
+    let mut out = [0u8; 32];
+    hk.expand(info.as_bytes(), &mut out)
+        .map_err(|_| CryptoError::Aead)?;
+    let key = AeadKey::from_bytes(&out);
+    out.zeroize();
+    key
+}
+
+/// Разбивает текст на нормализованные слова для индексации (нижний регистр, без дублей).
+pub fn tokenize_for_search(text: &str) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    search_words(text)
+        .map(normalize_search_word)
+        .filter(|w| seen.insert(w.clone()))
+        .collect()
+}
+
+fn search_words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() >= BLIND_INDEX_MIN_WORD_CHARS)
+}
+
+fn normalize_search_word(word: &str) -> String {
+    word.chars()
+        .take(BLIND_INDEX_MAX_WORD_CHARS)
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Токен слепого индекса для слова поискового запроса: Base64url(HMAC-SHA256(search_key, word)[0..16]).
+/// Слово нормализуется так же, как в [`tokenize_for_search`] (первое слово после разбиения
+/// по не буквенно-цифровым символам, обрезка, нижний регистр), поэтому "Привет," или "don't"
+/// дают тот же токен, что и при индексации. Для запроса из нескольких слов — [`blind_index_tokens`].
+pub fn blind_index_token(word: &str, search_key: &AeadKey) -> Result<String, CryptoError> {
+    let normalized = search_words(word)
+        .next()
+        .map(normalize_search_word)
+        .unwrap_or_default();
+    normalized_word_token(&normalized, search_key)
+}
+
+/// Токен для уже нормализованного слова из [`tokenize_for_search`]. Повторная нормализация
+/// не идемпотентна (to_lowercase может добавить не буквенные символы), поэтому её здесь нет.
+pub(crate) fn normalized_word_token(
+    word: &str,
+    search_key: &AeadKey,
+) -> Result<String, CryptoError> {
+    let mut key_bytes = search_key.to_bytes();
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key_bytes)
+        .map_err(|_| CryptoError::InvalidKeyLen("search key".into()))?;
+    key_bytes.zeroize();
+    mac.update(word.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    Ok(general_purpose::URL_SAFE_NO_PAD.encode(&digest[..BLIND_INDEX_TOKEN_LEN]))
+}
+
+/// Токены слепого индекса для всех слов сообщения (прикладываются к сообщению при отправке).
+pub fn blind_index_tokens(text: &str, search_key: &AeadKey) -> Result<Vec<String>, CryptoError> {
+    tokenize_for_search(text)
+        .iter()
+        .map(|w| normalized_word_token(w, search_key))
+        .collect()
+}
+
+// ============================================================================
+// Account recovery (challenge-response)
+// ============================================================================
+
+fn recovery_auth_signing_key(
+    recovery_key: &AeadKey,
+) -> Result<ed25519_dalek::SigningKey, CryptoError> {
+    let mut ikm = recovery_key.to_bytes();
+    let hk = Hkdf::<Sha256>::new(Some(b"ren-sdk-recovery-auth"), &ikm);
+    ikm.zeroize();
+    let mut seed = [0u8; 32];
+    hk.expand(b"ed25519", &mut seed)
+        .map_err(|_| CryptoError::Aead)?;
+    let signing_key = ed25519_dalek::SigningKey::from_bytes(&seed);
+    seed.zeroize();
+    Ok(signing_key)
+}
+
+/// Ed25519-ключ для подтверждения владения ключом восстановления (Base64, 32 байта).
+/// Деривируется из ключа восстановления (`derive_recovery_key_argon2id`) и передаётся
+/// серверу как `recovery_pubk`; сам ключ восстановления сервер не получает.
+pub fn recovery_auth_public_key(recovery_key: &AeadKey) -> Result<String, CryptoError> {
+    let signing_key = recovery_auth_signing_key(recovery_key)?;
+    Ok(b64_encode(signing_key.verifying_key().as_bytes()))
+}
+
+/// Подписывает вызов сервера из `POST /auth/recovery/challenge`.
+/// Подписывается строка `ren-recovery:{challenge_id}:{challenge}`; возвращает подпись в Base64.
+pub fn sign_recovery_challenge(
+    recovery_key: &AeadKey,
+    challenge_id: &str,
+    challenge_b64: &str,
+) -> Result<String, CryptoError> {
+    use ed25519_dalek::Signer;
+
+    let signing_key = recovery_auth_signing_key(recovery_key)?;
+    let message = format!("ren-recovery:{}:{}", challenge_id, challenge_b64);
+    Ok(b64_encode(&signing_key.sign(message.as_bytes()).to_bytes()))
+}
+
+// ============================================================================
+// Passkey (WebAuthn PRF)
+// ============================================================================
+
+/// Ключ для `pkebyprf` из результата PRF-расширения passkey (Base64, 32 байта).
+/// Приватный ключ шифруется им через `encrypt_data`, что позволяет входить по
+/// passkey без пароля. Вход PRF клиент берёт из `extensions.prf.eval.first`
+/// в параметрах церемонии от сервера.
+pub fn passkey_prf_key(prf_output_b64: &str) -> Result<AeadKey, CryptoError> {
+    let mut ikm = b64_decode(prf_output_b64)?;
+    if ikm.len() < 32 {
+        let len = ikm.len();
+        ikm.zeroize();
+        return Err(CryptoError::InvalidKeyLen(format!("{}", len)));
+    }
+    let hk = Hkdf::<Sha256>::new(Some(b"ren-sdk-passkey-prf"), &ikm);
+    ikm.zeroize();
+    let mut okm = [0u8; 32];
+    hk.expand(b"pkebyprf", &mut okm)
+        .map_err(|_| CryptoError::Aead)?;
+    let key = AeadKey::from_bytes(&okm);
+    okm.zeroize();
+    key
+}
+
+// ============================================================================
+// Device linking (QR)
+// ============================================================================
+
+/// Секрет привязки устройства (Base64, 32 байта). Передаётся новому устройству
+/// только через QR вместе с `link_id`/`code` от сервера и на сервер не отправляется:
+/// им аутентифицируются ключ нового устройства и конверт с приватным ключом.
+pub fn generate_device_link_secret() -> String {
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).expect("rand");
+    let out = b64_encode(&secret);
+    secret.zeroize();
+    out
+}
+
+fn device_link_mac(
+    link_secret_b64: &str,
+    purpose: &[u8],
+    parts: &[&str],
+) -> Result<Hmac<Sha256>, CryptoError> {
+    let mut ikm = b64_decode(link_secret_b64)?;
+    if ikm.len() != 32 {
+        let len = ikm.len();
+        ikm.zeroize();
+        return Err(CryptoError::InvalidKeyLen(format!("{}", len)));
+    }
+    let hk = Hkdf::<Sha256>::new(Some(b"ren-sdk-device-link"), &ikm);
+    ikm.zeroize();
+    let mut mac_key = [0u8; 32];
+    hk.expand(purpose, &mut mac_key)
+        .map_err(|_| CryptoError::Aead)?;
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&mac_key)
+        .map_err(|_| CryptoError::InvalidKeyLen("device link key".into()))?;
+    mac_key.zeroize();
+    // Части — Base64-строки, перевод строки в них встретиться не может
+    for part in parts {
+        mac.update(part.as_bytes());
+        mac.update(b"\n");
+    }
+    Ok(mac)
+}
+
+/// MAC эфемерного X25519-ключа нового устройства (`key_mac` в `/auth/device-links/claim`).
+pub fn device_link_key_mac(
+    link_secret_b64: &str,
+    public_key_b64: &str,
+) -> Result<String, CryptoError> {
+    let mac = device_link_mac(link_secret_b64, b"claimant-key", &[public_key_b64])?;
+    Ok(b64_encode(&mac.finalize().into_bytes()))
+}
+
+/// Проверка `key_mac` на устройстве, показавшем QR: без неё сервер мог бы
+/// подменить ключ нового устройства своим.
+pub fn verify_device_link_key_mac(
+    link_secret_b64: &str,
+    public_key_b64: &str,
+    mac_b64: &str,
+) -> Result<bool, CryptoError> {
+    let mac = device_link_mac(link_secret_b64, b"claimant-key", &[public_key_b64])?;
+    Ok(mac.verify_slice(&b64_decode(mac_b64)?).is_ok())
+}
+
+/// Шифрует `bundle` (JSON с приватным ключом и т.п.) для нового устройства:
+/// случайный ключ оборачивается через `wrap_symmetric_key` под его эфемерный ключ,
+/// `bundle` шифруется этим ключом, конверт подписывается MAC из секрета QR.
+pub fn seal_device_link_bundle(
+    bundle: &str,
+    link_secret_b64: &str,
+    receiver_public_key_b64: &str,
+) -> Result<DeviceLinkEnvelope, CryptoError> {
+    let key = generate_message_encryption_key();
+    let (wrapped_key, ephemeral_public_key, nonce) =
+        wrap_symmetric_key(&key, receiver_public_key_b64)?;
+    let ciphertext = encrypt_data(bundle, &key)?;
+    let mac = device_link_mac(
+        link_secret_b64,
+        b"envelope",
+        &[
+            receiver_public_key_b64,
+            &wrapped_key,
+            &ephemeral_public_key,
+            &nonce,
+            &ciphertext,
+        ],
+    )?;
+    Ok(DeviceLinkEnvelope {
+        wrapped_key,
+        ephemeral_public_key,
+        nonce,
+        ciphertext,
+        mac: b64_encode(&mac.finalize().into_bytes()),
+    })
+}
+
+/// Открывает конверт на новом устройстве эфемерным приватным ключом,
+/// предварительно проверив MAC секретом из QR.
+pub fn open_device_link_bundle(
+    envelope: &DeviceLinkEnvelope,
+    link_secret_b64: &str,
+    receiver_private_key_b64: &str,
+) -> Result<String, CryptoError> {
+    let receiver_sk = import_private_key_b64(receiver_private_key_b64)?;
+    let receiver_pk = export_public_key_b64(&X25519PublicKey::from(&receiver_sk));
+    let mac = device_link_mac(
+        link_secret_b64,
+        b"envelope",
+        &[
+            &receiver_pk,
+            &envelope.wrapped_key,
+            &envelope.ephemeral_public_key,
+            &envelope.nonce,
+            &envelope.ciphertext,
+        ],
+    )?;
+    mac.verify_slice(&b64_decode(&envelope.mac)?)
+        .map_err(|_| CryptoError::DeviceLink("envelope mac mismatch".into()))?;
+
+    let key = unwrap_symmetric_key(
+        &envelope.wrapped_key,
+        &envelope.ephemeral_public_key,
+        &envelope.nonce,
+        receiver_private_key_b64,
+    )?;
+    decrypt_data(&envelope.ciphertext, &key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_envelopes_round_trip_per_device() {
+        let message_key = generate_message_encryption_key();
+        let phone = generate_key_pair(false);
+        let laptop = generate_key_pair(false);
+        let devices = vec![
+            DeviceKey {
+                device_id: "phone".to_string(),
+                public_key: phone.public_key.clone(),
+            },
+            DeviceKey {
+                device_id: "laptop".to_string(),
+                public_key: laptop.public_key.clone(),
+            },
+        ];
+
+        let envelopes = wrap_symmetric_key_for_devices(&message_key, &devices).unwrap();
+        assert_eq!(envelopes.len(), 2);
+
+        for (device_id, private_key) in [
+            ("phone", &phone.private_key),
+            ("laptop", &laptop.private_key),
+        ] {
+            let unwrapped = unwrap_device_envelope(&envelopes[device_id], private_key).unwrap();
+            assert_eq!(unwrapped.to_bytes(), message_key.to_bytes());
+        }
+
+        // Конверт одного устройства не открывается ключом другого
+        assert!(unwrap_device_envelope(&envelopes["phone"], &laptop.private_key).is_err());
+    }
+
+    #[test]
+    fn query_token_matches_indexed_token() {
+        let key = derive_chat_search_key(&generate_message_encryption_key(), 7).unwrap();
+        let long_word = "Д".repeat(BLIND_INDEX_MAX_WORD_CHARS + 10);
+        let text = format!("Привет, don't {}", long_word);
+        let indexed = blind_index_tokens(&text, &key).unwrap();
+
+        for query in ["Привет,", "ПРИВЕТ", "don't", long_word.as_str()] {
+            let token = blind_index_token(query, &key).unwrap();
+            assert!(indexed.contains(&token), "{query}");
+        }
+        // Символ, который to_lowercase превращает в два, не ломает совпадение
+        let indexed = blind_index_tokens("İstanbul", &key).unwrap();
+        assert_eq!(indexed, vec![blind_index_token("İstanbul!", &key).unwrap()]);
+    }
+
+    #[test]
+    fn device_link_bundle_round_trip() {
+        let secret = generate_device_link_secret();
+        let claimant = generate_key_pair(false);
+        let key_mac = device_link_key_mac(&secret, &claimant.public_key).unwrap();
+        assert!(verify_device_link_key_mac(&secret, &claimant.public_key, &key_mac).unwrap());
+
+        let envelope =
+            seal_device_link_bundle("{\"pk\":\"x\"}", &secret, &claimant.public_key).unwrap();
+        let bundle = open_device_link_bundle(&envelope, &secret, &claimant.private_key).unwrap();
+        assert_eq!(bundle, "{\"pk\":\"x\"}");
+    }
+
+    #[test]
+    fn device_link_rejects_swapped_claimant_key() {
+        // Сервер не знает секрета из QR и не может выдать свой ключ за ключ устройства
+        let secret = generate_device_link_secret();
+        let claimant = generate_key_pair(false);
+        let server = generate_key_pair(false);
+        let key_mac = device_link_key_mac(&secret, &claimant.public_key).unwrap();
+        assert!(!verify_device_link_key_mac(&secret, &server.public_key, &key_mac).unwrap());
+
+        let forged =
+            device_link_key_mac(&generate_device_link_secret(), &server.public_key).unwrap();
+        assert!(!verify_device_link_key_mac(&secret, &server.public_key, &forged).unwrap());
+    }
+
+    #[test]
+    fn device_link_bundle_rejects_tampering() {
+        let secret = generate_device_link_secret();
+        let claimant = generate_key_pair(false);
+        let envelope = seal_device_link_bundle("bundle", &secret, &claimant.public_key).unwrap();
+
+        let mut bad_mac = envelope.clone();
+        bad_mac.mac = device_link_key_mac(&secret, &claimant.public_key).unwrap();
+        assert!(open_device_link_bundle(&bad_mac, &secret, &claimant.private_key).is_err());
+
+        let resealed = seal_device_link_bundle("bundle", &secret, &claimant.public_key).unwrap();
+        let mut spliced = envelope.clone();
+        spliced.ciphertext = resealed.ciphertext;
+        assert!(open_device_link_bundle(&spliced, &secret, &claimant.private_key).is_err());
+
+        let other_secret = generate_device_link_secret();
+        assert!(open_device_link_bundle(&envelope, &other_secret, &claimant.private_key).is_err());
+
+        // Конверт для чужого ключа (подменённого сервером) не открывается ключом устройства
+        let server = generate_key_pair(false);
+        let for_server = seal_device_link_bundle("bundle", &secret, &server.public_key).unwrap();
+        assert!(open_device_link_bundle(&for_server, &secret, &claimant.private_key).is_err());
+    }
+}
@@ -5,13 +5,22 @@ use std::panic::{catch_unwind, UnwindSafe};
 use std::ptr;
 use zeroize::Zeroize;
 
+use crate::crypto::types::{DeviceKey, DeviceLinkEnvelope, KdfParams};
 use crate::crypto::{
     decrypt_data, decrypt_file, decrypt_file_raw, decrypt_message, derive_key_from_password,
     derive_key_from_string, derive_recovery_key_argon2id, generate_recovery_salt,
     encrypt_data, encrypt_file, encrypt_message, generate_key_pair,
     generate_message_encryption_key, generate_nonce, generate_salt, unwrap_symmetric_key,
-    wrap_symmetric_key, validate_recovery_entropy,
+    wrap_symmetric_key, wrap_symmetric_key_for_devices, validate_recovery_entropy,
+    blind_index_token, blind_index_tokens, derive_chat_search_key,
+    recovery_auth_public_key, sign_recovery_challenge, derive_master_key, passkey_prf_key,
+    generate_device_link_secret, device_link_key_mac, verify_device_link_key_mac,
+    seal_device_link_bundle, open_device_link_bundle,
 };
+#[cfg(feature = "store")]
+use crate::store::{MessageStore, StoredMessage};
+#[cfg(feature = "store")]
+use std::panic::AssertUnwindSafe;
 
 // ============================================================================
 // Helper functions для работы со строками C
@@ -248,6 +257,72 @@ pub extern "C" fn ren_derive_key_from_password(
     })
 }
 
+/// Деривирует мастер-ключ по параметрам KDF пользователя.
+/// `params_json` — объект `kdf_params` из `UserAuthResponse`.
+///
+/// # Returns
+/// Base64-encoded 32-byte key, or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_derive_master_key(
+    password: *const c_char,
+    salt_b64: *const c_char,
+    params_json: *const c_char,
+) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let pwd = match c_str_to_str(password) {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+        let salt = match c_str_to_str(salt_b64) {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+        let params: KdfParams =
+            match c_str_to_str(params_json).and_then(|s| serde_json::from_str(s).ok()) {
+                Some(p) => p,
+                None => return ptr::null_mut(),
+            };
+
+        match derive_master_key(pwd, salt, &params) {
+            Ok(key) => {
+                let mut bytes = key.to_bytes();
+                let out = rust_str_to_c(general_purpose::STANDARD.encode(bytes));
+                bytes.zeroize();
+                out
+            }
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Рекомендуемые параметры KDF (для регистрации и апгрейда при входе).
+///
+/// # Returns
+/// JSON-объект `KdfParams`, or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_recommended_kdf_params() -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        match serde_json::to_string(&KdfParams::recommended()) {
+            Ok(json) => rust_str_to_c(json),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Проверяет, нужно ли перешифровать `pkebymk` под рекомендуемые параметры KDF.
+///
+/// # Returns
+/// 1 if upgrade is needed, 0 if not, -1 on error.
+#[no_mangle]
+pub extern "C" fn ren_kdf_needs_upgrade(params_json: *const c_char) -> i32 {
+    ffi_catch(-1, || {
+        match c_str_to_str(params_json).and_then(|s| serde_json::from_str::<KdfParams>(s).ok()) {
+            Some(params) => params.needs_upgrade() as i32,
+            None => -1,
+        }
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn ren_derive_key_from_string(secret: *const c_char) -> *mut c_char {
     ffi_catch(ptr::null_mut(), || {
@@ -845,3 +920,564 @@ pub extern "C" fn ren_unwrap_symmetric_key_bytes(
         }
     })
 }
+
+/// Оборачивает ключ сообщения под каждое устройство получателей.
+///
+/// # Arguments
+/// * `devices_json` - JSON array of `{"device_id", "public_key"}` (из `GET /users/:id/devices`)
+///
+/// # Returns
+/// JSON object `{device_id: {"key", "ephem_pub_key", "iv"}}` for the message `envelopes`,
+/// or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_wrap_symmetric_key_for_devices(
+    key_b64: *const c_char,
+    devices_json: *const c_char,
+) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let Some(key) = aead_key_from_c_b64(key_b64) else {
+            return ptr::null_mut();
+        };
+        let devices = match c_str_to_str(devices_json)
+            .and_then(|s| serde_json::from_str::<Vec<DeviceKey>>(s).ok())
+        {
+            Some(d) => d,
+            None => return ptr::null_mut(),
+        };
+
+        match wrap_symmetric_key_for_devices(&key, &devices)
+            .ok()
+            .and_then(|envelopes| serde_json::to_string(&envelopes).ok())
+        {
+            Some(json) => rust_str_to_c(json),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+// ============================================================================
+// Слепой индекс (поиск по зашифрованным сообщениям)
+// ============================================================================
+
+fn aead_key_from_c_b64(key_b64: *const c_char) -> Option<crate::crypto::types::AeadKey> {
+    let key_str = c_str_to_str(key_b64)?;
+    let mut key_bytes = general_purpose::STANDARD.decode(key_str).ok()?;
+    let key = crate::crypto::types::AeadKey::from_bytes(&key_bytes).ok();
+    key_bytes.zeroize();
+    key
+}
+
+/// Деривирует ключ слепого индекса чата из персонального секрета (Base64, 32 байта).
+///
+/// # Returns
+/// Base64-encoded 32-byte key, or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_derive_chat_search_key(
+    secret_b64: *const c_char,
+    chat_id: i64,
+) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let secret = match aead_key_from_c_b64(secret_b64) {
+            Some(k) => k,
+            None => return ptr::null_mut(),
+        };
+
+        match derive_chat_search_key(&secret, chat_id) {
+            Ok(key) => {
+                let mut bytes = key.to_bytes();
+                let out = rust_str_to_c(general_purpose::STANDARD.encode(bytes));
+                bytes.zeroize();
+                out
+            }
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Токены слепого индекса для текста сообщения.
+///
+/// # Returns
+/// JSON-массив строк (например, `["tok1","tok2"]`), or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_blind_index_tokens(
+    text: *const c_char,
+    search_key_b64: *const c_char,
+) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let text = match c_str_to_str(text) {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+        let key = match aead_key_from_c_b64(search_key_b64) {
+            Some(k) => k,
+            None => return ptr::null_mut(),
+        };
+
+        match blind_index_tokens(text, &key)
+            .ok()
+            .and_then(|tokens| serde_json::to_string(&tokens).ok())
+        {
+            Some(json) => rust_str_to_c(json),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+/// Токен слепого индекса для одного слова поискового запроса.
+///
+/// # Returns
+/// Base64url token, or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_blind_index_token(
+    word: *const c_char,
+    search_key_b64: *const c_char,
+) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let word = match c_str_to_str(word) {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+        let key = match aead_key_from_c_b64(search_key_b64) {
+            Some(k) => k,
+            None => return ptr::null_mut(),
+        };
+
+        match blind_index_token(word, &key) {
+            Ok(token) => rust_str_to_c(token),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+// ============================================================================
+// Восстановление аккаунта (challenge-response)
+// ============================================================================
+
+/// Публичный Ed25519-ключ для подтверждения владения ключом восстановления.
+///
+/// # Returns
+/// Base64-encoded 32-byte public key (`recovery_pubk`), or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_recovery_auth_public_key(recovery_key_b64: *const c_char) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let key = match aead_key_from_c_b64(recovery_key_b64) {
+            Some(k) => k,
+            None => return ptr::null_mut(),
+        };
+
+        match recovery_auth_public_key(&key) {
+            Ok(public_key) => rust_str_to_c(public_key),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Подпись вызова восстановления (`challenge_id`, `challenge` из `/auth/recovery/challenge`).
+///
+/// # Returns
+/// Base64-encoded Ed25519 signature, or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_sign_recovery_challenge(
+    recovery_key_b64: *const c_char,
+    challenge_id: *const c_char,
+    challenge_b64: *const c_char,
+) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let key = match aead_key_from_c_b64(recovery_key_b64) {
+            Some(k) => k,
+            None => return ptr::null_mut(),
+        };
+        let challenge_id = match c_str_to_str(challenge_id) {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+        let challenge = match c_str_to_str(challenge_b64) {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+
+        match sign_recovery_challenge(&key, challenge_id, challenge) {
+            Ok(signature) => rust_str_to_c(signature),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+// ============================================================================
+// Passkey (WebAuthn PRF)
+// ============================================================================
+
+/// Ключ для шифрования приватного ключа (`pkebyprf`) из результата PRF passkey.
+///
+/// # Returns
+/// Base64-encoded 32-byte key, or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_passkey_prf_key(prf_output_b64: *const c_char) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let prf_output = match c_str_to_str(prf_output_b64) {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+
+        match passkey_prf_key(prf_output) {
+            Ok(key) => {
+                let mut bytes = key.to_bytes();
+                let out = rust_str_to_c(general_purpose::STANDARD.encode(bytes));
+                bytes.zeroize();
+                out
+            }
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+// ============================================================================
+// Привязка устройства (QR)
+// ============================================================================
+
+/// Новый секрет привязки для QR (не отправляется на сервер).
+///
+/// # Returns
+/// Base64-encoded 32-byte secret, or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_generate_device_link_secret() -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        rust_str_to_c(generate_device_link_secret())
+    })
+}
+
+/// MAC эфемерного ключа нового устройства (`key_mac` для `/auth/device-links/claim`).
+///
+/// # Returns
+/// Base64-encoded HMAC-SHA256, or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_device_link_key_mac(
+    link_secret_b64: *const c_char,
+    public_key_b64: *const c_char,
+) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let secret = match c_str_to_str(link_secret_b64) {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+        let public_key = match c_str_to_str(public_key_b64) {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+
+        match device_link_key_mac(secret, public_key) {
+            Ok(mac) => rust_str_to_c(mac),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Проверка `key_mac` на устройстве, показавшем QR.
+///
+/// # Returns
+/// 1 if the MAC is valid, 0 if not, -1 on error.
+#[no_mangle]
+pub extern "C" fn ren_verify_device_link_key_mac(
+    link_secret_b64: *const c_char,
+    public_key_b64: *const c_char,
+    mac_b64: *const c_char,
+) -> i32 {
+    ffi_catch(-1, || {
+        let (Some(secret), Some(public_key), Some(mac)) = (
+            c_str_to_str(link_secret_b64),
+            c_str_to_str(public_key_b64),
+            c_str_to_str(mac_b64),
+        ) else {
+            return -1;
+        };
+
+        match verify_device_link_key_mac(secret, public_key, mac) {
+            Ok(valid) => valid as i32,
+            Err(_) => -1,
+        }
+    })
+}
+
+/// Шифрует `bundle` для нового устройства.
+///
+/// # Returns
+/// JSON-encoded `DeviceLinkEnvelope`, or null pointer on error.
+#[no_mangle]
+pub extern "C" fn ren_seal_device_link_bundle(
+    bundle: *const c_char,
+    link_secret_b64: *const c_char,
+    receiver_public_key_b64: *const c_char,
+) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let (Some(bundle), Some(secret), Some(receiver)) = (
+            c_str_to_str(bundle),
+            c_str_to_str(link_secret_b64),
+            c_str_to_str(receiver_public_key_b64),
+        ) else {
+            return ptr::null_mut();
+        };
+
+        match seal_device_link_bundle(bundle, secret, receiver)
+            .ok()
+            .and_then(|envelope| serde_json::to_string(&envelope).ok())
+        {
+            Some(json) => rust_str_to_c(json),
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+/// Открывает конверт привязки эфемерным приватным ключом нового устройства.
+///
+/// # Returns
+/// Decrypted bundle, or null pointer on error (including MAC mismatch).
+#[no_mangle]
+pub extern "C" fn ren_open_device_link_bundle(
+    envelope_json: *const c_char,
+    link_secret_b64: *const c_char,
+    receiver_private_key_b64: *const c_char,
+) -> *mut c_char {
+    ffi_catch(ptr::null_mut(), || {
+        let envelope = match c_str_to_str(envelope_json)
+            .and_then(|s| serde_json::from_str::<DeviceLinkEnvelope>(s).ok())
+        {
+            Some(e) => e,
+            None => return ptr::null_mut(),
+        };
+        let (Some(secret), Some(private_key)) = (
+            c_str_to_str(link_secret_b64),
+            c_str_to_str(receiver_private_key_b64),
+        ) else {
+            return ptr::null_mut();
+        };
+
+        match open_device_link_bundle(&envelope, secret, private_key) {
+            Ok(bundle) => rust_str_to_c(bundle),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+// ============================================================================
+// Локальное хранилище сообщений (SQLite)
+// ============================================================================
+
+/// Непрозрачный дескриптор локального хранилища сообщений.
+#[cfg(feature = "store")]
+pub struct RenMessageStore(crate::store::SqliteMessageStore);
+
+/// Открывает (или создаёт) зашифрованное хранилище сообщений.
+///
+/// # Returns
+/// Дескриптор хранилища, or null pointer on error. Освобождается через `ren_store_close`.
+#[cfg(feature = "store")]
+#[no_mangle]
+pub extern "C" fn ren_store_open(
+    path: *const c_char,
+    key_b64: *const c_char,
+) -> *mut RenMessageStore {
+    ffi_catch(ptr::null_mut(), || {
+        let path = match c_str_to_str(path) {
+            Some(s) => s,
+            None => return ptr::null_mut(),
+        };
+        let key = match aead_key_from_c_b64(key_b64) {
+            Some(k) => k,
+            None => return ptr::null_mut(),
+        };
+
+        match crate::store::SqliteMessageStore::open(path, &key) {
+            Ok(store) => Box::into_raw(Box::new(RenMessageStore(store))),
+            Err(_) => ptr::null_mut(),
+        }
+    })
+}
+
+/// Закрывает хранилище и освобождает дескриптор.
+///
+/// # Safety
+/// `store` должен быть получен из `ren_store_open` и не использоваться после вызова.
+#[cfg(feature = "store")]
+#[no_mangle]
+pub unsafe extern "C" fn ren_store_close(store: *mut RenMessageStore) {
+    ffi_catch(
+        (),
+        AssertUnwindSafe(|| {
+            if !store.is_null() {
+                drop(Box::from_raw(store));
+            }
+        }),
+    )
+}
+
+/// Добавляет или заменяет сообщения. `messages_json` — JSON-массив `StoredMessage`.
+///
+/// # Returns
+/// 1 on success, 0 on error.
+///
+/// # Safety
+/// `store` должен быть валидным дескриптором из `ren_store_open`.
+#[cfg(feature = "store")]
+#[no_mangle]
+pub unsafe extern "C" fn ren_store_insert(
+    store: *mut RenMessageStore,
+    messages_json: *const c_char,
+) -> i32 {
+    ffi_catch(
+        0,
+        AssertUnwindSafe(|| {
+            let store = match store.as_mut() {
+                Some(s) => s,
+                None => return 0,
+            };
+            let messages: Vec<StoredMessage> =
+                match c_str_to_str(messages_json).and_then(|s| serde_json::from_str(s).ok()) {
+                    Some(m) => m,
+                    None => return 0,
+                };
+
+            match store.0.insert(&messages) {
+                Ok(()) => 1,
+                Err(_) => 0,
+            }
+        }),
+    )
+}
+
+/// Сообщения чата от новых к старым. `before_id <= 0` — с самого нового.
+///
+/// # Returns
+/// JSON-массив `StoredMessage`, or null pointer on error.
+///
+/// # Safety
+/// `store` должен быть валидным дескриптором из `ren_store_open`.
+#[cfg(feature = "store")]
+#[no_mangle]
+pub unsafe extern "C" fn ren_store_query(
+    store: *mut RenMessageStore,
+    chat_id: i64,
+    before_id: i64,
+    limit: u32,
+) -> *mut c_char {
+    ffi_catch(
+        ptr::null_mut(),
+        AssertUnwindSafe(|| {
+            let store = match store.as_ref() {
+                Some(s) => s,
+                None => return ptr::null_mut(),
+            };
+            let before_id = (before_id > 0).then_some(before_id);
+
+            match store
+                .0
+                .query(chat_id, before_id, limit as usize)
+                .ok()
+                .and_then(|messages| serde_json::to_string(&messages).ok())
+            {
+                Some(json) => rust_str_to_c(json),
+                None => ptr::null_mut(),
+            }
+        }),
+    )
+}
+
+/// Полнотекстовый поиск по расшифрованным сообщениям. `chat_id <= 0` — по всем чатам.
+///
+/// # Returns
+/// JSON-массив `StoredMessage` (от новых к старым), or null pointer on error.
+///
+/// # Safety
+/// `store` должен быть валидным дескриптором из `ren_store_open`.
+#[cfg(feature = "store")]
+#[no_mangle]
+pub unsafe extern "C" fn ren_store_search(
+    store: *mut RenMessageStore,
+    chat_id: i64,
+    query: *const c_char,
+    limit: u32,
+) -> *mut c_char {
+    ffi_catch(
+        ptr::null_mut(),
+        AssertUnwindSafe(|| {
+            let store = match store.as_ref() {
+                Some(s) => s,
+                None => return ptr::null_mut(),
+            };
+            let query = match c_str_to_str(query) {
+                Some(s) => s,
+                None => return ptr::null_mut(),
+            };
+            let chat_id = (chat_id > 0).then_some(chat_id);
+
+            match store
+                .0
+                .search(chat_id, query, limit as usize)
+                .ok()
+                .and_then(|messages| serde_json::to_string(&messages).ok())
+            {
+                Some(json) => rust_str_to_c(json),
+                None => ptr::null_mut(),
+            }
+        }),
+    )
+}
+
+/// Удаляет сообщения по id. `ids_json` — JSON-массив чисел.
+///
+/// # Returns
+/// 1 on success, 0 on error.
+///
+/// # Safety
+/// `store` должен быть валидным дескриптором из `ren_store_open`.
+#[cfg(feature = "store")]
+#[no_mangle]
+pub unsafe extern "C" fn ren_store_remove(
+    store: *mut RenMessageStore,
+    ids_json: *const c_char,
+) -> i32 {
+    ffi_catch(
+        0,
+        AssertUnwindSafe(|| {
+            let store = match store.as_mut() {
+                Some(s) => s,
+                None => return 0,
+            };
+            let ids: Vec<i64> =
+                match c_str_to_str(ids_json).and_then(|s| serde_json::from_str(s).ok()) {
+                    Some(ids) => ids,
+                    None => return 0,
+                };
+
+            match store.0.remove(&ids) {
+                Ok(()) => 1,
+                Err(_) => 0,
+            }
+        }),
+    )
+}
+
+/// Полностью очищает хранилище (выход из аккаунта, отзыв устройства).
+///
+/// # Returns
+/// 1 on success, 0 on error.
+///
+/// # Safety
+/// `store` должен быть валидным дескриптором из `ren_store_open`.
+#[cfg(feature = "store")]
+#[no_mangle]
+pub unsafe extern "C" fn ren_store_wipe(store: *mut RenMessageStore) -> i32 {
+    ffi_catch(
+        0,
+        AssertUnwindSafe(|| {
+            let store = match store.as_mut() {
+                Some(s) => s,
+                None => return 0,
+            };
+
+            match store.0.wipe() {
+                Ok(()) => 1,
+                Err(_) => 0,
+            }
+        }),
+    )
+}
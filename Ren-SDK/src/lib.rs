@@ -1,4 +1,5 @@
 pub mod crypto;
+pub mod store;
 
 #[cfg(feature = "ffi")]
 pub mod ffi;
@@ -9,14 +10,27 @@ pub mod wasm;
 // Re-export основных типов для удобства
 pub use crypto::{
     decrypt_data, decrypt_file, decrypt_message, derive_key_from_password, derive_key_from_string,
+    derive_master_key,
     derive_recovery_key_argon2id, derive_recovery_key_argon2id_with_config,
     encrypt_data, encrypt_file, encrypt_message, generate_key_pair, generate_nonce, generate_salt,
     generate_recovery_salt, validate_recovery_entropy,
     generate_identity_key_pair, sign_public_key, verify_signed_public_key,
     unwrap_symmetric_key, wrap_symmetric_key,
+    unwrap_device_envelope, wrap_symmetric_key_for_devices,
+    blind_index_token, blind_index_tokens, derive_chat_search_key, tokenize_for_search,
+    recovery_auth_public_key, sign_recovery_challenge,
+    passkey_prf_key,
+    generate_device_link_secret, device_link_key_mac, verify_device_link_key_mac,
+    seal_device_link_bundle, open_device_link_bundle,
 };
 
 pub use crypto::types::{
-    AeadKey, Argon2Config, CryptoError, DecryptedFileWithMessage, EncryptedFile,
-    EncryptedFileWithMessage, EncryptedMessage, IdentityKeyPair, KeyPair, SignedPublicKey,
+    AeadKey, Argon2Config, CryptoError, DecryptedFileWithMessage, DeviceEnvelope, DeviceKey,
+    DeviceLinkEnvelope, EncryptedFile,
+    EncryptedFileWithMessage, EncryptedMessage, IdentityKeyPair, KdfParams, KeyPair, SignedPublicKey,
 };
+
+pub use store::{MemoryMessageStore, MessageStore, StoreError, StoredMessage};
+
+#[cfg(feature = "store")]
+pub use store::SqliteMessageStore;
@@ -15,6 +15,10 @@ pub enum CryptoError {
     Argon2(String),
     #[error("signature error: {0}")]
     Signature(String),
+    #[error("kdf error: {0}")]
+    Kdf(String),
+    #[error("device link error: {0}")]
+    DeviceLink(String),
 }
 
 impl From<chacha20poly1305::aead::Error> for CryptoError {
@@ -69,6 +73,58 @@ impl Default for Argon2Config {
     }
 }
 
+/// Алгоритмы KDF мастер-ключа
+pub const KDF_ALGORITHM_PBKDF2: &str = "pbkdf2-sha256";
+pub const KDF_ALGORITHM_ARGON2ID: &str = "argon2id";
+/// Текущая версия параметров KDF: всё, что ниже, клиент перешифровывает при входе
+pub const KDF_CURRENT_VERSION: u32 = 2;
+
+/// Параметры деривации мастер-ключа из пароля. Хранятся на сервере вместе с
+/// `pkebymk`/`salt` и приходят в `UserAuthResponse.kdf_params`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KdfParams {
+    /// "pbkdf2-sha256" | "argon2id"
+    pub algorithm: String,
+    /// Память Argon2id в KiB (для PBKDF2 — None)
+    #[serde(default)]
+    pub memory_kib: Option<u32>,
+    pub iterations: u32,
+    /// Параллелизм Argon2id (для PBKDF2 — None)
+    #[serde(default)]
+    pub parallelism: Option<u32>,
+    pub version: u32,
+}
+
+impl KdfParams {
+    /// Исходная схема: PBKDF2-HMAC-SHA256, 100k итераций (аккаунты без записи KDF).
+    pub fn legacy_pbkdf2() -> Self {
+        KdfParams {
+            algorithm: KDF_ALGORITHM_PBKDF2.to_string(),
+            memory_kib: None,
+            iterations: 100_000,
+            parallelism: None,
+            version: 1,
+        }
+    }
+
+    /// Рекомендуемые параметры для новых аккаунтов и апгрейда: Argon2id 64 MiB / 3 / 4.
+    pub fn recommended() -> Self {
+        let defaults = Argon2Config::default();
+        KdfParams {
+            algorithm: KDF_ALGORITHM_ARGON2ID.to_string(),
+            memory_kib: Some(defaults.memory_kib),
+            iterations: defaults.iterations,
+            parallelism: Some(defaults.parallelism),
+            version: KDF_CURRENT_VERSION,
+        }
+    }
+
+    /// true, если мастер-ключ стоит перешифровать под `recommended()` при следующем входе.
+    pub fn needs_upgrade(&self) -> bool {
+        self.version < KDF_CURRENT_VERSION
+    }
+}
+
 /// P0-2: Identity key pair for Ed25519 signing (used for key authentication)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IdentityKeyPair {
@@ -95,6 +151,33 @@ pub struct EncryptedMessage {
     pub nonce: String,
 }
 
+/// Ключ устройства получателя (элемент ответа `GET /users/:id/devices`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceKey {
+    pub device_id: String,  // id устройства (не id сессии)
+    pub public_key: String, // X25519 (Base64, 32 bytes)
+}
+
+/// Ключ сообщения, обёрнутый для одного устройства; лежит в `envelopes`
+/// сообщения под `device_id`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceEnvelope {
+    pub key: String,
+    pub ephem_pub_key: String,
+    pub iv: String,
+}
+
+/// Конверт привязки устройства (`POST /users/me/device-links/:id/approve`).
+/// Сервер хранит его как непрозрачный JSON и отдаёт новому устройству.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceLinkEnvelope {
+    pub wrapped_key: String, // wrap_symmetric_key для ключа нового устройства
+    pub ephemeral_public_key: String,
+    pub nonce: String,
+    pub ciphertext: String, // encrypt_data(bundle) обёрнутым ключом
+    pub mac: String,        // HMAC-SHA256 ключом из секрета QR
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct EncryptedFile {
     pub ciphertext: String,
@@ -0,0 +1,551 @@
+//! Локальное зашифрованное хранилище сообщений (кэш + поиск) для всех клиентов.
+//!
+//! Сервер не видит тексты E2EE-сообщений, поэтому кэш и поиск живут на устройстве.
+//! Две реализации одного контракта [`MessageStore`]:
+//! - [`SqliteMessageStore`] (feature `store`) — файл SQLite для нативных платформ;
+//! - [`MemoryMessageStore`] — in-memory вариант для WASM, с зашифрованным снимком
+//!   для сохранения в IndexedDB на стороне JS.
+//!
+//! Шифрование на диске: в открытом виде хранятся только `id` и `chat_id` (нужны для
+//! выборок); остальное — ChaCha20-Poly1305 под ключом, деривированным из ключа хранилища.
+//! Полнотекстовый индекс строится по расшифрованному тексту, но хранит не слова, а
+//! токены слепого индекса (HMAC), поэтому сам индекс не раскрывает содержимое.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use crate::crypto::types::{AeadKey, CryptoError};
+use crate::crypto::{decrypt_data, encrypt_data, normalized_word_token, tokenize_for_search};
+
+/// Максимальное число сообщений в одной выборке.
+pub const MAX_STORE_LIMIT: usize = 500;
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("crypto error: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("storage error: {0}")]
+    Storage(String),
+}
+
+#[cfg(feature = "store")]
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Storage(e.to_string())
+    }
+}
+
+/// Сообщение в локальном кэше (уже расшифрованное клиентом). Ключ — `Message.id` сервера.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    pub created_at: String,
+    pub message_type: String,
+    /// Расшифрованный текст сообщения.
+    pub text: String,
+    /// Произвольные данные клиента (например, JSON метаданных файлов).
+    #[serde(default)]
+    pub extra: Option<String>,
+}
+
+/// Общий контракт хранилища: insert/get/query/search/remove/wipe.
+pub trait MessageStore {
+    /// Добавляет или заменяет сообщения (по `id`).
+    fn insert(&mut self, messages: &[StoredMessage]) -> Result<(), StoreError>;
+    fn get(&self, id: i64) -> Result<Option<StoredMessage>, StoreError>;
+    /// Сообщения чата от новых к старым; `before_id` — курсор пагинации.
+    fn query(
+        &self,
+        chat_id: i64,
+        before_id: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, StoreError>;
+    /// Поиск по словам (все слова должны встретиться); `chat_id = None` — по всем чатам.
+    fn search(
+        &self,
+        chat_id: Option<i64>,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, StoreError>;
+    fn remove(&mut self, ids: &[i64]) -> Result<(), StoreError>;
+    /// Полностью очищает хранилище (выход из аккаунта, отзыв устройства).
+    fn wipe(&mut self) -> Result<(), StoreError>;
+}
+
+/// Ключи хранилища: шифрование записей и слепой индекс.
+struct StoreKeys {
+    data: AeadKey,
+    index: AeadKey,
+}
+
+impl StoreKeys {
+    fn derive(master: &AeadKey) -> Result<Self, StoreError> {
+        Ok(StoreKeys {
+            data: derive_store_subkey(master, b"ren-sdk-store-data")?,
+            index: derive_store_subkey(master, b"ren-sdk-store-index")?,
+        })
+    }
+
+    fn seal(&self, msg: &StoredMessage) -> Result<String, StoreError> {
+        let mut json = serde_json::to_string(msg)?;
+        let sealed = encrypt_data(&json, &self.data);
+        json.zeroize();
+        Ok(sealed?)
+    }
+
+    fn open(&self, sealed: &str) -> Result<StoredMessage, StoreError> {
+        let mut json = decrypt_data(sealed, &self.data)?;
+        let msg = serde_json::from_str(&json);
+        json.zeroize();
+        Ok(msg?)
+    }
+
+    fn tokens(&self, text: &str) -> Result<Vec<String>, StoreError> {
+        tokenize_for_search(text)
+            .iter()
+            .map(|w| normalized_word_token(w, &self.index).map_err(StoreError::from))
+            .collect()
+    }
+}
+
+fn derive_store_subkey(master: &AeadKey, info: &[u8]) -> Result<AeadKey, StoreError> {
+    let mut ikm = master.to_bytes();
+    let hk = Hkdf::<Sha256>::new(Some(b"ren-sdk-store"), &ikm);
+    ikm.zeroize();
+    let mut out = [0u8; 32];
+    hk.expand(info, &mut out).map_err(|_| CryptoError::Aead)?;
+    let key = AeadKey::from_bytes(&out);
+    out.zeroize();
+    Ok(key?)
+}
+
+fn clamp_limit(limit: usize) -> usize {
+    limit.clamp(1, MAX_STORE_LIMIT)
+}
+
+// ============================================================================
+// In-memory (WASM)
+// ============================================================================
+
+/// In-memory хранилище. Для персистентности в браузере JS сохраняет
+/// [`MemoryMessageStore::export_snapshot`] (зашифрованный снимок) в IndexedDB.
+pub struct MemoryMessageStore {
+    keys: StoreKeys,
+    messages: BTreeMap<i64, StoredMessage>,
+    index: HashMap<String, HashSet<i64>>,
+}
+
+impl MemoryMessageStore {
+    pub fn new(key: &AeadKey) -> Result<Self, StoreError> {
+        Ok(MemoryMessageStore {
+            keys: StoreKeys::derive(key)?,
+            messages: BTreeMap::new(),
+            index: HashMap::new(),
+        })
+    }
+
+    /// Восстанавливает хранилище из снимка, созданного `export_snapshot` с тем же ключом.
+    pub fn from_snapshot(key: &AeadKey, snapshot: &str) -> Result<Self, StoreError> {
+        let mut store = Self::new(key)?;
+        let sealed: Vec<String> = serde_json::from_str(snapshot)?;
+        let messages = sealed
+            .iter()
+            .map(|s| store.keys.open(s))
+            .collect::<Result<Vec<_>, _>>()?;
+        store.insert(&messages)?;
+        Ok(store)
+    }
+
+    /// Зашифрованный снимок всех сообщений (JSON-массив зашифрованных записей).
+    pub fn export_snapshot(&self) -> Result<String, StoreError> {
+        let sealed = self
+            .messages
+            .values()
+            .map(|m| self.keys.seal(m))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(serde_json::to_string(&sealed)?)
+    }
+
+    fn unindex(&mut self, id: i64) {
+        self.index.retain(|_, ids| {
+            ids.remove(&id);
+            !ids.is_empty()
+        });
+    }
+}
+
+impl MessageStore for MemoryMessageStore {
+    fn insert(&mut self, messages: &[StoredMessage]) -> Result<(), StoreError> {
+        for msg in messages {
+            let tokens = self.keys.tokens(&msg.text)?;
+            if self.messages.contains_key(&msg.id) {
+                self.unindex(msg.id);
+            }
+            for token in tokens {
+                self.index.entry(token).or_default().insert(msg.id);
+            }
+            self.messages.insert(msg.id, msg.clone());
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: i64) -> Result<Option<StoredMessage>, StoreError> {
+        Ok(self.messages.get(&id).cloned())
+    }
+
+    fn query(
+        &self,
+        chat_id: i64,
+        before_id: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, StoreError> {
+        let upper = before_id.unwrap_or(i64::MAX);
+        Ok(self
+            .messages
+            .range(..upper)
+            .rev()
+            .map(|(_, m)| m)
+            .filter(|m| m.chat_id == chat_id)
+            .take(clamp_limit(limit))
+            .cloned()
+            .collect())
+    }
+
+    fn search(
+        &self,
+        chat_id: Option<i64>,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>, StoreError> {
+        let tokens = self.keys.tokens(query)?;
+        if tokens.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut ids: Option<HashSet<i64>> = None;
+        for token in &tokens {
+            let matched = self.index.get(token).cloned().unwrap_or_default();
+            ids = Some(match ids {
+                Some(acc) => acc.intersection(&matched).copied().collect(),
+                None => matched,
+            });
+        }
+        let mut ids: Vec<i64> = ids.unwrap_or_default().into_iter().collect();
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| self.messages.get(&id))
+            .filter(|m| chat_id.is_none_or(|c| m.chat_id == c))
+            .take(clamp_limit(limit))
+            .cloned()
+            .collect())
+    }
+
+    fn remove(&mut self, ids: &[i64]) -> Result<(), StoreError> {
+        for id in ids {
+            if self.messages.remove(id).is_some() {
+                self.unindex(*id);
+            }
+        }
+        Ok(())
+    }
+
+    fn wipe(&mut self) -> Result<(), StoreError> {
+        self.messages.clear();
+        self.index.clear();
+        Ok(())
+    }
+}
+
+// ============================================================================
+// SQLite (нативные платформы)
+// ============================================================================
+
+#[cfg(feature = "store")]
+pub use sqlite::SqliteMessageStore;
+
+#[cfg(feature = "store")]
+mod sqlite {
+    use rusqlite::{params, Connection, OptionalExtension};
+
+    use super::{clamp_limit, MessageStore, StoreError, StoreKeys, StoredMessage};
+    use crate::crypto::types::AeadKey;
+
+    const SCHEMA: &str = r#"
+        CREATE TABLE IF NOT EXISTS messages (
+            id INTEGER PRIMARY KEY,
+            chat_id INTEGER NOT NULL,
+            payload TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_messages_chat_id ON messages(chat_id, id DESC);
+        CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+            tokens,
+            tokenize = "unicode61 tokenchars '-_'"
+        );
+    "#;
+
+    /// Хранилище на SQLite. `path = ":memory:"` — временная БД (тесты).
+    pub struct SqliteMessageStore {
+        conn: Connection,
+        keys: StoreKeys,
+    }
+
+    impl SqliteMessageStore {
+        pub fn open(path: &str, key: &AeadKey) -> Result<Self, StoreError> {
+            let conn = Connection::open(path)?;
+            conn.execute_batch(SCHEMA)?;
+            Ok(SqliteMessageStore {
+                conn,
+                keys: StoreKeys::derive(key)?,
+            })
+        }
+
+        fn load(
+            &self,
+            sql: &str,
+            params: &[&dyn rusqlite::ToSql],
+        ) -> Result<Vec<StoredMessage>, StoreError> {
+            let mut stmt = self.conn.prepare(sql)?;
+            let sealed = stmt
+                .query_map(params, |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            sealed.iter().map(|s| self.keys.open(s)).collect()
+        }
+    }
+
+    impl MessageStore for SqliteMessageStore {
+        fn insert(&mut self, messages: &[StoredMessage]) -> Result<(), StoreError> {
+            let tx = self.conn.transaction()?;
+            for msg in messages {
+                let payload = self.keys.seal(msg)?;
+                let tokens = self.keys.tokens(&msg.text)?.join(" ");
+                tx.execute(
+                    "INSERT OR REPLACE INTO messages (id, chat_id, payload) VALUES (?1, ?2, ?3)",
+                    params![msg.id, msg.chat_id, payload],
+                )?;
+                tx.execute("DELETE FROM messages_fts WHERE rowid = ?1", params![msg.id])?;
+                tx.execute(
+                    "INSERT INTO messages_fts (rowid, tokens) VALUES (?1, ?2)",
+                    params![msg.id, tokens],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        }
+
+        fn get(&self, id: i64) -> Result<Option<StoredMessage>, StoreError> {
+            let sealed: Option<String> = self
+                .conn
+                .query_row(
+                    "SELECT payload FROM messages WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            sealed.map(|s| self.keys.open(&s)).transpose()
+        }
+
+        fn query(
+            &self,
+            chat_id: i64,
+            before_id: Option<i64>,
+            limit: usize,
+        ) -> Result<Vec<StoredMessage>, StoreError> {
+            self.load(
+                "SELECT payload FROM messages
+                 WHERE chat_id = ?1 AND (?2 IS NULL OR id < ?2)
+                 ORDER BY id DESC
+                 LIMIT ?3",
+                &[&chat_id, &before_id, &(clamp_limit(limit) as i64)],
+            )
+        }
+
+        fn search(
+            &self,
+            chat_id: Option<i64>,
+            query: &str,
+            limit: usize,
+        ) -> Result<Vec<StoredMessage>, StoreError> {
+            let tokens = self.keys.tokens(query)?;
+            if tokens.is_empty() {
+                return Ok(Vec::new());
+            }
+            // Токены — base64url, кавычки внутри невозможны.
+            let fts_query = tokens
+                .iter()
+                .map(|t| format!("\"{}\"", t))
+                .collect::<Vec<_>>()
+                .join(" AND ");
+            self.load(
+                "SELECT m.payload FROM messages_fts f
+                 JOIN messages m ON m.id = f.rowid
+                 WHERE messages_fts MATCH ?1 AND (?2 IS NULL OR m.chat_id = ?2)
+                 ORDER BY m.id DESC
+                 LIMIT ?3",
+                &[&fts_query, &chat_id, &(clamp_limit(limit) as i64)],
+            )
+        }
+
+        fn remove(&mut self, ids: &[i64]) -> Result<(), StoreError> {
+            let tx = self.conn.transaction()?;
+            for id in ids {
+                tx.execute("DELETE FROM messages WHERE id = ?1", params![id])?;
+                tx.execute("DELETE FROM messages_fts WHERE rowid = ?1", params![id])?;
+            }
+            tx.commit()?;
+            Ok(())
+        }
+
+        fn wipe(&mut self) -> Result<(), StoreError> {
+            self.conn
+                .execute_batch("DELETE FROM messages; DELETE FROM messages_fts; VACUUM;")?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_message_encryption_key;
+
+    fn msg(id: i64, chat_id: i64, text: &str) -> StoredMessage {
+        StoredMessage {
+            id,
+            chat_id,
+            sender_id: 7,
+            created_at: format!("2026-01-01T00:00:{:02}Z", id % 60),
+            message_type: "text".to_string(),
+            text: text.to_string(),
+            extra: None,
+        }
+    }
+
+    // Один набор проверок для обеих реализаций, чтобы они не расходились.
+    fn exercise(store: &mut dyn MessageStore) {
+        store
+            .insert(&[
+                msg(1, 10, "Привет, как дела?"),
+                msg(2, 10, "Встречаемся завтра у метро"),
+                msg(3, 20, "Завтра дедлайн по проекту"),
+                msg(4, 10, "Hello world"),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            store.get(2).unwrap().unwrap().text,
+            "Встречаемся завтра у метро"
+        );
+        assert!(store.get(99).unwrap().is_none());
+
+        let page: Vec<i64> = store
+            .query(10, None, 2)
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(page, vec![4, 2]);
+        let next: Vec<i64> = store
+            .query(10, Some(2), 10)
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(next, vec![1]);
+
+        let found: Vec<i64> = store
+            .search(None, "ЗАВТРА", 10)
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(found, vec![3, 2]);
+        let in_chat: Vec<i64> = store
+            .search(Some(10), "завтра", 10)
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(in_chat, vec![2]);
+        assert!(store.search(None, "завтра hello", 10).unwrap().is_empty());
+        assert!(store.search(None, "", 10).unwrap().is_empty());
+
+        // Повторная вставка заменяет текст и индекс
+        store.insert(&[msg(2, 10, "Планы изменились")]).unwrap();
+        assert!(store.search(Some(10), "завтра", 10).unwrap().is_empty());
+        assert_eq!(store.search(None, "планы", 10).unwrap()[0].id, 2);
+
+        store.remove(&[4]).unwrap();
+        assert!(store.get(4).unwrap().is_none());
+        assert!(store.search(None, "hello", 10).unwrap().is_empty());
+
+        store.wipe().unwrap();
+        assert!(store.query(10, None, 10).unwrap().is_empty());
+        assert!(store.search(None, "дедлайн", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_store_contract() {
+        let key = generate_message_encryption_key();
+        let mut store = MemoryMessageStore::new(&key).unwrap();
+        exercise(&mut store);
+    }
+
+    #[test]
+    fn memory_snapshot_roundtrip_requires_same_key() {
+        let key = generate_message_encryption_key();
+        let mut store = MemoryMessageStore::new(&key).unwrap();
+        store.insert(&[msg(1, 10, "секретный текст")]).unwrap();
+
+        let snapshot = store.export_snapshot().unwrap();
+        assert!(!snapshot.contains("секретный"));
+
+        let restored = MemoryMessageStore::from_snapshot(&key, &snapshot).unwrap();
+        assert_eq!(restored.search(None, "секретный", 10).unwrap().len(), 1);
+
+        let other = generate_message_encryption_key();
+        assert!(MemoryMessageStore::from_snapshot(&other, &snapshot).is_err());
+    }
+
+    #[cfg(feature = "store")]
+    #[test]
+    fn sqlite_store_contract() {
+        let key = generate_message_encryption_key();
+        let mut store = SqliteMessageStore::open(":memory:", &key).unwrap();
+        exercise(&mut store);
+    }
+
+    #[cfg(feature = "store")]
+    #[test]
+    fn sqlite_store_is_encrypted_at_rest() {
+        let dir = std::env::temp_dir().join(format!("ren-store-{}", std::process::id()));
+        let path = dir.to_string_lossy().to_string();
+        let key = generate_message_encryption_key();
+        {
+            let mut store = SqliteMessageStore::open(&path, &key).unwrap();
+            store.insert(&[msg(1, 10, "совершенно секретно")]).unwrap();
+        }
+
+        let raw = std::fs::read(&dir).unwrap();
+        let needle = "секретно".as_bytes();
+        assert!(!raw.windows(needle.len()).any(|w| w == needle));
+
+        let store = SqliteMessageStore::open(&path, &key).unwrap();
+        assert_eq!(store.search(None, "секретно", 10).unwrap().len(), 1);
+
+        let other = generate_message_encryption_key();
+        let foreign = SqliteMessageStore::open(&path, &other).unwrap();
+        assert!(foreign.get(1).is_err());
+        assert!(foreign.search(None, "секретно", 10).unwrap().is_empty());
+
+        let _ = std::fs::remove_file(&dir);
+    }
+}
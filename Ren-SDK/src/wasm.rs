@@ -3,12 +3,18 @@ use serde::{Deserialize, Serialize};
 use serde_wasm_bindgen::to_value;
 use wasm_bindgen::prelude::*;
 
-use crate::crypto::types::AeadKey;
+use crate::crypto::types::{AeadKey, DeviceKey, DeviceLinkEnvelope, KdfParams};
+use crate::store::{MemoryMessageStore, MessageStore, StoreError, StoredMessage};
 use crate::crypto::{
     decrypt_data, decrypt_file, decrypt_file_with_message, decrypt_message,
     derive_key_from_password, derive_key_from_string, encrypt_data, encrypt_file,
     encrypt_file_with_message, encrypt_message, generate_key_pair, generate_message_encryption_key,
     generate_nonce, generate_salt, unwrap_symmetric_key, wrap_symmetric_key,
+    wrap_symmetric_key_for_devices,
+    blind_index_token, blind_index_tokens, derive_chat_search_key,
+    recovery_auth_public_key, sign_recovery_challenge, derive_master_key, passkey_prf_key,
+    generate_device_link_secret, device_link_key_mac, verify_device_link_key_mac,
+    seal_device_link_bundle, open_device_link_bundle,
 };
 
 // ============================================================================
@@ -180,6 +186,29 @@ pub fn wasm_derive_key_from_password(password: &str, salt_b64: &str) -> Result<S
         .map_err(|e| JsValue::from_str(&format!("{}", e)))
 }
 
+#[wasm_bindgen(js_name = deriveMasterKey)]
+pub fn wasm_derive_master_key(
+    password: &str,
+    salt_b64: &str,
+    params: JsValue,
+) -> Result<String, JsValue> {
+    let params: KdfParams = serde_wasm_bindgen::from_value(params)?;
+    derive_master_key(password, salt_b64, &params)
+        .map(|key| general_purpose::STANDARD.encode(key.to_bytes()))
+        .map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+#[wasm_bindgen(js_name = recommendedKdfParams)]
+pub fn wasm_recommended_kdf_params() -> Result<JsValue, JsValue> {
+    Ok(to_value(&KdfParams::recommended())?)
+}
+
+#[wasm_bindgen(js_name = kdfNeedsUpgrade)]
+pub fn wasm_kdf_needs_upgrade(params: JsValue) -> Result<bool, JsValue> {
+    let params: KdfParams = serde_wasm_bindgen::from_value(params)?;
+    Ok(params.needs_upgrade())
+}
+
 #[wasm_bindgen(js_name = deriveKeyFromString)]
 pub fn wasm_derive_key_from_string(secret: &str) -> Result<String, JsValue> {
     derive_key_from_string(secret)
@@ -377,3 +406,206 @@ pub fn wasm_unwrap_symmetric_key(
     })
     .map_err(|e| JsValue::from_str(&format!("{}", e)))
 }
+
+/// `devices` — массив `{device_id, public_key}`; результат — объект `envelopes`
+/// сообщения `{device_id: {key, ephem_pub_key, iv}}`.
+#[wasm_bindgen(js_name = wrapSymmetricKeyForDevices)]
+pub fn wasm_wrap_symmetric_key_for_devices(
+    key_b64: &str,
+    devices: JsValue,
+) -> Result<JsValue, JsValue> {
+    let key = aead_key_from_b64(key_b64)?;
+    let devices: Vec<DeviceKey> = serde_wasm_bindgen::from_value(devices)?;
+    let envelopes = wrap_symmetric_key_for_devices(&key, &devices)
+        .map_err(|e| JsValue::from_str(&format!("{}", e)))?;
+    Ok(to_value(&envelopes)?)
+}
+
+// ============================================================================
+// Слепой индекс (поиск по зашифрованным сообщениям)
+// ============================================================================
+
+fn aead_key_from_b64(key_b64: &str) -> Result<AeadKey, JsValue> {
+    let key_bytes = general_purpose::STANDARD
+        .decode(key_b64)
+        .map_err(|e| JsValue::from_str(&format!("{}", e)))?;
+    AeadKey::from_bytes(&key_bytes).map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+#[wasm_bindgen(js_name = deriveChatSearchKey)]
+pub fn wasm_derive_chat_search_key(secret_b64: &str, chat_id: i64) -> Result<String, JsValue> {
+    let secret = aead_key_from_b64(secret_b64)?;
+    derive_chat_search_key(&secret, chat_id)
+        .map(|key| general_purpose::STANDARD.encode(key.to_bytes()))
+        .map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+#[wasm_bindgen(js_name = blindIndexTokens)]
+pub fn wasm_blind_index_tokens(text: &str, search_key_b64: &str) -> Result<Vec<String>, JsValue> {
+    let key = aead_key_from_b64(search_key_b64)?;
+    blind_index_tokens(text, &key).map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+#[wasm_bindgen(js_name = blindIndexToken)]
+pub fn wasm_blind_index_token(word: &str, search_key_b64: &str) -> Result<String, JsValue> {
+    let key = aead_key_from_b64(search_key_b64)?;
+    blind_index_token(word, &key).map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+// ============================================================================
+// Восстановление аккаунта (challenge-response)
+// ============================================================================
+
+#[wasm_bindgen(js_name = recoveryAuthPublicKey)]
+pub fn wasm_recovery_auth_public_key(recovery_key_b64: &str) -> Result<String, JsValue> {
+    let key = aead_key_from_b64(recovery_key_b64)?;
+    recovery_auth_public_key(&key).map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+#[wasm_bindgen(js_name = signRecoveryChallenge)]
+pub fn wasm_sign_recovery_challenge(
+    recovery_key_b64: &str,
+    challenge_id: &str,
+    challenge_b64: &str,
+) -> Result<String, JsValue> {
+    let key = aead_key_from_b64(recovery_key_b64)?;
+    sign_recovery_challenge(&key, challenge_id, challenge_b64)
+        .map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+// ============================================================================
+// Passkey (WebAuthn PRF)
+// ============================================================================
+
+#[wasm_bindgen(js_name = passkeyPrfKey)]
+pub fn wasm_passkey_prf_key(prf_output_b64: &str) -> Result<String, JsValue> {
+    passkey_prf_key(prf_output_b64)
+        .map(|key| general_purpose::STANDARD.encode(key.to_bytes()))
+        .map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+// ============================================================================
+// Привязка устройства (QR)
+// ============================================================================
+
+#[wasm_bindgen(js_name = generateDeviceLinkSecret)]
+pub fn wasm_generate_device_link_secret() -> String {
+    generate_device_link_secret()
+}
+
+#[wasm_bindgen(js_name = deviceLinkKeyMac)]
+pub fn wasm_device_link_key_mac(
+    link_secret_b64: &str,
+    public_key_b64: &str,
+) -> Result<String, JsValue> {
+    device_link_key_mac(link_secret_b64, public_key_b64)
+        .map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+#[wasm_bindgen(js_name = verifyDeviceLinkKeyMac)]
+pub fn wasm_verify_device_link_key_mac(
+    link_secret_b64: &str,
+    public_key_b64: &str,
+    mac_b64: &str,
+) -> Result<bool, JsValue> {
+    verify_device_link_key_mac(link_secret_b64, public_key_b64, mac_b64)
+        .map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+#[wasm_bindgen(js_name = sealDeviceLinkBundle)]
+pub fn wasm_seal_device_link_bundle(
+    bundle: &str,
+    link_secret_b64: &str,
+    receiver_public_key_b64: &str,
+) -> Result<JsValue, JsValue> {
+    let envelope = seal_device_link_bundle(bundle, link_secret_b64, receiver_public_key_b64)
+        .map_err(|e| JsValue::from_str(&format!("{}", e)))?;
+    Ok(to_value(&envelope)?)
+}
+
+#[wasm_bindgen(js_name = openDeviceLinkBundle)]
+pub fn wasm_open_device_link_bundle(
+    envelope: JsValue,
+    link_secret_b64: &str,
+    receiver_private_key_b64: &str,
+) -> Result<String, JsValue> {
+    let envelope: DeviceLinkEnvelope = serde_wasm_bindgen::from_value(envelope)?;
+    open_device_link_bundle(&envelope, link_secret_b64, receiver_private_key_b64)
+        .map_err(|e| JsValue::from_str(&format!("{}", e)))
+}
+
+// ============================================================================
+// Локальное хранилище сообщений (in-memory, снимок — для IndexedDB)
+// ============================================================================
+
+#[wasm_bindgen(js_name = MessageStore)]
+pub struct WasmMessageStore {
+    inner: MemoryMessageStore,
+}
+
+fn store_err(e: StoreError) -> JsValue {
+    JsValue::from_str(&format!("{}", e))
+}
+
+#[wasm_bindgen(js_class = MessageStore)]
+impl WasmMessageStore {
+    #[wasm_bindgen(constructor)]
+    pub fn new(key_b64: &str) -> Result<WasmMessageStore, JsValue> {
+        let key = aead_key_from_b64(key_b64)?;
+        let inner = MemoryMessageStore::new(&key).map_err(store_err)?;
+        Ok(WasmMessageStore { inner })
+    }
+
+    /// Восстанавливает хранилище из зашифрованного снимка (`exportSnapshot`).
+    #[wasm_bindgen(js_name = fromSnapshot)]
+    pub fn from_snapshot(key_b64: &str, snapshot: &str) -> Result<WasmMessageStore, JsValue> {
+        let key = aead_key_from_b64(key_b64)?;
+        let inner = MemoryMessageStore::from_snapshot(&key, snapshot).map_err(store_err)?;
+        Ok(WasmMessageStore { inner })
+    }
+
+    /// Зашифрованный снимок для сохранения в IndexedDB.
+    #[wasm_bindgen(js_name = exportSnapshot)]
+    pub fn export_snapshot(&self) -> Result<String, JsValue> {
+        self.inner.export_snapshot().map_err(store_err)
+    }
+
+    pub fn insert(&mut self, messages: JsValue) -> Result<(), JsValue> {
+        let messages: Vec<StoredMessage> = serde_wasm_bindgen::from_value(messages)?;
+        self.inner.insert(&messages).map_err(store_err)
+    }
+
+    pub fn query(
+        &self,
+        chat_id: i64,
+        before_id: Option<i64>,
+        limit: usize,
+    ) -> Result<JsValue, JsValue> {
+        let messages = self
+            .inner
+            .query(chat_id, before_id, limit)
+            .map_err(store_err)?;
+        Ok(to_value(&messages)?)
+    }
+
+    pub fn search(
+        &self,
+        chat_id: Option<i64>,
+        query: &str,
+        limit: usize,
+    ) -> Result<JsValue, JsValue> {
+        let messages = self
+            .inner
+            .search(chat_id, query, limit)
+            .map_err(store_err)?;
+        Ok(to_value(&messages)?)
+    }
+
+    pub fn remove(&mut self, ids: Vec<i64>) -> Result<(), JsValue> {
+        self.inner.remove(&ids).map_err(store_err)
+    }
+
+    pub fn wipe(&mut self) -> Result<(), JsValue> {
+        self.inner.wipe().map_err(store_err)
+    }
+}
@@ -0,0 +1,10 @@
+-- Сообщения, скрытые пользователем только у себя ("удалить у меня")
+
+CREATE TABLE IF NOT EXISTS message_hidden (
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  hidden_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, message_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_hidden_message ON message_hidden(message_id);
@@ -0,0 +1,17 @@
+-- Слепой индекс для поиска по E2EE-сообщениям.
+-- Токены — HMAC(ключ чата конкретного пользователя, слово), сервер видит только непрозрачные строки.
+-- Каждый пользователь индексирует сообщения своим ключом, поэтому токены привязаны к user_id.
+
+CREATE TABLE IF NOT EXISTS message_search_tokens (
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  chat_id INTEGER NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  token TEXT NOT NULL,
+  PRIMARY KEY (user_id, message_id, token)
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_search_tokens_lookup
+  ON message_search_tokens(user_id, chat_id, token, message_id DESC);
+
+CREATE INDEX IF NOT EXISTS idx_message_search_tokens_message
+  ON message_search_tokens(message_id);
@@ -0,0 +1,28 @@
+-- Восстановление аккаунта по ключу восстановления (challenge-response)
+
+-- Ed25519-ключ, выведенный клиентом из ключа восстановления (Base64, 32 байта).
+-- Сервер проверяет им подпись вызова; сам ключ восстановления на сервер не передаётся.
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS recovery_pubk TEXT;
+
+CREATE TABLE IF NOT EXISTS recovery_challenges (
+  id UUID PRIMARY KEY,
+  -- NULL для несуществующего логина: ответ не должен раскрывать наличие аккаунта
+  user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+  challenge TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  expires_at TIMESTAMPTZ NOT NULL,
+  -- Попытка проверки подписи (успешная или нет) — вызов одноразовый
+  attempted_at TIMESTAMPTZ,
+  -- Выдаётся после успешной проверки, хранится только хеш
+  reset_token_hash TEXT,
+  reset_expires_at TIMESTAMPTZ,
+  used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_recovery_challenges_user ON recovery_challenges(user_id);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_recovery_challenges_reset_token
+  ON recovery_challenges(reset_token_hash)
+  WHERE reset_token_hash IS NOT NULL;
+
+COMMENT ON COLUMN users.recovery_pubk IS 'Ed25519 public key derived from the recovery key, used for recovery challenge-response';
@@ -0,0 +1,7 @@
+-- Версионируемые параметры KDF мастер-ключа (которым зашифрован pkebymk).
+-- Существующие аккаунты — PBKDF2-HMAC-SHA256 (100k), клиент перешифрует их под Argon2id при входе.
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS kdf_params JSONB NOT NULL
+  DEFAULT '{"algorithm": "pbkdf2-sha256", "iterations": 100000, "version": 1}'::JSONB;
+
+COMMENT ON COLUMN users.kdf_params IS 'Master key KDF parameters: {algorithm, memory_kib, iterations, parallelism, version}';
@@ -0,0 +1,38 @@
+-- Двухфакторная аутентификация (TOTP, RFC 6238)
+
+-- Секрет выдаётся при enroll и становится активным только после подтверждения
+-- первым кодом (confirmed_at IS NOT NULL).
+CREATE TABLE IF NOT EXISTS user_totp (
+  user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  secret TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  confirmed_at TIMESTAMPTZ,
+  -- Последний принятый временной шаг: защита от повторного использования кода
+  last_used_step BIGINT
+);
+
+-- Одноразовые резервные коды, хранятся только хеши
+CREATE TABLE IF NOT EXISTS totp_backup_codes (
+  id SERIAL PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  code_hash TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_totp_backup_codes_user ON totp_backup_codes(user_id);
+
+-- Промежуточный (pre-auth) токен между проверкой пароля и второго фактора.
+-- Строка в auth_sessions создаётся только после успешного второго шага.
+CREATE TABLE IF NOT EXISTS login_challenges (
+  id UUID PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  token_hash TEXT NOT NULL UNIQUE,
+  remember_me BOOLEAN NOT NULL DEFAULT FALSE,
+  failed_attempts INTEGER NOT NULL DEFAULT 0,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  expires_at TIMESTAMPTZ NOT NULL,
+  used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_challenges_user ON login_challenges(user_id);
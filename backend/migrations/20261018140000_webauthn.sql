@@ -0,0 +1,40 @@
+-- Вход по WebAuthn/passkey
+
+CREATE TABLE IF NOT EXISTS webauthn_credentials (
+  id SERIAL PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  -- Идентификатор credential (Base64url без паддинга), уникален глобально
+  credential_id TEXT NOT NULL UNIQUE,
+  -- COSE-алгоритм (-7 = ES256, -8 = EdDSA) и публичный ключ:
+  -- ES256 — SEC1 uncompressed point, EdDSA — 32 байта (Base64)
+  alg INTEGER NOT NULL,
+  public_key TEXT NOT NULL,
+  sign_count BIGINT NOT NULL DEFAULT 0,
+  name TEXT NOT NULL,
+  -- Приватный ключ, зашифрованный ключом из PRF-расширения этого credential.
+  -- Позволяет расшифровать E2EE-ключи без пароля.
+  pkebyprf TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_webauthn_credentials_user ON webauthn_credentials(user_id);
+
+-- Одноразовые вызовы для церемоний регистрации и входа
+CREATE TABLE IF NOT EXISTS webauthn_challenges (
+  id UUID PRIMARY KEY,
+  -- NULL для входа без логина (discoverable credential)
+  user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+  kind TEXT NOT NULL CHECK (kind IN ('register', 'login')),
+  challenge TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  expires_at TIMESTAMPTZ NOT NULL,
+  used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_webauthn_challenges_user ON webauthn_challenges(user_id);
+
+-- Каким passkey была открыта сессия (для списка устройств)
+ALTER TABLE auth_sessions
+  ADD COLUMN IF NOT EXISTS webauthn_credential_id INTEGER
+    REFERENCES webauthn_credentials(id) ON DELETE SET NULL;
@@ -0,0 +1,28 @@
+-- Привязка нового устройства по QR-коду с уже авторизованной сессии.
+-- Сервер только ретранслирует открытый ключ нового устройства и зашифрованный
+-- для него конверт с приватным ключом; сам ключ сервер не видит.
+
+CREATE TABLE IF NOT EXISTS device_links (
+  id UUID PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  -- Сессия, показавшая QR-код; при её отзыве привязка отменяется
+  initiator_session_id UUID NOT NULL REFERENCES auth_sessions(id) ON DELETE CASCADE,
+  -- Одноразовый код из QR (хранится только хеш)
+  code_hash TEXT NOT NULL,
+  failed_attempts INTEGER NOT NULL DEFAULT 0,
+  -- Заполняется новым устройством при сканировании
+  claim_token_hash TEXT UNIQUE,
+  claimant_pubk TEXT,
+  claimant_key_mac TEXT,
+  device_name TEXT,
+  -- Конверт с приватным ключом (JSON от клиента), удаляется после выдачи
+  envelope JSONB,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  expires_at TIMESTAMPTZ NOT NULL,
+  claimed_at TIMESTAMPTZ,
+  approved_at TIMESTAMPTZ,
+  completed_at TIMESTAMPTZ,
+  cancelled_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_device_links_user ON device_links(user_id);
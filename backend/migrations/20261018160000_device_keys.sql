@@ -0,0 +1,11 @@
+-- Собственный X25519-ключ каждого устройства (сессии).
+-- Конверты сообщений адресуются устройствам, поэтому отозванная сессия
+-- перестаёт получать ключи новых сообщений.
+
+ALTER TABLE auth_sessions
+  ADD COLUMN IF NOT EXISTS device_pubk TEXT,
+  ADD COLUMN IF NOT EXISTS device_key_registered_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS idx_auth_sessions_device_keys
+  ON auth_sessions(user_id)
+  WHERE device_pubk IS NOT NULL AND revoked_at IS NULL;
@@ -0,0 +1,14 @@
+-- Отдельный id устройства для адресации конвертов: GET /users/:id/devices
+-- отдаётся собеседникам, и id сессии (по нему отзывают сессию и обновляют
+-- токены) наружу попадать не должен.
+
+ALTER TABLE auth_sessions
+  ADD COLUMN IF NOT EXISTS device_id UUID;
+
+UPDATE auth_sessions
+SET device_id = gen_random_uuid()
+WHERE device_pubk IS NOT NULL AND device_id IS NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_auth_sessions_device_id
+  ON auth_sessions(device_id)
+  WHERE device_id IS NOT NULL;
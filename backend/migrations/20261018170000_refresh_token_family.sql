@@ -0,0 +1,17 @@
+-- Семейство refresh-токенов сессии: хеши всех уже заменённых токенов.
+-- Предъявление заменённого токена означает, что он утёк (его использовал
+-- кто-то кроме владельца), и вся сессия отзывается.
+
+CREATE TABLE IF NOT EXISTS auth_session_refresh_tokens (
+  token_hash TEXT PRIMARY KEY,
+  session_id UUID NOT NULL REFERENCES auth_sessions(id) ON DELETE CASCADE,
+  issued_at TIMESTAMPTZ NOT NULL,
+  superseded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_session_refresh_tokens_session
+  ON auth_session_refresh_tokens(session_id);
+
+-- Когда был выдан текущий refresh-токен сессии
+ALTER TABLE auth_sessions
+  ADD COLUMN IF NOT EXISTS refresh_issued_at TIMESTAMPTZ NOT NULL DEFAULT now();
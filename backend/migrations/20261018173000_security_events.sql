@@ -0,0 +1,13 @@
+-- Журнал событий безопасности аккаунта
+CREATE TABLE IF NOT EXISTS security_events (
+  id BIGSERIAL PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  session_id UUID,
+  kind TEXT NOT NULL,
+  ip_address TEXT,
+  city TEXT,
+  details JSONB,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_security_events_user ON security_events(user_id, id DESC);
@@ -0,0 +1,20 @@
+-- Общие счётчики rate limit для нескольких инстансов backend (RATE_LIMIT_STORE=postgres).
+-- Таблицы UNLOGGED: после сбоя Postgres счётчики просто начинаются заново.
+
+-- Скользящее окно: число запросов ключа в каждом окне фиксированной длины
+CREATE UNLOGGED TABLE IF NOT EXISTS rate_limit_windows (
+  key TEXT NOT NULL,
+  window_start TIMESTAMPTZ NOT NULL,
+  hits INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (key, window_start)
+);
+
+CREATE INDEX IF NOT EXISTS idx_rate_limit_windows_start ON rate_limit_windows(window_start);
+
+-- Неудачные попытки входа и блокировки (AuthRateLimiter)
+CREATE UNLOGGED TABLE IF NOT EXISTS rate_limit_failures (
+  key TEXT PRIMARY KEY,
+  failures INTEGER NOT NULL DEFAULT 0,
+  lockout_until TIMESTAMPTZ,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,25 @@
+-- Администрирование сервера.
+-- server_role — роль на уровне инстанса, не связана с ролями участников чатов
+-- (chat_participants.role). Первого администратора назначают из CLI:
+-- `backend admin grant <login>`.
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS server_role TEXT NOT NULL DEFAULT 'user'
+    CHECK (server_role IN ('user', 'admin'));
+
+-- Заблокированный пользователь не может войти, его сессии отозваны
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS suspended_at TIMESTAMPTZ,
+  ADD COLUMN IF NOT EXISTS suspended_reason TEXT;
+
+-- Журнал действий администраторов. admin_id NULL — действие из CLI
+CREATE TABLE IF NOT EXISTS admin_audit_log (
+  id BIGSERIAL PRIMARY KEY,
+  admin_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+  action TEXT NOT NULL,
+  target_user_id INTEGER,
+  target_chat_id INTEGER,
+  details JSONB,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_admin_audit_log_created ON admin_audit_log(created_at DESC);
@@ -0,0 +1,24 @@
+-- Приглашения для режимов регистрации invite / approval (registration.mode).
+-- Код хранится только хэшем, как refresh-токены; показывается один раз при создании.
+-- created_by NULL — приглашение создано из CLI или его автор удалён
+CREATE TABLE IF NOT EXISTS invite_codes (
+  id BIGSERIAL PRIMARY KEY,
+  code_hash TEXT NOT NULL UNIQUE,
+  created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+  max_uses INTEGER NOT NULL CHECK (max_uses > 0),
+  uses INTEGER NOT NULL DEFAULT 0,
+  expires_at TIMESTAMPTZ NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_invite_codes_created_by ON invite_codes(created_by);
+
+-- Кто кого пригласил и по какому коду
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS invited_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+  ADD COLUMN IF NOT EXISTS invite_code_id BIGINT REFERENCES invite_codes(id) ON DELETE SET NULL;
+
+-- Режим approval: аккаунт создан, но войти нельзя до подтверждения администратором
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS pending_approval BOOLEAN NOT NULL DEFAULT FALSE;
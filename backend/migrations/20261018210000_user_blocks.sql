@@ -0,0 +1,16 @@
+-- Блокировка пользователей: blocked_id не может создать private-чат с blocker_id,
+-- писать в существующий, видеть его присутствие и набор текста, добавлять в группы
+CREATE TABLE IF NOT EXISTS user_blocks (
+  blocker_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  blocked_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (blocker_id, blocked_id),
+  CHECK (blocker_id <> blocked_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_blocks_blocked ON user_blocks(blocked_id);
+
+-- Кто находит пользователя в /users/search: все, собеседники по private-чатам, никто
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS search_visibility TEXT NOT NULL DEFAULT 'everyone'
+    CHECK (search_visibility IN ('everyone', 'contacts', 'nobody'));
@@ -0,0 +1,283 @@
+use serde_json::json;
+use sqlx::{PgPool, postgres::PgPoolOptions};
+
+use crate::config::Config;
+use crate::route::admin::{
+    ACTION_CHAT_TAKEN_DOWN, ACTION_ROLE_CHANGED, ACTION_SESSIONS_REVOKED, ACTION_USER_APPROVED,
+    ACTION_USER_DELETED, ACTION_USER_SUSPENDED, ACTION_USER_UNSUSPENDED, SERVER_ROLE_ADMIN,
+    SERVER_ROLE_USER, approve_user, find_user_id, instance_stats, record_admin_action,
+    revoke_all_sessions, set_server_role, suspend_user, take_down_chat, unsuspend_user,
+};
+use crate::route::invites::{create_invite_code, invite_limits};
+use crate::route::users::delete_user;
+
+// `backend admin <команда>` — операции администратора напрямую с БД, без запущенного
+// сервера (например, назначить первого администратора). Конфигурация та же, что
+// у сервера (config.toml + окружение), миграции применяются перед командой.
+//
+// Запущенные инстансы узнают об отзыве сессий не позже чем через минуту
+// (кэш CurrentUser); открытые websocket-соединения живут до переподключения.
+const USAGE: &str = "\
+Использование: backend admin <команда>
+
+  grant <login|id>               назначить администратором сервера
+  revoke <login|id>              снять роль администратора
+  list                           администраторы сервера
+  suspend <login|id> [причина]   заблокировать и отозвать сессии
+  unsuspend <login|id>           снять блокировку
+  approve <login|id>             подтвердить регистрацию
+  invite [использований] [дней]  создать код приглашения
+  logout <login|id>              отозвать все сессии пользователя
+  delete <login|id>              удалить аккаунт
+  takedown <chat_id> [причина]   удалить чат вместе с вложениями
+  stats                          сводка по инстансу";
+
+// Код выхода: 0 — успех, 1 — ошибка выполнения, 2 — неверные аргументы
+pub async fn run(args: &[String]) -> i32 {
+    let Some((command, rest)) = args.split_first() else {
+        eprintln!("{}", USAGE);
+        return 2;
+    };
+    if matches!(command.as_str(), "help" | "-h" | "--help") {
+        println!("{}", USAGE);
+        return 0;
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let pool = match PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&config.database.url())
+        .await
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("Не удалось подключиться к базе данных: {}", e);
+            return 1;
+        }
+    };
+    if let Err(e) = crate::MIGRATOR.run(&pool).await {
+        eprintln!("Не удалось выполнить миграции базы данных: {}", e);
+        return 1;
+    }
+
+    let result = run_command(&pool, &config, command, rest).await;
+    pool.close().await;
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            0
+        }
+        Err(CliError::Usage) => {
+            eprintln!("{}", USAGE);
+            2
+        }
+        Err(CliError::Failed(message)) => {
+            eprintln!("{}", message);
+            1
+        }
+    }
+}
+
+enum CliError {
+    Usage,
+    Failed(String),
+}
+
+impl From<sqlx::Error> for CliError {
+    fn from(e: sqlx::Error) -> Self {
+        CliError::Failed(format!("Ошибка БД: {}", e))
+    }
+}
+
+async fn run_command(
+    pool: &PgPool,
+    config: &Config,
+    command: &str,
+    args: &[String],
+) -> Result<String, CliError> {
+    // Необязательная причина — остаток аргументов
+    let reason = args
+        .get(1..)
+        .map(|r| r.join(" "))
+        .filter(|r| !r.trim().is_empty());
+
+    match (command, args) {
+        ("grant" | "revoke", [target]) => {
+            let user_id = resolve_user(pool, target).await?;
+            let role = if command == "grant" {
+                SERVER_ROLE_ADMIN
+            } else {
+                SERVER_ROLE_USER
+            };
+            set_server_role(pool, user_id, role).await?;
+            audit(
+                pool,
+                ACTION_ROLE_CHANGED,
+                Some(user_id),
+                None,
+                json!({ "role": role }),
+            )
+            .await?;
+            Ok(format!("Пользователь {} теперь {}", target, role))
+        }
+        ("list", []) => {
+            let admins: Vec<(i32, String)> =
+                sqlx::query_as("SELECT id, login FROM users WHERE server_role = $1 ORDER BY id")
+                    .bind(SERVER_ROLE_ADMIN)
+                    .fetch_all(pool)
+                    .await?;
+            if admins.is_empty() {
+                return Ok("Администраторов нет".to_string());
+            }
+            Ok(admins
+                .iter()
+                .map(|(id, login)| format!("{}\t{}", id, login))
+                .collect::<Vec<_>>()
+                .join("\n"))
+        }
+        ("suspend", [target, ..]) => {
+            let user_id = resolve_user(pool, target).await?;
+            let revoked = suspend_user(pool, user_id, reason.as_deref())
+                .await?
+                .ok_or_else(|| not_found(target))?;
+            audit(
+                pool,
+                ACTION_USER_SUSPENDED,
+                Some(user_id),
+                None,
+                json!({ "reason": reason, "revoked_sessions": revoked.len() }),
+            )
+            .await?;
+            Ok(format!(
+                "Пользователь {} заблокирован, отозвано сессий: {}",
+                target,
+                revoked.len()
+            ))
+        }
+        ("unsuspend", [target]) => {
+            let user_id = resolve_user(pool, target).await?;
+            unsuspend_user(pool, user_id).await?;
+            audit(
+                pool,
+                ACTION_USER_UNSUSPENDED,
+                Some(user_id),
+                None,
+                json!({}),
+            )
+            .await?;
+            Ok(format!("Пользователь {} разблокирован", target))
+        }
+        ("approve", [target]) => {
+            let user_id = resolve_user(pool, target).await?;
+            approve_user(pool, user_id).await?;
+            audit(pool, ACTION_USER_APPROVED, Some(user_id), None, json!({})).await?;
+            Ok(format!("Регистрация пользователя {} подтверждена", target))
+        }
+        ("invite", [] | [_] | [_, _]) => {
+            let mut numbers = args.iter().map(|a| a.parse::<u32>());
+            let max_uses = numbers.next().transpose().map_err(|_| CliError::Usage)?;
+            let ttl_days = numbers.next().transpose().map_err(|_| CliError::Usage)?;
+            let (max_uses, ttl_days) = invite_limits(&config.registration, max_uses, ttl_days)
+                .map_err(CliError::Failed)?;
+            let (_, code, expires_at) = create_invite_code(
+                pool,
+                config.auth.jwt_secret.expose(),
+                None,
+                max_uses,
+                ttl_days,
+            )
+            .await?;
+            Ok(format!(
+                "{}\nиспользований: {}, действует до {}",
+                code, max_uses, expires_at
+            ))
+        }
+        ("logout", [target]) => {
+            let user_id = resolve_user(pool, target).await?;
+            let revoked = revoke_all_sessions(pool, user_id).await?;
+            audit(
+                pool,
+                ACTION_SESSIONS_REVOKED,
+                Some(user_id),
+                None,
+                json!({ "revoked_sessions": revoked.len() }),
+            )
+            .await?;
+            Ok(format!("Отозвано сессий: {}", revoked.len()))
+        }
+        ("delete", [target]) => {
+            let user_id = resolve_user(pool, target).await?;
+            if !delete_user(pool, &config.storage, user_id).await? {
+                return Err(not_found(target));
+            }
+            audit(
+                pool,
+                ACTION_USER_DELETED,
+                Some(user_id),
+                None,
+                json!({ "login": target }),
+            )
+            .await?;
+            Ok(format!("Пользователь {} удалён", target))
+        }
+        ("takedown", [chat_id, ..]) => {
+            let chat_id: i32 = chat_id
+                .parse()
+                .map_err(|_| CliError::Failed(format!("Некорректный id чата: {}", chat_id)))?;
+            let members = take_down_chat(pool, &config.storage, chat_id)
+                .await?
+                .ok_or_else(|| CliError::Failed(format!("Чат {} не найден", chat_id)))?;
+            audit(
+                pool,
+                ACTION_CHAT_TAKEN_DOWN,
+                None,
+                Some(chat_id),
+                json!({ "reason": reason, "members": members.len() }),
+            )
+            .await?;
+            Ok(format!("Чат {} удалён", chat_id))
+        }
+        ("stats", []) => {
+            let stats = instance_stats(pool).await?;
+            serde_json::to_string_pretty(&stats)
+                .map_err(|e| CliError::Failed(format!("Ошибка сериализации: {}", e)))
+        }
+        _ => Err(CliError::Usage),
+    }
+}
+
+fn not_found(target: &str) -> CliError {
+    CliError::Failed(format!("Пользователь {} не найден", target))
+}
+
+async fn resolve_user(pool: &PgPool, target: &str) -> Result<i32, CliError> {
+    find_user_id(pool, target)
+        .await?
+        .ok_or_else(|| not_found(target))
+}
+
+async fn audit(
+    pool: &PgPool,
+    action: &str,
+    target_user_id: Option<i32>,
+    target_chat_id: Option<i32>,
+    mut details: serde_json::Value,
+) -> Result<(), CliError> {
+    details["source"] = json!("cli");
+    record_admin_action(
+        pool,
+        None,
+        action,
+        target_user_id,
+        target_chat_id,
+        Some(details),
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,828 @@
+use axum::http::HeaderValue;
+use chrono::Duration as ChronoDuration;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::middleware::CounterStoreKind;
+use crate::middleware::client_ip::TrustedProxies;
+use crate::middleware::rate_limit::{AuthRateLimiterConfig, RateLimiterConfig};
+use crate::route::invites::RegistrationMode;
+use crate::telemetry::LogFormat;
+
+// Конфигурация сервера.
+// Источники по возрастанию приоритета:
+// 1. значения по умолчанию (ниже, у каждой секции);
+// 2. TOML-файл: путь из CONFIG_FILE, иначе ./config.toml, если он есть
+//    (пример — config.example.toml);
+// 3. переменные окружения (список — в Config::apply_env).
+// Все ошибки разбора и проверки собираются и выводятся разом при старте.
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Секрет: в Debug и в напечатанной конфигурации заменяется на <redacted>
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.0.is_empty() {
+            serializer.serialize_str("")
+        } else {
+            serializer.serialize_str("<redacted>")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub auth: AuthConfig,
+    pub storage: StorageConfig,
+    pub rate_limit: RateLimitConfig,
+    pub metrics: MetricsConfig,
+    pub webauthn: WebauthnConfig,
+    pub registration: RegistrationConfig,
+    pub features: FeaturesConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // Адрес HTTP/WS-сервера
+    pub listen: SocketAddr,
+    // Разрешённые CORS origins
+    pub cors_allow_origins: Vec<String>,
+    // Прокси (адреса или подсети), которым верим X-Forwarded-For / X-Real-IP
+    pub trusted_proxies: TrustedProxies,
+    // Сколько ждать запросы и websocket-соединения при остановке (SIGTERM)
+    pub shutdown_timeout_secs: u64,
+    // Сколько после SIGTERM отвечать 503 на /health/ready, продолжая принимать
+    // запросы, чтобы балансировщик успел снять инстанс
+    pub shutdown_ready_grace_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8081)),
+            cors_allow_origins: vec![
+                "http://localhost:3000".to_string(),
+                "http://127.0.0.1:3000".to_string(),
+            ],
+            trusted_proxies: TrustedProxies::loopback(),
+            shutdown_timeout_secs: 30,
+            shutdown_ready_grace_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: Secret,
+    pub name: String,
+    // Размер пула соединений
+    pub max_connections: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: 5432,
+            user: String::new(),
+            password: Secret::default(),
+            name: String::new(),
+            max_connections: 10,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn url(&self) -> String {
+        format!(
+            "postgres://{}:{}@{}:{}/{}",
+            self.user,
+            self.password.expose(),
+            self.host,
+            self.port,
+            self.name
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // Секрет подписи JWT и хешей refresh-токенов
+    pub jwt_secret: Secret,
+    // Срок жизни сессии (refresh-токена): обычный вход и вход с remember_me
+    pub refresh_ttl_days: i64,
+    pub remember_me_refresh_ttl_days: i64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            jwt_secret: Secret::default(),
+            refresh_ttl_days: 30,
+            remember_me_refresh_ttl_days: 365,
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn refresh_ttl(&self, remember_me: bool) -> ChronoDuration {
+        if remember_me {
+            ChronoDuration::days(self.remember_me_refresh_ttl_days)
+        } else {
+            ChronoDuration::days(self.refresh_ttl_days)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    // Корень загруженных файлов: media/ и avatars/ внутри
+    pub uploads_dir: PathBuf,
+    // Лимит на файл вложения (POST /media)
+    pub max_upload_bytes: usize,
+    // Лимит на файл аватара пользователя или чата
+    pub max_avatar_bytes: usize,
+    // Меньше свободного места на диске загрузок — /health/ready отвечает 503
+    pub min_free_bytes: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            uploads_dir: PathBuf::from("uploads"),
+            max_upload_bytes: 50 * 1024 * 1024,
+            max_avatar_bytes: 5 * 1024 * 1024,
+            min_free_bytes: 512 * 1024 * 1024,
+        }
+    }
+}
+
+impl StorageConfig {
+    // Путь на диске для относительного пути из БД (media/..., avatars/...)
+    pub fn path(&self, rel: impl AsRef<Path>) -> PathBuf {
+        self.uploads_dir.join(rel)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyLimits {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl FromStr for PolicyLimits {
+    type Err = ();
+
+    // "burst/per_minute", например "10/30"
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (burst, per_minute) = value.split_once('/').ok_or(())?;
+        Ok(PolicyLimits {
+            burst: burst.trim().parse().map_err(|_| ())?,
+            per_minute: per_minute.trim().parse().map_err(|_| ())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthLockoutConfig {
+    // Неудачных попыток входа до блокировки
+    pub max_failures: u32,
+    // Первая блокировка; каждая следующая длиннее в backoff_multiplier раз
+    pub lockout_secs: u64,
+    pub backoff_multiplier: u32,
+    pub max_lockout_secs: u64,
+}
+
+impl Default for AuthLockoutConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            lockout_secs: 60,
+            backoff_multiplier: 2,
+            max_lockout_secs: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // Где хранятся счётчики: memory (у каждого процесса свои) или postgres (общие)
+    pub store: CounterStoreKind,
+    // Переопределение лимитов политик по имени (auth, media, search, ws_connect,
+    // api, ws_message, ws_batch, ws_typing, ws_event — см. middleware::rate_limit)
+    pub policies: BTreeMap<String, PolicyLimits>,
+    pub auth_lockout: AuthLockoutConfig,
+}
+
+impl RateLimitConfig {
+    pub fn limiter_config(&self) -> RateLimiterConfig {
+        self.policies
+            .iter()
+            .fold(RateLimiterConfig::default(), |config, (name, limits)| {
+                config.with_limits(name, limits.burst, limits.per_minute)
+            })
+    }
+
+    pub fn auth_limiter_config(&self) -> AuthRateLimiterConfig {
+        AuthRateLimiterConfig {
+            max_failures: self.auth_lockout.max_failures,
+            lockout_duration: Duration::from_secs(self.auth_lockout.lockout_secs),
+            backoff_multiplier: self.auth_lockout.backoff_multiplier,
+            max_lockout: Duration::from_secs(self.auth_lockout.max_lockout_secs),
+        }
+    }
+}
+
+// Доступ к GET /metrics (см. route::metrics)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    // Требовать `Authorization: Bearer <token>`
+    pub token: Option<Secret>,
+    // Отдельный адрес, на котором отдаётся только /metrics
+    pub bind: Option<SocketAddr>,
+}
+
+// Параметры Relying Party для WebAuthn/passkey. rp_id — домен, к которому привязаны passkey;
+// origins — допустимые значения clientDataJSON.origin (веб и нативные клиенты).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebauthnConfig {
+    pub rp_id: String,
+    pub rp_name: String,
+    pub origins: Vec<String>,
+}
+
+impl Default for WebauthnConfig {
+    fn default() -> Self {
+        Self {
+            rp_id: "localhost".to_string(),
+            rp_name: "Ren".to_string(),
+            origins: vec!["http://localhost:3000".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrationConfig {
+    // open | closed | invite | approval
+    pub mode: RegistrationMode,
+    // Приглашения создают все пользователи (иначе — только администраторы сервера)
+    pub user_invites: bool,
+    // Предел использований одного приглашения
+    pub max_invite_uses: u32,
+    // Предел срока действия приглашения, дни
+    pub max_invite_ttl_days: u32,
+}
+
+impl Default for RegistrationConfig {
+    fn default() -> Self {
+        Self {
+            mode: RegistrationMode::Open,
+            user_invites: true,
+            max_invite_uses: 10,
+            max_invite_ttl_days: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeaturesConfig {
+    // Определять город входа внешним geo-сервисом (по умолчанию выключено:
+    // IP пользователей не уходит третьим лицам)
+    pub external_geo: bool,
+    // URL geo-сервиса, {ip} заменяется адресом клиента
+    pub ip_geo_url_template: String,
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> Self {
+        Self {
+            external_geo: false,
+            ip_geo_url_template: "https://ipwhois.app/json/{ip}".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // pretty | json; уровни — через RUST_LOG
+    pub format: LogFormat,
+}
+
+/// Ошибки конфигурации — все сразу, по одной на строку
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Некорректная конфигурация:")?;
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+// Применяет переменные окружения поверх значений из файла
+struct EnvOverrides<'a, F: Fn(&str) -> Option<String>> {
+    env: &'a F,
+    errors: &'a mut Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> EnvOverrides<'_, F> {
+    fn get(&self, var: &str) -> Option<String> {
+        (self.env)(var)
+    }
+
+    fn string(&mut self, var: &str, target: &mut String) {
+        if let Some(value) = self.get(var) {
+            *target = value;
+        }
+    }
+
+    fn secret(&mut self, var: &str, target: &mut Secret) {
+        if let Some(value) = self.get(var) {
+            *target = Secret(value);
+        }
+    }
+
+    fn list(&mut self, var: &str, target: &mut Vec<String>) {
+        if let Some(value) = self.get(var) {
+            *target = split_list(&value);
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, var: &str, target: &mut T) {
+        let Some(value) = self.get(var) else {
+            return;
+        };
+        match value.trim().parse() {
+            Ok(parsed) => *target = parsed,
+            Err(_) => self
+                .errors
+                .push(format!("{}: некорректное значение {:?}", var, value)),
+        }
+    }
+
+    // Пустое значение сбрасывает настройку
+    fn optional<T: FromStr>(&mut self, var: &str, target: &mut Option<T>) {
+        let Some(value) = self.get(var) else {
+            return;
+        };
+        if value.trim().is_empty() {
+            *target = None;
+            return;
+        }
+        match value.trim().parse() {
+            Ok(parsed) => *target = Some(parsed),
+            Err(_) => self
+                .errors
+                .push(format!("{}: некорректное значение {:?}", var, value)),
+        }
+    }
+
+    fn flag(&mut self, var: &str, target: &mut bool) {
+        let Some(value) = self.get(var) else {
+            return;
+        };
+        match value.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => *target = true,
+            "0" | "false" | "no" | "off" | "" => *target = false,
+            _ => self.errors.push(format!(
+                "{}: ожидается 0/1 или true/false, получено {:?}",
+                var, value
+            )),
+        }
+    }
+}
+
+impl Config {
+    /// Загружает конфигурацию из файла и окружения процесса и проверяет её
+    pub fn load() -> Result<Self, ConfigError> {
+        let env = |var: &str| std::env::var(var).ok();
+        let (path, required) = match env("CONFIG_FILE") {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+        let file = match std::fs::read_to_string(&path) {
+            Ok(text) => Some(text),
+            Err(e) if required || e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(ConfigError(vec![format!(
+                    "{}: не удалось прочитать файл конфигурации: {}",
+                    path.display(),
+                    e
+                )]));
+            }
+            Err(_) => None,
+        };
+        Self::from_sources(file.as_deref(), env)
+    }
+
+    pub fn from_sources<F>(file: Option<&str>, env: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut config: Config = match file {
+            Some(text) => toml::from_str(text)
+                .map_err(|e| ConfigError(vec![format!("файл конфигурации: {}", e)]))?,
+            None => Config::default(),
+        };
+
+        let mut errors = Vec::new();
+        config.apply_env(&env, &mut errors);
+        errors.extend(config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(errors))
+        }
+    }
+
+    fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, env: &F, errors: &mut Vec<String>) {
+        let mut o = EnvOverrides { env, errors };
+
+        o.parse("LISTEN_ADDR", &mut self.server.listen);
+        o.list("CORS_ALLOW_ORIGINS", &mut self.server.cors_allow_origins);
+        if let Some(value) = o.get("TRUSTED_PROXIES") {
+            match TrustedProxies::try_from(split_list(&value)) {
+                Ok(proxies) => self.server.trusted_proxies = proxies,
+                Err(e) => o.errors.push(format!("TRUSTED_PROXIES: {}", e)),
+            }
+        }
+        o.parse(
+            "SHUTDOWN_TIMEOUT_SECS",
+            &mut self.server.shutdown_timeout_secs,
+        );
+        o.parse(
+            "SHUTDOWN_READY_GRACE_SECS",
+            &mut self.server.shutdown_ready_grace_secs,
+        );
+
+        o.string("POSTGRES_HOST", &mut self.database.host);
+        o.parse("POSTGRES_PORT", &mut self.database.port);
+        o.string("POSTGRES_USER", &mut self.database.user);
+        o.secret("POSTGRES_PASSWORD", &mut self.database.password);
+        o.string("POSTGRES_DB", &mut self.database.name);
+        o.parse("DB_MAX_CONNECTIONS", &mut self.database.max_connections);
+
+        o.secret("JWT_SECRET", &mut self.auth.jwt_secret);
+        o.parse("REFRESH_TTL_DAYS", &mut self.auth.refresh_ttl_days);
+        o.parse(
+            "REMEMBER_ME_REFRESH_TTL_DAYS",
+            &mut self.auth.remember_me_refresh_ttl_days,
+        );
+
+        o.parse("UPLOADS_DIR", &mut self.storage.uploads_dir);
+        o.parse("MAX_UPLOAD_BYTES", &mut self.storage.max_upload_bytes);
+        o.parse("MAX_AVATAR_BYTES", &mut self.storage.max_avatar_bytes);
+        o.parse("MIN_FREE_DISK_BYTES", &mut self.storage.min_free_bytes);
+
+        if let Some(value) = o.get("RATE_LIMIT_STORE") {
+            match CounterStoreKind::parse(&value) {
+                Some(kind) => self.rate_limit.store = kind,
+                None => o.errors.push(format!(
+                    "RATE_LIMIT_STORE: ожидается memory или postgres, получено {:?}",
+                    value
+                )),
+            }
+        }
+        // RATE_LIMIT_<ИМЯ_ПОЛИТИКИ>=burst/per_minute, например RATE_LIMIT_AUTH=10/30
+        for name in RateLimiterConfig::default().policy_names() {
+            let var = format!("RATE_LIMIT_{}", name.to_ascii_uppercase());
+            let mut limits = self.rate_limit.policies.get(name).copied();
+            o.optional(&var, &mut limits);
+            if let Some(limits) = limits {
+                self.rate_limit.policies.insert(name.to_string(), limits);
+            }
+        }
+        let lockout = &mut self.rate_limit.auth_lockout;
+        o.parse("AUTH_MAX_FAILURES", &mut lockout.max_failures);
+        o.parse("AUTH_LOCKOUT_SECS", &mut lockout.lockout_secs);
+        o.parse("AUTH_LOCKOUT_BACKOFF", &mut lockout.backoff_multiplier);
+        o.parse("AUTH_MAX_LOCKOUT_SECS", &mut lockout.max_lockout_secs);
+
+        if let Some(value) = o.get("METRICS_TOKEN") {
+            self.metrics.token = Some(Secret(value.trim().to_string())).filter(|s| !s.is_empty());
+        }
+        o.optional("METRICS_BIND", &mut self.metrics.bind);
+
+        o.string("WEBAUTHN_RP_ID", &mut self.webauthn.rp_id);
+        o.string("WEBAUTHN_RP_NAME", &mut self.webauthn.rp_name);
+        o.list("WEBAUTHN_ORIGINS", &mut self.webauthn.origins);
+
+        if let Some(value) = o.get("REGISTRATION_MODE") {
+            match RegistrationMode::parse(&value) {
+                Some(mode) => self.registration.mode = mode,
+                None => o.errors.push(format!(
+                    "REGISTRATION_MODE: ожидается open, closed, invite или approval, получено {:?}",
+                    value
+                )),
+            }
+        }
+        o.flag(
+            "REGISTRATION_USER_INVITES",
+            &mut self.registration.user_invites,
+        );
+        o.parse("INVITE_MAX_USES", &mut self.registration.max_invite_uses);
+        o.parse(
+            "INVITE_MAX_TTL_DAYS",
+            &mut self.registration.max_invite_ttl_days,
+        );
+
+        o.flag("ENABLE_EXTERNAL_GEO", &mut self.features.external_geo);
+        o.string(
+            "IP_GEO_URL_TEMPLATE",
+            &mut self.features.ip_geo_url_template,
+        );
+
+        if let Some(value) = o.get("LOG_FORMAT") {
+            match LogFormat::parse(&value) {
+                Some(format) => self.log.format = format,
+                None => o.errors.push(format!(
+                    "LOG_FORMAT: ожидается pretty или json, получено {:?}",
+                    value
+                )),
+            }
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut require = |ok: bool, message: &str| {
+            if !ok {
+                errors.push(message.to_string());
+            }
+        };
+
+        for origin in &self.server.cors_allow_origins {
+            require(
+                HeaderValue::from_str(origin).is_ok(),
+                &format!(
+                    "server.cors_allow_origins: некорректный origin {:?}",
+                    origin
+                ),
+            );
+        }
+
+        require(
+            self.server.shutdown_timeout_secs > 0,
+            "server.shutdown_timeout_secs должен быть больше 0",
+        );
+
+        let db = &self.database;
+        require(
+            !db.host.trim().is_empty(),
+            "database.host (POSTGRES_HOST) не задан",
+        );
+        require(
+            !db.user.trim().is_empty(),
+            "database.user (POSTGRES_USER) не задан",
+        );
+        require(
+            !db.name.trim().is_empty(),
+            "database.name (POSTGRES_DB) не задан",
+        );
+        require(
+            db.max_connections > 0,
+            "database.max_connections должен быть больше 0",
+        );
+
+        let auth = &self.auth;
+        require(
+            !auth.jwt_secret.is_empty(),
+            "auth.jwt_secret (JWT_SECRET) не задан",
+        );
+        require(
+            auth.refresh_ttl_days > 0,
+            "auth.refresh_ttl_days должен быть больше 0",
+        );
+        require(
+            auth.remember_me_refresh_ttl_days >= auth.refresh_ttl_days,
+            "auth.remember_me_refresh_ttl_days не может быть меньше auth.refresh_ttl_days",
+        );
+
+        let storage = &self.storage;
+        require(
+            !storage.uploads_dir.as_os_str().is_empty(),
+            "storage.uploads_dir (UPLOADS_DIR) не задан",
+        );
+        require(
+            storage.max_upload_bytes > 0,
+            "storage.max_upload_bytes должен быть больше 0",
+        );
+        require(
+            storage.max_avatar_bytes > 0,
+            "storage.max_avatar_bytes должен быть больше 0",
+        );
+
+        let known = RateLimiterConfig::default().policy_names();
+        for (name, limits) in &self.rate_limit.policies {
+            require(
+                known.contains(name.as_str()),
+                &format!(
+                    "rate_limit.policies: неизвестная политика {:?} (есть: {})",
+                    name,
+                    known.iter().copied().collect::<Vec<_>>().join(", ")
+                ),
+            );
+            require(
+                limits.burst > 0 && limits.per_minute > 0,
+                &format!(
+                    "rate_limit.policies.{}: burst и per_minute должны быть больше 0",
+                    name
+                ),
+            );
+        }
+        let lockout = &self.rate_limit.auth_lockout;
+        require(
+            lockout.max_failures > 0,
+            "rate_limit.auth_lockout.max_failures должен быть больше 0",
+        );
+        require(
+            lockout.backoff_multiplier >= 1,
+            "rate_limit.auth_lockout.backoff_multiplier должен быть не меньше 1",
+        );
+        require(
+            lockout.lockout_secs > 0 && lockout.max_lockout_secs >= lockout.lockout_secs,
+            "rate_limit.auth_lockout: нужно 0 < lockout_secs <= max_lockout_secs",
+        );
+
+        require(
+            !self.webauthn.rp_id.trim().is_empty(),
+            "webauthn.rp_id (WEBAUTHN_RP_ID) не задан",
+        );
+        require(
+            !self.webauthn.origins.is_empty(),
+            "webauthn.origins (WEBAUTHN_ORIGINS) не заданы",
+        );
+
+        require(
+            self.registration.max_invite_uses > 0 && self.registration.max_invite_ttl_days > 0,
+            "registration: max_invite_uses и max_invite_ttl_days должны быть больше нуля",
+        );
+
+        require(
+            !self.features.external_geo || self.features.ip_geo_url_template.contains("{ip}"),
+            "features.ip_geo_url_template должен содержать {ip}",
+        );
+
+        errors
+    }
+
+    /// Действующая конфигурация в формате TOML, секреты скрыты
+    pub fn redacted(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_else(|e| format!("<{}>", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |var| vars.get(var).cloned()
+    }
+
+    const REQUIRED: &[(&str, &str)] = &[
+        ("POSTGRES_HOST", "db"),
+        ("POSTGRES_USER", "ren"),
+        ("POSTGRES_PASSWORD", "db-password"),
+        ("POSTGRES_DB", "ren"),
+        ("JWT_SECRET", "jwt-secret"),
+    ];
+
+    #[test]
+    fn env_overrides_file_and_secrets_are_redacted() {
+        let file = r#"
+            [server]
+            listen = "127.0.0.1:9000"
+
+            [storage]
+            max_upload_bytes = 1024
+
+            [rate_limit.policies]
+            auth = { burst = 3, per_minute = 6 }
+        "#;
+        let mut vars = REQUIRED.to_vec();
+        vars.push(("MAX_UPLOAD_BYTES", "2048"));
+        vars.push(("RATE_LIMIT_WS_TYPING", "2/10"));
+        let config = Config::from_sources(Some(file), env(&vars)).unwrap();
+
+        assert_eq!(config.server.listen, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.storage.max_upload_bytes, 2048);
+        assert_eq!(config.database.port, 5432);
+        assert_eq!(config.auth.refresh_ttl(true), ChronoDuration::days(365));
+
+        let limiter = config.rate_limit.limiter_config();
+        assert_eq!(limiter.policy("auth").map(|p| p.burst), Some(3));
+        assert_eq!(limiter.policy("ws_typing").map(|p| p.per_minute), Some(10));
+
+        let printed = config.redacted();
+        assert!(printed.contains("<redacted>"));
+        assert!(!printed.contains("db-password"));
+        assert!(!printed.contains("jwt-secret"));
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let file = r#"
+            [rate_limit.policies]
+            nope = { burst = 1, per_minute = 1 }
+        "#;
+        let vars = [("POSTGRES_PORT", "abc"), ("LOG_FORMAT", "xml")];
+        let errors = Config::from_sources(Some(file), env(&vars)).unwrap_err().0;
+
+        for expected in [
+            "POSTGRES_PORT",
+            "LOG_FORMAT",
+            "POSTGRES_HOST",
+            "JWT_SECRET",
+            "\"nope\"",
+        ] {
+            assert!(
+                errors.iter().any(|e| e.contains(expected)),
+                "нет ошибки про {expected}: {errors:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn example_file_is_valid() {
+        let file = include_str!("../config.example.toml");
+        let config = Config::from_sources(Some(file), env(REQUIRED)).unwrap();
+        assert_eq!(config.webauthn.rp_id, "messanger-ren.ru");
+    }
+
+    #[test]
+    fn unknown_file_keys_are_rejected() {
+        let file = "[storage]\nmax_upload = 1\n";
+        let errors = Config::from_sources(Some(file), env(REQUIRED))
+            .unwrap_err()
+            .0;
+        assert!(errors[0].contains("max_upload"));
+    }
+}
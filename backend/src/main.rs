@@ -4,13 +4,14 @@
 use axum::{
     Router,
     http::{HeaderName, HeaderValue, Method, header},
-    middleware::from_fn,
-    routing::get,
+    middleware::{from_fn, from_fn_with_state},
 };
 use dashmap::DashMap;
-use sqlx::{PgPool, postgres::PgPoolOptions};
+use sqlx::{PgPool, migrate::Migrator, postgres::PgPoolOptions};
+use std::future::IntoFuture;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::broadcast;
 use tower_http::cors::{AllowOrigin, CorsLayer};
@@ -21,8 +22,23 @@ mod route;
 pub mod models;
 // Подключаем модуль с экстракторами аутентификации
 pub mod middleware;
+// Подключаем модуль с типизированной конфигурацией
+pub mod config;
+// Подключаем модуль с метриками Prometheus
+pub mod metrics;
+// Подключаем модуль с настройкой логов и трассировки (tracing + OpenTelemetry)
+pub mod telemetry;
+// Подключаем модуль плавной остановки (SIGTERM / SIGINT)
+pub mod shutdown;
+// Подключаем команды администратора (`backend admin ...`)
+mod admin_cli;
+// Общие помощники тестов, которым нужна база
+#[cfg(test)]
+mod test_support;
 
-use crate::middleware::rate_limit::{RateLimiterConfig, AuthRateLimiterConfig};
+// Миграции из каталога ./migrations, встроенные в бинарник
+// (применяются при старте, сверяются в /health/ready)
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
 
 // Делаем состояние приложения доступным в остальных модулях (например, в маршрутах)
 // чтобы в хендлерах был доступ к пулу соединений PostgreSQL.
@@ -30,7 +46,7 @@ use crate::middleware::rate_limit::{RateLimiterConfig, AuthRateLimiterConfig};
 pub struct AppState {
     // Пул соединений с Postgres
     pub pool: PgPool,
-    // Секрет для подписи JWT-токенов, читается из переменной окружения JWT_SECRET
+    // Секрет для подписи JWT-токенов (auth.jwt_secret / JWT_SECRET)
     pub jwt_secret: String,
     // Хаб websocket-каналов по chat_id: широковещательная рассылка событий
     pub ws_hub: Arc<DashMap<i32, broadcast::Sender<String>>>,
@@ -42,53 +58,52 @@ pub struct AppState {
     pub rate_limiter: middleware::RateLimiter,
     // P1-7: Rate limiter для auth-эндпоинтов
     pub auth_rate_limiter: middleware::AuthRateLimiter,
+    // Живые websocket-соединения по session_id и кэш отзыва сессий для CurrentUser
+    pub sessions: middleware::SessionRegistry,
+    // Метрики Prometheus (GET /metrics)
+    pub metrics: Arc<metrics::Metrics>,
+    // Проверенная конфигурация сервера (config.toml + переменные окружения)
+    pub config: Arc<config::Config>,
+    // Сигнал остановки сервера и учёт живых websocket-соединений
+    pub shutdown: shutdown::Shutdown,
 }
 
 // Основная асинхронная функция запуска приложения
 async fn async_main() {
     // Загружаем переменные окружения из файла .env (если он есть).
     let _ = dotenvy::dotenv();
-    let postgres_user =
-        std::env::var("POSTGRES_USER").expect("Переменная окружения POSTGRES_USER не установлена");
-    let postgres_password = std::env::var("POSTGRES_PASSWORD")
-        .expect("Переменная окружения POSTGRES_PASSWORD не установлена");
-    let postgres_host =
-        std::env::var("POSTGRES_HOST").expect("Переменная окружения POSTGRES_HOST не установлена");
-    let postgres_port = std::env::var("POSTGRES_PORT").unwrap_or_else(|_| "8081".to_string());
-    let postgres_db =
-        std::env::var("POSTGRES_DB").expect("Переменная окружения POSTGRES_DB не установлена");
-    let database_url = format!(
-        "postgres://{}:{}@{}:{}/{}",
-        postgres_user, postgres_password, postgres_host, postgres_port, postgres_db
-    );
-    // Секрет для подписи JWT, обязателен
-    let jwt_secret =
-        std::env::var("JWT_SECRET").expect("Переменная окружения JWT_SECRET не установлена");
-    let cors_allow_origins = std::env::var("CORS_ALLOW_ORIGINS")
-        .unwrap_or_else(|_| {
-            "https://messanger-ren.ru,https://www.messanger-ren.ru,http://localhost:3000,http://127.0.0.1:3000".to_string()
-        })
-        .split(',')
-        .map(|s| s.trim())
-        .filter(|s| !s.is_empty())
-        .map(|s| HeaderValue::from_str(s).expect("invalid CORS_ALLOW_ORIGINS value"))
-        .collect::<Vec<HeaderValue>>();
+    // Конфигурация: значения по умолчанию ← config.toml (CONFIG_FILE) ← окружение
+    let config = match config::Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // Логи и трассировка: log.format, RUST_LOG, OTEL_EXPORTER_OTLP_ENDPOINT (см. telemetry)
+    let _telemetry = telemetry::init(config.log.format);
+    tracing::info!("Effective config:\n{}", config.redacted());
 
-    // Порт для прослушивания
-    let port = 8081;
+    // Значения уже проверены в Config::load
+    let cors_allow_origins = config
+        .server
+        .cors_allow_origins
+        .iter()
+        .filter_map(|s| HeaderValue::from_str(s).ok())
+        .collect::<Vec<HeaderValue>>();
 
     // Создаем пул соединений с БД.
     // Пул — это набор заранее открытых соединений, чтобы хендлеры могли быстро
     // получать доступ к БД без накладных расходов на установку соединения.
     let pool = PgPoolOptions::new()
-        .max_connections(10)
-        .connect(&database_url)
+        .max_connections(config.database.max_connections)
+        .connect(&config.database.url())
         .await
         .expect("Не удалось подключиться к базе данных");
 
     // Выполняем миграции при старте (из каталога ./migrations)
     // Это надёжнее, чем создавать таблицы вручную в коде.
-    sqlx::migrate!("./migrations")
+    MIGRATOR
         .run(&pool)
         .await
         .expect("Не удалось выполнить миграции базы данных");
@@ -99,35 +114,100 @@ async fn async_main() {
     let online_connections = Arc::new(DashMap::new());
     
     // P1-7: Initialize rate limiters
-    let rate_limiter = middleware::RateLimiter::new(RateLimiterConfig {
-        max_requests: 100, // 100 requests per minute
-        window_duration: std::time::Duration::from_secs(60),
-        enable_ip_limiting: true,
-        enable_account_limiting: true,
-    });
-    
-    let auth_rate_limiter = middleware::AuthRateLimiter::new(AuthRateLimiterConfig {
-        max_failures: 5, // 5 failed attempts
-        lockout_duration: std::time::Duration::from_secs(60), // 1 minute initial
-        backoff_multiplier: 2, // Exponential backoff
-        max_lockout: std::time::Duration::from_secs(3600), // Max 1 hour
-    });
-    
+    // Счётчики: rate_limit.store = memory (по умолчанию, свои у каждого процесса)
+    // или postgres (общие для всех инстансов backend)
+    let counter_store: Arc<dyn middleware::CounterStore> = match config.rate_limit.store {
+        middleware::CounterStoreKind::Memory => Arc::new(middleware::MemoryCounterStore::new()),
+        middleware::CounterStoreKind::Postgres => {
+            Arc::new(middleware::PostgresCounterStore::new(pool.clone()))
+        }
+    };
+
+    // Политики по группам маршрутов и типам WS-событий — в middleware::rate_limit,
+    // лимиты переопределяются в rate_limit.policies
+    let rate_limiter =
+        middleware::RateLimiter::new(config.rate_limit.limiter_config(), counter_store.clone());
+    let auth_rate_limiter =
+        middleware::AuthRateLimiter::new(config.rate_limit.auth_limiter_config(), counter_store);
+
     let state = AppState {
         pool,
-        jwt_secret,
+        jwt_secret: config.auth.jwt_secret.expose().to_string(),
         ws_hub,
         user_hub,
         online_connections,
         rate_limiter,
         auth_rate_limiter,
+        sessions: middleware::SessionRegistry::new(),
+        metrics: Arc::new(metrics::Metrics::new()),
+        config: config.clone(),
+        shutdown: shutdown::Shutdown::new(),
+    };
+    let shutdown = state.shutdown.clone();
+    let pool = state.pool.clone();
+
+    // Периодически выбрасываем устаревшие счётчики rate limiter'ов
+    // и истёкшие записи кэша сессий
+    let limiter = state.rate_limiter.clone();
+    let auth_limiter = state.auth_rate_limiter.clone();
+    let sessions = state.sessions.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            limiter.cleanup().await;
+            auth_limiter.cleanup().await;
+            sessions.prune();
+        }
+    });
+
+    // /metrics: на отдельном адресе (metrics.bind) или на основном с токеном (metrics.token)
+    let metrics_token = config
+        .metrics
+        .token
+        .as_ref()
+        .map(|t| t.expose().to_string());
+    let main_metrics_router = match (config.metrics.bind, metrics_token) {
+        (Some(bind), token) => {
+            let metrics_app = route::metrics::router(token).with_state(state.clone());
+            let listener = TcpListener::bind(bind)
+                .await
+                .expect("Не удалось открыть порт для метрик");
+            tracing::info!("Metrics on http://{bind}/metrics");
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, metrics_app).await {
+                    tracing::error!(error = %e, "Сервер метрик завершился с ошибкой");
+                }
+            });
+            Router::new()
+        }
+        (None, Some(token)) => {
+            tracing::info!("Metrics on /metrics (token required)");
+            route::metrics::router(Some(token))
+        }
+        (None, None) => {
+            tracing::info!("Metrics disabled: set metrics.token or metrics.bind");
+            Router::new()
+        }
     };
 
     // Сборка роутера приложения.
-    // Добавим простой health-check и подключим роуты авторизации.
+    // Подключим роуты авторизации и проверки состояния (/health, /health/live, /health/ready).
+    // Rate limit навешивается через Router::layer (после маршрутизации, виден MatchedPath)
+    // и не затрагивает health-check, добавленный после него.
+    // Метрики HTTP снимаются со всех маршрутов, включая health-check и /metrics.
     let app = Router::new()
-        .route("/health", get(|| async { "OK" }))
-        .merge(route::router())
+        .merge(route::router(&config))
+        .layer(from_fn_with_state(
+            state.clone(),
+            middleware::rate_limit_middleware,
+        ))
+        .merge(route::health::router())
+        .merge(main_metrics_router)
+        .layer(from_fn_with_state(
+            state.clone(),
+            middleware::track_http_metrics,
+        ))
         .layer(
             CorsLayer::new()
                 .allow_origin(AllowOrigin::list(cors_allow_origins))
@@ -144,28 +224,87 @@ async fn async_main() {
                     header::CONTENT_TYPE,
                     HeaderName::from_static("x-device-name"),
                     HeaderName::from_static("x-app-version"),
+                ])
+                .expose_headers([
+                    header::RETRY_AFTER,
+                    HeaderName::from_static("ratelimit-limit"),
+                    HeaderName::from_static("ratelimit-remaining"),
+                    HeaderName::from_static("ratelimit-reset"),
                 ]),
         )
         .layer(from_fn(middleware::logging))
         .with_state(state);
 
-    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
-    println!("Server running on http://{addr}");
+    let addr = config.server.listen;
+    tracing::info!("Server running on http://{addr}");
     let listener = TcpListener::bind(addr)
         .await
         .expect("Не удалось открыть порт для прослушивания");
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .expect("Сервер завершился с ошибкой");
+    // После сигнала axum перестаёт принимать соединения и дожидается текущих запросов,
+    // а websocket-обработчики рассылают server_shutdown и закрываются (см. shutdown)
+    let server_shutdown = shutdown.clone();
+    let mut server = tokio::spawn(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move { server_shutdown.triggered().await })
+        .into_future(),
+    );
+
+    tokio::select! {
+        _ = shutdown::signal() => {
+            // Сначала /health/ready отвечает 503, а запросы ещё обслуживаются:
+            // балансировщик успевает снять инстанс до закрытия порта
+            shutdown.begin_drain();
+            let grace = Duration::from_secs(config.server.shutdown_ready_grace_secs);
+            if !grace.is_zero() {
+                tracing::info!("Снимаемся с балансировки: ждём {}s", grace.as_secs());
+                tokio::select! {
+                    _ = tokio::time::sleep(grace) => {}
+                    _ = shutdown::signal() => tracing::info!("Повторный сигнал: останавливаемся сразу"),
+                }
+            }
+        }
+        result = &mut server => {
+            tracing::error!(?result, "Сервер завершился без сигнала остановки");
+        }
+    }
+    shutdown.trigger();
+
+    let timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    let deadline = tokio::time::Instant::now() + timeout;
+    match tokio::time::timeout_at(deadline, &mut server).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(e))) => tracing::error!(error = %e, "Сервер завершился с ошибкой"),
+        Ok(Err(e)) => tracing::error!(error = %e, "Задача сервера завершилась аварийно"),
+        Err(_) => tracing::warn!("HTTP-запросы не завершились за {}s", timeout.as_secs()),
+    }
+    let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+    if !shutdown.wait_sockets(remaining).await {
+        tracing::warn!(
+            live_sockets = shutdown.live_sockets(),
+            "Websocket-соединения не закрылись за {}s",
+            timeout.as_secs()
+        );
+    }
+
+    // Незавершённые к этому моменту задачи прерываются вместе с рантаймом;
+    // загрузки удаляют свои временные файлы (см. media::upload)
+    pool.close().await;
+    tracing::info!("Сервер остановлен");
 }
 
 fn main() {
     // Точка входа в приложение. Запускаем асинхронный рантайм Tokio.
-    // Отдельная асинхронная функция `async_main` содержит всю логику запуска.
-    tokio::runtime::Runtime::new()
-        .expect("failed to create tokio runtime")
-        .block_on(async_main());
+    // Отдельная асинхронная функция `async_main` содержит всю логику запуска,
+    // `backend admin ...` — команды администратора (см. admin_cli).
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let runtime = tokio::runtime::Runtime::new().expect("failed to create tokio runtime");
+    if args.first().map(String::as_str) == Some("admin") {
+        let _ = dotenvy::dotenv();
+        let code = runtime.block_on(admin_cli::run(&args[1..]));
+        std::process::exit(code);
+    }
+    runtime.block_on(async_main());
 }
@@ -0,0 +1,165 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::AppState;
+
+// Метрики Prometheus, отдаются на GET /metrics (см. route::metrics).
+// Счётчики обновляются в местах событий, gauge'и хабов и пула БД —
+// в момент опроса.
+pub struct Metrics {
+    registry: Registry,
+    // HTTP: method, path (MatchedPath), status
+    pub http_requests: IntCounterVec,
+    pub http_duration: HistogramVec,
+    // Открытые websocket-соединения
+    pub ws_connections: IntGauge,
+    // Каналы чатов в ws_hub
+    pub ws_chat_channels: IntGauge,
+    // Сообщения, отправленные через websocket, по message_type
+    pub ws_messages_sent: IntCounterVec,
+    // Подписчик broadcast-канала отстал и пропустил сообщения
+    pub ws_broadcast_lagged: IntCounter,
+    pub ws_broadcast_skipped: IntCounter,
+    // Пул соединений с БД: state = idle | active
+    pub db_pool_connections: IntGaugeVec,
+    pub db_pool_max: IntGauge,
+    // Отказы rate limiter'а по политике
+    pub rate_limit_rejections: IntCounterVec,
+    // Загруженные байты: kind = media | avatar
+    pub upload_bytes: IntCounterVec,
+}
+
+// Типы сообщений, которые попадают в метку как есть; остальные — "other"
+const MESSAGE_TYPE_LABELS: &[&str] = &["text", "image", "file", "voice", "video"];
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("ren".into()), None)
+            .expect("Не удалось создать реестр метрик");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP-запросы"),
+            &["method", "path", "status"],
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Время обработки HTTP-запроса",
+            ),
+            &["method", "path"],
+        )
+        .unwrap();
+        let ws_connections =
+            IntGauge::new("ws_connections", "Открытые websocket-соединения").unwrap();
+        let ws_chat_channels = IntGauge::new("ws_chat_channels", "Каналы чатов в ws_hub").unwrap();
+        let ws_messages_sent = IntCounterVec::new(
+            Opts::new("ws_messages_sent_total", "Отправленные сообщения"),
+            &["message_type"],
+        )
+        .unwrap();
+        let ws_broadcast_lagged = IntCounter::new(
+            "ws_broadcast_lagged_total",
+            "Отставания подписчиков broadcast-каналов",
+        )
+        .unwrap();
+        let ws_broadcast_skipped = IntCounter::new(
+            "ws_broadcast_skipped_messages_total",
+            "Сообщения, пропущенные отставшими подписчиками",
+        )
+        .unwrap();
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Соединения пула БД"),
+            &["state"],
+        )
+        .unwrap();
+        let db_pool_max = IntGauge::new("db_pool_max_connections", "Размер пула БД").unwrap();
+        let rate_limit_rejections = IntCounterVec::new(
+            Opts::new("rate_limit_rejections_total", "Отказы rate limiter'а"),
+            &["policy"],
+        )
+        .unwrap();
+        let upload_bytes = IntCounterVec::new(
+            Opts::new("upload_bytes_total", "Загруженные байты"),
+            &["kind"],
+        )
+        .unwrap();
+
+        let metrics = Self {
+            registry,
+            http_requests,
+            http_duration,
+            ws_connections,
+            ws_chat_channels,
+            ws_messages_sent,
+            ws_broadcast_lagged,
+            ws_broadcast_skipped,
+            db_pool_connections,
+            db_pool_max,
+            rate_limit_rejections,
+            upload_bytes,
+        };
+        metrics.register_all();
+        metrics
+    }
+
+    fn register_all(&self) {
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(self.http_requests.clone()),
+            Box::new(self.http_duration.clone()),
+            Box::new(self.ws_connections.clone()),
+            Box::new(self.ws_chat_channels.clone()),
+            Box::new(self.ws_messages_sent.clone()),
+            Box::new(self.ws_broadcast_lagged.clone()),
+            Box::new(self.ws_broadcast_skipped.clone()),
+            Box::new(self.db_pool_connections.clone()),
+            Box::new(self.db_pool_max.clone()),
+            Box::new(self.rate_limit_rejections.clone()),
+            Box::new(self.upload_bytes.clone()),
+        ];
+        for collector in collectors {
+            self.registry
+                .register(collector)
+                .expect("Не удалось зарегистрировать метрику");
+        }
+    }
+
+    pub fn message_sent(&self, message_type: &str) {
+        let label = MESSAGE_TYPE_LABELS
+            .iter()
+            .find(|t| **t == message_type)
+            .copied()
+            .unwrap_or("other");
+        self.ws_messages_sent.with_label_values(&[label]).inc();
+    }
+
+    // Текстовый формат Prometheus; перед выгрузкой обновляет gauge'и состояния
+    pub fn render(&self, state: &AppState) -> String {
+        self.ws_chat_channels.set(state.ws_hub.len() as i64);
+
+        let size = i64::from(state.pool.size());
+        let idle = state.pool.num_idle() as i64;
+        self.db_pool_connections
+            .with_label_values(&["idle"])
+            .set(idle);
+        self.db_pool_connections
+            .with_label_values(&["active"])
+            .set((size - idle).max(0));
+        self.db_pool_max
+            .set(i64::from(state.pool.options().get_max_connections()));
+
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            tracing::error!(error = %e, "Ошибка выгрузки метрик");
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
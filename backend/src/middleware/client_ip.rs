@@ -0,0 +1,186 @@
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+
+// Доверенные прокси (server.trusted_proxies): только от них принимаются
+// X-Forwarded-For / X-Real-IP. Остальным клиентам заголовки не верим —
+// иначе подставной XFF обходит лимиты по IP.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct TrustedProxies(Vec<IpNet>);
+
+// Адрес или подсеть в нотации CIDR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(IpNet { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+// IPv4, пришедший как IPv4-mapped IPv6 (::ffff:a.b.c.d), сравниваем как IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        v4 => v4,
+    }
+}
+
+impl TryFrom<Vec<String>> for TrustedProxies {
+    type Error = String;
+
+    fn try_from(values: Vec<String>) -> Result<Self, Self::Error> {
+        values
+            .iter()
+            .filter(|v| !v.trim().is_empty())
+            .map(|v| {
+                IpNet::parse(v).ok_or_else(|| format!("некорректный адрес или подсеть {:?}", v))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+impl From<TrustedProxies> for Vec<String> {
+    fn from(value: TrustedProxies) -> Self {
+        value.0.iter().map(IpNet::to_string).collect()
+    }
+}
+
+impl TrustedProxies {
+    pub fn loopback() -> Self {
+        TrustedProxies(vec![
+            IpNet::parse("127.0.0.0/8").expect("valid net"),
+            IpNet::parse("::1").expect("valid net"),
+        ])
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    // Адрес клиента: адрес сокета, а если соединение пришло от доверенного
+    // прокси — первый справа недоверенный адрес из X-Forwarded-For
+    // (левые значения клиент может подставить сам), затем X-Real-IP.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+        let peer = canonical(peer.ip());
+        if !self.contains(peer) {
+            return peer;
+        }
+
+        let forwarded: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .collect();
+        if !forwarded.is_empty() {
+            let mut nearest = peer;
+            for hop in forwarded.iter().rev() {
+                // Мусор в цепочке: дальше не верим, берём ближайший известный адрес
+                let Ok(ip) = hop.parse::<IpAddr>().map(canonical) else {
+                    return nearest;
+                };
+                if !self.contains(ip) {
+                    return ip;
+                }
+                nearest = ip;
+            }
+            return nearest;
+        }
+
+        headers
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<IpAddr>().ok())
+            .map(canonical)
+            .unwrap_or(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn proxies(list: &[&str]) -> TrustedProxies {
+        TrustedProxies::try_from(list.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    fn headers(xff: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(xff).unwrap());
+        headers
+    }
+
+    fn peer(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 5000)
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_forwarded_headers() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let ip = trusted.client_ip(&headers("1.2.3.4"), peer("203.0.113.7"));
+        assert_eq!(ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn trusted_peer_uses_rightmost_untrusted_hop() {
+        let trusted = proxies(&["10.0.0.0/8", "192.168.1.1"]);
+        // Клиент подставил 6.6.6.6, nginx дописал реальный адрес 198.51.100.2
+        let ip = trusted.client_ip(
+            &headers("6.6.6.6, 198.51.100.2, 192.168.1.1"),
+            peer("10.1.2.3"),
+        );
+        assert_eq!(ip, "198.51.100.2".parse::<IpAddr>().unwrap());
+
+        let ip = trusted.client_ip(&headers("garbage, 10.0.0.5"), peer("10.1.2.3"));
+        assert_eq!(ip, "10.0.0.5".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn parses_nets_and_mapped_addresses() {
+        let trusted = proxies(&["172.16.0.0/12", "::1"]);
+        assert!(trusted.contains("172.31.255.1".parse().unwrap()));
+        assert!(!trusted.contains("172.32.0.1".parse().unwrap()));
+        assert!(trusted.contains("::ffff:172.20.0.2".parse().unwrap()));
+        assert!(TrustedProxies::try_from(vec!["10.0.0.0/33".to_string()]).is_err());
+        assert!(TrustedProxies::try_from(vec!["proxy".to_string()]).is_err());
+    }
+}
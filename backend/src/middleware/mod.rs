@@ -1,9 +1,9 @@
-use axum::body::{Body, Bytes, to_bytes};
+use axum::body::{Body, HttpBody, to_bytes};
 use axum::extract::FromRef;
 use axum::{
     async_trait,
-    extract::{ConnectInfo, FromRequestParts, MatchedPath, Request, State},
-    http::{StatusCode, header, request::Parts},
+    extract::{ConnectInfo, FromRequestParts, MatchedPath, RawPathParams, Request, State},
+    http::{StatusCode, request::Parts},
     middleware::Next,
     response::Response,
 };
@@ -13,13 +13,24 @@ use uuid::Uuid;
 
 use crate::AppState;
 use crate::models::auth::Claims;
-use chrono::Local;
+use chrono::{DateTime, Utc};
 use std::net::SocketAddr;
 use std::time::Instant;
+use tracing::{Instrument, field};
 
+// Адрес клиента с учётом доверенных прокси
+pub mod client_ip;
 // Rate limiting module
 pub mod rate_limit;
 pub use rate_limit::{RateLimiter, AuthRateLimiter, rate_limit_middleware, auth_rate_limit_middleware};
+// Хранилища счётчиков rate limit: в памяти процесса или общее в Postgres
+pub mod rate_limit_store;
+pub use rate_limit_store::{
+    CounterStore, CounterStoreKind, MemoryCounterStore, PostgresCounterStore,
+};
+// Живые websocket-соединения и кэш отзыва сессий
+pub mod sessions;
+pub use sessions::{SessionCheck, SessionRegistry};
 
 // Экстрактор текущего пользователя из заголовка Authorization: Bearer <JWT>
 // Пример использования: fn handler(State(state): State<AppState>, CurrentUser { id, .. }: CurrentUser) { ... }
@@ -54,7 +65,8 @@ pub async fn ensure_member(
 }
 
 // Утилита: убедиться, что пользователь может отправлять сообщения в чат.
-// Для channel писать могут только owner/admin.
+// Для channel писать могут только owner/admin; в private-чат с блокировкой
+// (в любую сторону) писать нельзя.
 pub async fn ensure_can_send_message(
     state: &AppState,
     chat_id: i32,
@@ -95,6 +107,21 @@ pub async fn ensure_can_send_message(
             "Недостаточно прав: в channel писать могут только owner/admin".into(),
         ));
     }
+    if kind == "private"
+        && crate::route::privacy::private_chat_blocked(&state.pool, chat_id, user_id)
+            .await
+            .map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Ошибка БД: {}", e),
+                )
+            })?
+    {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Нельзя отправить сообщение: пользователь заблокирован".into(),
+        ));
+    }
 
     Ok(())
 }
@@ -168,45 +195,65 @@ where
             )
         })?;
 
-        let active: Option<i32> = sqlx::query_scalar(
-            r#"
-            SELECT 1
-            FROM auth_sessions
-            WHERE id = $1
-              AND user_id = $2
-              AND revoked_at IS NULL
-              AND expires_at > now()
-            LIMIT 1
-            "#,
-        )
-        .bind(session_id)
-        .bind(data.claims.sub)
-        .fetch_optional(&app_state.pool)
-        .await
-        .map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Ошибка проверки сессии".to_string(),
-            )
-        })?;
-
-        if active.is_none() {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                "Сессия недействительна".to_string(),
-            ));
+        match app_state.sessions.check(session_id, data.claims.sub) {
+            SessionCheck::Active => {}
+            SessionCheck::Revoked => {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    "Сессия недействительна".to_string(),
+                ));
+            }
+            SessionCheck::Unknown => {
+                let expires_at: Option<DateTime<Utc>> = sqlx::query_scalar(
+                    r#"
+                    SELECT expires_at
+                    FROM auth_sessions
+                    WHERE id = $1
+                      AND user_id = $2
+                      AND revoked_at IS NULL
+                      AND expires_at > now()
+                    LIMIT 1
+                    "#,
+                )
+                .bind(session_id)
+                .bind(data.claims.sub)
+                .fetch_optional(&app_state.pool)
+                .await
+                .map_err(|_| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Ошибка проверки сессии".to_string(),
+                    )
+                })?;
+
+                let Some(expires_at) = expires_at else {
+                    app_state.sessions.remember_inactive(session_id);
+                    return Err((
+                        StatusCode::UNAUTHORIZED,
+                        "Сессия недействительна".to_string(),
+                    ));
+                };
+                app_state
+                    .sessions
+                    .remember_active(session_id, data.claims.sub, expires_at);
+
+                // last_seen_at обновляем вместе с проверкой в БД, а не на каждый запрос
+                let _ = sqlx::query(
+                    r#"
+                    UPDATE auth_sessions
+                    SET last_seen_at = now()
+                    WHERE id = $1
+                    "#,
+                )
+                .bind(session_id)
+                .execute(&app_state.pool)
+                .await;
+            }
         }
 
-        let _ = sqlx::query(
-            r#"
-            UPDATE auth_sessions
-            SET last_seen_at = now()
-            WHERE id = $1
-            "#,
-        )
-        .bind(session_id)
-        .execute(&app_state.pool)
-        .await;
+        let span = tracing::Span::current();
+        span.record("user_id", data.claims.sub);
+        span.record("session_id", field::display(session_id));
 
         Ok(CurrentUser {
             id: data.claims.sub,
@@ -215,118 +262,168 @@ where
     }
 }
 
+// Экстрактор администратора сервера: CurrentUser с users.server_role = 'admin'.
+// Роль читается из БД на каждый запрос, чтобы снятие прав действовало сразу.
+#[derive(Clone, Copy, Debug)]
+pub struct CurrentAdmin {
+    pub id: i32,
+    pub session_id: Uuid,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CurrentAdmin
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let CurrentUser { id, session_id } = CurrentUser::from_request_parts(parts, state).await?;
+        let app_state = AppState::from_ref(state);
+
+        let role: Option<String> =
+            sqlx::query_scalar("SELECT server_role FROM users WHERE id = $1")
+                .bind(id)
+                .fetch_optional(&app_state.pool)
+                .await
+                .map_err(|_| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Ошибка проверки прав администратора".to_string(),
+                    )
+                })?;
+        if role.as_deref() != Some(crate::route::admin::SERVER_ROLE_ADMIN) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Недостаточно прав: требуется администратор сервера".to_string(),
+            ));
+        }
+
+        Ok(CurrentAdmin { id, session_id })
+    }
+}
+
+// Спан на каждый HTTP-запрос: метод, шаблон маршрута, chat_id из пути;
+// user_id и session_id дописывает экстрактор CurrentUser. Тела запросов
+// не логируются: в них бывают пароли, ключи и сообщения.
 pub async fn logging(req: Request, next: Next) -> Response {
     let started = Instant::now();
-    let now = Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
-    let method = req.method().clone();
-    let uri = req.uri().clone();
-    let query = sanitize_query(uri.query().unwrap_or(""));
-    let path = req
-        .extensions()
+    let (mut parts, body) = req.into_parts();
+    let query = sanitize_query(parts.uri.query().unwrap_or(""));
+    let path = parts
+        .extensions
         .get::<MatchedPath>()
         .map(|p| p.as_str().to_string())
-        .unwrap_or_else(|| uri.path().to_string());
-    let connect_ip = req
-        .extensions()
+        .unwrap_or_else(|| parts.uri.path().to_string());
+    let connect_ip = parts
+        .extensions
         .get::<ConnectInfo<SocketAddr>>()
         .map(|c| c.0.ip().to_string())
         .unwrap_or_else(|| "-".to_string());
-    let forwarded_ip = req
-        .headers()
+    let forwarded_ip = parts
+        .headers
         .get("x-forwarded-for")
         .and_then(|v| v.to_str().ok())
         .and_then(|v| v.split(',').next())
         .map(|s| s.trim().to_string())
         .filter(|s| !s.is_empty());
     let from = forwarded_ip.unwrap_or(connect_ip);
+    let chat_id = match RawPathParams::from_request_parts(&mut parts, &()).await {
+        Ok(params) => chat_id_param(&path, &params),
+        Err(_) => None,
+    };
+
+    let span = tracing::info_span!(
+        "http_request",
+        method = %parts.method,
+        path = %path,
+        client_ip = %from,
+        chat_id = chat_id,
+        user_id = field::Empty,
+        session_id = field::Empty,
+        status = field::Empty,
+    );
 
-    // IMPORTANT: do not consume request body for multipart uploads (it breaks parsing).
-    // We also bypass body preview for /media endpoints.
-    let (parts, body) = req.into_parts();
+    let req = Request::from_parts(parts, body);
+    let res = next.run(req).instrument(span.clone()).await;
 
-    let content_type = parts
-        .headers
-        .get(header::CONTENT_TYPE)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-
-    let should_skip_body =
-        path.starts_with("/media") || content_type.to_ascii_lowercase().starts_with("multipart/");
-
-    if should_skip_body {
-        let req = Request::from_parts(parts, body);
-        let res = next.run(req).await;
-
-        let status = res.status();
-        let elapsed = started.elapsed();
-        println!(
-            "{} | {} | {} {} | {} ms | {}",
-            now,
-            from,
-            method,
-            path,
-            elapsed.as_millis(),
-            status.as_u16()
-        );
-        if !query.is_empty() {
-            println!("  query: {}", query);
-        }
-        if status.is_client_error() || status.is_server_error() {
-            println!("Ошибка: статус {}", status.as_u16());
-        }
+    let status = res.status();
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    span.record("status", status.as_u16());
+    let _entered = span.enter();
+
+    if !status.is_client_error() && !status.is_server_error() {
+        tracing::info!(elapsed_ms, query = %query, "request completed");
         return res;
     }
 
-    // Read and clone body with limit
-    let bytes: Bytes = match to_bytes(body, 64 * 1024).await {
-        // 64KB limit
-        Ok(b) => b,
-        Err(_) => Bytes::new(),
-    };
-    // Try to render body as UTF-8 text, truncate for safety
-    // Redact sensitive payloads on auth routes.
-    let body_preview = if path.starts_with("/auth/") {
-        String::from("<redacted>")
-    } else {
-        match std::str::from_utf8(&bytes) {
-            Ok(s) if !s.is_empty() => {
-                let max = 2000.min(s.len());
-                let snippet = &s[..max];
-                // Quick heuristic: if body likely contains secrets, redact.
-                if snippet.contains("\"password\"")
-                    || snippet.contains("\"token\"")
-                    || snippet.contains("Bearer ")
-                {
-                    String::from("<redacted>")
-                } else {
-                    snippet.to_string()
-                }
+    // Текст ошибки (StatusCode, String) из обработчика — в лог вместе со спаном.
+    // Читаем только тела известного небольшого размера; остальные (потоки,
+    // большие ответы) уходят клиенту нетронутыми, в логе — только статус.
+    let (parts, body) = res.into_parts();
+    let small = body
+        .size_hint()
+        .exact()
+        .is_some_and(|len| len <= ERROR_LOG_BODY_LIMIT as u64);
+    let (body, error) = if small {
+        match to_bytes(body, ERROR_LOG_BODY_LIMIT).await {
+            Ok(bytes) => {
+                let error = String::from_utf8_lossy(&bytes).into_owned();
+                (Body::from(bytes), error)
             }
-            _ => String::new(),
+            Err(e) => (Body::empty(), format!("<тело не прочитано: {e}>")),
         }
+    } else {
+        (body, "<тело не логируется>".to_string())
     };
-    // Rebuild request with the consumed body
-    let req = Request::from_parts(parts, Body::from(bytes.clone()));
+    if status.is_server_error() {
+        tracing::error!(elapsed_ms, query = %query, error = %error, "request failed");
+    } else {
+        tracing::warn!(elapsed_ms, query = %query, error = %error, "request rejected");
+    }
+    Response::from_parts(parts, body)
+}
+
+// Тела ошибок больше этого размера в лог не читаются
+const ERROR_LOG_BODY_LIMIT: usize = 4 * 1024;
+
+// chat_id из параметров пути: /chats/:chat_id/... и /chats/:id/...
+fn chat_id_param(path: &str, params: &RawPathParams) -> Option<i64> {
+    params
+        .iter()
+        .find(|(name, _)| *name == "chat_id" || (*name == "id" && path.starts_with("/chats/")))
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+// Счётчик и гистограмма HTTP-запросов по шаблону маршрута (MatchedPath),
+// а не по фактическому пути: иначе id в URL раздувают число рядов метрики.
+pub async fn track_http_metrics(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let method = req.method().as_str().to_string();
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
 
     let res = next.run(req).await;
-    let status = res.status();
-    let elapsed = started.elapsed();
-    println!(
-        "{} | {} | {} {} | {} ms | {}",
-        now,
-        from,
-        method,
-        path,
-        elapsed.as_millis(),
-        status.as_u16()
-    );
-    // extra line with query and body for tests
-    if !query.is_empty() || !body_preview.is_empty() {
-        println!("  query: {}\n  body: {}", query, body_preview);
-    }
-    if status.is_client_error() || status.is_server_error() {
-        println!("Ошибка: статус {}", status.as_u16());
-    }
+
+    let status = res.status().as_u16().to_string();
+    state
+        .metrics
+        .http_requests
+        .with_label_values(&[&method, &path, &status])
+        .inc();
+    state
+        .metrics
+        .http_duration
+        .with_label_values(&[&method, &path])
+        .observe(started.elapsed().as_secs_f64());
     res
 }
 
@@ -393,3 +490,137 @@ pub async fn ensure_admin(
     }
     Ok(())
 }
+
+// Сколько времени после отправки автор может удалить сообщение у всех.
+pub const DELETE_FOR_EVERYONE_WINDOW_HOURS: i64 = 48;
+
+// Утилита: убедиться, что пользователь может удалить сообщение у всех участников.
+// Автор — в пределах DELETE_FOR_EVERYONE_WINDOW_HOURS. В group/channel чужие сообщения
+// удаляют admin/owner (без ограничения по времени), но не сообщения owner.
+// В private чужие сообщения удалить у всех нельзя.
+pub async fn ensure_can_delete_for_everyone(
+    state: &AppState,
+    chat_id: i32,
+    message_id: i64,
+    user_id: i32,
+) -> Result<(), (StatusCode, String)> {
+    let row = sqlx::query(
+        r#"
+        SELECT m.sender_id, m.created_at, c.kind, COALESCE(sp.role, 'member') AS sender_role
+        FROM messages m
+        JOIN chats c ON c.id = m.chat_id
+        LEFT JOIN chat_participants sp ON sp.chat_id = m.chat_id AND sp.user_id = m.sender_id
+        WHERE m.id = $1 AND m.chat_id = $2 AND m.deleted_at IS NULL
+        "#,
+    )
+    .bind(message_id)
+    .bind(chat_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+
+    let Some(row) = row else {
+        return Err((StatusCode::NOT_FOUND, "Сообщение не найдено".into()));
+    };
+
+    let sender_id: i32 = row.try_get("sender_id").unwrap_or_default();
+    let is_author = sender_id == user_id;
+    let kind: String = row.try_get("kind").unwrap_or_default();
+    let sender_role: String = row
+        .try_get("sender_role")
+        .unwrap_or_else(|_| "member".to_string());
+    let created_at: chrono::DateTime<chrono::Utc> = row
+        .try_get("created_at")
+        .unwrap_or_else(|_| chrono::Utc::now());
+
+    // Чужие сообщения в group/channel — только для admin/owner.
+    let actor_role = if !is_author && kind != "private" {
+        ensure_admin(state, chat_id, user_id).await?;
+        "admin"
+    } else {
+        "member"
+    };
+
+    if let Some(err) = delete_for_everyone_denial(
+        &kind,
+        actor_role,
+        &sender_role,
+        is_author,
+        chrono::Utc::now() - created_at,
+    ) {
+        return Err((StatusCode::FORBIDDEN, err));
+    }
+
+    Ok(())
+}
+
+// Правила удаления у всех без обращения к БД (общие для одиночного и пакетного удаления).
+// Возвращает текст ошибки, если удаление запрещено.
+pub fn delete_for_everyone_denial(
+    kind: &str,
+    actor_role: &str,
+    sender_role: &str,
+    is_author: bool,
+    age: chrono::Duration,
+) -> Option<String> {
+    if is_author {
+        if age > chrono::Duration::hours(DELETE_FOR_EVERYONE_WINDOW_HOURS) {
+            return Some(format!(
+                "Удалить у всех можно только в течение {} ч после отправки",
+                DELETE_FOR_EVERYONE_WINDOW_HOURS
+            ));
+        }
+        return None;
+    }
+
+    if kind == "private" {
+        return Some(
+            "Недостаточно прав: в личном чате у всех можно удалить только свои сообщения".into(),
+        );
+    }
+    if actor_role != "admin" && actor_role != "owner" {
+        return Some("Недостаточно прав (нужна роль admin/owner)".into());
+    }
+    if sender_role == "owner" {
+        return Some("Недостаточно прав: нельзя удалить сообщение владельца чата".into());
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DELETE_FOR_EVERYONE_WINDOW_HOURS, delete_for_everyone_denial};
+    use chrono::Duration;
+
+    #[test]
+    fn author_can_delete_within_window() {
+        let age = Duration::hours(1);
+        assert!(delete_for_everyone_denial("private", "member", "member", true, age).is_none());
+    }
+
+    #[test]
+    fn author_cannot_delete_after_window() {
+        let age = Duration::hours(DELETE_FOR_EVERYONE_WINDOW_HOURS + 1);
+        assert!(delete_for_everyone_denial("group", "owner", "owner", true, age).is_some());
+    }
+
+    #[test]
+    fn admin_can_delete_member_message_in_group() {
+        let age = Duration::days(30);
+        assert!(delete_for_everyone_denial("group", "admin", "member", false, age).is_none());
+    }
+
+    #[test]
+    fn others_cannot_delete_in_private_or_owner_messages() {
+        let age = Duration::minutes(1);
+        assert!(delete_for_everyone_denial("private", "owner", "member", false, age).is_some());
+        assert!(delete_for_everyone_denial("group", "member", "member", false, age).is_some());
+        assert!(delete_for_everyone_denial("channel", "admin", "owner", false, age).is_some());
+    }
+}
@@ -1,133 +1,303 @@
 use axum::{
-    extract::{Request, State},
-    http::StatusCode,
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
     middleware::Next,
-    response::Response,
-};
-use dashmap::DashMap;
-use std::{
-    sync::Arc,
-    time::{Duration, Instant},
+    response::{IntoResponse, Response},
 };
+use jsonwebtoken::{DecodingKey, Validation, decode};
+use std::{collections::BTreeSet, net::SocketAddr, sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use super::rate_limit_store::CounterStore;
+use crate::AppState;
+use crate::models::auth::Claims;
+use crate::route::auth::extract_ip;
+
+// P1-7: Rate limiter middleware
+//
+// Token bucket per (policy, key). Policies are declared per route group and
+// per WebSocket event type; each one sets the burst (bucket capacity), the
+// sustained rate and what the bucket is keyed by.
+
+/// What a bucket is keyed by. `User` / `Session` fall back to the client IP
+/// for unauthenticated requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitKey {
+    Ip,
+    User,
+    Session,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitPolicy {
+    /// Policy name, also the bucket namespace
+    pub name: &'static str,
+    /// Bucket capacity: requests allowed back to back
+    pub burst: u32,
+    /// Sustained rate the bucket refills at
+    pub per_minute: u32,
+    pub key: RateLimitKey,
+}
+
+impl RateLimitPolicy {
+    pub const fn new(name: &'static str, burst: u32, per_minute: u32, key: RateLimitKey) -> Self {
+        Self {
+            name,
+            burst,
+            per_minute,
+            key,
+        }
+    }
+}
+
+/// Route group: first policy whose prefix matches the route path wins
+#[derive(Clone, Copy, Debug)]
+pub struct RoutePolicy {
+    pub path_prefix: &'static str,
+    pub policy: RateLimitPolicy,
+}
+
+impl RoutePolicy {
+    pub const fn new(path_prefix: &'static str, policy: RateLimitPolicy) -> Self {
+        Self {
+            path_prefix,
+            policy,
+        }
+    }
+}
 
-/// P1-7: Rate limiter middleware
-/// 
-/// Supports multiple bucketing strategies:
-/// - IP-based: rate limit by client IP address
-/// - Account-based: rate limit by user ID (after auth)
-/// - Device-based: rate limit by device fingerprint
+/// WebSocket client event (`type` field of the frame)
+#[derive(Clone, Copy, Debug)]
+pub struct WsEventPolicy {
+    pub event: &'static str,
+    pub policy: RateLimitPolicy,
+}
+
+impl WsEventPolicy {
+    pub const fn new(event: &'static str, policy: RateLimitPolicy) -> Self {
+        Self { event, policy }
+    }
+}
+
+const AUTH_POLICY: RateLimitPolicy = RateLimitPolicy::new("auth", 10, 30, RateLimitKey::Ip);
+const MEDIA_POLICY: RateLimitPolicy = RateLimitPolicy::new("media", 10, 30, RateLimitKey::User);
+const SEARCH_POLICY: RateLimitPolicy = RateLimitPolicy::new("search", 10, 60, RateLimitKey::User);
+const WS_CONNECT_POLICY: RateLimitPolicy =
+    RateLimitPolicy::new("ws_connect", 5, 12, RateLimitKey::Ip);
+const API_POLICY: RateLimitPolicy = RateLimitPolicy::new("api", 60, 300, RateLimitKey::User);
+
+const WS_MESSAGE_POLICY: RateLimitPolicy =
+    RateLimitPolicy::new("ws_message", 20, 60, RateLimitKey::Session);
+const WS_BATCH_POLICY: RateLimitPolicy =
+    RateLimitPolicy::new("ws_batch", 5, 20, RateLimitKey::Session);
+const WS_TYPING_POLICY: RateLimitPolicy =
+    RateLimitPolicy::new("ws_typing", 5, 30, RateLimitKey::Session);
+const WS_EVENT_POLICY: RateLimitPolicy =
+    RateLimitPolicy::new("ws_event", 30, 120, RateLimitKey::Session);
+
+/// Policy table for HTTP route groups
+pub const ROUTE_POLICIES: &[RoutePolicy] = &[
+    RoutePolicy::new("/auth/", AUTH_POLICY),
+    RoutePolicy::new("/media", MEDIA_POLICY),
+    RoutePolicy::new("/users/search", SEARCH_POLICY),
+    RoutePolicy::new("/chats/:chat_id/search", SEARCH_POLICY),
+    RoutePolicy::new("/ws", WS_CONNECT_POLICY),
+];
+
+/// Policy table for WebSocket client events
+pub const WS_EVENT_POLICIES: &[WsEventPolicy] = &[
+    WsEventPolicy::new("send_message", WS_MESSAGE_POLICY),
+    WsEventPolicy::new("voice_message", WS_MESSAGE_POLICY),
+    WsEventPolicy::new("video_message", WS_MESSAGE_POLICY),
+    WsEventPolicy::new("edit_message", WS_MESSAGE_POLICY),
+    WsEventPolicy::new("delete_message", WS_MESSAGE_POLICY),
+    WsEventPolicy::new("forward_message", WS_MESSAGE_POLICY),
+    WsEventPolicy::new("delete_messages", WS_BATCH_POLICY),
+    WsEventPolicy::new("forward_messages", WS_BATCH_POLICY),
+    WsEventPolicy::new("typing", WS_TYPING_POLICY),
+];
 
 #[derive(Clone)]
 pub struct RateLimiter {
-    // IP-based buckets: IP -> (request_count, window_start)
-    ip_buckets: Arc<DashMap<String, (u32, Instant)>>,
-    
-    // Account-based buckets: user_id -> (request_count, window_start)
-    account_buckets: Arc<DashMap<i32, (u32, Instant)>>,
-    
+    // Counters: in this process or shared between instances
+    store: Arc<dyn CounterStore>,
+
     // Configuration
-    config: RateLimiterConfig,
+    config: Arc<RateLimiterConfig>,
 }
 
 #[derive(Clone)]
 pub struct RateLimiterConfig {
-    /// Maximum requests per window
-    pub max_requests: u32,
-    /// Window duration
-    pub window_duration: Duration,
-    /// Enable IP-based limiting
-    pub enable_ip_limiting: bool,
-    /// Enable account-based limiting (requires auth)
-    pub enable_account_limiting: bool,
+    /// Route groups, most specific prefix first
+    pub routes: Vec<RoutePolicy>,
+    /// Policy for routes not listed in `routes`
+    pub default_route: RateLimitPolicy,
+    /// WebSocket client events
+    pub ws_events: Vec<WsEventPolicy>,
+    /// Policy for WebSocket events not listed in `ws_events`
+    pub default_ws_event: RateLimitPolicy,
+}
+
+impl RateLimiterConfig {
+    fn policies_mut(&mut self) -> impl Iterator<Item = &mut RateLimitPolicy> {
+        self.routes
+            .iter_mut()
+            .map(|r| &mut r.policy)
+            .chain(self.ws_events.iter_mut().map(|e| &mut e.policy))
+            .chain([&mut self.default_route, &mut self.default_ws_event])
+    }
+
+    fn policies(&self) -> impl Iterator<Item = &RateLimitPolicy> {
+        self.routes
+            .iter()
+            .map(|r| &r.policy)
+            .chain(self.ws_events.iter().map(|e| &e.policy))
+            .chain([&self.default_route, &self.default_ws_event])
+    }
+
+    /// Overrides the limits of every entry using the named policy
+    pub fn with_limits(mut self, name: &str, burst: u32, per_minute: u32) -> Self {
+        for policy in self.policies_mut().filter(|p| p.name == name) {
+            policy.burst = burst;
+            policy.per_minute = per_minute;
+        }
+        self
+    }
+
+    pub fn policy(&self, name: &str) -> Option<RateLimitPolicy> {
+        self.policies().find(|p| p.name == name).copied()
+    }
+
+    pub fn policy_names(&self) -> BTreeSet<&'static str> {
+        self.policies().map(|p| p.name).collect()
+    }
 }
 
 impl Default for RateLimiterConfig {
     fn default() -> Self {
         Self {
-            max_requests: 100, // 100 requests per window
-            window_duration: Duration::from_secs(60), // 1 minute window
-            enable_ip_limiting: true,
-            enable_account_limiting: false,
+            routes: ROUTE_POLICIES.to_vec(),
+            default_route: API_POLICY,
+            ws_events: WS_EVENT_POLICIES.to_vec(),
+            default_ws_event: WS_EVENT_POLICY,
         }
     }
 }
 
+/// Outcome of taking one token from a bucket
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the bucket is full again
+    pub reset_after: Duration,
+    /// Until the next request is allowed (only when denied)
+    pub retry_after: Option<Duration>,
+}
+
 impl RateLimiter {
-    pub fn new(config: RateLimiterConfig) -> Self {
+    pub fn new(config: RateLimiterConfig, store: Arc<dyn CounterStore>) -> Self {
         Self {
-            ip_buckets: Arc::new(DashMap::new()),
-            account_buckets: Arc::new(DashMap::new()),
-            config,
+            store,
+            config: Arc::new(config),
         }
     }
-    
-    /// Check if request should be allowed based on IP
-    pub fn check_ip_limit(&self, ip: &str) -> bool {
-        if !self.config.enable_ip_limiting {
-            return true;
-        }
-        
-        let now = Instant::now();
-        let mut entry = self.ip_buckets.entry(ip.to_string()).or_insert((0, now));
-        
-        // Check if window has expired
-        if now.duration_since(entry.1) > self.config.window_duration {
-            // Reset window
-            *entry = (1, now);
-            return true;
-        }
-        
-        // Check if limit exceeded
-        if entry.0 >= self.config.max_requests {
-            return false;
-        }
-        
-        // Increment counter
-        entry.0 += 1;
-        true
+
+    /// Policy for an HTTP route (matched path, e.g. `/chats/:chat_id/messages`)
+    pub fn route_policy(&self, path: &str) -> RateLimitPolicy {
+        self.config
+            .routes
+            .iter()
+            .find(|r| path.starts_with(r.path_prefix))
+            .map(|r| r.policy)
+            .unwrap_or(self.config.default_route)
     }
-    
-    /// Check if request should be allowed based on user ID
-    pub fn check_account_limit(&self, user_id: i32, max_requests: Option<u32>) -> bool {
-        if !self.config.enable_account_limiting {
-            return true;
-        }
-        
-        let now = Instant::now();
-        let limit = max_requests.unwrap_or(self.config.max_requests);
-        
-        let mut entry = self.account_buckets.entry(user_id).or_insert((0, now));
-        
-        // Check if window has expired
-        if now.duration_since(entry.1) > self.config.window_duration {
-            // Reset window
-            *entry = (1, now);
-            return true;
-        }
-        
-        // Check if limit exceeded
-        if entry.0 >= limit {
-            return false;
+
+    /// Policy for a WebSocket client event type
+    pub fn ws_policy(&self, event: &str) -> RateLimitPolicy {
+        self.config
+            .ws_events
+            .iter()
+            .find(|e| e.event == event)
+            .map(|e| e.policy)
+            .unwrap_or(self.config.default_ws_event)
+    }
+
+    /// Count one request against the `(policy, key)` limit. If the counter
+    /// store is unavailable the request is let through.
+    pub async fn check(&self, policy: &RateLimitPolicy, key: &str) -> RateLimitDecision {
+        let bucket = format!("{}:{}", policy.name, key);
+        match self.store.hit(policy, &bucket).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                tracing::error!(error = %e, "Ошибка хранилища rate limit");
+                RateLimitDecision {
+                    allowed: true,
+                    limit: policy.burst,
+                    remaining: policy.burst,
+                    reset_after: Duration::ZERO,
+                    retry_after: None,
+                }
+            }
         }
-        
-        // Increment counter
-        entry.0 += 1;
-        true
     }
-    
-    /// Cleanup old entries (call periodically)
+
+    /// Cleanup old entries (call periodically): a counter idle for longer
+    /// than two refill periods of the slowest policy no longer matters
     pub async fn cleanup(&self) {
-        let now = Instant::now();
-        
-        // Cleanup IP buckets
-        self.ip_buckets.retain(|_, (_, start)| {
-            now.duration_since(*start) <= self.config.window_duration
-        });
-        
-        // Cleanup account buckets
-        self.account_buckets.retain(|_, (_, start)| {
-            now.duration_since(*start) <= self.config.window_duration
-        });
+        let max_idle = self
+            .config
+            .routes
+            .iter()
+            .map(|r| r.policy)
+            .chain(self.config.ws_events.iter().map(|e| e.policy))
+            .chain([self.config.default_route, self.config.default_ws_event])
+            .map(|p| 2.0 * f64::from(p.burst.max(1)) * 60.0 / f64::from(p.per_minute.max(1)))
+            .fold(0.0, f64::max);
+
+        if let Err(e) = self
+            .store
+            .cleanup_hits(Duration::from_secs_f64(max_idle))
+            .await
+        {
+            tracing::warn!(error = %e, "Ошибка очистки счётчиков rate limit");
+        }
+    }
+}
+
+/// Bucket key for a request: the client IP or, for `User` / `Session`
+/// policies, the authenticated user or session
+pub fn rate_limit_subject(
+    key: RateLimitKey,
+    ip: &str,
+    user_id: Option<i32>,
+    session_id: Option<Uuid>,
+) -> String {
+    match (key, user_id, session_id) {
+        (RateLimitKey::User, Some(user_id), _) => format!("user:{user_id}"),
+        (RateLimitKey::Session, _, Some(session_id)) => format!("session:{session_id}"),
+        _ => format!("ip:{ip}"),
+    }
+}
+
+/// `RateLimit-*` headers (IETF draft) and `Retry-After` for a denied request
+pub fn apply_rate_limit_headers(headers: &mut HeaderMap, decision: &RateLimitDecision) {
+    let secs = |d: Duration| HeaderValue::from(d.as_secs_f64().ceil() as u64);
+    headers.insert(
+        HeaderName::from_static("ratelimit-limit"),
+        HeaderValue::from(decision.limit),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-remaining"),
+        HeaderValue::from(decision.remaining),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-reset"),
+        secs(decision.reset_after),
+    );
+    if let Some(retry_after) = decision.retry_after {
+        headers.insert(header::RETRY_AFTER, secs(retry_after));
     }
 }
 
@@ -135,12 +305,9 @@ impl RateLimiter {
 /// More restrictive limits for login/register/refresh
 #[derive(Clone)]
 pub struct AuthRateLimiter {
-    // IP-based: IP -> (failed_attempts, lockout_until)
-    ip_failures: Arc<DashMap<String, (u32, Option<Instant>)>>,
-    
-    // Account-based: user_id -> (failed_attempts, lockout_until)
-    account_failures: Arc<DashMap<String, (u32, Option<Instant>)>>,
-    
+    // Failure counters under "auth:ip:<ip>" / "auth:account:<login>"
+    store: Arc<dyn CounterStore>,
+
     config: AuthRateLimiterConfig,
 }
 
@@ -168,156 +335,161 @@ impl Default for AuthRateLimiterConfig {
 }
 
 impl AuthRateLimiter {
-    pub fn new(config: AuthRateLimiterConfig) -> Self {
-        Self {
-            ip_failures: Arc::new(DashMap::new()),
-            account_failures: Arc::new(DashMap::new()),
-            config,
+    pub fn new(config: AuthRateLimiterConfig, store: Arc<dyn CounterStore>) -> Self {
+        Self { store, config }
+    }
+
+    fn keys(ip: &str, account: Option<&str>) -> Vec<String> {
+        let mut keys = vec![format!("auth:ip:{ip}")];
+        if let Some(account) = account {
+            keys.push(format!("auth:account:{account}"));
         }
+        keys
     }
-    
+
     /// Record a failed authentication attempt
     /// Returns (allowed, lockout_seconds)
-    pub fn record_failure(&self, ip: &str, account: Option<&str>) -> (bool, Option<u64>) {
-        let now = Instant::now();
-        
-        // Check IP-based lockout
-        if let Some(mut entry) = self.ip_failures.get_mut(ip) {
-            if let Some(lockout_until) = entry.1 {
-                if now < lockout_until {
-                    let remaining = lockout_until.duration_since(now).as_secs();
-                    return (false, Some(remaining));
-                } else {
-                    // Lockout expired, reset
-                    entry.0 = 0;
-                    entry.1 = None;
-                }
-            }
-            
-            entry.0 += 1;
-            
-            if entry.0 >= self.config.max_failures {
-                // Calculate lockout with exponential backoff
-                let backoff = self.config.lockout_duration.as_secs()
-                    * (self.config.backoff_multiplier as u64).pow((entry.0 - self.config.max_failures).min(10));
-                let lockout = Duration::from_secs(backoff.min(self.config.max_lockout.as_secs()));
-                entry.1 = Some(now + lockout);
-                
-                return (false, Some(lockout.as_secs()));
-            }
-        } else {
-            self.ip_failures.insert(ip.to_string(), (1, None));
-        }
-        
-        // Check account-based lockout
-        if let Some(account) = account {
-            if let Some(mut entry) = self.account_failures.get_mut(account) {
-                if let Some(lockout_until) = entry.1 {
-                    if now < lockout_until {
-                        let remaining = lockout_until.duration_since(now).as_secs();
-                        return (false, Some(remaining));
-                    } else {
-                        entry.0 = 0;
-                        entry.1 = None;
-                    }
-                }
-                
-                entry.0 += 1;
-                
-                if entry.0 >= self.config.max_failures {
-                    let backoff = self.config.lockout_duration.as_secs()
-                        * (self.config.backoff_multiplier as u64).pow((entry.0 - self.config.max_failures).min(10));
-                    let lockout = Duration::from_secs(backoff.min(self.config.max_lockout.as_secs()));
-                    entry.1 = Some(now + lockout);
-                    
-                    return (false, Some(lockout.as_secs()));
+    ///
+    /// Fails closed: if the store is unavailable the attempt is not allowed,
+    /// otherwise a store outage would lift the brute-force lockout.
+    pub async fn record_failure(&self, ip: &str, account: Option<&str>) -> (bool, Option<u64>) {
+        // IP first: once the IP is locked the account counter is left alone
+        for key in Self::keys(ip, account) {
+            match self.store.record_failure(&key, &self.config).await {
+                Ok(Some(lockout)) => return (false, Some(lockout.as_secs())),
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!(
+                        error = %e,
+                        key = %key,
+                        "Ошибка хранилища rate limit: попытка отклонена"
+                    );
+                    return (false, None);
                 }
-            } else {
-                self.account_failures.insert(account.to_string(), (1, None));
             }
         }
-        
+
         (true, None)
     }
-    
+
     /// Record a successful authentication - reset counters
-    pub fn record_success(&self, ip: &str, account: Option<&str>) {
-        self.ip_failures.remove(ip);
-        if let Some(acc) = account {
-            self.account_failures.remove(&acc.to_string());
+    pub async fn record_success(&self, ip: &str, account: Option<&str>) {
+        for key in Self::keys(ip, account) {
+            if let Err(e) = self.store.reset_failures(&key).await {
+                tracing::error!(error = %e, "Ошибка хранилища rate limit");
+            }
         }
     }
-    
+
     /// Check if request is allowed (without recording)
-    pub fn is_allowed(&self, ip: &str, account: Option<&str>) -> bool {
-        let now = Instant::now();
-        
-        // Check IP lockout
-        if let Some(entry) = self.ip_failures.get(ip) {
-            if let Some(lockout_until) = entry.1 {
-                if now < lockout_until {
+    ///
+    /// Fails closed: a store error denies the attempt (callers answer 429)
+    pub async fn is_allowed(&self, ip: &str, account: Option<&str>) -> bool {
+        for key in Self::keys(ip, account) {
+            match self.store.failures(&key).await {
+                Ok(state) if state.locked_for.is_some() => return false,
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!(
+                        error = %e,
+                        key = %key,
+                        "Ошибка хранилища rate limit: попытка отклонена"
+                    );
                     return false;
                 }
             }
         }
-        
-        // Check account lockout
-        if let Some(account) = account {
-            if let Some(entry) = self.account_failures.get(&account.to_string()) {
-                if let Some(lockout_until) = entry.1 {
-                    if now < lockout_until {
-                        return false;
-                    }
-                }
-            }
-        }
-        
+
         true
     }
-    
+
     /// Get remaining attempts for an IP/account
-    pub fn get_remaining_attempts(&self, ip: &str, account: Option<&str>) -> u32 {
-        let ip_remaining = self.ip_failures
-            .get(ip)
-            .map(|e| self.config.max_failures.saturating_sub(e.0))
-            .unwrap_or(self.config.max_failures);
-        
-        let account_remaining = account
-            .and_then(|acc| self.account_failures.get(acc))
-            .map(|e| self.config.max_failures.saturating_sub(e.0))
-            .unwrap_or(self.config.max_failures);
-        
-        ip_remaining.min(account_remaining)
+    pub async fn get_remaining_attempts(&self, ip: &str, account: Option<&str>) -> u32 {
+        let mut remaining = self.config.max_failures;
+        for key in Self::keys(ip, account) {
+            if let Ok(state) = self.store.failures(&key).await {
+                remaining = remaining.min(self.config.max_failures.saturating_sub(state.failures));
+            }
+        }
+        remaining
+    }
+
+    /// Cleanup counters without an active lockout (call periodically)
+    pub async fn cleanup(&self) {
+        if let Err(e) = self.store.cleanup_failures(self.config.max_lockout).await {
+            tracing::warn!(error = %e, "Ошибка очистки счётчиков rate limit");
+        }
     }
 }
 
 /// P1-7: Middleware for general rate limiting
+///
+/// Mounted with `Router::layer`, so it runs after routing and sees the matched
+/// path. The JWT is only decoded here to pick the bucket; the session itself is
+/// checked later by `CurrentUser`.
 pub async fn rate_limit_middleware(
-    State(limiter): State<RateLimiter>,
+    State(state): State<AppState>,
     req: Request,
     next: Next,
-) -> Result<Response, StatusCode> {
-    // Extract IP from request
+) -> Response {
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    let policy = state.rate_limiter.route_policy(&path);
+
     let ip = req
         .extensions()
-        .get::<axum::extract::ConnectInfo<std::net::SocketAddr>>()
-        .map(|ci| ci.0.ip().to_string())
-        .or_else(|| {
-            req.headers()
-                .get("x-forwarded-for")
-                .and_then(|v| v.to_str().ok())
-                .and_then(|s| s.split(',').next())
-                .map(|s| s.trim().to_string())
-        })
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ci| extract_ip(&state, req.headers(), ci.0))
         .unwrap_or_else(|| "unknown".to_string());
-    
-    // Check IP-based rate limit
-    if !limiter.check_ip_limit(&ip) {
-        return Err(StatusCode::TOO_MANY_REQUESTS);
-    }
-    
-    // Continue to next middleware/handler
-    Ok(next.run(req).await)
+    let claims = if policy.key == RateLimitKey::Ip {
+        None
+    } else {
+        bearer_claims(req.headers(), &state.jwt_secret)
+    };
+    let subject = rate_limit_subject(
+        policy.key,
+        &ip,
+        claims.as_ref().map(|c| c.sub),
+        claims.as_ref().and_then(|c| Uuid::parse_str(&c.sid).ok()),
+    );
+
+    let decision = state.rate_limiter.check(&policy, &subject).await;
+    let mut response = if decision.allowed {
+        next.run(req).await
+    } else {
+        state
+            .metrics
+            .rate_limit_rejections
+            .with_label_values(&[policy.name])
+            .inc();
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            "Слишком много запросов, попробуйте позже".to_string(),
+        )
+            .into_response()
+    };
+    apply_rate_limit_headers(response.headers_mut(), &decision);
+    response
+}
+
+fn bearer_claims(headers: &HeaderMap, jwt_secret: &str) -> Option<Claims> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.strip_prefix("Bearer ")
+                .or_else(|| v.strip_prefix("bearer "))
+        })?;
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(jwt_secret.as_bytes()),
+        &Validation::default(),
+    )
+    .ok()
+    .map(|data| data.claims)
 }
 
 /// P1-7: Middleware for strict auth rate limiting
@@ -341,9 +513,113 @@ pub async fn auth_rate_limit_middleware(
         .unwrap_or_else(|| "unknown".to_string());
     
     // Check if allowed
-    if !limiter.is_allowed(&ip, None) {
+    if !limiter.is_allowed(&ip, None).await {
         return Err(StatusCode::TOO_MANY_REQUESTS);
     }
     
     Ok(next.run(req).await)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::rate_limit_store::{FailureState, MemoryCounterStore};
+
+    fn memory_limiter() -> RateLimiter {
+        RateLimiter::new(
+            RateLimiterConfig::default(),
+            Arc::new(MemoryCounterStore::new()),
+        )
+    }
+
+    #[tokio::test]
+    async fn bucket_allows_burst_then_denies_with_retry_after() {
+        let limiter = memory_limiter();
+        let policy = RateLimitPolicy::new("test", 3, 60, RateLimitKey::Ip);
+
+        for remaining in [2, 1, 0] {
+            let decision = limiter.check(&policy, "ip:127.0.0.1").await;
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+
+        let denied = limiter.check(&policy, "ip:127.0.0.1").await;
+        assert!(!denied.allowed);
+        let retry_after = denied.retry_after.unwrap();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+
+        // Другой ключ — своя корзина
+        assert!(limiter.check(&policy, "ip:10.0.0.1").await.allowed);
+    }
+
+    #[test]
+    fn policies_are_picked_from_tables() {
+        let limiter = memory_limiter();
+        assert_eq!(limiter.route_policy("/auth/login").name, "auth");
+        assert_eq!(
+            limiter.route_policy("/chats/:chat_id/search/index").name,
+            "search"
+        );
+        assert_eq!(limiter.route_policy("/chats/:chat_id/messages").name, "api");
+        assert_eq!(limiter.ws_policy("typing").name, "ws_typing");
+        assert_eq!(limiter.ws_policy("join_chat").name, "ws_event");
+
+        let session_id = Uuid::new_v4();
+        assert_eq!(
+            rate_limit_subject(RateLimitKey::Session, "1.2.3.4", Some(7), Some(session_id)),
+            format!("session:{session_id}")
+        );
+        assert_eq!(
+            rate_limit_subject(RateLimitKey::User, "1.2.3.4", None, None),
+            "ip:1.2.3.4"
+        );
+    }
+
+    // Хранилище, которое всегда недоступно
+    struct BrokenStore;
+
+    #[axum::async_trait]
+    impl CounterStore for BrokenStore {
+        async fn hit(
+            &self,
+            _policy: &RateLimitPolicy,
+            _key: &str,
+        ) -> Result<RateLimitDecision, sqlx::Error> {
+            Err(sqlx::Error::PoolTimedOut)
+        }
+
+        async fn failures(&self, _key: &str) -> Result<FailureState, sqlx::Error> {
+            Err(sqlx::Error::PoolTimedOut)
+        }
+
+        async fn record_failure(
+            &self,
+            _key: &str,
+            _config: &AuthRateLimiterConfig,
+        ) -> Result<Option<Duration>, sqlx::Error> {
+            Err(sqlx::Error::PoolTimedOut)
+        }
+
+        async fn reset_failures(&self, _key: &str) -> Result<(), sqlx::Error> {
+            Err(sqlx::Error::PoolTimedOut)
+        }
+
+        async fn cleanup_hits(&self, _max_idle: Duration) -> Result<(), sqlx::Error> {
+            Err(sqlx::Error::PoolTimedOut)
+        }
+
+        async fn cleanup_failures(&self, _max_idle: Duration) -> Result<(), sqlx::Error> {
+            Err(sqlx::Error::PoolTimedOut)
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_limiter_fails_closed_on_store_errors() {
+        let limiter = AuthRateLimiter::new(AuthRateLimiterConfig::default(), Arc::new(BrokenStore));
+        assert!(!limiter.is_allowed("1.2.3.4", Some("alice")).await);
+        assert_eq!(
+            limiter.record_failure("1.2.3.4", Some("alice")).await,
+            (false, None)
+        );
+    }
+}
@@ -0,0 +1,569 @@
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::time::{Duration, Instant};
+
+use super::rate_limit::{AuthRateLimiterConfig, RateLimitDecision, RateLimitPolicy};
+
+/// Counter storage shared by `RateLimiter` and `AuthRateLimiter`.
+///
+/// `memory` keeps counters in this process (token buckets); `postgres` shares
+/// them between backend instances (sliding window). Selected by
+/// `RATE_LIMIT_STORE`.
+#[async_trait]
+pub trait CounterStore: Send + Sync {
+    /// Count one request against the `(policy, key)` limit
+    async fn hit(
+        &self,
+        policy: &RateLimitPolicy,
+        key: &str,
+    ) -> Result<RateLimitDecision, sqlx::Error>;
+
+    /// Failed attempts and the active lockout for a key
+    async fn failures(&self, key: &str) -> Result<FailureState, sqlx::Error>;
+
+    /// Record a failed attempt. Returns the lockout if the key is (now) locked
+    async fn record_failure(
+        &self,
+        key: &str,
+        config: &AuthRateLimiterConfig,
+    ) -> Result<Option<Duration>, sqlx::Error>;
+
+    async fn reset_failures(&self, key: &str) -> Result<(), sqlx::Error>;
+
+    /// Drop request counters untouched for `max_idle`
+    async fn cleanup_hits(&self, max_idle: Duration) -> Result<(), sqlx::Error>;
+
+    /// Drop unlocked failure counters untouched for `max_idle`
+    async fn cleanup_failures(&self, max_idle: Duration) -> Result<(), sqlx::Error>;
+}
+
+/// Where counters live: per process (`memory`) or shared between instances (`postgres`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CounterStoreKind {
+    #[default]
+    Memory,
+    #[serde(alias = "pg")]
+    Postgres,
+}
+
+impl CounterStoreKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "memory" => Some(Self::Memory),
+            "postgres" | "pg" => Some(Self::Postgres),
+            _ => None,
+        }
+    }
+}
+
+/// Failure counter as seen by callers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FailureState {
+    pub failures: u32,
+    pub locked_for: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FailureRecord {
+    failures: u32,
+    lockout_until: Option<DateTime<Utc>>,
+    updated_at: DateTime<Utc>,
+}
+
+impl FailureRecord {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            failures: 0,
+            lockout_until: None,
+            updated_at: now,
+        }
+    }
+
+    fn locked_for(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.lockout_until
+            .filter(|until| *until > now)
+            .and_then(|until| (until - now).to_std().ok())
+    }
+
+    fn state(&self, now: DateTime<Utc>) -> FailureState {
+        FailureState {
+            failures: self.failures,
+            locked_for: self.locked_for(now),
+        }
+    }
+
+    /// While locked, attempts are not counted; after the lockout the counter
+    /// starts over. Reaching `max_failures` locks with exponential backoff.
+    fn apply_failure(
+        &mut self,
+        now: DateTime<Utc>,
+        config: &AuthRateLimiterConfig,
+    ) -> Option<Duration> {
+        if let Some(remaining) = self.locked_for(now) {
+            return Some(remaining);
+        }
+        if self.lockout_until.is_some() {
+            // Lockout expired, reset
+            self.failures = 0;
+            self.lockout_until = None;
+        }
+
+        self.failures += 1;
+        self.updated_at = now;
+        if self.failures < config.max_failures {
+            return None;
+        }
+
+        // Calculate lockout with exponential backoff
+        let backoff = config.lockout_duration.as_secs()
+            * u64::from(config.backoff_multiplier)
+                .pow((self.failures - config.max_failures).min(10));
+        let lockout = Duration::from_secs(backoff.min(config.max_lockout.as_secs()));
+        self.lockout_until = chrono::Duration::from_std(lockout).ok().map(|d| now + d);
+        Some(lockout)
+    }
+}
+
+fn allow_rate(policy: &RateLimitPolicy) -> f64 {
+    f64::from(policy.per_minute.max(1)) / 60.0
+}
+
+// ---------------------------
+// In-memory: token bucket per key
+// ---------------------------
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+pub struct MemoryCounterStore {
+    // (policy, key) -> token bucket
+    buckets: DashMap<String, TokenBucket>,
+    // key -> failed attempts / lockout
+    failures: DashMap<String, FailureRecord>,
+}
+
+impl MemoryCounterStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn take_token(&self, policy: &RateLimitPolicy, key: &str) -> RateLimitDecision {
+        let now = Instant::now();
+        let burst = f64::from(policy.burst.max(1));
+        let rate = allow_rate(policy);
+
+        let mut bucket = self.buckets.entry(key.to_string()).or_insert(TokenBucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        // Refill for the time since the last request
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let tokens = bucket.tokens;
+
+        RateLimitDecision {
+            allowed,
+            limit: policy.burst,
+            remaining: tokens.floor() as u32,
+            reset_after: Duration::from_secs_f64((burst - tokens) / rate),
+            retry_after: (!allowed).then(|| Duration::from_secs_f64((1.0 - tokens) / rate)),
+        }
+    }
+}
+
+#[async_trait]
+impl CounterStore for MemoryCounterStore {
+    async fn hit(
+        &self,
+        policy: &RateLimitPolicy,
+        key: &str,
+    ) -> Result<RateLimitDecision, sqlx::Error> {
+        Ok(self.take_token(policy, key))
+    }
+
+    async fn failures(&self, key: &str) -> Result<FailureState, sqlx::Error> {
+        Ok(self
+            .failures
+            .get(key)
+            .map(|r| r.state(Utc::now()))
+            .unwrap_or_default())
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        config: &AuthRateLimiterConfig,
+    ) -> Result<Option<Duration>, sqlx::Error> {
+        let now = Utc::now();
+        let mut record = self
+            .failures
+            .entry(key.to_string())
+            .or_insert_with(|| FailureRecord::new(now));
+        Ok(record.apply_failure(now, config))
+    }
+
+    async fn reset_failures(&self, key: &str) -> Result<(), sqlx::Error> {
+        self.failures.remove(key);
+        Ok(())
+    }
+
+    async fn cleanup_hits(&self, max_idle: Duration) -> Result<(), sqlx::Error> {
+        let now = Instant::now();
+        self.buckets
+            .retain(|_, bucket| now.duration_since(bucket.updated) <= max_idle);
+        Ok(())
+    }
+
+    async fn cleanup_failures(&self, max_idle: Duration) -> Result<(), sqlx::Error> {
+        let now = Utc::now();
+        self.failures.retain(|_, record| {
+            record.locked_for(now).is_some()
+                || (now - record.updated_at).to_std().unwrap_or_default() <= max_idle
+        });
+        Ok(())
+    }
+}
+
+// ---------------------------
+// Postgres: sliding window shared by all instances
+// ---------------------------
+
+pub struct PostgresCounterStore {
+    pool: PgPool,
+}
+
+impl PostgresCounterStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+/// Window length for a policy: `burst` requests per window gives the same
+/// sustained rate as the token bucket
+fn policy_window(policy: &RateLimitPolicy) -> Duration {
+    let secs = f64::from(policy.burst.max(1)) / allow_rate(policy);
+    Duration::from_secs_f64(secs.max(1.0))
+}
+
+/// Sliding window estimate: hits of the previous window weighted by how much
+/// of it still overlaps the sliding window, plus hits of the current one
+/// (including this request). Denied requests are counted too.
+fn sliding_window_decision(
+    limit: u32,
+    window: Duration,
+    elapsed: Duration,
+    previous: u32,
+    current: u32,
+) -> RateLimitDecision {
+    let w = window.as_secs_f64();
+    let e = elapsed.as_secs_f64().min(w);
+    let limit_f = f64::from(limit);
+    let previous_f = f64::from(previous);
+    let current_f = f64::from(current);
+
+    let estimate = previous_f * (w - e) / w + current_f;
+    let allowed = estimate <= limit_f;
+
+    let retry_after = (!allowed).then(|| {
+        // Room left in this window for the weighted previous one
+        let room = limit_f - current_f - 1.0;
+        let at = if room >= 0.0 && previous > 0 {
+            w * (1.0 - room / previous_f)
+        } else {
+            // Only in the next window, where this one becomes "previous"
+            let next = if current > 0 {
+                (w * (1.0 - (limit_f - 1.0) / current_f)).max(0.0)
+            } else {
+                0.0
+            };
+            w + next
+        };
+        Duration::from_secs_f64((at - e).max(0.0))
+    });
+
+    RateLimitDecision {
+        allowed,
+        limit,
+        remaining: (limit_f - estimate).max(0.0).floor() as u32,
+        reset_after: Duration::from_secs_f64(w - e),
+        retry_after,
+    }
+}
+
+#[async_trait]
+impl CounterStore for PostgresCounterStore {
+    async fn hit(
+        &self,
+        policy: &RateLimitPolicy,
+        key: &str,
+    ) -> Result<RateLimitDecision, sqlx::Error> {
+        let window = policy_window(policy);
+        // Окна выровнены по времени БД, чтобы у всех инстансов они совпадали
+        let row = sqlx::query(
+            r#"
+            WITH w AS (
+                SELECT to_timestamp(floor(extract(epoch FROM now()) / $2) * $2) AS start,
+                       now() AS now
+            ), hit AS (
+                INSERT INTO rate_limit_windows (key, window_start, hits)
+                SELECT $1, w.start, 1 FROM w
+                ON CONFLICT (key, window_start)
+                DO UPDATE SET hits = rate_limit_windows.hits + 1
+                RETURNING hits
+            )
+            SELECT
+                (SELECT hits FROM hit) AS current,
+                COALESCE((
+                    SELECT r.hits
+                    FROM rate_limit_windows r
+                    WHERE r.key = $1
+                      AND r.window_start = w.start - make_interval(secs => $2)
+                ), 0) AS previous,
+                extract(epoch FROM w.now - w.start)::FLOAT8 AS elapsed
+            FROM w
+            "#,
+        )
+        .bind(key)
+        .bind(window.as_secs_f64())
+        .fetch_one(&self.pool)
+        .await?;
+
+        let current: i32 = row.try_get("current")?;
+        let previous: i32 = row.try_get("previous")?;
+        let elapsed: f64 = row.try_get("elapsed")?;
+
+        Ok(sliding_window_decision(
+            policy.burst,
+            window,
+            Duration::from_secs_f64(elapsed.max(0.0)),
+            previous.max(0) as u32,
+            current.max(0) as u32,
+        ))
+    }
+
+    async fn failures(&self, key: &str) -> Result<FailureState, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            SELECT failures, lockout_until, updated_at, now() AS now
+            FROM rate_limit_failures
+            WHERE key = $1
+            "#,
+        )
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(match row {
+            Some(row) => {
+                let now: DateTime<Utc> = row.try_get("now")?;
+                failure_record_from_row(&row)?.state(now)
+            }
+            None => FailureState::default(),
+        })
+    }
+
+    async fn record_failure(
+        &self,
+        key: &str,
+        config: &AuthRateLimiterConfig,
+    ) -> Result<Option<Duration>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("INSERT INTO rate_limit_failures (key) VALUES ($1) ON CONFLICT DO NOTHING")
+            .bind(key)
+            .execute(&mut *tx)
+            .await?;
+
+        // Строка блокируется до конца транзакции: параллельные попытки
+        // с разных инстансов считаются по очереди
+        let row = sqlx::query(
+            r#"
+            SELECT failures, lockout_until, updated_at, now() AS now
+            FROM rate_limit_failures
+            WHERE key = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(key)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let now: DateTime<Utc> = row.try_get("now")?;
+        let mut record = failure_record_from_row(&row)?;
+        let lockout = record.apply_failure(now, config);
+
+        sqlx::query(
+            r#"
+            UPDATE rate_limit_failures
+            SET failures = $2, lockout_until = $3, updated_at = $4
+            WHERE key = $1
+            "#,
+        )
+        .bind(key)
+        .bind(record.failures as i32)
+        .bind(record.lockout_until)
+        .bind(record.updated_at)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(lockout)
+    }
+
+    async fn reset_failures(&self, key: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM rate_limit_failures WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn cleanup_hits(&self, max_idle: Duration) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "DELETE FROM rate_limit_windows WHERE window_start < now() - make_interval(secs => $1)",
+        )
+        .bind(max_idle.as_secs_f64())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn cleanup_failures(&self, max_idle: Duration) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM rate_limit_failures
+            WHERE (lockout_until IS NULL OR lockout_until < now())
+              AND updated_at < now() - make_interval(secs => $1)
+            "#,
+        )
+        .bind(max_idle.as_secs_f64())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+fn failure_record_from_row(row: &sqlx::postgres::PgRow) -> Result<FailureRecord, sqlx::Error> {
+    Ok(FailureRecord {
+        failures: row.try_get::<i32, _>("failures")?.max(0) as u32,
+        lockout_until: row.try_get("lockout_until")?,
+        updated_at: row.try_get("updated_at")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::rate_limit::RateLimitKey;
+
+    const POLICY: RateLimitPolicy = RateLimitPolicy::new("test", 3, 60, RateLimitKey::Ip);
+
+    fn auth_config() -> AuthRateLimiterConfig {
+        AuthRateLimiterConfig {
+            max_failures: 2,
+            lockout_duration: Duration::from_secs(60),
+            backoff_multiplier: 2,
+            max_lockout: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn sliding_window_weights_previous_window() {
+        let window = Duration::from_secs(10);
+        // Половина окна прошла: 4 * 0.5 + 1 = 3 <= 3
+        let half = Duration::from_secs(5);
+        assert!(sliding_window_decision(3, window, half, 4, 1).allowed);
+
+        let denied = sliding_window_decision(3, window, half, 4, 2);
+        assert!(!denied.allowed);
+        // 4 * (10 - t) / 10 + 2 + 1 <= 3  =>  t = 10, т.е. через 5 секунд
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(5)));
+        assert_eq!(denied.remaining, 0);
+    }
+
+    #[test]
+    fn failures_lock_with_backoff_and_reset_after_lockout() {
+        let config = auth_config();
+        let now = Utc::now();
+        let mut record = FailureRecord::new(now);
+
+        assert_eq!(record.apply_failure(now, &config), None);
+        assert_eq!(
+            record.apply_failure(now, &config),
+            Some(Duration::from_secs(60))
+        );
+        // Во время блокировки попытки не считаются
+        assert_eq!(record.failures, 2);
+        assert!(record.state(now).locked_for.is_some());
+
+        let later = now + chrono::Duration::seconds(61);
+        assert_eq!(record.state(later).locked_for, None);
+        assert_eq!(record.apply_failure(later, &config), None);
+        assert_eq!(record.failures, 1);
+    }
+
+    #[tokio::test]
+    async fn memory_store_counts_failures_per_key() {
+        let store = MemoryCounterStore::new();
+        let config = auth_config();
+
+        assert_eq!(store.record_failure("a", &config).await.unwrap(), None);
+        assert!(store.record_failure("a", &config).await.unwrap().is_some());
+        assert!(store.failures("a").await.unwrap().locked_for.is_some());
+        assert_eq!(store.failures("b").await.unwrap(), FailureState::default());
+
+        store.reset_failures("a").await.unwrap();
+        assert_eq!(store.failures("a").await.unwrap(), FailureState::default());
+    }
+
+    // Общий счётчик в Postgres: запускается, только если задан
+    // RATE_LIMIT_TEST_DATABASE_URL (например, postgres://postgres@localhost/ren_test)
+    #[tokio::test]
+    async fn postgres_store_shares_counters() {
+        let Ok(url) = std::env::var("RATE_LIMIT_TEST_DATABASE_URL") else {
+            return;
+        };
+        let pool = PgPool::connect(&url).await.unwrap();
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+        // Два "инстанса" с одной базой видят одни и те же счётчики
+        let first = PostgresCounterStore::new(pool.clone());
+        let second = PostgresCounterStore::new(pool);
+        let key = format!("test:{}", uuid::Uuid::new_v4());
+
+        for _ in 0..POLICY.burst {
+            assert!(first.hit(&POLICY, &key).await.unwrap().allowed);
+        }
+        let denied = second.hit(&POLICY, &key).await.unwrap();
+        assert!(!denied.allowed);
+        assert!(denied.retry_after.is_some());
+
+        let config = auth_config();
+        assert_eq!(first.record_failure(&key, &config).await.unwrap(), None);
+        assert!(
+            second
+                .record_failure(&key, &config)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(first.failures(&key).await.unwrap().locked_for.is_some());
+
+        second.reset_failures(&key).await.unwrap();
+        assert_eq!(first.failures(&key).await.unwrap(), FailureState::default());
+    }
+}
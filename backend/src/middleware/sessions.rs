@@ -0,0 +1,235 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+// Сколько CurrentUser доверяет закэшированной проверке активной сессии.
+// Отзыв на этом же инстансе виден сразу, отзыв на соседнем — не позже TTL.
+const ACTIVE_SESSION_TTL: Duration = Duration::from_secs(60);
+// Отозванную сессию помним дольше срока жизни access-токена (15 минут)
+const REVOKED_SESSION_TTL: Duration = Duration::from_secs(20 * 60);
+
+// Результат проверки сессии по кэшу
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionCheck {
+    Active,
+    Revoked,
+    Unknown,
+}
+
+struct CachedSession {
+    user_id: i32,
+    expires_at: DateTime<Utc>,
+    checked_at: Instant,
+}
+
+struct LiveSocket {
+    id: u64,
+    user_id: i32,
+    close: oneshot::Sender<String>,
+}
+
+// Реестр сессий процесса:
+// - живые websocket-соединения по session_id, чтобы закрывать их при отзыве;
+// - кэш активных и отозванных сессий для CurrentUser без запроса в БД.
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sockets: Arc<DashMap<Uuid, Vec<LiveSocket>>>,
+    active: Arc<DashMap<Uuid, CachedSession>>,
+    revoked: Arc<DashMap<Uuid, Instant>>,
+    next_socket_id: Arc<AtomicU64>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn check(&self, session_id: Uuid, user_id: i32) -> SessionCheck {
+        if let Some(at) = self.revoked.get(&session_id)
+            && at.elapsed() < REVOKED_SESSION_TTL
+        {
+            return SessionCheck::Revoked;
+        }
+        match self.active.get(&session_id) {
+            Some(s)
+                if s.user_id == user_id
+                    && s.expires_at > Utc::now()
+                    && s.checked_at.elapsed() < ACTIVE_SESSION_TTL =>
+            {
+                SessionCheck::Active
+            }
+            _ => SessionCheck::Unknown,
+        }
+    }
+
+    // Запоминает результат проверки сессии в БД
+    pub fn remember_active(&self, session_id: Uuid, user_id: i32, expires_at: DateTime<Utc>) {
+        self.active.insert(
+            session_id,
+            CachedSession {
+                user_id,
+                expires_at,
+                checked_at: Instant::now(),
+            },
+        );
+    }
+
+    // Сессия не найдена среди активных: снова активной она не станет
+    pub fn remember_inactive(&self, session_id: Uuid) {
+        self.active.remove(&session_id);
+        self.revoked.insert(session_id, Instant::now());
+    }
+
+    /// Выбрасывает записи кэша, которые check() уже не использует: истёкшие
+    /// или давно не проверявшиеся активные сессии и отозванные старше
+    /// REVOKED_SESSION_TTL (их access-токены к этому времени истекли).
+    /// Вызывается из периодической очистки.
+    pub fn prune(&self) {
+        let now = Utc::now();
+        self.active
+            .retain(|_, s| s.expires_at > now && s.checked_at.elapsed() < ACTIVE_SESSION_TTL);
+        self.revoked
+            .retain(|_, at| at.elapsed() < REVOKED_SESSION_TTL);
+    }
+
+    /// Регистрирует websocket сессии. Получатель срабатывает с причиной,
+    /// когда сессию отзывают; для уже отозванной сессии — сразу.
+    pub fn register_socket(
+        &self,
+        session_id: Uuid,
+        user_id: i32,
+    ) -> (u64, oneshot::Receiver<String>) {
+        let id = self.next_socket_id.fetch_add(1, Ordering::Relaxed);
+        let (close, rx) = oneshot::channel();
+        if self.check(session_id, user_id) == SessionCheck::Revoked {
+            let _ = close.send("session_revoked".to_string());
+            return (id, rx);
+        }
+        self.sockets
+            .entry(session_id)
+            .or_default()
+            .push(LiveSocket { id, user_id, close });
+        (id, rx)
+    }
+
+    pub fn unregister_socket(&self, session_id: Uuid, socket_id: u64) {
+        let now_empty = match self.sockets.get_mut(&session_id) {
+            Some(mut sockets) => {
+                sockets.retain(|s| s.id != socket_id);
+                sockets.is_empty()
+            }
+            None => false,
+        };
+        // remove нельзя вызывать, пока удерживается guard от get_mut
+        if now_empty {
+            self.sockets
+                .remove_if(&session_id, |_, sockets| sockets.is_empty());
+        }
+    }
+
+    /// Отзыв сессий: CurrentUser сразу перестаёт их принимать,
+    /// а их websocket-соединения закрываются с указанной причиной.
+    pub fn revoke<I>(&self, session_ids: I, reason: &str)
+    where
+        I: IntoIterator<Item = Uuid>,
+    {
+        for session_id in session_ids {
+            self.remember_inactive(session_id);
+            if let Some((_, sockets)) = self.sockets.remove(&session_id) {
+                for socket in sockets {
+                    let _ = socket.close.send(reason.to_string());
+                }
+            }
+        }
+    }
+
+    // Отзыв всех известных процессу сессий пользователя (например, при удалении аккаунта)
+    pub fn revoke_user(&self, user_id: i32, reason: &str) {
+        let mut session_ids: Vec<Uuid> = self
+            .active
+            .iter()
+            .filter(|s| s.user_id == user_id)
+            .map(|s| *s.key())
+            .collect();
+        session_ids.extend(
+            self.sockets
+                .iter()
+                .filter(|s| s.iter().any(|socket| socket.user_id == user_id))
+                .map(|s| *s.key()),
+        );
+        self.revoke(session_ids, reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revoke_closes_sockets_and_invalidates_cache() {
+        let registry = SessionRegistry::new();
+        let session_id = Uuid::new_v4();
+        registry.remember_active(session_id, 7, Utc::now() + chrono::Duration::days(1));
+        assert_eq!(registry.check(session_id, 7), SessionCheck::Active);
+        assert_eq!(registry.check(session_id, 8), SessionCheck::Unknown);
+
+        let (_, mut rx) = registry.register_socket(session_id, 7);
+        assert!(rx.try_recv().is_err());
+
+        registry.revoke([session_id], "logout");
+        assert_eq!(rx.try_recv().unwrap(), "logout");
+        assert_eq!(registry.check(session_id, 7), SessionCheck::Revoked);
+    }
+
+    #[test]
+    fn socket_of_revoked_session_is_closed_immediately() {
+        let registry = SessionRegistry::new();
+        let session_id = Uuid::new_v4();
+        registry.revoke([session_id], "session_revoked");
+
+        let (_, mut rx) = registry.register_socket(session_id, 7);
+        assert_eq!(rx.try_recv().unwrap(), "session_revoked");
+    }
+
+    #[test]
+    fn revoke_user_closes_only_their_sessions() {
+        let registry = SessionRegistry::new();
+        let (mine, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let (_, mut mine_rx) = registry.register_socket(mine, 7);
+        let (socket_id, mut other_rx) = registry.register_socket(other, 8);
+
+        registry.revoke_user(7, "account_deleted");
+        assert_eq!(mine_rx.try_recv().unwrap(), "account_deleted");
+        assert!(other_rx.try_recv().is_err());
+
+        registry.unregister_socket(other, socket_id);
+        assert!(registry.sockets.is_empty());
+    }
+
+    #[test]
+    fn prune_drops_expired_entries() {
+        let registry = SessionRegistry::new();
+        let (live, expired, revoked) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        registry.remember_active(live, 7, Utc::now() + chrono::Duration::days(1));
+        registry.remember_active(expired, 7, Utc::now() - chrono::Duration::seconds(1));
+        registry.remember_inactive(revoked);
+        // Instant не уходит раньше запуска системы: на свежей машине пропускаем
+        let stale = Uuid::new_v4();
+        if let Some(at) = Instant::now().checked_sub(REVOKED_SESSION_TTL + Duration::from_secs(1)) {
+            registry.revoked.insert(stale, at);
+        }
+
+        registry.prune();
+
+        assert!(registry.active.contains_key(&live));
+        assert!(!registry.active.contains_key(&expired));
+        assert!(!registry.revoked.contains_key(&stale));
+        assert_eq!(registry.check(revoked, 7), SessionCheck::Revoked);
+    }
+}
@@ -26,6 +26,82 @@ pub struct UserResponse {
     pub avatar: Option<String>,
 }
 
+pub const KDF_ALGORITHM_PBKDF2: &str = "pbkdf2-sha256";
+pub const KDF_ALGORITHM_ARGON2ID: &str = "argon2id";
+// Актуальная версия параметров KDF; более старые клиент перешифровывает при входе
+pub const KDF_CURRENT_VERSION: i32 = 2;
+
+// Параметры деривации мастер-ключа из пароля (которым зашифрован pkebymk).
+// Сервер их не использует для проверки пароля — только хранит и отдаёт клиенту.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KdfParams {
+    pub algorithm: String, // 'pbkdf2-sha256' | 'argon2id'
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_kib: Option<i32>,
+    pub iterations: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallelism: Option<i32>,
+    pub version: i32,
+}
+
+impl KdfParams {
+    // Схема аккаунтов, созданных до появления записи KDF
+    pub fn legacy_pbkdf2() -> Self {
+        KdfParams {
+            algorithm: KDF_ALGORITHM_PBKDF2.to_string(),
+            memory_kib: None,
+            iterations: 100_000,
+            parallelism: None,
+            version: 1,
+        }
+    }
+
+    // Рекомендуемые параметры (совпадают с KdfParams::recommended() в Ren-SDK)
+    pub fn recommended() -> Self {
+        KdfParams {
+            algorithm: KDF_ALGORITHM_ARGON2ID.to_string(),
+            memory_kib: Some(65536),
+            iterations: 3,
+            parallelism: Some(4),
+            version: KDF_CURRENT_VERSION,
+        }
+    }
+
+    pub fn needs_upgrade(&self) -> bool {
+        self.version < KDF_CURRENT_VERSION
+    }
+
+    // Отсекает заведомо слабые или нереалистичные параметры
+    pub fn validate(&self) -> Result<(), String> {
+        if self.version < 1 {
+            return Err("kdf_params.version должен быть >= 1".into());
+        }
+        match self.algorithm.as_str() {
+            KDF_ALGORITHM_PBKDF2 => {
+                if self.iterations < 100_000 {
+                    return Err("kdf_params: PBKDF2 требует >= 100000 итераций".into());
+                }
+            }
+            KDF_ALGORITHM_ARGON2ID => {
+                let memory = self.memory_kib.unwrap_or(0);
+                let parallelism = self.parallelism.unwrap_or(0);
+                if !(19_456..=1_048_576).contains(&memory) {
+                    return Err(
+                        "kdf_params: memory_kib Argon2id вне диапазона 19456..1048576".into(),
+                    );
+                }
+                if !(1..=16).contains(&self.iterations) || !(1..=16).contains(&parallelism) {
+                    return Err(
+                        "kdf_params: iterations/parallelism Argon2id вне диапазона 1..16".into(),
+                    );
+                }
+            }
+            _ => return Err("kdf_params: неизвестный алгоритм".into()),
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, FromRow, Clone)]
 pub struct UserAuthResponse {
     pub id: i32,
@@ -37,6 +113,8 @@ pub struct UserAuthResponse {
     pub pkebyrk: String,
     pub salt: String,
     pub pubk: String,
+    #[sqlx(json)]
+    pub kdf_params: KdfParams,
 }
 
 #[derive(Deserialize, Clone)]
@@ -49,6 +127,15 @@ pub struct UserRegisterRequest {
     pub pkebyrk: String,
     pub pubk: String,
     pub salt: String,
+    // Ed25519-ключ для восстановления аккаунта (см. /auth/recovery/*), опционально
+    #[serde(default)]
+    pub recovery_pubk: Option<String>,
+    // Параметры KDF, которыми получен мастер-ключ для pkebymk (по умолчанию — PBKDF2)
+    #[serde(default)]
+    pub kdf_params: Option<KdfParams>,
+    // Код приглашения (обязателен в режиме registration.mode = invite)
+    #[serde(default)]
+    pub invite_code: Option<String>,
 }
 
 // Тело запроса на вход (аутентификацию)
@@ -68,6 +155,13 @@ pub struct LoginResponse {
     pub token: String,
     pub refresh_token: String,
     pub session_id: String,
+    // Если kdf_params пользователя устарели — рекомендуемые параметры для
+    // перешифрования pkebymk через POST /users/me/kdf
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kdf_upgrade: Option<KdfParams>,
+    // Вход по passkey: приватный ключ, зашифрованный ключом из PRF-расширения
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pkebyprf: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -82,6 +176,273 @@ pub struct RefreshResponse {
     pub session_id: String,
 }
 
+// Смена пароля: новый пароль (хешируется Argon2 на сервере) и перешифрованный
+// клиентом приватный ключ под мастер-ключом, выведенным из нового пароля
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub old_password: String,
+    pub new_password: String,
+    pub pkebymk: String,
+    pub salt: String,
+    #[serde(default)]
+    pub kdf_params: Option<KdfParams>,
+}
+
+// Апгрейд KDF при входе: тот же пароль, pkebymk под новым мастер-ключом
+#[derive(Deserialize)]
+pub struct KdfUpgradeRequest {
+    pub password: String,
+    pub pkebymk: String,
+    pub salt: String,
+    pub kdf_params: KdfParams,
+}
+
+// Восстановление аккаунта: шаг 1 — запрос вызова
+#[derive(Deserialize)]
+pub struct RecoveryChallengeRequest {
+    pub login: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryChallengeResponse {
+    pub challenge_id: String,
+    pub challenge: String, // Base64, 32 случайных байта
+    pub expires_in: i64,   // секунды
+}
+
+// Шаг 2 — подпись вызова ключом, выведенным из ключа восстановления
+#[derive(Deserialize)]
+pub struct RecoveryVerifyRequest {
+    pub challenge_id: String,
+    pub signature: String, // Base64 Ed25519 над "ren-recovery:{challenge_id}:{challenge}"
+}
+
+#[derive(Serialize)]
+pub struct RecoveryVerifyResponse {
+    pub pkebyrk: String,
+    pub reset_token: String,
+    pub expires_in: i64, // секунды
+}
+
+// Шаг 3 — новый пароль и приватный ключ, перешифрованный под новый мастер-ключ
+#[derive(Deserialize)]
+pub struct RecoveryResetRequest {
+    pub reset_token: String,
+    pub new_password: String,
+    pub pkebymk: String,
+    pub salt: String,
+    #[serde(default)]
+    pub kdf_params: Option<KdfParams>,
+}
+
+// Установка/замена ключа проверки восстановления для существующего аккаунта
+#[derive(Deserialize)]
+pub struct SetRecoveryKeyRequest {
+    pub password: String,
+    pub recovery_pubk: String,
+}
+
+// Ответ /auth/login, если у аккаунта включена 2FA: сессия ещё не создана,
+// вход завершается через POST /auth/login/2fa
+#[derive(Serialize)]
+pub struct TwoFactorRequiredResponse {
+    pub message: String,
+    pub two_factor_required: bool,
+    pub pre_auth_token: String,
+    pub methods: Vec<String>,
+    pub expires_in: i64, // секунды
+}
+
+// Второй шаг входа: TOTP-код или одноразовый резервный код
+#[derive(Deserialize)]
+pub struct LoginTwoFactorRequest {
+    pub pre_auth_token: String,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpEnrollRequest {
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct TotpEnrollResponse {
+    pub secret: String, // Base32 (RFC 4648, без паддинга)
+    pub otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpConfirmRequest {
+    pub code: String,
+}
+
+// Резервные коды возвращаются в открытом виде только один раз
+#[derive(Serialize)]
+pub struct TotpBackupCodesResponse {
+    pub backup_codes: Vec<String>,
+}
+
+// Отключение 2FA и перевыпуск резервных кодов требуют пароль и действующий код
+#[derive(Deserialize)]
+pub struct TotpVerifyRequest {
+    pub password: String,
+    pub code: String,
+}
+
+// WebAuthn/passkey. Бинарные поля церемоний передаются в Base64url без паддинга,
+// как их отдаёт navigator.credentials.create()/get() после кодирования клиентом.
+#[derive(Serialize)]
+pub struct PasskeyOptionsResponse {
+    pub challenge_id: String,
+    // PublicKeyCredentialCreationOptions / PublicKeyCredentialRequestOptions
+    pub public_key: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyAttestationResponse {
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyRegistrationCredential {
+    pub id: String,
+    pub response: PasskeyAttestationResponse,
+}
+
+// Добавление passkey = новый способ входа, поэтому требуем текущий пароль
+#[derive(Deserialize)]
+pub struct PasskeyRegisterOptionsRequest {
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyRegisterRequest {
+    pub challenge_id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub credential: PasskeyRegistrationCredential,
+    // Приватный ключ, зашифрованный ключом из PRF (если аутентификатор вернул PRF при создании)
+    #[serde(default)]
+    pub pkebyprf: Option<String>,
+}
+
+// Переименование passkey и/или загрузка pkebyprf после первого входа
+// (часть аутентификаторов отдаёт результат PRF только при get())
+#[derive(Deserialize)]
+pub struct PasskeyUpdateRequest {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub pkebyprf: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PasskeyResponse {
+    pub id: i32,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub has_prf: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginOptionsRequest {
+    // Без логина — вход через discoverable credential
+    #[serde(default)]
+    pub login: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyAssertionResponse {
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(default)]
+    pub user_handle: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyAssertionCredential {
+    pub id: String,
+    pub response: PasskeyAssertionResponse,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginRequest {
+    pub challenge_id: String,
+    pub credential: PasskeyAssertionCredential,
+    pub remember_me: Option<bool>,
+}
+
+// Привязка устройства по QR-коду. Поля public_key/key_mac/envelope сервер
+// не интерпретирует: их формирует и проверяет Ren-SDK на обоих устройствах.
+#[derive(Serialize)]
+pub struct DeviceLinkCreateResponse {
+    pub link_id: String,
+    pub code: String, // одноразовый код для QR
+    pub expires_in: i64, // секунды
+}
+
+#[derive(Deserialize)]
+pub struct DeviceLinkClaimRequest {
+    pub link_id: String,
+    pub code: String,
+    // Эфемерный X25519-ключ нового устройства и его MAC секретом из QR
+    pub public_key: String,
+    pub key_mac: String,
+    #[serde(default)]
+    pub device_name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceLinkClaimResponse {
+    pub claim_token: String,
+    pub expires_in: i64, // секунды
+}
+
+#[derive(Serialize)]
+pub struct DeviceLinkStatusResponse {
+    pub link_id: String,
+    pub status: String, // pending | claimed | approved | completed | cancelled | expired
+    pub public_key: Option<String>,
+    pub key_mac: Option<String>,
+    pub device_name: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct DeviceLinkApproveRequest {
+    pub envelope: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+pub struct DeviceLinkCompleteRequest {
+    pub link_id: String,
+    pub claim_token: String,
+    pub remember_me: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceLinkCompleteResponse {
+    #[serde(flatten)]
+    pub login: LoginResponse,
+    pub envelope: serde_json::Value,
+}
+
+// Регистрация собственного X25519-ключа текущего устройства
+#[derive(Deserialize)]
+pub struct DeviceKeyRequest {
+    pub public_key: String,
+}
+
+// Ключ устройства для адресации конвертов
+#[derive(Serialize)]
+pub struct DeviceKeyResponse {
+    pub device_id: String,
+    pub public_key: String,
+}
+
 #[derive(Serialize)]
 pub struct SessionResponse {
     pub id: String,
@@ -92,6 +453,8 @@ pub struct SessionResponse {
     pub login_at: DateTime<Utc>,
     pub last_seen_at: DateTime<Utc>,
     pub is_current: bool,
+    // Имя passkey, которым была открыта сессия (None — вход по паролю)
+    pub passkey_name: Option<String>,
 }
 
 // Полезная нагрузка (claims) для JWT
@@ -116,3 +479,31 @@ pub struct SignedPublicKeyResponse {
     pub signed_at: String,
     pub identity_key: String, // Ed25519 public key for verification
 }
+
+#[cfg(test)]
+mod tests {
+    use super::KdfParams;
+
+    #[test]
+    fn kdf_presets_are_valid() {
+        assert!(KdfParams::legacy_pbkdf2().validate().is_ok());
+        assert!(KdfParams::legacy_pbkdf2().needs_upgrade());
+        assert!(KdfParams::recommended().validate().is_ok());
+        assert!(!KdfParams::recommended().needs_upgrade());
+    }
+
+    #[test]
+    fn kdf_weak_or_unknown_params_are_rejected() {
+        let mut weak_pbkdf2 = KdfParams::legacy_pbkdf2();
+        weak_pbkdf2.iterations = 10_000;
+        assert!(weak_pbkdf2.validate().is_err());
+
+        let mut weak_argon = KdfParams::recommended();
+        weak_argon.memory_kib = Some(1024);
+        assert!(weak_argon.validate().is_err());
+
+        let mut unknown = KdfParams::recommended();
+        unknown.algorithm = "scrypt".into();
+        assert!(unknown.validate().is_err());
+    }
+}
@@ -87,3 +87,37 @@ pub struct CreateChatRequest {
     pub title: Option<String>, // только для групп
     pub user_ids: Vec<i32>,    // участники (включая текущего пользователя)
 }
+
+// Элемент пакетной пересылки: клиент заново шифрует каждое сообщение для целевого чата
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ForwardItem {
+    pub message_id: i64, // id исходного сообщения
+    pub message: String, // зашифрованное сообщение для целевого чата
+    pub message_type: Option<String>,
+    pub envelopes: Option<Value>,
+    pub metadata: Option<Vec<FileMetadata>>,
+}
+
+// Результат по одному сообщению в пакетной операции (удаление/пересылка)
+#[derive(Serialize, Clone)]
+pub struct BatchItemResult {
+    pub message_id: i64,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_message_id: Option<i64>, // только для пересылки
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Элемент медиа-галереи чата: метаданные одного файла + отправитель и время сообщения
+#[derive(Serialize, Clone)]
+pub struct MediaItem {
+    pub message_id: i64,
+    pub sender_id: i64,
+    pub created_at: String,
+    pub message_type: String,
+    pub kind: String, // 'image' | 'video' | 'file' | 'voice'
+    #[serde(flatten)]
+    pub file: FileMetadata,
+    pub envelope: Option<Value>, // конверт текущего пользователя для расшифровки файла
+}
@@ -0,0 +1,791 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{delete, get, post, put},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::{PgPool, Row, postgres::PgRow};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::config::StorageConfig;
+use crate::middleware::CurrentAdmin;
+use crate::route::devices::publish_device_keys_changed;
+use crate::route::users::delete_user;
+use crate::route::ws::{publish_payload_to_users, publish_session_revoked};
+
+// Администрирование сервера (только users.server_role = 'admin', см. CurrentAdmin):
+// - GET    /admin/stats                  — сводка по инстансу
+// - GET    /admin/users                  — пользователи (q, suspended, pending, before_id, limit)
+// - POST   /admin/users/:id/approve      — подтвердить регистрацию (registration.mode = approval)
+// - PUT    /admin/users/:id/role         — назначить роль сервера: user | admin
+// - POST   /admin/users/:id/suspend      — заблокировать: вход запрещён, сессии отозваны
+// - POST   /admin/users/:id/unsuspend    — снять блокировку
+// - DELETE /admin/users/:id/sessions     — отозвать все сессии (взломанный аккаунт)
+// - DELETE /admin/users/:id              — удалить аккаунт
+// - POST   /admin/chats/:id/takedown     — удалить чат вместе с вложениями
+// - GET    /admin/audit-log              — журнал действий администраторов
+//
+// Пароль пользователя администратор сменить не может: ключи шифрования выводятся
+// из пароля. После отзыва сессий владелец восстанавливает доступ ключом восстановления.
+// Те же операции без запущенного сервера — `backend admin ...` (см. admin_cli).
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/admin/stats", get(stats))
+        .route("/admin/users", get(list_users))
+        .route("/admin/users/:id", delete(delete_user_by_admin))
+        .route("/admin/users/:id/role", put(update_role))
+        .route("/admin/users/:id/approve", post(approve))
+        .route("/admin/users/:id/suspend", post(suspend))
+        .route("/admin/users/:id/unsuspend", post(unsuspend))
+        .route(
+            "/admin/users/:id/sessions",
+            delete(revoke_sessions_by_admin),
+        )
+        .route("/admin/chats/:id/takedown", post(take_down_chat_by_admin))
+        .route("/admin/audit-log", get(audit_log))
+}
+
+pub const SERVER_ROLE_USER: &str = "user";
+pub const SERVER_ROLE_ADMIN: &str = "admin";
+
+// Действия в admin_audit_log
+pub const ACTION_ROLE_CHANGED: &str = "role_changed";
+pub const ACTION_USER_SUSPENDED: &str = "user_suspended";
+pub const ACTION_USER_UNSUSPENDED: &str = "user_unsuspended";
+pub const ACTION_SESSIONS_REVOKED: &str = "sessions_revoked";
+pub const ACTION_USER_DELETED: &str = "user_deleted";
+pub const ACTION_CHAT_TAKEN_DOWN: &str = "chat_taken_down";
+pub const ACTION_USER_APPROVED: &str = "user_approved";
+
+// Причины в событии session_revoked
+const REVOKE_REASON_SUSPENDED: &str = "account_suspended";
+const REVOKE_REASON_ADMIN: &str = "admin_revoked";
+
+const MAX_REASON_LEN: usize = 500;
+
+#[derive(Serialize)]
+pub(crate) struct AdminUserResponse {
+    pub id: i32,
+    pub login: String,
+    pub username: String,
+    pub nickname: Option<String>,
+    pub server_role: String,
+    pub suspended_at: Option<DateTime<Utc>>,
+    pub suspended_reason: Option<String>,
+    pub pending_approval: bool,
+    pub invited_by: Option<i32>,
+    pub active_sessions: i64,
+    pub storage_bytes: i64,
+}
+
+#[derive(Deserialize)]
+struct UsersQuery {
+    q: Option<String>,
+    suspended: Option<bool>,
+    pending: Option<bool>,
+    before_id: Option<i32>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct UsersResponse {
+    users: Vec<AdminUserResponse>,
+    next_before_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct RoleRequest {
+    role: String,
+}
+
+#[derive(Deserialize)]
+struct ReasonRequest {
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AuditLogQuery {
+    before_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AuditLogEntry {
+    id: i64,
+    admin_id: Option<i32>,
+    action: String,
+    target_user_id: Option<i32>,
+    target_chat_id: Option<i32>,
+    details: Option<Value>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct AuditLogResponse {
+    entries: Vec<AuditLogEntry>,
+    next_before_id: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct InstanceStats {
+    pub users: i64,
+    pub admins: i64,
+    pub suspended_users: i64,
+    pub pending_users: i64,
+    pub active_sessions: i64,
+    // Число чатов по kind: private / group / channel
+    pub chats: BTreeMap<String, i64>,
+    pub messages: i64,
+    pub media_files: i64,
+    pub media_bytes: i64,
+    // Только у запущенного сервера (в CLI неизвестно)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub online_users: Option<usize>,
+}
+
+fn db_error(e: sqlx::Error) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Ошибка БД: {}", e),
+    )
+}
+
+fn user_not_found() -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, "Пользователь не найден".into())
+}
+
+pub(crate) fn parse_server_role(role: &str) -> Option<&'static str> {
+    match role.trim().to_lowercase().as_str() {
+        SERVER_ROLE_USER => Some(SERVER_ROLE_USER),
+        SERVER_ROLE_ADMIN => Some(SERVER_ROLE_ADMIN),
+        _ => None,
+    }
+}
+
+// Пустая причина — без причины; слишком длинная — ошибка
+fn parse_reason(body: Option<Json<ReasonRequest>>) -> Result<Option<String>, (StatusCode, String)> {
+    let reason = body
+        .and_then(|Json(b)| b.reason)
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty());
+    if reason
+        .as_ref()
+        .is_some_and(|r| r.chars().count() > MAX_REASON_LEN)
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Причина длиннее {} символов", MAX_REASON_LEN),
+        ));
+    }
+    Ok(reason)
+}
+
+fn reject_self(admin_id: i32, user_id: i32, message: &str) -> Result<(), (StatusCode, String)> {
+    if admin_id == user_id {
+        return Err((StatusCode::BAD_REQUEST, message.to_string()));
+    }
+    Ok(())
+}
+
+async fn stats(
+    State(state): State<AppState>,
+    CurrentAdmin { .. }: CurrentAdmin,
+) -> Result<Json<InstanceStats>, (StatusCode, String)> {
+    let mut stats = instance_stats(&state.pool).await.map_err(db_error)?;
+    stats.online_users = Some(state.online_connections.len());
+    Ok(Json(stats))
+}
+
+async fn list_users(
+    State(state): State<AppState>,
+    CurrentAdmin { .. }: CurrentAdmin,
+    Query(q): Query<UsersQuery>,
+) -> Result<Json<UsersResponse>, (StatusCode, String)> {
+    let limit = q.limit.unwrap_or(50).clamp(1, 200);
+    let search = q.q.as_deref().map(str::trim).filter(|s| !s.is_empty());
+    let id_q: Option<i32> = search.and_then(|s| s.parse().ok());
+    let like = search.map(|s| format!("%{}%", s));
+
+    let rows = sqlx::query(
+        r#"
+        SELECT
+            u.id, u.login, u.username, u.nickname, u.server_role,
+            u.suspended_at, u.suspended_reason, u.pending_approval, u.invited_by,
+            (
+                SELECT COUNT(*)
+                FROM auth_sessions s
+                WHERE s.user_id = u.id
+                  AND s.revoked_at IS NULL
+                  AND s.expires_at > now()
+            ) AS active_sessions,
+            (
+                SELECT COALESCE(SUM(m.size), 0)::INT8
+                FROM media_files m
+                WHERE m.owner_id = u.id
+            ) AS storage_bytes
+        FROM users u
+        WHERE ($1::TEXT IS NULL OR u.id = $2 OR u.login ILIKE $1 OR u.username ILIKE $1)
+          AND ($3::BOOL IS NULL OR (u.suspended_at IS NOT NULL) = $3)
+          AND ($4::BOOL IS NULL OR u.pending_approval = $4)
+          AND ($5::INT4 IS NULL OR u.id < $5)
+        ORDER BY u.id DESC
+        LIMIT $6
+        "#,
+    )
+    .bind(like)
+    .bind(id_q)
+    .bind(q.suspended)
+    .bind(q.pending)
+    .bind(q.before_id)
+    .bind(limit)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let users: Vec<AdminUserResponse> = rows.iter().map(admin_user_from_row).collect();
+    let next_before_id = if users.len() as i64 == limit {
+        users.last().map(|u| u.id)
+    } else {
+        None
+    };
+    Ok(Json(UsersResponse {
+        users,
+        next_before_id,
+    }))
+}
+
+fn admin_user_from_row(row: &PgRow) -> AdminUserResponse {
+    AdminUserResponse {
+        id: row.try_get("id").unwrap_or_default(),
+        login: row.try_get("login").unwrap_or_default(),
+        username: row.try_get("username").unwrap_or_default(),
+        nickname: row.try_get("nickname").ok().flatten(),
+        server_role: row
+            .try_get("server_role")
+            .unwrap_or_else(|_| SERVER_ROLE_USER.to_string()),
+        suspended_at: row.try_get("suspended_at").ok().flatten(),
+        suspended_reason: row.try_get("suspended_reason").ok().flatten(),
+        pending_approval: row.try_get("pending_approval").unwrap_or_default(),
+        invited_by: row.try_get("invited_by").ok().flatten(),
+        active_sessions: row.try_get("active_sessions").unwrap_or_default(),
+        storage_bytes: row.try_get("storage_bytes").unwrap_or_default(),
+    }
+}
+
+async fn update_role(
+    State(state): State<AppState>,
+    CurrentAdmin { id: admin_id, .. }: CurrentAdmin,
+    Path(user_id): Path<i32>,
+    Json(body): Json<RoleRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let role = parse_server_role(&body.role).ok_or((
+        StatusCode::BAD_REQUEST,
+        "Роль сервера должна быть user или admin".into(),
+    ))?;
+    reject_self(admin_id, user_id, "Нельзя изменить собственную роль")?;
+
+    if !set_server_role(&state.pool, user_id, role)
+        .await
+        .map_err(db_error)?
+    {
+        return Err(user_not_found());
+    }
+    audit(
+        &state,
+        admin_id,
+        ACTION_ROLE_CHANGED,
+        Some(user_id),
+        None,
+        Some(json!({ "role": role })),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn approve(
+    State(state): State<AppState>,
+    CurrentAdmin { id: admin_id, .. }: CurrentAdmin,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !approve_user(&state.pool, user_id).await.map_err(db_error)? {
+        return Err(user_not_found());
+    }
+    audit(
+        &state,
+        admin_id,
+        ACTION_USER_APPROVED,
+        Some(user_id),
+        None,
+        None,
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn suspend(
+    State(state): State<AppState>,
+    CurrentAdmin { id: admin_id, .. }: CurrentAdmin,
+    Path(user_id): Path<i32>,
+    body: Option<Json<ReasonRequest>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let reason = parse_reason(body)?;
+    reject_self(admin_id, user_id, "Нельзя заблокировать самого себя")?;
+
+    let revoked = suspend_user(&state.pool, user_id, reason.as_deref())
+        .await
+        .map_err(db_error)?
+        .ok_or_else(user_not_found)?;
+    close_revoked_sessions(&state, user_id, &revoked, REVOKE_REASON_SUSPENDED).await;
+    audit(
+        &state,
+        admin_id,
+        ACTION_USER_SUSPENDED,
+        Some(user_id),
+        None,
+        Some(json!({ "reason": reason, "revoked_sessions": revoked.len() })),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unsuspend(
+    State(state): State<AppState>,
+    CurrentAdmin { id: admin_id, .. }: CurrentAdmin,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !unsuspend_user(&state.pool, user_id)
+        .await
+        .map_err(db_error)?
+    {
+        return Err(user_not_found());
+    }
+    audit(
+        &state,
+        admin_id,
+        ACTION_USER_UNSUSPENDED,
+        Some(user_id),
+        None,
+        None,
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn revoke_sessions_by_admin(
+    State(state): State<AppState>,
+    CurrentAdmin { id: admin_id, .. }: CurrentAdmin,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    reject_self(
+        admin_id,
+        user_id,
+        "Свои сессии завершаются через /auth/sessions",
+    )?;
+    if !user_exists(&state.pool, user_id).await.map_err(db_error)? {
+        return Err(user_not_found());
+    }
+
+    let revoked = revoke_all_sessions(&state.pool, user_id)
+        .await
+        .map_err(db_error)?;
+    close_revoked_sessions(&state, user_id, &revoked, REVOKE_REASON_ADMIN).await;
+    audit(
+        &state,
+        admin_id,
+        ACTION_SESSIONS_REVOKED,
+        Some(user_id),
+        None,
+        Some(json!({ "revoked_sessions": revoked.len() })),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_user_by_admin(
+    State(state): State<AppState>,
+    CurrentAdmin { id: admin_id, .. }: CurrentAdmin,
+    Path(user_id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    reject_self(admin_id, user_id, "Свой аккаунт удаляется через /users/me")?;
+
+    let login: Option<String> = sqlx::query_scalar("SELECT login FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?;
+    let Some(login) = login else {
+        return Err(user_not_found());
+    };
+
+    if !delete_user(&state.pool, &state.config.storage, user_id)
+        .await
+        .map_err(db_error)?
+    {
+        return Err(user_not_found());
+    }
+    state.sessions.revoke_user(user_id, "account_deleted");
+    audit(
+        &state,
+        admin_id,
+        ACTION_USER_DELETED,
+        Some(user_id),
+        None,
+        Some(json!({ "login": login })),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn take_down_chat_by_admin(
+    State(state): State<AppState>,
+    CurrentAdmin { id: admin_id, .. }: CurrentAdmin,
+    Path(chat_id): Path<i32>,
+    body: Option<Json<ReasonRequest>>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let reason = parse_reason(body)?;
+    let members = take_down_chat(&state.pool, &state.config.storage, chat_id)
+        .await
+        .map_err(db_error)?
+        .ok_or((StatusCode::NOT_FOUND, "Чат не найден".into()))?;
+
+    let payload = json!({
+        "type": "chat_deleted",
+        "chat_id": chat_id,
+        "reason": "takedown",
+    })
+    .to_string();
+    publish_payload_to_users(&state, &members, payload);
+    state.ws_hub.remove(&chat_id);
+
+    audit(
+        &state,
+        admin_id,
+        ACTION_CHAT_TAKEN_DOWN,
+        None,
+        Some(chat_id),
+        Some(json!({ "reason": reason, "members": members.len() })),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn audit_log(
+    State(state): State<AppState>,
+    CurrentAdmin { .. }: CurrentAdmin,
+    Query(q): Query<AuditLogQuery>,
+) -> Result<Json<AuditLogResponse>, (StatusCode, String)> {
+    let limit = q.limit.unwrap_or(50).clamp(1, 200);
+    let rows = sqlx::query(
+        r#"
+        SELECT id, admin_id, action, target_user_id, target_chat_id, details, created_at
+        FROM admin_audit_log
+        WHERE ($1::INT8 IS NULL OR id < $1)
+        ORDER BY id DESC
+        LIMIT $2
+        "#,
+    )
+    .bind(q.before_id)
+    .bind(limit)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let entries: Vec<AuditLogEntry> = rows
+        .iter()
+        .map(|row| AuditLogEntry {
+            id: row.try_get("id").unwrap_or_default(),
+            admin_id: row.try_get("admin_id").ok().flatten(),
+            action: row.try_get("action").unwrap_or_default(),
+            target_user_id: row.try_get("target_user_id").ok().flatten(),
+            target_chat_id: row.try_get("target_chat_id").ok().flatten(),
+            details: row.try_get("details").ok().flatten(),
+            created_at: row
+                .try_get::<DateTime<Utc>, _>("created_at")
+                .unwrap_or_else(|_| Utc::now()),
+        })
+        .collect();
+    let next_before_id = if entries.len() as i64 == limit {
+        entries.last().map(|e| e.id)
+    } else {
+        None
+    };
+    Ok(Json(AuditLogResponse {
+        entries,
+        next_before_id,
+    }))
+}
+
+// Сессии уже отозваны в БД: уведомляем устройства, закрываем websocket'ы
+// и сбрасываем кэш CurrentUser, собеседники перечитывают список устройств
+async fn close_revoked_sessions(state: &AppState, user_id: i32, revoked: &[Uuid], reason: &str) {
+    let ids: Vec<String> = revoked.iter().map(Uuid::to_string).collect();
+    publish_session_revoked(state, user_id, &ids, reason);
+    state.sessions.revoke_user(user_id, reason);
+    publish_device_keys_changed(state, user_id).await;
+}
+
+// Действие уже выполнено, поэтому ошибка журнала только выводится в лог
+async fn audit(
+    state: &AppState,
+    admin_id: i32,
+    action: &str,
+    target_user_id: Option<i32>,
+    target_chat_id: Option<i32>,
+    details: Option<Value>,
+) {
+    if let Err(e) = record_admin_action(
+        &state.pool,
+        Some(admin_id),
+        action,
+        target_user_id,
+        target_chat_id,
+        details,
+    )
+    .await
+    {
+        tracing::error!(action, error = %e, "Ошибка записи в журнал администратора");
+    }
+}
+
+// ---------------------------
+// Операции над БД: общие для /admin и CLI (`backend admin ...`)
+// ---------------------------
+
+// Пользователь по login или числовому id
+pub(crate) async fn find_user_id(
+    pool: &PgPool,
+    login_or_id: &str,
+) -> Result<Option<i32>, sqlx::Error> {
+    let id: Option<i32> = login_or_id.trim().parse().ok();
+    sqlx::query_scalar(
+        "SELECT id FROM users WHERE login = $1 OR id = $2 ORDER BY login = $1 DESC LIMIT 1",
+    )
+    .bind(login_or_id.trim())
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn user_exists(pool: &PgPool, user_id: i32) -> Result<bool, sqlx::Error> {
+    let found: Option<i32> = sqlx::query_scalar("SELECT id FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(found.is_some())
+}
+
+pub(crate) async fn set_server_role(
+    pool: &PgPool,
+    user_id: i32,
+    role: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE users SET server_role = $2 WHERE id = $1")
+        .bind(user_id)
+        .bind(role)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Блокирует пользователя и отзывает его сессии. None — пользователя нет.
+pub(crate) async fn suspend_user(
+    pool: &PgPool,
+    user_id: i32,
+    reason: Option<&str>,
+) -> Result<Option<Vec<Uuid>>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(
+        r#"
+        UPDATE users
+        SET suspended_at = COALESCE(suspended_at, now()),
+            suspended_reason = $2
+        WHERE id = $1
+        "#,
+    )
+    .bind(user_id)
+    .bind(reason)
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+    let revoked = revoke_sessions_in(&mut tx, user_id).await?;
+    tx.commit().await?;
+    Ok(Some(revoked))
+}
+
+// Снимает ожидание подтверждения регистрации; повторный вызов ничего не меняет
+pub(crate) async fn approve_user(pool: &PgPool, user_id: i32) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE users SET pending_approval = FALSE WHERE id = $1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub(crate) async fn unsuspend_user(pool: &PgPool, user_id: i32) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE users
+        SET suspended_at = NULL,
+            suspended_reason = NULL
+        WHERE id = $1
+        "#,
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub(crate) async fn revoke_all_sessions(
+    pool: &PgPool,
+    user_id: i32,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let revoked = revoke_sessions_in(&mut tx, user_id).await?;
+    tx.commit().await?;
+    Ok(revoked)
+}
+
+async fn revoke_sessions_in(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: i32,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        UPDATE auth_sessions
+        SET revoked_at = now()
+        WHERE user_id = $1
+          AND revoked_at IS NULL
+        RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&mut **tx)
+    .await
+}
+
+// Удаляет чат, его вложения (строки media_files и файлы) и возвращает
+// бывших участников для уведомления. None — чата нет.
+pub(crate) async fn take_down_chat(
+    pool: &PgPool,
+    storage: &StorageConfig,
+    chat_id: i32,
+) -> Result<Option<Vec<i32>>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let members: Vec<i32> =
+        sqlx::query_scalar("SELECT user_id FROM chat_participants WHERE chat_id = $1")
+            .bind(chat_id)
+            .fetch_all(&mut *tx)
+            .await?;
+    let paths: Vec<String> =
+        sqlx::query_scalar("DELETE FROM media_files WHERE chat_id = $1 RETURNING path")
+            .bind(chat_id)
+            .fetch_all(&mut *tx)
+            .await?;
+    let result = sqlx::query("DELETE FROM chats WHERE id = $1")
+        .bind(chat_id)
+        .execute(&mut *tx)
+        .await?;
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+    tx.commit().await?;
+
+    for path in paths {
+        let _ = tokio::fs::remove_file(storage.path(&path)).await;
+    }
+    Ok(Some(members))
+}
+
+pub(crate) async fn instance_stats(pool: &PgPool) -> Result<InstanceStats, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM users) AS users,
+            (SELECT COUNT(*) FROM users WHERE server_role = 'admin') AS admins,
+            (SELECT COUNT(*) FROM users WHERE suspended_at IS NOT NULL) AS suspended_users,
+            (SELECT COUNT(*) FROM users WHERE pending_approval) AS pending_users,
+            (
+                SELECT COUNT(*)
+                FROM auth_sessions
+                WHERE revoked_at IS NULL AND expires_at > now()
+            ) AS active_sessions,
+            (SELECT COUNT(*) FROM messages) AS messages,
+            (SELECT COUNT(*) FROM media_files) AS media_files,
+            (SELECT COALESCE(SUM(size), 0)::INT8 FROM media_files) AS media_bytes
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+    let chats: Vec<(String, i64)> =
+        sqlx::query_as("SELECT kind, COUNT(*) FROM chats GROUP BY kind")
+            .fetch_all(pool)
+            .await?;
+
+    Ok(InstanceStats {
+        users: row.try_get("users").unwrap_or_default(),
+        admins: row.try_get("admins").unwrap_or_default(),
+        suspended_users: row.try_get("suspended_users").unwrap_or_default(),
+        pending_users: row.try_get("pending_users").unwrap_or_default(),
+        active_sessions: row.try_get("active_sessions").unwrap_or_default(),
+        chats: chats.into_iter().collect(),
+        messages: row.try_get("messages").unwrap_or_default(),
+        media_files: row.try_get("media_files").unwrap_or_default(),
+        media_bytes: row.try_get("media_bytes").unwrap_or_default(),
+        online_users: None,
+    })
+}
+
+// admin_id = None — действие выполнено из CLI
+pub(crate) async fn record_admin_action(
+    pool: &PgPool,
+    admin_id: Option<i32>,
+    action: &str,
+    target_user_id: Option<i32>,
+    target_chat_id: Option<i32>,
+    details: Option<Value>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO admin_audit_log (admin_id, action, target_user_id, target_chat_id, details)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(admin_id)
+    .bind(action)
+    .bind(target_user_id)
+    .bind(target_chat_id)
+    .bind(details)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_server_roles_and_reasons() {
+        assert_eq!(parse_server_role(" Admin "), Some(SERVER_ROLE_ADMIN));
+        assert_eq!(parse_server_role("user"), Some(SERVER_ROLE_USER));
+        assert_eq!(parse_server_role("owner"), None);
+
+        let reason = |r: &str| {
+            parse_reason(Some(Json(ReasonRequest {
+                reason: Some(r.to_string()),
+            })))
+        };
+        assert_eq!(reason("  spam ").unwrap().as_deref(), Some("spam"));
+        assert_eq!(reason("   ").unwrap(), None);
+        assert!(reason(&"x".repeat(MAX_REASON_LEN + 1)).is_err());
+        assert_eq!(parse_reason(None).unwrap(), None);
+    }
+}
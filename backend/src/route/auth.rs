@@ -2,19 +2,33 @@ use axum::{
     Json, Router,
     extract::{ConnectInfo, Path, State},
     http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{delete, get, post},
 };
 use serde::Deserialize;
+use serde_json::json;
 use sqlx::Row;
+use sqlx::postgres::PgRow;
 use std::net::SocketAddr;
 use std::time::Duration;
 
 use crate::AppState;
 use crate::middleware::CurrentUser;
 use crate::models::auth::{
-    Claims, LoginRequest, LoginResponse, RefreshRequest, RefreshResponse, SessionResponse,
-    UserAuthResponse, UserRegisterRequest,
+    ChangePasswordRequest, Claims, KdfParams, KdfUpgradeRequest, LoginRequest, LoginResponse,
+    RefreshRequest, RefreshResponse, SessionResponse, UserAuthResponse, UserRegisterRequest,
 };
+use crate::route::devices::publish_device_keys_changed;
+use crate::route::invites::{RegistrationMode, consume_invite};
+use crate::route::passkeys::PasskeyLogin;
+use crate::route::recovery::parse_recovery_pubk;
+use crate::route::security_events::{
+    EVENT_KDF_UPGRADED, EVENT_LOGOUT, EVENT_PASSWORD_CHANGED, EVENT_REFRESH_TOKEN_REUSE,
+    EVENT_SESSION_REVOKED, LOGIN_METHOD_PASSWORD, log_failed_login, log_login, log_security_event,
+    record_security_event,
+};
+use crate::route::two_factor::{start_login_challenge, totp_enabled};
+use crate::route::ws::publish_session_revoked;
 
 use argon2::Argon2;
 use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
@@ -36,12 +50,16 @@ pub fn router() -> Router<AppState> {
             get(list_sessions).delete(delete_other_sessions),
         )
         .route("/auth/sessions/:id", delete(delete_session))
+        .route("/users/me/password", post(change_password))
+        .route("/users/me/kdf", post(upgrade_kdf))
 }
 
+// В режиме registration.mode = approval без приглашения аккаунт создаётся
+// неактивным и ответ — 202: войти можно после подтверждения администратором
 async fn register(
     State(state): State<AppState>,
     Json(payload): Json<UserRegisterRequest>,
-) -> Result<Json<UserAuthResponse>, (StatusCode, String)> {
+) -> Result<(StatusCode, Json<UserAuthResponse>), (StatusCode, String)> {
     let UserRegisterRequest {
         mut login,
         mut username,
@@ -51,8 +69,31 @@ async fn register(
         pkebyrk,
         pubk,
         salt,
+        recovery_pubk,
+        kdf_params,
+        invite_code,
     } = payload;
 
+    let mode = state.config.registration.mode;
+    let invite_code = invite_code
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty());
+    match mode {
+        RegistrationMode::Closed => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Регистрация на сервере закрыта".into(),
+            ));
+        }
+        RegistrationMode::Invite if invite_code.is_none() => {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Регистрация только по приглашению".into(),
+            ));
+        }
+        _ => {}
+    }
+
     login = login.trim().to_string();
     username = username.trim().to_string();
     // Если nickname не передан, устанавливаем его равным username
@@ -76,22 +117,47 @@ async fn register(
         ));
     }
 
-    let password_salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(password.as_bytes(), &password_salt)
-        .map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Не удалось захешировать пароль".into(),
-            )
-        })?
-        .to_string();
+    let recovery_pubk = recovery_pubk.filter(|k| !k.trim().is_empty());
+    if let Some(key) = recovery_pubk.as_deref() {
+        parse_recovery_pubk(key)?;
+    }
+
+    let kdf_params = resolve_kdf_params(kdf_params)?;
+    let password_hash = hash_password(&password)?;
+
+    let mut tx = state.pool.begin().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+    // Использование приглашения засчитывается вместе с созданием пользователя
+    let invite = match invite_code.as_deref() {
+        Some(code) => Some(
+            consume_invite(&mut tx, &state.jwt_secret, code)
+                .await
+                .map_err(|e| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Ошибка БД: {}", e),
+                    )
+                })?
+                .ok_or((
+                    StatusCode::FORBIDDEN,
+                    "Приглашение недействительно, истекло или уже использовано".to_string(),
+                ))?,
+        ),
+        None => None,
+    };
+    let pending_approval = mode == RegistrationMode::Approval && invite.is_none();
 
     let row = sqlx::query(
         r#"
-        INSERT INTO users (login, username, nickname, password, pkebymk, pkebyrk, pubk, salt)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        INSERT INTO users (
+            login, username, nickname, password, pkebymk, pkebyrk, pubk, salt, recovery_pubk,
+            kdf_params, invited_by, invite_code_id, pending_approval
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
         RETURNING id
         "#,
     )
@@ -103,7 +169,12 @@ async fn register(
     .bind(&pkebyrk)
     .bind(&pubk)
     .bind(&salt)
-    .fetch_one(&state.pool)
+    .bind(&recovery_pubk)
+    .bind(sqlx::types::Json(&kdf_params))
+    .bind(invite.as_ref().and_then(|i| i.created_by))
+    .bind(invite.as_ref().map(|i| i.id))
+    .bind(pending_approval)
+    .fetch_one(&mut *tx)
     .await
     .map_err(|e| match e {
         sqlx::Error::Database(db_err) => {
@@ -131,10 +202,16 @@ async fn register(
             "Ошибка чтения id пользователя".into(),
         )
     })?;
+    tx.commit().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
 
     let rec = sqlx::query_as::<_, UserAuthResponse>(
         r#"
-        SELECT id, login, username, nickname, avatar, pkebymk, pkebyrk, salt, pubk
+        SELECT id, login, username, nickname, avatar, pkebymk, pkebyrk, salt, pubk, kdf_params
         FROM users
         WHERE id = $1
         "#,
@@ -149,7 +226,12 @@ async fn register(
         )
     })?;
 
-    Ok(Json(rec))
+    let status = if pending_approval {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(rec)))
 }
 
 async fn login(
@@ -157,10 +239,14 @@ async fn login(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
     Json(payload): Json<LoginRequest>,
-) -> Result<Json<LoginResponse>, (StatusCode, String)> {
+) -> Result<Response, (StatusCode, String)> {
     // P1-7: Check rate limit before processing
     let ip = addr.ip().to_string();
-    if !state.auth_rate_limiter.is_allowed(&ip, Some(&payload.login)) {
+    if !state
+        .auth_rate_limiter
+        .is_allowed(&ip, Some(&payload.login))
+        .await
+    {
         return Err((
             StatusCode::TOO_MANY_REQUESTS,
             "Слишком много попыток входа. Повторите позже.".into(),
@@ -169,7 +255,8 @@ async fn login(
     
     let row = sqlx::query(
         r#"
-        SELECT id, login, username, nickname, avatar, password, pkebymk, pkebyrk, salt, pubk
+        SELECT id, login, username, nickname, avatar, password, pkebymk, pkebyrk, salt, pubk,
+               kdf_params
         FROM users
         WHERE login = $1
         "#,
@@ -186,8 +273,11 @@ async fn login(
 
     let Some(row) = row else {
         // P1-7: Record failed attempt
-        let (_allowed, _lockout_secs) = state.auth_rate_limiter.record_failure(&ip, Some(&payload.login));
-        
+        let (_allowed, _lockout_secs) = state
+            .auth_rate_limiter
+            .record_failure(&ip, Some(&payload.login))
+            .await;
+
         return Err((StatusCode::UNAUTHORIZED, "Неверный логин или пароль".into()));
     };
 
@@ -204,19 +294,52 @@ async fn login(
         )
     })?;
 
-    Argon2::default()
+    if Argon2::default()
         .verify_password(payload.password.as_bytes(), &parsed_hash)
-        .map_err(|_e| {
-            // P1-7: Record failed attempt on password mismatch
-            let (_allowed, _lockout_secs) = state.auth_rate_limiter.record_failure(&ip, Some(&payload.login));
-            
-            (StatusCode::UNAUTHORIZED, "Неверный логин или пароль".into())
-        })?;
+        .is_err()
+    {
+        // P1-7: Record failed attempt on password mismatch
+        let (_allowed, _lockout_secs) = state
+            .auth_rate_limiter
+            .record_failure(&ip, Some(&payload.login))
+            .await;
+
+        let user_id: i32 = row.try_get("id").unwrap_or_default();
+        log_failed_login(&state, &headers, addr, user_id, LOGIN_METHOD_PASSWORD).await;
+        return Err((StatusCode::UNAUTHORIZED, "Неверный логин или пароль".into()));
+    }
+
+    let user = user_auth_from_row(&row);
+    let remember_me = payload.remember_me.unwrap_or(false);
+
+    // 2FA: пароль верный, но сессия создаётся только после второго фактора.
+    // Счётчики лимитера не сбрасываем до завершения входа.
+    if totp_enabled(&state.pool, user.id).await? {
+        let challenge = start_login_challenge(&state, user.id, remember_me).await?;
+        return Ok(Json(challenge).into_response());
+    }
 
     // P1-7: Record successful login - reset counters
-    state.auth_rate_limiter.record_success(&ip, Some(&payload.login));
+    state
+        .auth_rate_limiter
+        .record_success(&ip, Some(&payload.login))
+        .await;
+
+    let response = create_login_session(
+        &state,
+        &headers,
+        addr,
+        user,
+        remember_me,
+        None,
+        LOGIN_METHOD_PASSWORD,
+    )
+    .await?;
+    Ok(Json(response).into_response())
+}
 
-    let user = UserAuthResponse {
+pub(crate) fn user_auth_from_row(row: &PgRow) -> UserAuthResponse {
+    UserAuthResponse {
         id: row.try_get("id").unwrap_or_default(),
         login: row.try_get("login").unwrap_or_default(),
         username: row.try_get("username").unwrap_or_default(),
@@ -226,24 +349,63 @@ async fn login(
         pkebyrk: row.try_get("pkebyrk").unwrap_or_default(),
         pubk: row.try_get("pubk").unwrap_or_default(),
         salt: row.try_get("salt").unwrap_or_default(),
-    };
+        kdf_params: row
+            .try_get::<sqlx::types::Json<KdfParams>, _>("kdf_params")
+            .map(|p| p.0)
+            .unwrap_or_else(|_| KdfParams::legacy_pbkdf2()),
+    }
+}
 
-    let remember_me = payload.remember_me.unwrap_or(false);
-    let ip_address = extract_ip(&headers, addr);
-    let city = resolve_city(&headers, &ip_address).await;
+// Создаёт строку auth_sessions и выдаёт пару токенов — общий финал
+// для входа по паролю, второго шага 2FA и входа по passkey
+pub(crate) async fn create_login_session(
+    state: &AppState,
+    headers: &HeaderMap,
+    addr: SocketAddr,
+    user: UserAuthResponse,
+    remember_me: bool,
+    passkey: Option<PasskeyLogin>,
+    method: &str,
+) -> Result<LoginResponse, (StatusCode, String)> {
+    // Заблокированный или ещё не подтверждённый администратором аккаунт
+    // новых сессий не получает
+    let status: Option<(bool, bool)> = sqlx::query_as(
+        "SELECT suspended_at IS NOT NULL, pending_approval FROM users WHERE id = $1",
+    )
+    .bind(user.id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+    if let Some((suspended, pending_approval)) = status {
+        if suspended {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Аккаунт заблокирован администратором".into(),
+            ));
+        }
+        if pending_approval {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Регистрация ожидает подтверждения администратором".into(),
+            ));
+        }
+    }
+
+    let ip_address = extract_ip(state, headers, addr);
+    let city = resolve_city(state, headers, &ip_address).await;
     let app_version =
-        extract_header(&headers, "x-app-version").unwrap_or_else(|| "unknown".to_string());
-    let user_agent =
-        extract_header(&headers, "user-agent").unwrap_or_else(|| "unknown".to_string());
-    let device_name = extract_header(&headers, "x-device-name")
+        extract_header(headers, "x-app-version").unwrap_or_else(|| "unknown".to_string());
+    let user_agent = extract_header(headers, "user-agent").unwrap_or_else(|| "unknown".to_string());
+    let device_name = extract_header(headers, "x-device-name")
         .filter(|s| !s.trim().is_empty())
         .unwrap_or_else(|| user_agent.clone());
 
-    let refresh_ttl = if remember_me {
-        ChronoDuration::days(365)
-    } else {
-        ChronoDuration::days(30)
-    };
+    let refresh_ttl = state.config.auth.refresh_ttl(remember_me);
     let session_expires_at = Utc::now() + refresh_ttl;
 
     let session_id = Uuid::new_v4();
@@ -254,9 +416,9 @@ async fn login(
         r#"
         INSERT INTO auth_sessions (
             id, user_id, refresh_token_hash, device_name, user_agent, ip_address,
-            city, app_version, remember_me, expires_at
+            city, app_version, remember_me, expires_at, webauthn_credential_id
         )
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
         "#,
     )
     .bind(session_id)
@@ -264,11 +426,12 @@ async fn login(
     .bind(refresh_hash)
     .bind(device_name)
     .bind(user_agent)
-    .bind(ip_address)
-    .bind(city)
+    .bind(&ip_address)
+    .bind(&city)
     .bind(app_version)
     .bind(remember_me)
     .bind(session_expires_at)
+    .bind(passkey.as_ref().map(|p| p.credential_id))
     .execute(&state.pool)
     .await
     .map_err(|e| {
@@ -278,15 +441,20 @@ async fn login(
         )
     })?;
 
-    let token = issue_access_token(&state, &user, session_id)?;
+    log_login(state, user.id, session_id, &ip_address, &city, method).await;
+
+    let token = issue_access_token(state, &user, session_id)?;
+    let kdf_upgrade = user.kdf_params.needs_upgrade().then(KdfParams::recommended);
 
-    Ok(Json(LoginResponse {
+    Ok(LoginResponse {
         message: "Успешный вход".into(),
         user,
         token,
         refresh_token,
         session_id: session_id.to_string(),
-    }))
+        kdf_upgrade,
+        pkebyprf: passkey.and_then(|p| p.pkebyprf),
+    })
 }
 
 async fn refresh(
@@ -300,6 +468,17 @@ async fn refresh(
     }
 
     let refresh_hash = hash_refresh_token(&state.jwt_secret, &payload.refresh_token);
+    let ip_address = extract_ip(&state, &headers, addr);
+
+    let mut tx = state.pool.begin().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+
+    // Строка сессии блокируется до конца ротации: из двух одновременных запросов
+    // с одним токеном второй увидит уже заменённый хеш.
     let row = sqlx::query(
         r#"
         SELECT
@@ -315,10 +494,11 @@ async fn refresh(
           AND s.revoked_at IS NULL
           AND s.expires_at > now()
         LIMIT 1
+        FOR UPDATE OF s
         "#,
     )
-    .bind(refresh_hash)
-    .fetch_optional(&state.pool)
+    .bind(&refresh_hash)
+    .fetch_optional(&mut *tx)
     .await
     .map_err(|e| {
         (
@@ -328,6 +508,8 @@ async fn refresh(
     })?;
 
     let Some(row) = row else {
+        drop(tx);
+        revoke_on_refresh_reuse(&state, &headers, &ip_address, &refresh_hash).await?;
         return Err((StatusCode::UNAUTHORIZED, "Невалидный refresh_token".into()));
     };
 
@@ -351,15 +533,10 @@ async fn refresh(
     let new_refresh_token = generate_refresh_token();
     let new_refresh_hash = hash_refresh_token(&state.jwt_secret, &new_refresh_token);
 
-    let refresh_ttl = if remember_me {
-        ChronoDuration::days(365)
-    } else {
-        ChronoDuration::days(30)
-    };
+    let refresh_ttl = state.config.auth.refresh_ttl(remember_me);
     let new_expires_at = Utc::now() + refresh_ttl;
 
-    let ip_address = extract_ip(&headers, addr);
-    let city = resolve_city(&headers, &ip_address).await;
+    let city = resolve_city(&state, &headers, &ip_address).await;
     let app_version =
         extract_header(&headers, "x-app-version").unwrap_or_else(|| "unknown".to_string());
     let user_agent =
@@ -368,6 +545,26 @@ async fn refresh(
         .filter(|s| !s.trim().is_empty())
         .unwrap_or_else(|| user_agent.clone());
 
+    // Заменяемый токен остаётся в семействе сессии для обнаружения повторного использования
+    sqlx::query(
+        r#"
+        INSERT INTO auth_session_refresh_tokens (token_hash, session_id, issued_at)
+        SELECT refresh_token_hash, id, refresh_issued_at
+        FROM auth_sessions
+        WHERE id = $1
+        ON CONFLICT (token_hash) DO NOTHING
+        "#,
+    )
+    .bind(session_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+
     sqlx::query(
         r#"
         UPDATE auth_sessions
@@ -379,7 +576,8 @@ async fn refresh(
             city = $5,
             app_version = $6,
             last_seen_at = now(),
-            expires_at = $7
+            expires_at = $7,
+            refresh_issued_at = now()
         WHERE id = $8
         "#,
     )
@@ -391,7 +589,7 @@ async fn refresh(
     .bind(app_version)
     .bind(new_expires_at)
     .bind(session_id)
-    .execute(&state.pool)
+    .execute(&mut *tx)
     .await
     .map_err(|e| {
         (
@@ -400,6 +598,13 @@ async fn refresh(
         )
     })?;
 
+    tx.commit().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+
     let claims_user = UserAuthResponse {
         id: user_id,
         login,
@@ -410,6 +615,7 @@ async fn refresh(
         pkebyrk: String::new(),
         pubk: String::new(),
         salt: String::new(),
+        kdf_params: KdfParams::legacy_pbkdf2(),
     };
 
     let token = issue_access_token(&state, &claims_user, session_id)?;
@@ -421,6 +627,77 @@ async fn refresh(
     }))
 }
 
+// Предъявлен уже заменённый refresh-токен: им воспользовался кто-то ещё
+// (владелец или похититель), поэтому сессия отзывается целиком, а остальные
+// сессии пользователя получают уведомление.
+async fn revoke_on_refresh_reuse(
+    state: &AppState,
+    headers: &HeaderMap,
+    ip_address: &str,
+    refresh_hash: &str,
+) -> Result<(), (StatusCode, String)> {
+    let revoked = sqlx::query(
+        r#"
+        UPDATE auth_sessions s
+        SET revoked_at = now()
+        FROM auth_session_refresh_tokens t
+        WHERE t.token_hash = $1
+          AND s.id = t.session_id
+          AND s.revoked_at IS NULL
+        RETURNING s.id, s.user_id, t.superseded_at
+        "#,
+    )
+    .bind(refresh_hash)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+
+    let Some(row) = revoked else {
+        return Ok(());
+    };
+    let session_id: Uuid = row.try_get("id").map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Ошибка чтения session id".into(),
+        )
+    })?;
+    let user_id: i32 = row.try_get("user_id").unwrap_or_default();
+    let superseded_at: Option<chrono::DateTime<Utc>> = row.try_get("superseded_at").ok();
+
+    let city = resolve_city(state, headers, ip_address).await;
+    record_security_event(
+        &state.pool,
+        user_id,
+        Some(session_id),
+        EVENT_REFRESH_TOKEN_REUSE,
+        ip_address,
+        &city,
+        Some(json!({ "superseded_at": superseded_at })),
+    )
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+
+    publish_session_revoked(
+        state,
+        user_id,
+        &[session_id.to_string()],
+        EVENT_REFRESH_TOKEN_REUSE,
+    );
+    publish_device_keys_changed(state, user_id).await;
+
+    Ok(())
+}
+
 async fn list_sessions(
     State(state): State<AppState>,
     CurrentUser {
@@ -437,8 +714,10 @@ async fn list_sessions(
             city,
             app_version,
             created_at,
-            last_seen_at
-        FROM auth_sessions
+            last_seen_at,
+            (SELECT name FROM webauthn_credentials c WHERE c.id = s.webauthn_credential_id)
+                AS passkey_name
+        FROM auth_sessions s
         WHERE user_id = $1
           AND revoked_at IS NULL
           AND expires_at > now()
@@ -499,6 +778,7 @@ async fn list_sessions(
                 )
             })?,
             is_current: id == current_session_id,
+            passkey_name: row.try_get("passkey_name").ok().flatten(),
         });
     }
 
@@ -507,7 +787,12 @@ async fn list_sessions(
 
 async fn delete_session(
     State(state): State<AppState>,
-    CurrentUser { id: user_id, .. }: CurrentUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    CurrentUser {
+        id: user_id,
+        session_id: current_session_id,
+    }: CurrentUser,
     Path(session_id): Path<String>,
 ) -> Result<StatusCode, (StatusCode, String)> {
     let session_uuid = Uuid::parse_str(&session_id)
@@ -537,28 +822,50 @@ async fn delete_session(
         return Err((StatusCode::NOT_FOUND, "Сессия не найдена".into()));
     }
 
+    publish_session_revoked(
+        &state,
+        user_id,
+        &[session_uuid.to_string()],
+        EVENT_SESSION_REVOKED,
+    );
+    // Собеседники перестают адресовать конверты отозванному устройству
+    publish_device_keys_changed(&state, user_id).await;
+    log_security_event(
+        &state,
+        &headers,
+        addr,
+        user_id,
+        Some(current_session_id),
+        EVENT_SESSION_REVOKED,
+        Some(json!({ "revoked_session_ids": [session_uuid.to_string()] })),
+    )
+    .await;
+
     Ok(StatusCode::NO_CONTENT)
 }
 
 async fn delete_other_sessions(
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     CurrentUser {
         id: user_id,
         session_id: current_session_id,
     }: CurrentUser,
 ) -> Result<StatusCode, (StatusCode, String)> {
-    sqlx::query(
+    let revoked: Vec<Uuid> = sqlx::query_scalar(
         r#"
         UPDATE auth_sessions
         SET revoked_at = now()
         WHERE user_id = $1
           AND id <> $2
           AND revoked_at IS NULL
+        RETURNING id
         "#,
     )
     .bind(user_id)
     .bind(current_session_id)
-    .execute(&state.pool)
+    .fetch_all(&state.pool)
     .await
     .map_err(|e| {
         (
@@ -567,17 +874,35 @@ async fn delete_other_sessions(
         )
     })?;
 
+    let revoked: Vec<String> = revoked.iter().map(Uuid::to_string).collect();
+    publish_session_revoked(&state, user_id, &revoked, EVENT_SESSION_REVOKED);
+    publish_device_keys_changed(&state, user_id).await;
+    if !revoked.is_empty() {
+        log_security_event(
+            &state,
+            &headers,
+            addr,
+            user_id,
+            Some(current_session_id),
+            EVENT_SESSION_REVOKED,
+            Some(json!({ "revoked_session_ids": revoked })),
+        )
+        .await;
+    }
+
     Ok(StatusCode::NO_CONTENT)
 }
 
 async fn logout(
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     CurrentUser {
         id: user_id,
         session_id,
     }: CurrentUser,
 ) -> Result<StatusCode, (StatusCode, String)> {
-    let _ = sqlx::query(
+    let result = sqlx::query(
         r#"
         UPDATE auth_sessions
         SET revoked_at = now()
@@ -597,9 +922,314 @@ async fn logout(
         )
     })?;
 
+    // Websocket-соединения этой сессии закрываются сразу
+    state.sessions.revoke([session_id], EVENT_LOGOUT);
+
+    if result.rows_affected() > 0 {
+        log_security_event(
+            &state,
+            &headers,
+            addr,
+            user_id,
+            Some(session_id),
+            EVENT_LOGOUT,
+            None,
+        )
+        .await;
+    }
+
     Ok(StatusCode::NO_CONTENT)
 }
 
+// ---------------------------
+// POST /users/me/password — смена пароля с перешифрованием приватного ключа
+// ---------------------------
+async fn change_password(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    CurrentUser {
+        id: user_id,
+        session_id: current_session_id,
+    }: CurrentUser,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if payload.new_password.len() < 6 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Некорректные данные (минимум: пароль >= 6 символов)".into(),
+        ));
+    }
+    if payload.pkebymk.trim().is_empty() || payload.salt.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "pkebymk и salt обязательны".into()));
+    }
+    let row = sqlx::query("SELECT login, password, kdf_params FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Ошибка БД: {}", e),
+            )
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "Пользователь не найден".into()))?;
+    let login: String = row.try_get("login").unwrap_or_default();
+    let hashed: String = row.try_get("password").unwrap_or_default();
+    let current = row
+        .try_get::<sqlx::types::Json<KdfParams>, _>("kdf_params")
+        .map(|p| p.0)
+        .unwrap_or_else(|_| KdfParams::legacy_pbkdf2());
+    let kdf_params = resolve_kdf_params_update(payload.kdf_params, current)?;
+
+    // Проверка старого пароля — такой же перебор, как и вход: общие счётчики по IP/логину
+    let ip = addr.ip().to_string();
+    if !state.auth_rate_limiter.is_allowed(&ip, Some(&login)).await {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "Слишком много попыток. Повторите позже.".into(),
+        ));
+    }
+    if !verify_password(&hashed, &payload.old_password)? {
+        let (_allowed, _lockout_secs) = state
+            .auth_rate_limiter
+            .record_failure(&ip, Some(&login))
+            .await;
+        return Err((StatusCode::UNAUTHORIZED, "Неверный текущий пароль".into()));
+    }
+    state
+        .auth_rate_limiter
+        .record_success(&ip, Some(&login))
+        .await;
+
+    let password_hash = hash_password(&payload.new_password)?;
+
+    // Пароль, pkebymk и salt меняются только вместе: иначе клиент не сможет
+    // расшифровать приватный ключ при следующем входе.
+    let mut tx = state.pool.begin().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+
+    sqlx::query(
+        "UPDATE users SET password = $2, pkebymk = $3, salt = $4, kdf_params = $5 WHERE id = $1",
+    )
+    .bind(user_id)
+    .bind(&password_hash)
+    .bind(&payload.pkebymk)
+    .bind(&payload.salt)
+    .bind(sqlx::types::Json(&kdf_params))
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+
+    let revoked = revoke_user_sessions(&mut tx, user_id, Some(current_session_id)).await?;
+
+    tx.commit().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+
+    publish_session_revoked(&state, user_id, &revoked, EVENT_PASSWORD_CHANGED);
+    publish_device_keys_changed(&state, user_id).await;
+    log_security_event(
+        &state,
+        &headers,
+        addr,
+        user_id,
+        Some(current_session_id),
+        EVENT_PASSWORD_CHANGED,
+        Some(json!({ "revoked_session_ids": revoked })),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// ---------------------------
+// POST /users/me/kdf — перешифрование pkebymk под актуальные параметры KDF
+// (клиент делает это сразу после входа, если в LoginResponse пришёл kdf_upgrade)
+// ---------------------------
+async fn upgrade_kdf(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    CurrentUser {
+        id: user_id,
+        session_id,
+    }: CurrentUser,
+    Json(payload): Json<KdfUpgradeRequest>,
+) -> Result<Json<KdfParams>, (StatusCode, String)> {
+    if payload.pkebymk.trim().is_empty() || payload.salt.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "pkebymk и salt обязательны".into()));
+    }
+    let kdf_params = resolve_kdf_params(Some(payload.kdf_params))?;
+
+    let row = sqlx::query("SELECT login, password, kdf_params FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Ошибка БД: {}", e),
+            )
+        })?
+        .ok_or((StatusCode::NOT_FOUND, "Пользователь не найден".into()))?;
+    let login: String = row.try_get("login").unwrap_or_default();
+    let hashed: String = row.try_get("password").unwrap_or_default();
+    let current = row
+        .try_get::<sqlx::types::Json<KdfParams>, _>("kdf_params")
+        .map(|p| p.0)
+        .unwrap_or_else(|_| KdfParams::legacy_pbkdf2());
+
+    // Понижать версию нельзя: иначе украденный токен позволил бы ослабить защиту ключа
+    let kdf_params = resolve_kdf_params_update(Some(kdf_params), current.clone())?;
+
+    // Пароль подтверждает, что pkebymk зашифрован ключом, выведенным из него же
+    let ip = addr.ip().to_string();
+    if !state.auth_rate_limiter.is_allowed(&ip, Some(&login)).await {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "Слишком много попыток. Повторите позже.".into(),
+        ));
+    }
+    if !verify_password(&hashed, &payload.password)? {
+        let (_allowed, _lockout_secs) = state
+            .auth_rate_limiter
+            .record_failure(&ip, Some(&login))
+            .await;
+        return Err((StatusCode::UNAUTHORIZED, "Неверный пароль".into()));
+    }
+    state
+        .auth_rate_limiter
+        .record_success(&ip, Some(&login))
+        .await;
+
+    sqlx::query("UPDATE users SET pkebymk = $2, salt = $3, kdf_params = $4 WHERE id = $1")
+        .bind(user_id)
+        .bind(&payload.pkebymk)
+        .bind(&payload.salt)
+        .bind(sqlx::types::Json(&kdf_params))
+        .execute(&state.pool)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Ошибка БД: {}", e),
+            )
+        })?;
+
+    log_security_event(
+        &state,
+        &headers,
+        addr,
+        user_id,
+        Some(session_id),
+        EVENT_KDF_UPGRADED,
+        Some(json!({ "from_version": current.version, "to_version": kdf_params.version })),
+    )
+    .await;
+
+    Ok(Json(kdf_params))
+}
+
+// Параметры KDF из запроса; старые клиенты их не присылают — это PBKDF2
+pub(crate) fn resolve_kdf_params(
+    params: Option<KdfParams>,
+) -> Result<KdfParams, (StatusCode, String)> {
+    let params = params.unwrap_or_else(KdfParams::legacy_pbkdf2);
+    params
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok(params)
+}
+
+// Параметры KDF при смене пароля: без них остаются текущие (иначе Argon2id
+// откатился бы к PBKDF2), а версию ниже текущей принять нельзя
+pub(crate) fn resolve_kdf_params_update(
+    params: Option<KdfParams>,
+    current: KdfParams,
+) -> Result<KdfParams, (StatusCode, String)> {
+    let Some(params) = params else {
+        return Ok(current);
+    };
+    params
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if params.version < current.version {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "kdf_params: версия ниже текущей".into(),
+        ));
+    }
+    Ok(params)
+}
+
+// Отзывает все активные сессии пользователя (кроме `except`) и возвращает их id
+pub(crate) async fn revoke_user_sessions(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: i32,
+    except: Option<Uuid>,
+) -> Result<Vec<String>, (StatusCode, String)> {
+    let ids: Vec<Uuid> = sqlx::query_scalar(
+        r#"
+        UPDATE auth_sessions
+        SET revoked_at = now()
+        WHERE user_id = $1
+          AND ($2::UUID IS NULL OR id <> $2)
+          AND revoked_at IS NULL
+        RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .bind(except)
+    .fetch_all(&mut **tx)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Ошибка БД: {}", e),
+        )
+    })?;
+
+    Ok(ids.into_iter().map(|id| id.to_string()).collect())
+}
+
+pub(crate) fn hash_password(password: &str) -> Result<String, (StatusCode, String)> {
+    let password_salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &password_salt)
+        .map(|h| h.to_string())
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Не удалось захешировать пароль".into(),
+            )
+        })
+}
+
+pub(crate) fn verify_password(hashed: &str, password: &str) -> Result<bool, (StatusCode, String)> {
+    let parsed_hash = PasswordHash::new(hashed).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Некорректный формат хеша пароля".into(),
+        )
+    })?;
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok())
+}
+
 fn issue_access_token(
     state: &AppState,
     user: &UserAuthResponse,
@@ -629,14 +1259,14 @@ fn issue_access_token(
     })
 }
 
-fn hash_refresh_token(secret: &str, refresh_token: &str) -> String {
+pub(crate) fn hash_refresh_token(secret: &str, refresh_token: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(secret.as_bytes());
     hasher.update(refresh_token.as_bytes());
     format!("{:x}", hasher.finalize())
 }
 
-fn generate_refresh_token() -> String {
+pub(crate) fn generate_refresh_token() -> String {
     let mut bytes = [0u8; 48];
     OsRng.fill_bytes(&mut bytes);
     let mut token = String::with_capacity(bytes.len() * 2);
@@ -647,21 +1277,15 @@ fn generate_refresh_token() -> String {
     token
 }
 
-fn extract_ip(headers: &HeaderMap, addr: SocketAddr) -> String {
-    if let Some(forwarded) = extract_header(headers, "x-forwarded-for") {
-        let ip = forwarded.split(',').next().map(str::trim).unwrap_or("");
-        if !ip.is_empty() {
-            return ip.to_string();
-        }
-    }
-
-    if let Some(real_ip) = extract_header(headers, "x-real-ip") {
-        if !real_ip.trim().is_empty() {
-            return real_ip;
-        }
-    }
-
-    addr.ip().to_string()
+// Заголовки прокси учитываются, только если соединение пришло от
+// server.trusted_proxies (см. TrustedProxies::client_ip)
+pub(crate) fn extract_ip(state: &AppState, headers: &HeaderMap, addr: SocketAddr) -> String {
+    state
+        .config
+        .server
+        .trusted_proxies
+        .client_ip(headers, addr)
+        .to_string()
 }
 
 fn extract_city_from_headers(headers: &HeaderMap) -> Option<String> {
@@ -678,7 +1302,11 @@ fn extract_header(headers: &HeaderMap, name: &str) -> Option<String> {
         .map(|v| v.trim().to_string())
 }
 
-async fn resolve_city(headers: &HeaderMap, ip_address: &str) -> String {
+pub(crate) async fn resolve_city(
+    state: &AppState,
+    headers: &HeaderMap,
+    ip_address: &str,
+) -> String {
     // P2-12: Geo-Service Privacy - Check headers first (from trusted proxy)
     if let Some(city) = extract_city_from_headers(headers) {
         return city;
@@ -693,20 +1321,15 @@ async fn resolve_city(headers: &HeaderMap, ip_address: &str) -> String {
     }
 
     // P2-12: Geo-Service Privacy - External geo-requests disabled by default
-    // Set ENABLE_EXTERNAL_GEO=1 to enable external lookups (not recommended for privacy)
-    let enable_external = std::env::var("ENABLE_EXTERNAL_GEO")
-        .unwrap_or_else(|_| "0".to_string())
-        .trim()
-        .eq("1");
-    
-    if !enable_external {
+    // Set features.external_geo (ENABLE_EXTERNAL_GEO=1) to enable external lookups (not recommended for privacy)
+    if !state.config.features.external_geo {
         // P2-12: Return "Unknown" instead of making external request
         return "Unknown".to_string();
     }
 
     // External geo lookup is disabled by default for privacy
     // If explicitly enabled, use local/offline geo-DB instead
-    resolve_city_by_ipwhois(ip_address)
+    resolve_city_by_ipwhois(&state.config.features.ip_geo_url_template, ip_address)
         .await
         .filter(|v| !v.trim().is_empty())
         .unwrap_or_else(|| "Unknown".to_string())
@@ -718,14 +1341,12 @@ struct IpWhoisResponse {
     city: Option<String>,
 }
 
-async fn resolve_city_by_ipwhois(ip_address: &str) -> Option<String> {
+async fn resolve_city_by_ipwhois(template: &str, ip_address: &str) -> Option<String> {
     let client = reqwest::Client::builder()
         .timeout(Duration::from_secs(3))
         .build()
         .ok()?;
 
-    let template = std::env::var("IP_GEO_URL_TEMPLATE")
-        .unwrap_or_else(|_| "https://ipwhois.app/json/{ip}".to_string());
     let url = template.replace("{ip}", ip_address);
     let response = client.get(url).send().await.ok()?;
     if !response.status().is_success() {
@@ -739,3 +1360,25 @@ async fn resolve_city_by_ipwhois(ip_address: &str) -> Option<String> {
 
     body.city.map(|v| v.trim().to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kdf_params_update_keeps_stored_and_rejects_downgrade() {
+        let argon = KdfParams::recommended();
+        assert_eq!(
+            resolve_kdf_params_update(None, argon.clone()).unwrap(),
+            argon
+        );
+
+        let err =
+            resolve_kdf_params_update(Some(KdfParams::legacy_pbkdf2()), argon.clone()).unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+
+        let upgraded =
+            resolve_kdf_params_update(Some(argon.clone()), KdfParams::legacy_pbkdf2()).unwrap();
+        assert_eq!(upgraded, argon);
+    }
+}
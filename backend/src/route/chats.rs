@@ -16,6 +16,7 @@ use crate::AppState;
 use crate::middleware::CurrentUser; // экстрактор текущего пользователя
 use crate::middleware::ensure_member;
 use crate::models::chats::{Chat, CreateChatRequest, FileMetadata, Message};
+use crate::route::privacy::blocked_by;
 use crate::route::ws::{
     publish_chat_created, publish_chat_updated, publish_member_added, publish_member_removed,
     publish_member_role_changed, publish_message_delivered, publish_message_read,
@@ -139,7 +140,7 @@ fn normalize_member_role(kind: &str, role: Option<&str>) -> Result<String, (Stat
     }
 }
 
-async fn load_chat_recipients(
+pub(crate) async fn load_chat_recipients(
     state: &AppState,
     chat_id: i32,
 ) -> Result<Vec<i32>, (StatusCode, String)> {
@@ -482,16 +483,15 @@ async fn update_chat_avatar(
     let new_avatar_path = if remove_avatar {
         None
     } else if let Some(data) = avatar_data {
-        const MAX_AVATAR_BYTES: usize = 5 * 1024 * 1024;
-        if data.len() > MAX_AVATAR_BYTES {
+        if data.len() > state.config.storage.max_avatar_bytes {
             return Err((
                 StatusCode::BAD_REQUEST,
                 "Слишком большой файл аватара".into(),
             ));
         }
 
-        let avatars_dir = StdPath::new("uploads/avatars");
-        fs::create_dir_all(avatars_dir).await.map_err(|e| {
+        let avatars_dir = state.config.storage.path("avatars");
+        fs::create_dir_all(&avatars_dir).await.map_err(|e| {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Не удалось создать директорию для аватаров: {}", e),
@@ -509,7 +509,7 @@ async fn update_chat_avatar(
             _ => "jpg".to_string(),
         };
 
-        let new_path = format!("uploads/avatars/chat_{}.{}", id, extension);
+        let new_path = avatars_dir.join(format!("chat_{}.{}", id, extension));
         let mut file = fs::File::create(&new_path).await.map_err(|e| {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -535,7 +535,7 @@ async fn update_chat_avatar(
 
     if let Some(ref old_path) = old_avatar_path {
         if new_avatar_path.as_ref() != Some(old_path) {
-            let full_old_path = StdPath::new("uploads").join(old_path);
+            let full_old_path = state.config.storage.path(old_path);
             if full_old_path.exists() {
                 let _ = fs::remove_file(&full_old_path).await;
             }
@@ -610,6 +610,28 @@ async fn create_chat(
         }
     }
 
+    // Заблокировавшего текущего пользователя нельзя позвать ни в private-чат, ни в группу
+    let others: Vec<i32> = body
+        .user_ids
+        .iter()
+        .copied()
+        .filter(|uid| *uid != current_user_id)
+        .collect();
+    let blockers = blocked_by(&state.pool, current_user_id, &others)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Ошибка БД: {}", e),
+            )
+        })?;
+    if !blockers.is_empty() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Пользователь ограничил возможность добавлять его в чаты".into(),
+        ));
+    }
+
     let mut tx: Transaction<'_, Postgres> = state.pool.begin().await.map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -990,6 +1012,10 @@ async fn list_chats(
                   AND m.deleted_at IS NULL
                   AND m.sender_id <> $1
                   AND m.id::INT8 > COALESCE(p.last_read_message_id::INT8, 0)
+                  AND NOT EXISTS (
+                      SELECT 1 FROM message_hidden h
+                      WHERE h.message_id = m.id AND h.user_id = $1
+                  )
             ) AS unread_count
         FROM chats c
         JOIN chat_participants p ON p.chat_id = c.id
@@ -1005,6 +1031,10 @@ async fn list_chats(
             FROM messages m
             WHERE m.chat_id = c.id
               AND m.deleted_at IS NULL
+              AND NOT EXISTS (
+                  SELECT 1 FROM message_hidden h
+                  WHERE h.message_id = m.id AND h.user_id = $1
+              )
             ORDER BY m.id DESC
             LIMIT 1
         ) lm ON TRUE
@@ -1122,6 +1152,10 @@ async fn get_messages(
               AND deleted_at IS NULL
               AND ($2::INT8 IS NULL OR id::INT8 < $2::INT8)
               AND ($3::INT8 IS NULL OR id::INT8 > $3::INT8)
+              AND NOT EXISTS (
+                  SELECT 1 FROM message_hidden h
+                  WHERE h.message_id = messages.id AND h.user_id = $5
+              )
             ORDER BY created_at DESC
             LIMIT $4
         ) t
@@ -1132,6 +1166,7 @@ async fn get_messages(
     .bind(before_id)
     .bind(after_id)
     .bind(limit)
+    .bind(current_user_id)
     .fetch_all(&state.pool)
     .await
     .map_err(|e| {
@@ -1508,6 +1543,20 @@ async fn add_member(
             "Нельзя добавить самого себя через этот endpoint".into(),
         ));
     }
+    let blockers = blocked_by(&state.pool, current_user_id, &[body.user_id])
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Ошибка БД: {}", e),
+            )
+        })?;
+    if !blockers.is_empty() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Пользователь ограничил возможность добавлять его в чаты".into(),
+        ));
+    }
 
     let exists_member: Option<String> = sqlx::query_scalar(
         "SELECT role FROM chat_participants WHERE chat_id = $1 AND user_id = $2 LIMIT 1",
@@ -1999,7 +2048,8 @@ struct DeleteOptions {
 
 #[cfg(test)]
 mod tests {
-    use super::normalize_member_role;
+    use super::*;
+    use crate::test_support;
     use axum::http::StatusCode;
 
     #[test]
@@ -2026,4 +2076,78 @@ mod tests {
         let role = normalize_member_role("channel", Some("  MEMBER  ")).expect("role");
         assert_eq!(role, "member");
     }
+
+    fn chat_request(kind: &str, user_ids: Vec<i32>) -> Json<CreateChatRequest> {
+        Json(CreateChatRequest {
+            kind: kind.to_string(),
+            title: (kind != "private").then(|| "Группа".to_string()),
+            user_ids,
+        })
+    }
+
+    #[tokio::test]
+    async fn blocker_cannot_be_pulled_into_chats() {
+        let Some(state) = test_support::state().await else {
+            return;
+        };
+        let pool = state.pool.clone();
+        let (me, blocker) = (
+            test_support::user(&pool).await,
+            test_support::user(&pool).await,
+        );
+        sqlx::query("INSERT INTO user_blocks (blocker_id, blocked_id) VALUES ($1, $2)")
+            .bind(blocker)
+            .bind(me)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let err = create_chat(
+            State(state.clone()),
+            test_support::current(me),
+            chat_request("private", vec![me, blocker]),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+        let err = create_chat(
+            State(state.clone()),
+            test_support::current(me),
+            chat_request("group", vec![me, blocker]),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+
+        let group = create_chat(
+            State(state.clone()),
+            test_support::current(me),
+            chat_request("group", vec![me]),
+        )
+        .await
+        .ok()
+        .unwrap();
+        let add = |user_id| {
+            add_member(
+                State(state.clone()),
+                test_support::current(me),
+                Path(group.id),
+                Json(AddMemberRequest {
+                    user_id,
+                    role: None,
+                }),
+            )
+        };
+        assert_eq!(add(blocker).await.unwrap_err().0, StatusCode::FORBIDDEN);
+
+        // Заблокированный сам может позвать меня: ограничение только у заблокировавшего
+        sqlx::query("DELETE FROM user_blocks WHERE blocker_id = $1")
+            .bind(blocker)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(add(blocker).await.is_ok());
+    }
 }
@@ -0,0 +1,605 @@
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Utc};
+use rand_core::{OsRng, RngCore};
+use serde_json::json;
+use sqlx::Row;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::middleware::CurrentUser;
+use crate::models::auth::{
+    DeviceLinkApproveRequest, DeviceLinkClaimRequest, DeviceLinkClaimResponse,
+    DeviceLinkCompleteRequest, DeviceLinkCompleteResponse, DeviceLinkCreateResponse,
+    DeviceLinkStatusResponse,
+};
+use crate::route::auth::{
+    create_login_session, generate_refresh_token, hash_refresh_token, user_auth_from_row,
+};
+use crate::route::security_events::LOGIN_METHOD_DEVICE_LINK;
+use crate::route::ws::publish_payload_to_users;
+
+// Время жизни привязки целиком: от показа QR до выдачи сессии
+const LINK_TTL_SECS: i64 = 5 * 60;
+// Неверных кодов на одну привязку, после чего она отменяется
+const LINK_MAX_ATTEMPTS: i32 = 5;
+const DEVICE_NAME_MAX_CHARS: usize = 64;
+// Конверт — обёрнутый ключ и зашифрованный приватный ключ, несколько сотен байт
+const ENVELOPE_MAX_BYTES: usize = 16 * 1024;
+
+// Привязка нового устройства по QR-коду:
+// - POST /users/me/device-links             — авторизованная сессия создаёт привязку и код для QR
+// - GET /users/me/device-links/:id          — статус и открытый ключ нового устройства
+// - POST /users/me/device-links/:id/approve — отдать конверт с приватным ключом
+// - DELETE /users/me/device-links/:id       — отменить привязку
+// - POST /auth/device-links/claim           — новое устройство предъявляет код и свой ключ
+// - POST /auth/device-links/complete        — новое устройство забирает конверт и сессию
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/users/me/device-links", post(create_link))
+        .route(
+            "/users/me/device-links/:id",
+            get(link_status).delete(cancel_link),
+        )
+        .route("/users/me/device-links/:id/approve", post(approve_link))
+        .route("/auth/device-links/claim", post(claim_link))
+        .route("/auth/device-links/complete", post(complete_link))
+}
+
+fn db_error(e: sqlx::Error) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Ошибка БД: {}", e),
+    )
+}
+
+fn too_many_attempts() -> (StatusCode, String) {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        "Слишком много попыток. Повторите позже.".into(),
+    )
+}
+
+fn invalid_link() -> (StatusCode, String) {
+    (
+        StatusCode::UNAUTHORIZED,
+        "Недействительный или просроченный код привязки".into(),
+    )
+}
+
+fn parse_link_id(id: &str) -> Result<Uuid, (StatusCode, String)> {
+    Uuid::parse_str(id.trim()).map_err(|_| invalid_link())
+}
+
+fn link_status_name(row: &sqlx::postgres::PgRow) -> &'static str {
+    let is_set = |column: &str| {
+        row.try_get::<Option<DateTime<Utc>>, _>(column)
+            .ok()
+            .flatten()
+            .is_some()
+    };
+    let expires_at: DateTime<Utc> = row.try_get("expires_at").unwrap_or_else(|_| Utc::now());
+    if is_set("cancelled_at") {
+        "cancelled"
+    } else if is_set("completed_at") {
+        "completed"
+    } else if expires_at <= Utc::now() {
+        "expired"
+    } else if is_set("approved_at") {
+        "approved"
+    } else if is_set("claimed_at") {
+        "claimed"
+    } else {
+        "pending"
+    }
+}
+
+async fn create_link(
+    State(state): State<AppState>,
+    CurrentUser {
+        id: user_id,
+        session_id,
+    }: CurrentUser,
+) -> Result<Json<DeviceLinkCreateResponse>, (StatusCode, String)> {
+    // Одна активная привязка на сессию: новый QR делает старый недействительным
+    sqlx::query(
+        r#"
+        UPDATE device_links
+        SET cancelled_at = now(), envelope = NULL
+        WHERE initiator_session_id = $1 AND completed_at IS NULL AND cancelled_at IS NULL
+        "#,
+    )
+    .bind(session_id)
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let mut code_bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut code_bytes);
+    let code = general_purpose::URL_SAFE_NO_PAD.encode(code_bytes);
+    let link_id = Uuid::new_v4();
+
+    sqlx::query(
+        r#"
+        INSERT INTO device_links (id, user_id, initiator_session_id, code_hash, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(link_id)
+    .bind(user_id)
+    .bind(session_id)
+    .bind(hash_refresh_token(&state.jwt_secret, &code))
+    .bind(Utc::now() + chrono::Duration::seconds(LINK_TTL_SECS))
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    Ok(Json(DeviceLinkCreateResponse {
+        link_id: link_id.to_string(),
+        code,
+        expires_in: LINK_TTL_SECS,
+    }))
+}
+
+async fn link_status(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    Path(id): Path<String>,
+) -> Result<Json<DeviceLinkStatusResponse>, (StatusCode, String)> {
+    let id = Uuid::parse_str(id.trim())
+        .map_err(|_| (StatusCode::NOT_FOUND, "Привязка не найдена".into()))?;
+    let row = sqlx::query(
+        r#"
+        SELECT id, claimant_pubk, claimant_key_mac, device_name, expires_at,
+               claimed_at, approved_at, completed_at, cancelled_at
+        FROM device_links
+        WHERE id = $1 AND user_id = $2
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?
+    .ok_or((StatusCode::NOT_FOUND, "Привязка не найдена".into()))?;
+
+    Ok(Json(DeviceLinkStatusResponse {
+        link_id: id.to_string(),
+        status: link_status_name(&row).to_string(),
+        public_key: row.try_get("claimant_pubk").ok().flatten(),
+        key_mac: row.try_get("claimant_key_mac").ok().flatten(),
+        device_name: row.try_get("device_name").ok().flatten(),
+        expires_at: row.try_get("expires_at").map_err(db_error)?,
+    }))
+}
+
+async fn approve_link(
+    State(state): State<AppState>,
+    CurrentUser {
+        id: user_id,
+        session_id,
+    }: CurrentUser,
+    Path(id): Path<String>,
+    Json(payload): Json<DeviceLinkApproveRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let id = Uuid::parse_str(id.trim())
+        .map_err(|_| (StatusCode::NOT_FOUND, "Привязка не найдена".into()))?;
+    if !payload.envelope.is_object() || payload.envelope.to_string().len() > ENVELOPE_MAX_BYTES {
+        return Err((StatusCode::BAD_REQUEST, "Некорректный конверт".into()));
+    }
+
+    // Подтверждает только сессия, показавшая QR: секрет из QR есть только у неё
+    let result = sqlx::query(
+        r#"
+        UPDATE device_links
+        SET envelope = $4, approved_at = now()
+        WHERE id = $1
+          AND user_id = $2
+          AND initiator_session_id = $3
+          AND claimed_at IS NOT NULL
+          AND approved_at IS NULL
+          AND cancelled_at IS NULL
+          AND expires_at > now()
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .bind(session_id)
+    .bind(&payload.envelope)
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            "Привязка недоступна для подтверждения".into(),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn cancel_link(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let id = Uuid::parse_str(id.trim())
+        .map_err(|_| (StatusCode::NOT_FOUND, "Привязка не найдена".into()))?;
+    let result = sqlx::query(
+        r#"
+        UPDATE device_links
+        SET cancelled_at = now(), envelope = NULL
+        WHERE id = $1 AND user_id = $2 AND completed_at IS NULL AND cancelled_at IS NULL
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "Привязка не найдена".into()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn claim_link(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<DeviceLinkClaimRequest>,
+) -> Result<Json<DeviceLinkClaimResponse>, (StatusCode, String)> {
+    let ip = addr.ip().to_string();
+    if !state.auth_rate_limiter.is_allowed(&ip, None).await {
+        return Err(too_many_attempts());
+    }
+
+    let public_key = payload.public_key.trim();
+    let key_len = general_purpose::STANDARD
+        .decode(public_key)
+        .map(|k| k.len())
+        .unwrap_or(0);
+    if key_len != 32 || payload.key_mac.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "public_key (X25519, 32 байта) и key_mac обязательны".into(),
+        ));
+    }
+    let device_name = payload
+        .device_name
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.chars().take(DEVICE_NAME_MAX_CHARS).collect::<String>());
+
+    let link_id = parse_link_id(&payload.link_id)?;
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+    let row = sqlx::query(
+        r#"
+        SELECT user_id, code_hash
+        FROM device_links
+        WHERE id = $1
+          AND claimed_at IS NULL
+          AND cancelled_at IS NULL
+          AND expires_at > now()
+        FOR UPDATE
+        "#,
+    )
+    .bind(link_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    let Some(row) = row else {
+        let (_allowed, _lockout_secs) = state.auth_rate_limiter.record_failure(&ip, None).await;
+        return Err(invalid_link());
+    };
+    let user_id: i32 = row.try_get("user_id").map_err(db_error)?;
+    let code_hash: String = row.try_get("code_hash").unwrap_or_default();
+
+    if hash_refresh_token(&state.jwt_secret, payload.code.trim()) != code_hash {
+        let (_allowed, _lockout_secs) = state.auth_rate_limiter.record_failure(&ip, None).await;
+        sqlx::query(
+            r#"
+            UPDATE device_links
+            SET failed_attempts = failed_attempts + 1,
+                cancelled_at = CASE WHEN failed_attempts + 1 >= $2 THEN now() ELSE cancelled_at END
+            WHERE id = $1
+            "#,
+        )
+        .bind(link_id)
+        .bind(LINK_MAX_ATTEMPTS)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+        tx.commit().await.map_err(db_error)?;
+        return Err(invalid_link());
+    }
+
+    let claim_token = generate_refresh_token();
+    sqlx::query(
+        r#"
+        UPDATE device_links
+        SET claim_token_hash = $2, claimant_pubk = $3, claimant_key_mac = $4,
+            device_name = $5, claimed_at = now()
+        WHERE id = $1
+        "#,
+    )
+    .bind(link_id)
+    .bind(hash_refresh_token(&state.jwt_secret, &claim_token))
+    .bind(public_key)
+    .bind(payload.key_mac.trim())
+    .bind(&device_name)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+
+    // Показавшая QR сессия проверяет key_mac и подтверждает привязку
+    let event = json!({
+        "type": "device_link_claimed",
+        "link_id": link_id.to_string(),
+        "public_key": public_key,
+        "key_mac": payload.key_mac.trim(),
+        "device_name": device_name,
+    })
+    .to_string();
+    publish_payload_to_users(&state, &[user_id], event);
+
+    Ok(Json(DeviceLinkClaimResponse {
+        claim_token,
+        expires_in: LINK_TTL_SECS,
+    }))
+}
+
+async fn complete_link(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<DeviceLinkCompleteRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    let ip = addr.ip().to_string();
+    if !state.auth_rate_limiter.is_allowed(&ip, None).await {
+        return Err(too_many_attempts());
+    }
+
+    let link_id = parse_link_id(&payload.link_id)?;
+    let claim_hash = hash_refresh_token(&state.jwt_secret, payload.claim_token.trim());
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    // Сессия-инициатор должна оставаться активной до выдачи конверта
+    let row = sqlx::query(
+        r#"
+        SELECT l.envelope, l.expires_at, l.claimed_at, l.approved_at, l.completed_at,
+               CASE WHEN s.revoked_at IS NULL THEN l.cancelled_at
+                    ELSE COALESCE(l.cancelled_at, s.revoked_at) END AS cancelled_at,
+               u.id, u.login, u.username, u.nickname, u.avatar, u.pkebymk, u.pkebyrk,
+               u.salt, u.pubk, u.kdf_params
+        FROM device_links l
+        JOIN auth_sessions s ON s.id = l.initiator_session_id
+        JOIN users u ON u.id = l.user_id
+        WHERE l.id = $1 AND l.claim_token_hash = $2
+        FOR UPDATE OF l
+        "#,
+    )
+    .bind(link_id)
+    .bind(claim_hash)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    let Some(row) = row else {
+        let (_allowed, _lockout_secs) = state.auth_rate_limiter.record_failure(&ip, None).await;
+        return Err(invalid_link());
+    };
+
+    match link_status_name(&row) {
+        "approved" => {}
+        "claimed" => {
+            // Инициатор ещё не подтвердил — клиент повторяет запрос позже
+            return Ok((StatusCode::ACCEPTED, Json(json!({ "status": "claimed" }))).into_response());
+        }
+        "expired" => {
+            return Err((StatusCode::GONE, "Срок действия привязки истёк".into()));
+        }
+        _ => {
+            return Err((
+                StatusCode::GONE,
+                "Привязка отменена или уже использована".into(),
+            ));
+        }
+    }
+
+    let envelope: serde_json::Value = row
+        .try_get::<Option<serde_json::Value>, _>("envelope")
+        .ok()
+        .flatten()
+        .ok_or((
+            StatusCode::GONE,
+            "Привязка отменена или уже использована".into(),
+        ))?;
+
+    sqlx::query("UPDATE device_links SET completed_at = now(), envelope = NULL WHERE id = $1")
+        .bind(link_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+
+    let user = user_auth_from_row(&row);
+    let user_id = user.id;
+    let remember_me = payload.remember_me.unwrap_or(false);
+    // Пароль и второй фактор не запрашиваются: вход подтвердила уже авторизованная сессия
+    let login = create_login_session(
+        &state,
+        &headers,
+        addr,
+        user,
+        remember_me,
+        None,
+        LOGIN_METHOD_DEVICE_LINK,
+    )
+    .await?;
+
+    let event = json!({
+        "type": "device_link_completed",
+        "link_id": link_id.to_string(),
+        "session_id": login.session_id,
+    })
+    .to_string();
+    publish_payload_to_users(&state, &[user_id], event);
+
+    Ok(Json(DeviceLinkCompleteResponse { login, envelope }).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // У каждой попытки свой адрес, чтобы не упереться в общий лимитер входа
+    fn addr(n: u8) -> ConnectInfo<SocketAddr> {
+        ConnectInfo(SocketAddr::from(([10, 1, 0, n], 4000)))
+    }
+
+    fn claim(link_id: &str, code: &str) -> Json<DeviceLinkClaimRequest> {
+        Json(DeviceLinkClaimRequest {
+            link_id: link_id.to_string(),
+            code: code.to_string(),
+            public_key: general_purpose::STANDARD.encode([7u8; 32]),
+            key_mac: "mac".to_string(),
+            device_name: Some("Телефон".to_string()),
+        })
+    }
+
+    async fn status(state: &AppState, owner: &CurrentUser, link_id: &str) -> String {
+        link_status(State(state.clone()), *owner, Path(link_id.to_string()))
+            .await
+            .unwrap()
+            .0
+            .status
+    }
+
+    #[tokio::test]
+    async fn link_goes_through_every_status() {
+        let Some(state) = test_support::state().await else {
+            return;
+        };
+        let user_id = test_support::user(&state.pool).await;
+        let owner = test_support::session(&state.pool, user_id).await;
+
+        let link = create_link(State(state.clone()), owner).await.unwrap().0;
+        assert_eq!(status(&state, &owner, &link.link_id).await, "pending");
+
+        let claimed = claim_link(
+            State(state.clone()),
+            addr(1),
+            claim(&link.link_id, &link.code),
+        )
+        .await
+        .unwrap()
+        .0;
+        assert_eq!(status(&state, &owner, &link.link_id).await, "claimed");
+        let complete = || {
+            complete_link(
+                State(state.clone()),
+                addr(2),
+                HeaderMap::new(),
+                Json(DeviceLinkCompleteRequest {
+                    link_id: link.link_id.clone(),
+                    claim_token: claimed.claim_token.clone(),
+                    remember_me: None,
+                }),
+            )
+        };
+        // До подтверждения новое устройство только ждёт
+        assert_eq!(complete().await.unwrap().status(), StatusCode::ACCEPTED);
+
+        // Подтвердить может только сессия, показавшая QR
+        let other = test_support::session(&state.pool, user_id).await;
+        let approve = |current: CurrentUser| {
+            approve_link(
+                State(state.clone()),
+                current,
+                Path(link.link_id.clone()),
+                Json(DeviceLinkApproveRequest {
+                    envelope: json!({ "mac": "m" }),
+                }),
+            )
+        };
+        assert_eq!(approve(other).await.unwrap_err().0, StatusCode::CONFLICT);
+        assert_eq!(approve(owner).await.unwrap(), StatusCode::NO_CONTENT);
+        assert_eq!(status(&state, &owner, &link.link_id).await, "approved");
+
+        assert_eq!(complete().await.unwrap().status(), StatusCode::OK);
+        assert_eq!(status(&state, &owner, &link.link_id).await, "completed");
+        assert_eq!(complete().await.unwrap_err().0, StatusCode::GONE);
+
+        // Новый QR той же сессии отменяет прежний, истёкший показывается как expired
+        let first = create_link(State(state.clone()), owner).await.unwrap().0;
+        let second = create_link(State(state.clone()), owner).await.unwrap().0;
+        assert_eq!(status(&state, &owner, &first.link_id).await, "cancelled");
+        sqlx::query("UPDATE device_links SET expires_at = now() WHERE id = $1")
+            .bind(Uuid::parse_str(&second.link_id).unwrap())
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(status(&state, &owner, &second.link_id).await, "expired");
+        let err = claim_link(
+            State(state.clone()),
+            addr(3),
+            claim(&second.link_id, &second.code),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_cancel_link() {
+        let Some(state) = test_support::state().await else {
+            return;
+        };
+        let user_id = test_support::user(&state.pool).await;
+        let owner = test_support::session(&state.pool, user_id).await;
+        let link = create_link(State(state.clone()), owner).await.unwrap().0;
+
+        for attempt in 0..LINK_MAX_ATTEMPTS {
+            assert_eq!(status(&state, &owner, &link.link_id).await, "pending");
+            let err = claim_link(
+                State(state.clone()),
+                addr(10 + attempt as u8),
+                claim(&link.link_id, "wrong"),
+            )
+            .await
+            .err()
+            .unwrap();
+            assert_eq!(err.0, StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(status(&state, &owner, &link.link_id).await, "cancelled");
+
+        // После отмены не подходит и верный код
+        let err = claim_link(
+            State(state.clone()),
+            addr(100),
+            claim(&link.link_id, &link.code),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.0, StatusCode::UNAUTHORIZED);
+        let err = cancel_link(State(state.clone()), owner, Path(link.link_id.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,345 @@
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, put},
+};
+use base64::{Engine as _, engine::general_purpose};
+use serde_json::{Map, Value, json};
+use sqlx::Row;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::middleware::CurrentUser;
+use crate::models::auth::{DeviceKeyRequest, DeviceKeyResponse};
+use crate::route::security_events::{EVENT_DEVICE_KEY_REGISTERED, log_security_event};
+use crate::route::ws::publish_payload_to_users;
+
+// Ключи устройств для адресации конвертов сообщений:
+// - PUT /users/me/devices/key — зарегистрировать (или заменить) X25519-ключ текущей сессии,
+//   в ответ — id устройства
+// - GET /users/:id/devices    — активные устройства пользователя с их ключами
+//   (только себе и собеседникам по общим чатам)
+//
+// Конверт, адресованный устройству, лежит в messages.envelopes под id устройства
+// (не id сессии): {"<device_id>": {"key", "ephem_pub_key", "iv"}}. Конверты под id
+// пользователя остаются для клиентов, ещё не зарегистрировавших ключ устройства,
+// и отбрасываются, как только ключи есть у всех активных сессий получателя.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/users/me/devices/key", put(register_device_key))
+        .route("/users/:id/devices", get(list_devices))
+}
+
+fn db_error(e: sqlx::Error) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Ошибка БД: {}", e),
+    )
+}
+
+fn decode_b64_len(value: &str, len: usize) -> bool {
+    general_purpose::STANDARD
+        .decode(value.trim())
+        .map(|bytes| bytes.len() == len)
+        .unwrap_or(false)
+}
+
+async fn register_device_key(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    CurrentUser { id, session_id }: CurrentUser,
+    Json(body): Json<DeviceKeyRequest>,
+) -> Result<Json<DeviceKeyResponse>, (StatusCode, String)> {
+    let public_key = body.public_key.trim();
+    if !decode_b64_len(public_key, 32) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Ключ устройства должен быть X25519-ключом (32 байта, Base64)".into(),
+        ));
+    }
+
+    // Id устройства выдаётся при первой регистрации ключа и при замене ключа не меняется
+    let device_id: Option<Uuid> = sqlx::query_scalar(
+        r#"
+        UPDATE auth_sessions
+        SET device_pubk = $3,
+            device_key_registered_at = now(),
+            device_id = COALESCE(device_id, $4)
+        WHERE id = $1
+          AND user_id = $2
+          AND revoked_at IS NULL
+        RETURNING device_id
+        "#,
+    )
+    .bind(session_id)
+    .bind(id)
+    .bind(public_key)
+    .bind(Uuid::new_v4())
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let Some(device_id) = device_id else {
+        return Err((StatusCode::UNAUTHORIZED, "Сессия отозвана".into()));
+    };
+
+    publish_device_keys_changed(&state, id).await;
+    log_security_event(
+        &state,
+        &headers,
+        addr,
+        id,
+        Some(session_id),
+        EVENT_DEVICE_KEY_REGISTERED,
+        None,
+    )
+    .await;
+
+    Ok(Json(DeviceKeyResponse {
+        device_id: device_id.to_string(),
+        public_key: public_key.to_string(),
+    }))
+}
+
+async fn list_devices(
+    State(state): State<AppState>,
+    CurrentUser {
+        id: current_user_id,
+        ..
+    }: CurrentUser,
+    Path(user_id): Path<String>,
+) -> Result<Json<Vec<DeviceKeyResponse>>, (StatusCode, String)> {
+    let user_id: i32 = user_id.parse().map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            "Некорректный ID пользователя".into(),
+        )
+    })?;
+
+    // Ключи нужны только тем, кто может написать пользователю. Для остальных
+    // ответ как для несуществующего пользователя.
+    let shares_chat: bool = sqlx::query_scalar(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM chat_participants mine
+            JOIN chat_participants other ON other.chat_id = mine.chat_id
+            WHERE mine.user_id = $1 AND other.user_id = $2
+        )
+        "#,
+    )
+    .bind(current_user_id)
+    .bind(user_id)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(db_error)?;
+    if user_id != current_user_id && !shares_chat {
+        return Err((StatusCode::NOT_FOUND, "Пользователь не найден".into()));
+    }
+
+    let rows = sqlx::query(
+        r#"
+        SELECT device_id, device_pubk
+        FROM auth_sessions
+        WHERE user_id = $1
+          AND device_pubk IS NOT NULL
+          AND device_id IS NOT NULL
+          AND revoked_at IS NULL
+          AND expires_at > now()
+        ORDER BY device_key_registered_at ASC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let devices = rows
+        .into_iter()
+        .map(|row| DeviceKeyResponse {
+            device_id: row
+                .try_get::<Uuid, _>("device_id")
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+            public_key: row.try_get("device_pubk").unwrap_or_default(),
+        })
+        .collect();
+
+    Ok(Json(devices))
+}
+
+// Сообщает пользователю и его собеседникам, что список устройств изменился:
+// клиенты перечитывают GET /users/:id/devices перед следующей отправкой.
+pub(crate) async fn publish_device_keys_changed(state: &AppState, user_id: i32) {
+    let peers: Vec<i32> = sqlx::query_scalar(
+        r#"
+        SELECT DISTINCT other.user_id
+        FROM chat_participants mine
+        JOIN chat_participants other ON other.chat_id = mine.chat_id
+        WHERE mine.user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&state.pool)
+    .await
+    .unwrap_or_default();
+
+    let mut recipients = peers;
+    recipients.push(user_id);
+    let payload = json!({
+        "type": "device_keys_changed",
+        "user_id": user_id,
+    })
+    .to_string();
+    publish_payload_to_users(state, &recipients, payload);
+}
+
+// Id устройств, которым адресованы конверты (ключи-UUID)
+fn device_ids(envelopes: &Map<String, Value>) -> Vec<Uuid> {
+    envelopes
+        .keys()
+        .filter_map(|k| Uuid::parse_str(k).ok())
+        .collect()
+}
+
+// Оставляет конверты только активных устройств (`active`). Конверт под id
+// пользователя убирается, если все активные сессии этого пользователя уже
+// зарегистрировали ключи (`device_keyed`): иначе его расшифрует и отозванное
+// устройство, у которого остался ключ пользователя.
+fn retain_device_envelopes(
+    envelopes: &mut Map<String, Value>,
+    active: &HashSet<Uuid>,
+    device_keyed: &HashSet<i32>,
+) {
+    envelopes.retain(|k, _| {
+        if let Ok(device_id) = Uuid::parse_str(k) {
+            return active.contains(&device_id);
+        }
+        match k.parse::<i32>() {
+            Ok(user_id) => !device_keyed.contains(&user_id),
+            Err(_) => true,
+        }
+    });
+}
+
+/// Оставляет в конвертах сообщения только активные устройства участников чата:
+/// отозванные и чужие сессии новых ключей не получают. Конверты под id
+/// пользователя остаются лишь для тех участников, у кого есть сессии без ключа
+/// устройства.
+pub(crate) async fn filter_device_envelopes(
+    state: &AppState,
+    chat_id: i32,
+    envelopes: Option<Value>,
+) -> Result<Option<Value>, (StatusCode, String)> {
+    let Some(Value::Object(mut map)) = envelopes else {
+        return Ok(envelopes);
+    };
+
+    let ids = device_ids(&map);
+    let active: Vec<Uuid> = if ids.is_empty() {
+        Vec::new()
+    } else {
+        sqlx::query_scalar(
+            r#"
+            SELECT s.device_id
+            FROM auth_sessions s
+            JOIN chat_participants p ON p.user_id = s.user_id AND p.chat_id = $1
+            WHERE s.device_id = ANY($2)
+              AND s.device_pubk IS NOT NULL
+              AND s.revoked_at IS NULL
+              AND s.expires_at > now()
+            "#,
+        )
+        .bind(chat_id)
+        .bind(&ids)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(db_error)?
+    };
+
+    let device_keyed: Vec<i32> = sqlx::query_scalar(
+        r#"
+        SELECT p.user_id
+        FROM chat_participants p
+        JOIN auth_sessions s
+          ON s.user_id = p.user_id
+         AND s.revoked_at IS NULL
+         AND s.expires_at > now()
+        WHERE p.chat_id = $1
+        GROUP BY p.user_id
+        HAVING bool_and(s.device_pubk IS NOT NULL AND s.device_id IS NOT NULL)
+        "#,
+    )
+    .bind(chat_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    retain_device_envelopes(
+        &mut map,
+        &active.into_iter().collect(),
+        &device_keyed.into_iter().collect(),
+    );
+    Ok(Some(Value::Object(map)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_inactive_devices_and_keeps_user_keyed_envelopes() {
+        let active_id = Uuid::new_v4();
+        let revoked_id = Uuid::new_v4();
+        let mut envelopes = json!({
+            "7": {"key": "legacy"},
+            active_id.to_string(): {"key": "a"},
+            revoked_id.to_string(): {"key": "r"},
+        })
+        .as_object()
+        .cloned()
+        .unwrap();
+
+        assert_eq!(device_ids(&envelopes).len(), 2);
+
+        // У пользователя 7 ещё есть сессия без ключа устройства
+        let active = HashSet::from([active_id]);
+        retain_device_envelopes(&mut envelopes, &active, &HashSet::new());
+
+        assert!(envelopes.contains_key("7"));
+        assert!(envelopes.contains_key(&active_id.to_string()));
+        assert!(!envelopes.contains_key(&revoked_id.to_string()));
+    }
+
+    #[test]
+    fn revoked_device_gets_nothing_once_all_devices_have_keys() {
+        let active_id = Uuid::new_v4();
+        let revoked_id = Uuid::new_v4();
+        let mut envelopes = json!({
+            "7": {"key": "legacy"},
+            "8": {"key": "legacy-peer"},
+            active_id.to_string(): {"key": "a"},
+            revoked_id.to_string(): {"key": "r"},
+        })
+        .as_object()
+        .cloned()
+        .unwrap();
+
+        retain_device_envelopes(
+            &mut envelopes,
+            &HashSet::from([active_id]),
+            &HashSet::from([7]),
+        );
+
+        // Ни конверта отозванного устройства, ни конверта под ключ пользователя,
+        // который у отозванного устройства остался
+        assert!(!envelopes.contains_key(&revoked_id.to_string()));
+        assert!(!envelopes.contains_key("7"));
+        assert!(envelopes.contains_key(&active_id.to_string()));
+        assert!(envelopes.contains_key("8"));
+    }
+}
@@ -0,0 +1,229 @@
+use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+use serde_json::{Value, json};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::{AppState, MIGRATOR};
+
+// Проверки состояния для балансировщика и оркестратора:
+// - GET /health       — статический "OK" (для старых проверок)
+// - GET /health/live  — процесс жив и обслуживает запросы; от БД не зависит
+// - GET /health/ready — готов принимать трафик: БД, миграции, хранилище, место на диске.
+//   Во время плавной остановки отвечает 503, чтобы upstream nginx снял инстанс.
+//
+// Ответ ready — 200 или 503 с разбивкой по проверкам:
+// {"status": "ready" | "not_ready", "checks": {"database": "ok" | "fail", ...}}
+// Адрес публичный, поэтому подробности (ошибки БД, пул, пути) только в логе.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/health", get(|| async { "OK" }))
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+}
+
+// Проверка, не уложившаяся в этот срок, считается проваленной
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+async fn live() -> Json<Value> {
+    Json(json!({ "status": "alive" }))
+}
+
+async fn ready(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    let (database, migrations, storage, disk) = tokio::join!(
+        with_timeout(check_database(&state)),
+        with_timeout(check_migrations(&state)),
+        with_timeout(check_storage(&state)),
+        with_timeout(check_disk(&state)),
+    );
+    let shutdown = if state.shutdown.is_draining() {
+        (false, json!({ "reason": "shutting_down" }))
+    } else {
+        (true, json!({}))
+    };
+
+    let checks = [
+        ("database", database),
+        ("migrations", migrations),
+        ("storage", storage),
+        ("disk", disk),
+        ("shutdown", shutdown),
+    ];
+    let ready = checks.iter().all(|(_, (ok, _))| *ok);
+    let checks: serde_json::Map<String, Value> = checks
+        .into_iter()
+        .map(|(name, (ok, details))| {
+            if !ok {
+                tracing::warn!(check = name, details = %details, "Проверка готовности не прошла");
+            }
+            (name.to_string(), json!(if ok { "ok" } else { "fail" }))
+        })
+        .collect();
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "status": if ready { "ready" } else { "not_ready" },
+            "checks": checks,
+        })),
+    )
+}
+
+// Результат проверки: прошла ли она и подробности для лога
+type CheckResult = (bool, Value);
+
+async fn with_timeout(check: impl Future<Output = CheckResult>) -> CheckResult {
+    tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| (false, json!({ "error": "timeout" })))
+}
+
+async fn check_database(state: &AppState) -> CheckResult {
+    let started = Instant::now();
+    let result = sqlx::query_scalar::<_, i32>("SELECT 1")
+        .fetch_one(&state.pool)
+        .await;
+    let pool = json!({
+        "size": state.pool.size(),
+        "idle": state.pool.num_idle(),
+    });
+    match result {
+        Ok(_) => (
+            true,
+            json!({
+                "latency_ms": started.elapsed().as_millis() as u64,
+                "pool": pool,
+            }),
+        ),
+        Err(e) => (false, json!({ "error": e.to_string(), "pool": pool })),
+    }
+}
+
+async fn check_migrations(state: &AppState) -> CheckResult {
+    let applied: Vec<i64> =
+        match sqlx::query_scalar("SELECT version FROM _sqlx_migrations WHERE success")
+            .fetch_all(&state.pool)
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => return (false, json!({ "error": e.to_string() })),
+        };
+    let expected = MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .map(|m| m.version);
+    let pending = pending_versions(expected, applied.iter().copied());
+    (
+        pending.is_empty(),
+        json!({
+            "applied": applied.len(),
+            "pending": pending,
+        }),
+    )
+}
+
+// Версии миграций сборки, которых нет среди применённых в БД
+fn pending_versions(
+    expected: impl IntoIterator<Item = i64>,
+    applied: impl IntoIterator<Item = i64>,
+) -> Vec<i64> {
+    let applied: BTreeSet<i64> = applied.into_iter().collect();
+    let mut pending: Vec<i64> = expected
+        .into_iter()
+        .filter(|v| !applied.contains(v))
+        .collect();
+    pending.sort_unstable();
+    pending
+}
+
+// Каталог загрузок доступен на запись: создаём и удаляем пробный файл
+async fn check_storage(state: &AppState) -> CheckResult {
+    let dir = &state.config.storage.uploads_dir;
+    let probe = dir.join(format!(".health-{}", Uuid::new_v4()));
+    let result = async {
+        fs::create_dir_all(dir).await?;
+        fs::write(&probe, b"ok").await?;
+        fs::remove_file(&probe).await
+    }
+    .await;
+    match result {
+        Ok(()) => (true, json!({ "path": dir })),
+        Err(e) => {
+            let _ = fs::remove_file(&probe).await;
+            (false, json!({ "path": dir, "error": e.to_string() }))
+        }
+    }
+}
+
+async fn check_disk(state: &AppState) -> CheckResult {
+    // Каталог может быть ещё не создан: берём ближайший существующий родитель
+    let dir = state
+        .config
+        .storage
+        .uploads_dir
+        .ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .unwrap_or(Path::new("."))
+        .to_path_buf();
+    let min_free_bytes = state.config.storage.min_free_bytes;
+    let free = tokio::task::spawn_blocking(move || available_space(&dir)).await;
+    match free {
+        Ok(Ok(free_bytes)) => (
+            free_bytes >= min_free_bytes,
+            json!({
+                "free_bytes": free_bytes,
+                "min_free_bytes": min_free_bytes,
+            }),
+        ),
+        Ok(Err(e)) => (false, json!({ "error": e.to_string() })),
+        Err(e) => (false, json!({ "error": e.to_string() })),
+    }
+}
+
+// Свободное для непривилегированного процесса место на файловой системе каталога
+#[cfg(unix)]
+fn available_space(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: path — валидная C-строка, stat — выделенная структура нужного типа
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "проверка свободного места поддерживается только на unix",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_missing_migrations_in_order() {
+        assert_eq!(pending_versions([3, 1, 2], [1, 5]), vec![2, 3]);
+        assert!(pending_versions([1, 2], [2, 1]).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn measures_free_space_of_existing_dir() {
+        assert!(available_space(Path::new(".")).unwrap() > 0);
+        assert!(available_space(Path::new("./no-such-dir")).is_err());
+    }
+}
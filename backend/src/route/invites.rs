@@ -0,0 +1,339 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{delete, get},
+};
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::{PgPool, Row};
+
+use crate::AppState;
+use crate::config::RegistrationConfig;
+use crate::middleware::CurrentUser;
+use crate::route::admin::SERVER_ROLE_ADMIN;
+use crate::route::auth::hash_refresh_token;
+
+// Режим регистрации (registration.mode) и приглашения:
+// - GET    /auth/registration — режим регистрации для клиента (без авторизации)
+// - POST   /invites           — создать приглашение; код возвращается один раз
+// - GET    /invites           — мои приглашения (без кодов)
+// - DELETE /invites/:id       — отозвать приглашение (своё; администратор — любое)
+//
+// Код передаётся в /auth/register полем invite_code. Приглашения создают
+// администраторы сервера, а при registration.user_invites — все пользователи.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/auth/registration", get(registration_info))
+        .route("/invites", get(list_invites).post(create_invite))
+        .route("/invites/:id", delete(revoke_invite))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationMode {
+    // Любой может зарегистрироваться
+    #[default]
+    Open,
+    // Регистрация отключена
+    Closed,
+    // Только с действующим кодом приглашения
+    Invite,
+    // Аккаунт активирует администратор; с приглашением — сразу
+    Approval,
+}
+
+impl RegistrationMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "open" => Some(RegistrationMode::Open),
+            "closed" => Some(RegistrationMode::Closed),
+            "invite" => Some(RegistrationMode::Invite),
+            "approval" => Some(RegistrationMode::Approval),
+            _ => None,
+        }
+    }
+}
+
+// Приглашение, использованное при регистрации
+pub(crate) struct AcceptedInvite {
+    pub id: i64,
+    pub created_by: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct CreateInviteRequest {
+    max_uses: Option<u32>,
+    expires_in_days: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct InviteResponse {
+    id: i64,
+    max_uses: i32,
+    uses: i32,
+    expires_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+    revoked_at: Option<DateTime<Utc>>,
+    // Зарегистрированные по приглашению
+    invited_users: Vec<i32>,
+}
+
+#[derive(Serialize)]
+struct CreatedInviteResponse {
+    id: i64,
+    code: String,
+    max_uses: u32,
+    expires_at: DateTime<Utc>,
+}
+
+// Срок приглашения по умолчанию, дни (не больше registration.max_invite_ttl_days)
+const DEFAULT_INVITE_TTL_DAYS: u32 = 7;
+const INVITE_CODE_BYTES: usize = 16;
+
+fn db_error(e: sqlx::Error) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Ошибка БД: {}", e),
+    )
+}
+
+async fn registration_info(State(state): State<AppState>) -> Json<Value> {
+    let registration = &state.config.registration;
+    Json(json!({
+        "mode": registration.mode,
+        "user_invites": registration.user_invites,
+    }))
+}
+
+async fn create_invite(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    body: Option<Json<CreateInviteRequest>>,
+) -> Result<(StatusCode, Json<CreatedInviteResponse>), (StatusCode, String)> {
+    let registration = &state.config.registration;
+    if registration.mode == RegistrationMode::Closed {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Регистрация на сервере закрыта".into(),
+        ));
+    }
+    if !registration.user_invites && !is_server_admin(&state.pool, user_id).await? {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Приглашения создают только администраторы сервера".into(),
+        ));
+    }
+
+    let (max_uses, ttl_days) = invite_limits(
+        registration,
+        body.as_ref().and_then(|b| b.max_uses),
+        body.as_ref().and_then(|b| b.expires_in_days),
+    )
+    .map_err(|message| (StatusCode::BAD_REQUEST, message))?;
+
+    let (id, code, expires_at) = create_invite_code(
+        &state.pool,
+        &state.jwt_secret,
+        Some(user_id),
+        max_uses,
+        ttl_days,
+    )
+    .await
+    .map_err(db_error)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedInviteResponse {
+            id,
+            code,
+            max_uses,
+            expires_at,
+        }),
+    ))
+}
+
+async fn list_invites(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+) -> Result<Json<Vec<InviteResponse>>, (StatusCode, String)> {
+    let rows = sqlx::query(
+        r#"
+        SELECT
+            i.id, i.max_uses, i.uses, i.expires_at, i.created_at, i.revoked_at,
+            COALESCE(
+                (SELECT array_agg(u.id ORDER BY u.id) FROM users u WHERE u.invite_code_id = i.id),
+                '{}'
+            ) AS invited_users
+        FROM invite_codes i
+        WHERE i.created_by = $1
+        ORDER BY i.id DESC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let invites = rows
+        .iter()
+        .map(|row| InviteResponse {
+            id: row.try_get("id").unwrap_or_default(),
+            max_uses: row.try_get("max_uses").unwrap_or_default(),
+            uses: row.try_get("uses").unwrap_or_default(),
+            expires_at: row
+                .try_get::<DateTime<Utc>, _>("expires_at")
+                .unwrap_or_else(|_| Utc::now()),
+            created_at: row
+                .try_get::<DateTime<Utc>, _>("created_at")
+                .unwrap_or_else(|_| Utc::now()),
+            revoked_at: row.try_get("revoked_at").ok().flatten(),
+            invited_users: row.try_get("invited_users").unwrap_or_default(),
+        })
+        .collect();
+    Ok(Json(invites))
+}
+
+async fn revoke_invite(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    Path(invite_id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let is_admin = is_server_admin(&state.pool, user_id).await?;
+    let result = sqlx::query(
+        r#"
+        UPDATE invite_codes
+        SET revoked_at = COALESCE(revoked_at, now())
+        WHERE id = $1
+          AND (created_by = $2 OR $3)
+        "#,
+    )
+    .bind(invite_id)
+    .bind(user_id)
+    .bind(is_admin)
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "Приглашение не найдено".into()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn is_server_admin(pool: &PgPool, user_id: i32) -> Result<bool, (StatusCode, String)> {
+    let role: Option<String> = sqlx::query_scalar("SELECT server_role FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?;
+    Ok(role.as_deref() == Some(SERVER_ROLE_ADMIN))
+}
+
+// Число использований и срок приглашения с учётом пределов конфигурации
+pub(crate) fn invite_limits(
+    registration: &RegistrationConfig,
+    max_uses: Option<u32>,
+    ttl_days: Option<u32>,
+) -> Result<(u32, u32), String> {
+    let max_uses = max_uses.unwrap_or(1);
+    if max_uses == 0 || max_uses > registration.max_invite_uses {
+        return Err(format!(
+            "max_uses должно быть от 1 до {}",
+            registration.max_invite_uses
+        ));
+    }
+    let ttl_days =
+        ttl_days.unwrap_or(DEFAULT_INVITE_TTL_DAYS.min(registration.max_invite_ttl_days));
+    if ttl_days == 0 || ttl_days > registration.max_invite_ttl_days {
+        return Err(format!(
+            "expires_in_days должно быть от 1 до {}",
+            registration.max_invite_ttl_days
+        ));
+    }
+    Ok((max_uses, ttl_days))
+}
+
+// Создаёт приглашение и возвращает (id, код, срок действия).
+// created_by None — приглашение из CLI
+pub(crate) async fn create_invite_code(
+    pool: &PgPool,
+    secret: &str,
+    created_by: Option<i32>,
+    max_uses: u32,
+    ttl_days: u32,
+) -> Result<(i64, String, DateTime<Utc>), sqlx::Error> {
+    let mut code_bytes = [0u8; INVITE_CODE_BYTES];
+    OsRng.fill_bytes(&mut code_bytes);
+    let code = general_purpose::URL_SAFE_NO_PAD.encode(code_bytes);
+    let expires_at = Utc::now() + ChronoDuration::days(ttl_days as i64);
+
+    let id: i64 = sqlx::query_scalar(
+        r#"
+        INSERT INTO invite_codes (code_hash, created_by, max_uses, expires_at)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id
+        "#,
+    )
+    .bind(hash_refresh_token(secret, &code))
+    .bind(created_by)
+    .bind(max_uses as i32)
+    .bind(expires_at)
+    .fetch_one(pool)
+    .await?;
+    Ok((id, code, expires_at))
+}
+
+// Засчитывает использование приглашения в транзакции регистрации: если
+// регистрация не удастся, использование откатится. None — код недействителен,
+// отозван, истёк или исчерпан.
+pub(crate) async fn consume_invite(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    secret: &str,
+    code: &str,
+) -> Result<Option<AcceptedInvite>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        UPDATE invite_codes
+        SET uses = uses + 1
+        WHERE code_hash = $1
+          AND revoked_at IS NULL
+          AND expires_at > now()
+          AND uses < max_uses
+        RETURNING id, created_by
+        "#,
+    )
+    .bind(hash_refresh_token(secret, code.trim()))
+    .fetch_optional(&mut **tx)
+    .await?;
+    Ok(row.map(|row| AcceptedInvite {
+        id: row.try_get("id").unwrap_or_default(),
+        created_by: row.try_get("created_by").ok().flatten(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invite_limits_respect_config() {
+        let registration = RegistrationConfig {
+            max_invite_uses: 5,
+            max_invite_ttl_days: 3,
+            ..RegistrationConfig::default()
+        };
+        assert_eq!(invite_limits(&registration, None, None), Ok((1, 3)));
+        assert_eq!(invite_limits(&registration, Some(5), Some(1)), Ok((5, 1)));
+        assert!(invite_limits(&registration, Some(0), None).is_err());
+        assert!(invite_limits(&registration, Some(6), None).is_err());
+        assert!(invite_limits(&registration, None, Some(4)).is_err());
+        assert_eq!(
+            RegistrationMode::parse(" Invite "),
+            Some(RegistrationMode::Invite)
+        );
+        assert_eq!(RegistrationMode::parse("invites"), None);
+    }
+}
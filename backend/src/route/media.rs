@@ -12,6 +12,7 @@ use bytes::Bytes;
 use futures_util::TryStreamExt;
 use serde::Serialize;
 use sqlx::Row;
+use std::path::PathBuf;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 use tokio_util::io::ReaderStream;
@@ -28,11 +29,33 @@ struct UploadMediaResponse {
     size: i64,
 }
 
-pub fn router() -> Router<AppState> {
+// max_upload_bytes — storage.max_upload_bytes из конфигурации
+pub fn router(max_upload_bytes: usize) -> Router<AppState> {
     Router::new()
         .route("/media", post(upload_media))
         .route("/media/:id", get(download_media))
-        .layer(DefaultBodyLimit::max(50 * 1024 * 1024))
+        .layer(DefaultBodyLimit::max(max_upload_bytes))
+}
+
+// Файл загрузки, ещё не записанный в media_files. Удаляется при любом выходе из
+// обработчика до commit: ошибка, обрыв соединения клиента или остановка сервера.
+#[derive(Default)]
+struct PartialUpload {
+    path: Option<PathBuf>,
+}
+
+impl PartialUpload {
+    fn commit(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for PartialUpload {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
 }
 
 async fn upload_media(
@@ -45,7 +68,7 @@ async fn upload_media(
     let mut chat_id: Option<i32> = None;
 
     // Stream file straight to disk to avoid buffering large ciphertext in RAM.
-    let mut tmp_full_path: Option<String> = None;
+    let mut partial = PartialUpload::default();
     let mut rel_path: Option<String> = None;
     let mut written: i64 = 0;
 
@@ -58,7 +81,7 @@ async fn upload_media(
         let name = field.name().unwrap_or("").to_string();
         match name.as_str() {
             "file" => {
-                if tmp_full_path.is_some() {
+                if partial.path.is_some() {
                     continue;
                 }
 
@@ -69,7 +92,8 @@ async fn upload_media(
                     mimetype = field.content_type().map(|s| s.to_string());
                 }
 
-                fs::create_dir_all("uploads/media").await.map_err(|e| {
+                let media_dir = state.config.storage.path("media");
+                fs::create_dir_all(&media_dir).await.map_err(|e| {
                     (
                         StatusCode::INTERNAL_SERVER_ERROR,
                         format!("Не удалось создать директорию: {}", e),
@@ -78,9 +102,8 @@ async fn upload_media(
 
                 let uuid = Uuid::new_v4().to_string();
                 let rp = format!("media/{}_{}", user_id, uuid);
-                let fp = format!("uploads/{}", &rp);
+                let fp = state.config.storage.path(&rp);
                 rel_path = Some(rp);
-                tmp_full_path = Some(fp.clone());
 
                 let mut f = fs::File::create(&fp).await.map_err(|e| {
                     (
@@ -88,8 +111,9 @@ async fn upload_media(
                         format!("Не удалось создать файл: {}", e),
                     )
                 })?;
+                partial.path = Some(fp);
 
-                const MAX_BYTES: i64 = 50 * 1024 * 1024;
+                let max_bytes = state.config.storage.max_upload_bytes as i64;
 
                 let mut stream = field;
                 while let Some(chunk) = stream.chunk().await.map_err(|e| {
@@ -102,8 +126,7 @@ async fn upload_media(
                         continue;
                     }
                     written += chunk.len() as i64;
-                    if written > MAX_BYTES {
-                        let _ = fs::remove_file(&fp).await;
+                    if written > max_bytes {
                         return Err((StatusCode::BAD_REQUEST, "Слишком большой файл".into()));
                     }
                     f.write_all(&chunk).await.map_err(|e| {
@@ -142,17 +165,9 @@ async fn upload_media(
     }
 
     let chat_id = chat_id.ok_or((StatusCode::BAD_REQUEST, "chat_id обязателен".into()))?;
-    if let Err((sc, msg)) = ensure_member(&state, chat_id, user_id).await {
-        if let Some(fp) = tmp_full_path.as_ref() {
-            let _ = fs::remove_file(fp).await;
-        }
-        return Err((sc, msg));
-    }
+    ensure_member(&state, chat_id, user_id).await?;
 
-    if tmp_full_path.is_none() || rel_path.is_none() || written <= 0 {
-        if let Some(fp) = tmp_full_path.as_ref() {
-            let _ = fs::remove_file(fp).await;
-        }
+    if partial.path.is_none() || rel_path.is_none() || written <= 0 {
         return Err((StatusCode::BAD_REQUEST, "file обязателен".into()));
     }
 
@@ -183,7 +198,14 @@ async fn upload_media(
         )
     })?;
 
+    partial.commit();
+
     let file_id: i64 = row.try_get("id").unwrap_or_default();
+    state
+        .metrics
+        .upload_bytes
+        .with_label_values(&["media"])
+        .inc_by(written as u64);
 
     Ok(Json(UploadMediaResponse {
         file_id,
@@ -233,7 +255,7 @@ async fn download_media(
         ensure_member(&state, chat_id, user_id).await?;
     }
 
-    let full_path = format!("uploads/{}", rel_path);
+    let full_path = state.config.storage.path(&rel_path);
 
     let meta = fs::metadata(&full_path)
         .await
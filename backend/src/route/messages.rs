@@ -0,0 +1,1127 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::Row;
+use sqlx::postgres::PgRow;
+use std::collections::{HashMap, HashSet};
+
+use crate::AppState;
+use crate::middleware::{CurrentUser, delete_for_everyone_denial};
+use crate::middleware::{ensure_can_send_message, ensure_member};
+use crate::models::chats::{BatchItemResult, FileMetadata, ForwardItem, MediaItem, Message};
+use crate::route::chats::load_chat_recipients;
+use crate::route::devices::filter_device_envelopes;
+use crate::route::ws::{publish_messages_deleted, publish_messages_forwarded};
+
+// Максимальное число сообщений в одной пакетной операции
+pub const MAX_BATCH_SIZE: usize = 100;
+
+// Ограничения слепого индекса: токенов на сообщение, токенов в запросе, длина токена
+const MAX_TOKENS_PER_MESSAGE: usize = 256;
+const MAX_QUERY_TOKENS: usize = 16;
+const MAX_TOKEN_LEN: usize = 64;
+
+// Категории медиа-галереи чата
+const MEDIA_KINDS: [&str; 4] = ["image", "video", "file", "voice"];
+
+// ---------------------------
+// Конструктор роутера
+// ---------------------------
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/chats/:chat_id/messages/delete", post(delete_messages))
+        .route("/chats/:chat_id/messages/forward", post(forward_messages))
+        .route("/chats/:chat_id/search", get(search_messages))
+        .route("/chats/:chat_id/search/index", post(index_messages))
+        .route("/chats/:chat_id/media", get(get_chat_media))
+}
+
+#[derive(Deserialize)]
+struct DeleteMessagesRequest {
+    message_ids: Vec<i64>,
+    scope: Option<String>, // 'everyone' (по умолчанию) | 'me'
+}
+
+#[derive(Deserialize)]
+struct ForwardMessagesRequest {
+    from_chat_id: i32,
+    items: Vec<ForwardItem>,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    tokens: Option<String>, // токены слепого индекса через запятую (все должны совпасть)
+    message_type: Option<String>,
+    has_files: Option<bool>,
+    from: Option<String>, // RFC3339, включительно
+    to: Option<String>,   // RFC3339, не включительно
+    before_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    message_ids: Vec<i64>,
+    next_before_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct MediaQuery {
+    #[serde(rename = "type")]
+    kind: Option<String>, // 'image' | 'video' | 'file' | 'voice'
+    before_id: Option<i64>,
+    limit: Option<i64>, // число сообщений на странице
+}
+
+#[derive(Serialize)]
+struct MediaResponse {
+    items: Vec<MediaItem>,
+    next_before_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct IndexItem {
+    message_id: i64,
+    tokens: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct IndexMessagesRequest {
+    items: Vec<IndexItem>,
+}
+
+fn db_error(e: sqlx::Error) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Ошибка БД: {}", e),
+    )
+}
+
+fn item_error(message_id: i64, error: &str) -> BatchItemResult {
+    BatchItemResult {
+        message_id,
+        ok: false,
+        new_message_id: None,
+        error: Some(error.to_string()),
+    }
+}
+
+fn item_ok(message_id: i64, new_message_id: Option<i64>) -> BatchItemResult {
+    BatchItemResult {
+        message_id,
+        ok: true,
+        new_message_id,
+        error: None,
+    }
+}
+
+fn ensure_batch_size(len: usize) -> Result<(), (StatusCode, String)> {
+    if len == 0 {
+        return Err((StatusCode::BAD_REQUEST, "Список сообщений пуст".into()));
+    }
+    if len > MAX_BATCH_SIZE {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Не более {} сообщений за один запрос", MAX_BATCH_SIZE),
+        ));
+    }
+    Ok(())
+}
+
+fn message_from_row(row: &PgRow) -> Message {
+    let metadata_value: Option<Value> = row.try_get("metadata").ok().flatten();
+    let metadata_vec: Option<Vec<FileMetadata>> =
+        metadata_value.and_then(|v| serde_json::from_value(v).ok());
+    let has_files = metadata_vec.as_ref().map(|m| !m.is_empty());
+
+    Message {
+        id: row.try_get("id").unwrap_or_default(),
+        chat_id: row.try_get("chat_id").unwrap_or_default(),
+        sender_id: row.try_get("sender_id").unwrap_or_default(),
+        message: row.try_get("message").unwrap_or_default(),
+        message_type: row
+            .try_get("message_type")
+            .unwrap_or_else(|_| "text".to_string()),
+        created_at: row
+            .try_get::<chrono::DateTime<chrono::Utc>, _>("created_at")
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default(),
+        edited_at: row
+            .try_get::<chrono::DateTime<chrono::Utc>, _>("edited_at")
+            .ok()
+            .map(|t| t.to_rfc3339()),
+        reply_to_message_id: row.try_get("reply_to_message_id").ok(),
+        forwarded_from_message_id: row.try_get("forwarded_from_message_id").ok(),
+        forwarded_from_chat_id: row.try_get("forwarded_from_chat_id").ok(),
+        forwarded_from_sender_id: row.try_get("forwarded_from_sender_id").ok(),
+        deleted_at: row
+            .try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at")
+            .ok()
+            .map(|t| t.to_rfc3339()),
+        deleted_by: row.try_get("deleted_by").ok(),
+        is_read: row.try_get("is_read").unwrap_or(false),
+        is_delivered: row.try_get("is_delivered").unwrap_or(false),
+        has_files,
+        metadata: metadata_vec,
+        envelopes: row.try_get("envelopes").ok().flatten(),
+        status: Some("sent".to_string()),
+    }
+}
+
+// Пакетное удаление сообщений одного чата (общая логика для HTTP и WS).
+// Права участника проверяются один раз, все изменения — в одной транзакции.
+// Недопустимые сообщения не прерывают пакет, а попадают в результат с ошибкой.
+pub async fn delete_messages_batch(
+    state: &AppState,
+    chat_id: i32,
+    user_id: i32,
+    message_ids: &[i64],
+    scope: &str,
+) -> Result<Vec<BatchItemResult>, (StatusCode, String)> {
+    if scope != "everyone" && scope != "me" {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Некорректный scope (ожидается everyone или me)".into(),
+        ));
+    }
+
+    let mut seen = HashSet::<i64>::new();
+    let ids: Vec<i64> = message_ids
+        .iter()
+        .copied()
+        .filter(|id| seen.insert(*id))
+        .collect();
+    ensure_batch_size(ids.len())?;
+
+    let member = sqlx::query(
+        r#"
+        SELECT c.kind, COALESCE(cp.role, 'member') AS role
+        FROM chats c
+        JOIN chat_participants cp ON cp.chat_id = c.id
+        WHERE c.id = $1 AND cp.user_id = $2
+        "#,
+    )
+    .bind(chat_id)
+    .bind(user_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let Some(member) = member else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Нет доступа: вы не являетесь участником чата".into(),
+        ));
+    };
+    let kind: String = member.try_get("kind").unwrap_or_default();
+    let actor_role: String = member
+        .try_get("role")
+        .unwrap_or_else(|_| "member".to_string());
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT
+            m.id::INT8 AS id,
+            m.sender_id,
+            m.created_at,
+            COALESCE(sp.role, 'member') AS sender_role
+        FROM messages m
+        LEFT JOIN chat_participants sp ON sp.chat_id = m.chat_id AND sp.user_id = m.sender_id
+        WHERE m.chat_id = $1
+          AND m.id::INT8 = ANY($2)
+          AND m.deleted_at IS NULL
+        FOR UPDATE OF m
+        "#,
+    )
+    .bind(chat_id)
+    .bind(&ids)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    let now = chrono::Utc::now();
+    let mut denials = HashMap::<i64, String>::new();
+    let mut allowed = Vec::<i64>::new();
+    for row in &rows {
+        let id: i64 = row.try_get("id").unwrap_or_default();
+        if scope == "me" {
+            allowed.push(id);
+            continue;
+        }
+        let sender_id: i32 = row.try_get("sender_id").unwrap_or_default();
+        let sender_role: String = row
+            .try_get("sender_role")
+            .unwrap_or_else(|_| "member".to_string());
+        let created_at: chrono::DateTime<chrono::Utc> = row.try_get("created_at").unwrap_or(now);
+        match delete_for_everyone_denial(
+            &kind,
+            &actor_role,
+            &sender_role,
+            sender_id == user_id,
+            now - created_at,
+        ) {
+            Some(err) => {
+                denials.insert(id, err);
+            }
+            None => allowed.push(id),
+        }
+    }
+
+    let mut deleted_at = now.to_rfc3339();
+    if !allowed.is_empty() {
+        if scope == "me" {
+            sqlx::query(
+                r#"
+                INSERT INTO message_hidden (user_id, message_id)
+                SELECT $1, UNNEST($2::INT8[])::INT4
+                ON CONFLICT (user_id, message_id) DO NOTHING
+                "#,
+            )
+            .bind(user_id)
+            .bind(&allowed)
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+        } else {
+            // Как и при одиночном удалении — вычищаем содержимое на сервере.
+            let ts: Option<chrono::DateTime<chrono::Utc>> = sqlx::query_scalar(
+                r#"
+                UPDATE messages
+                SET deleted_at = now(),
+                    deleted_by = $3,
+                    message = NULL,
+                    body = NULL,
+                    envelopes = NULL,
+                    metadata = NULL
+                WHERE chat_id = $1 AND id::INT8 = ANY($2)
+                RETURNING deleted_at
+                "#,
+            )
+            .bind(chat_id)
+            .bind(&allowed)
+            .bind(user_id)
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(db_error)?
+            .into_iter()
+            .next();
+            if let Some(ts) = ts {
+                deleted_at = ts.to_rfc3339();
+            }
+
+            sqlx::query("DELETE FROM message_search_tokens WHERE message_id::INT8 = ANY($1)")
+                .bind(&allowed)
+                .execute(&mut *tx)
+                .await
+                .map_err(db_error)?;
+        }
+    }
+
+    tx.commit().await.map_err(db_error)?;
+
+    if !allowed.is_empty() {
+        let recipients = if scope == "me" {
+            vec![user_id]
+        } else {
+            load_chat_recipients(state, chat_id).await?
+        };
+        publish_messages_deleted(
+            state,
+            &recipients,
+            chat_id,
+            &allowed,
+            &deleted_at,
+            user_id,
+            scope,
+        );
+    }
+
+    let allowed: HashSet<i64> = allowed.into_iter().collect();
+    Ok(ids
+        .into_iter()
+        .map(|id| {
+            if allowed.contains(&id) {
+                item_ok(id, None)
+            } else if let Some(err) = denials.get(&id) {
+                item_error(id, err)
+            } else {
+                item_error(id, "Сообщение не найдено")
+            }
+        })
+        .collect())
+}
+
+// Пакетная пересылка сообщений из одного чата в другой (общая логика для HTTP и WS).
+// Членство в обоих чатах проверяется один раз, все вставки — в одной транзакции.
+pub async fn forward_messages_batch(
+    state: &AppState,
+    from_chat_id: i32,
+    to_chat_id: i32,
+    user_id: i32,
+    items: Vec<ForwardItem>,
+) -> Result<Vec<BatchItemResult>, (StatusCode, String)> {
+    ensure_batch_size(items.len())?;
+
+    // Должен быть участником исходного чата и иметь право писать в целевой
+    ensure_member(state, from_chat_id, user_id).await?;
+    ensure_can_send_message(state, to_chat_id, user_id).await?;
+
+    let ids: Vec<i64> = items.iter().map(|i| i.message_id).collect();
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    let rows = sqlx::query(
+        r#"
+        SELECT id::INT8 AS id, sender_id
+        FROM messages
+        WHERE chat_id = $1
+          AND id::INT8 = ANY($2)
+          AND deleted_at IS NULL
+        "#,
+    )
+    .bind(from_chat_id)
+    .bind(&ids)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    let original_senders: HashMap<i64, i32> = rows
+        .iter()
+        .map(|r| {
+            (
+                r.try_get::<i64, _>("id").unwrap_or_default(),
+                r.try_get::<i32, _>("sender_id").unwrap_or_default(),
+            )
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(items.len());
+    let mut forwarded = Vec::<Message>::new();
+
+    for item in items {
+        let Some(original_sender_id) = original_senders.get(&item.message_id).copied() else {
+            results.push(item_error(item.message_id, "Исходное сообщение не найдено"));
+            continue;
+        };
+
+        let envelopes = filter_device_envelopes(state, to_chat_id, item.envelopes).await?;
+        let msg_type = item.message_type.unwrap_or_else(|| "text".to_string());
+        let metadata_json = item
+            .metadata
+            .as_ref()
+            .and_then(|m| serde_json::to_value(m).ok());
+
+        let row = sqlx::query(
+            r#"
+            INSERT INTO messages (
+                chat_id,
+                sender_id,
+                message,
+                message_type,
+                envelopes,
+                metadata,
+                forwarded_from_message_id,
+                forwarded_from_chat_id,
+                forwarded_from_sender_id
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING
+                id::INT8 AS id,
+                chat_id::INT8 AS chat_id,
+                sender_id::INT8 AS sender_id,
+                message,
+                message_type,
+                created_at,
+                edited_at,
+                reply_to_message_id::INT8 AS reply_to_message_id,
+                forwarded_from_message_id::INT8 AS forwarded_from_message_id,
+                forwarded_from_chat_id::INT8 AS forwarded_from_chat_id,
+                forwarded_from_sender_id::INT8 AS forwarded_from_sender_id,
+                deleted_at,
+                deleted_by::INT8 AS deleted_by,
+                is_read,
+                is_delivered,
+                envelopes,
+                metadata
+            "#,
+        )
+        .bind(to_chat_id)
+        .bind(user_id)
+        .bind(&item.message)
+        .bind(&msg_type)
+        .bind(&envelopes)
+        .bind(&metadata_json)
+        .bind(item.message_id as i32)
+        .bind(from_chat_id)
+        .bind(original_sender_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+        let msg = message_from_row(&row);
+        results.push(item_ok(item.message_id, Some(msg.id)));
+        forwarded.push(msg);
+    }
+
+    tx.commit().await.map_err(db_error)?;
+
+    if !forwarded.is_empty() {
+        let recipients = load_chat_recipients(state, to_chat_id).await?;
+        publish_messages_forwarded(
+            state,
+            &recipients,
+            to_chat_id,
+            from_chat_id,
+            &forwarded,
+            user_id,
+        );
+    }
+
+    Ok(results)
+}
+
+// ---------------------------
+// POST /chats/{chat_id}/messages/delete — пакетное удаление (у всех или только у себя)
+// ---------------------------
+async fn delete_messages(
+    State(state): State<AppState>,
+    CurrentUser {
+        id: current_user_id,
+        ..
+    }: CurrentUser,
+    Path(chat_id): Path<i32>,
+    Json(body): Json<DeleteMessagesRequest>,
+) -> Result<Json<Vec<BatchItemResult>>, (StatusCode, String)> {
+    let scope = body.scope.as_deref().unwrap_or("everyone");
+    let results =
+        delete_messages_batch(&state, chat_id, current_user_id, &body.message_ids, scope).await?;
+    Ok(Json(results))
+}
+
+// ---------------------------
+// POST /chats/{chat_id}/messages/forward — пакетная пересылка в чат chat_id
+// ---------------------------
+async fn forward_messages(
+    State(state): State<AppState>,
+    CurrentUser {
+        id: current_user_id,
+        ..
+    }: CurrentUser,
+    Path(chat_id): Path<i32>,
+    Json(body): Json<ForwardMessagesRequest>,
+) -> Result<Json<Vec<BatchItemResult>>, (StatusCode, String)> {
+    let results = forward_messages_batch(
+        &state,
+        body.from_chat_id,
+        chat_id,
+        current_user_id,
+        body.items,
+    )
+    .await?;
+    Ok(Json(results))
+}
+
+fn is_valid_token(token: &str) -> bool {
+    !token.is_empty()
+        && token.len() <= MAX_TOKEN_LEN
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+// Разбирает `tokens=a,b,c` поискового запроса: без дублей, только base64url.
+fn parse_query_tokens(raw: &str) -> Result<Vec<String>, (StatusCode, String)> {
+    let mut tokens = Vec::<String>::new();
+    for t in raw.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        if !is_valid_token(t) {
+            return Err((StatusCode::BAD_REQUEST, "Некорректный токен поиска".into()));
+        }
+        if !tokens.iter().any(|x| x == t) {
+            tokens.push(t.to_string());
+        }
+    }
+    if tokens.len() > MAX_QUERY_TOKENS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Не более {} токенов в запросе", MAX_QUERY_TOKENS),
+        ));
+    }
+    Ok(tokens)
+}
+
+fn parse_rfc3339(
+    value: Option<&str>,
+    field: &str,
+) -> Result<Option<chrono::DateTime<chrono::Utc>>, (StatusCode, String)> {
+    let Some(v) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    chrono::DateTime::parse_from_rfc3339(v)
+        .map(|t| Some(t.with_timezone(&chrono::Utc)))
+        .map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("{}: ожидается дата в формате RFC3339", field),
+            )
+        })
+}
+
+fn parse_media_kind(value: Option<&str>) -> Result<Option<&'static str>, (StatusCode, String)> {
+    let Some(v) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    MEDIA_KINDS
+        .iter()
+        .find(|k| k.eq_ignore_ascii_case(v))
+        .copied()
+        .map(Some)
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            "type: ожидается image | video | file | voice".into(),
+        ))
+}
+
+// Заменяет слепой индекс пользователя для сообщения.
+// Некорректные токены отбрасываются, лишние — обрезаются до MAX_TOKENS_PER_MESSAGE.
+pub async fn save_search_tokens(
+    state: &AppState,
+    user_id: i32,
+    chat_id: i32,
+    message_id: i64,
+    tokens: &[String],
+) -> Result<(), (StatusCode, String)> {
+    let mut unique = HashSet::<&str>::new();
+    let tokens: Vec<String> = tokens
+        .iter()
+        .map(|t| t.trim())
+        .filter(|t| is_valid_token(t) && unique.insert(*t))
+        .take(MAX_TOKENS_PER_MESSAGE)
+        .map(str::to_string)
+        .collect();
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    sqlx::query("DELETE FROM message_search_tokens WHERE user_id = $1 AND message_id = $2")
+        .bind(user_id)
+        .bind(message_id as i32)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    if !tokens.is_empty() {
+        // Индексировать можно только живые сообщения этого чата
+        sqlx::query(
+            r#"
+            INSERT INTO message_search_tokens (user_id, chat_id, message_id, token)
+            SELECT $1, m.chat_id, m.id, t.token
+            FROM messages m
+            CROSS JOIN UNNEST($4::TEXT[]) AS t(token)
+            WHERE m.id = $3 AND m.chat_id = $2 AND m.deleted_at IS NULL
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user_id)
+        .bind(chat_id)
+        .bind(message_id as i32)
+        .bind(&tokens)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    }
+
+    tx.commit().await.map_err(db_error)?;
+    Ok(())
+}
+
+// Удаляет слепой индекс сообщений у всех пользователей (редактирование, удаление у всех).
+pub async fn remove_search_tokens(
+    state: &AppState,
+    message_ids: &[i64],
+) -> Result<(), (StatusCode, String)> {
+    sqlx::query("DELETE FROM message_search_tokens WHERE message_id::INT8 = ANY($1)")
+        .bind(message_ids)
+        .execute(&state.pool)
+        .await
+        .map_err(db_error)?;
+    Ok(())
+}
+
+// ---------------------------
+// POST /chats/{chat_id}/search/index — проиндексировать полученные сообщения своим ключом
+// ---------------------------
+async fn index_messages(
+    State(state): State<AppState>,
+    CurrentUser {
+        id: current_user_id,
+        ..
+    }: CurrentUser,
+    Path(chat_id): Path<i32>,
+    Json(body): Json<IndexMessagesRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    ensure_batch_size(body.items.len())?;
+    ensure_member(&state, chat_id, current_user_id).await?;
+
+    for item in &body.items {
+        save_search_tokens(
+            &state,
+            current_user_id,
+            chat_id,
+            item.message_id,
+            &item.tokens,
+        )
+        .await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// ---------------------------
+// GET /chats/{chat_id}/search — поиск по слепому индексу и фильтрам (тип, файлы, даты)
+// ---------------------------
+async fn search_messages(
+    State(state): State<AppState>,
+    CurrentUser {
+        id: current_user_id,
+        ..
+    }: CurrentUser,
+    Path(chat_id): Path<i32>,
+    Query(q): Query<SearchQuery>,
+) -> Result<Json<SearchResponse>, (StatusCode, String)> {
+    ensure_member(&state, chat_id, current_user_id).await?;
+
+    let tokens = parse_query_tokens(q.tokens.as_deref().unwrap_or(""))?;
+    let from = parse_rfc3339(q.from.as_deref(), "from")?;
+    let to = parse_rfc3339(q.to.as_deref(), "to")?;
+    let message_type = q
+        .message_type
+        .as_deref()
+        .map(str::trim)
+        .filter(|v| !v.is_empty());
+    let limit = q.limit.unwrap_or(50).clamp(1, 200);
+
+    if tokens.is_empty()
+        && message_type.is_none()
+        && q.has_files.is_none()
+        && from.is_none()
+        && to.is_none()
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Нужен хотя бы один критерий поиска".into(),
+        ));
+    }
+
+    let rows = sqlx::query(
+        r#"
+        SELECT m.id::INT8 AS id
+        FROM messages m
+        WHERE m.chat_id = $1
+          AND m.deleted_at IS NULL
+          AND NOT EXISTS (
+              SELECT 1 FROM message_hidden h
+              WHERE h.message_id = m.id AND h.user_id = $2
+          )
+          AND (
+              cardinality($3::TEXT[]) = 0
+              OR (
+                  SELECT COUNT(*)
+                  FROM message_search_tokens t
+                  WHERE t.user_id = $2
+                    AND t.message_id = m.id
+                    AND t.token = ANY($3::TEXT[])
+              ) = cardinality($3::TEXT[])
+          )
+          AND ($4::TEXT IS NULL OR COALESCE(m.message_type, 'text') = $4)
+          AND (
+              $5::BOOL IS NULL
+              OR (
+                  CASE
+                      WHEN jsonb_typeof(m.metadata) = 'array' THEN jsonb_array_length(m.metadata)
+                      ELSE 0
+                  END > 0
+              ) = $5
+          )
+          AND ($6::TIMESTAMPTZ IS NULL OR m.created_at >= $6)
+          AND ($7::TIMESTAMPTZ IS NULL OR m.created_at < $7)
+          AND ($8::INT8 IS NULL OR m.id::INT8 < $8)
+        ORDER BY m.id DESC
+        LIMIT $9
+        "#,
+    )
+    .bind(chat_id)
+    .bind(current_user_id)
+    .bind(&tokens)
+    .bind(message_type)
+    .bind(q.has_files)
+    .bind(from)
+    .bind(to)
+    .bind(q.before_id)
+    .bind(limit)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let message_ids: Vec<i64> = rows
+        .into_iter()
+        .map(|r| r.try_get::<i64, _>("id").unwrap_or_default())
+        .collect();
+    let next_before_id = if message_ids.len() as i64 == limit {
+        message_ids.last().copied()
+    } else {
+        None
+    };
+
+    Ok(Json(SearchResponse {
+        message_ids,
+        next_before_id,
+    }))
+}
+
+// ---------------------------
+// GET /chats/{id}/media — медиа-галерея чата (курсор по id сообщения)
+// ---------------------------
+async fn get_chat_media(
+    State(state): State<AppState>,
+    CurrentUser {
+        id: current_user_id,
+        session_id,
+    }: CurrentUser,
+    Path(chat_id): Path<i32>,
+    Query(q): Query<MediaQuery>,
+) -> Result<Json<MediaResponse>, (StatusCode, String)> {
+    ensure_member(&state, chat_id, current_user_id).await?;
+
+    let kind = parse_media_kind(q.kind.as_deref())?;
+    let limit = q.limit.unwrap_or(30).clamp(1, 100);
+
+    // Файл из metadata попадает в галерею, только если он не удалён и (для загруженных
+    // через /media) запись media_files ещё существует и принадлежит этому чату —
+    // иначе вложение считается истёкшим. Страница ограничивается числом сообщений,
+    // чтобы курсор before_id не разрывал вложения одного сообщения.
+    // Конверт берётся адресованный устройству текущей сессии, а если его нет — пользователю.
+    let rows = sqlx::query(
+        r#"
+        WITH items AS (
+            SELECT
+                m.id::INT8 AS message_id,
+                m.sender_id::INT8 AS sender_id,
+                m.created_at,
+                COALESCE(m.message_type, 'text') AS message_type,
+                COALESCE(
+                    m.envelopes -> (
+                        SELECT s.device_id::TEXT FROM auth_sessions s WHERE s.id = $6
+                    ),
+                    m.envelopes -> ($2::INT4)::TEXT
+                ) AS envelope,
+                e.item,
+                e.idx,
+                CASE
+                    WHEN COALESCE(m.message_type, 'text') = 'voice' THEN 'voice'
+                    WHEN COALESCE(m.message_type, 'text') = 'video'
+                      OR COALESCE(f.mimetype, e.item->>'mimetype', '') LIKE 'video/%' THEN 'video'
+                    WHEN COALESCE(f.mimetype, e.item->>'mimetype', '') LIKE 'image/%' THEN 'image'
+                    ELSE 'file'
+                END AS kind
+            FROM messages m
+            CROSS JOIN LATERAL jsonb_array_elements(
+                CASE WHEN jsonb_typeof(m.metadata) = 'array' THEN m.metadata ELSE '[]'::JSONB END
+            ) WITH ORDINALITY AS e(item, idx)
+            LEFT JOIN media_files f
+              ON f.id::INT8 = CASE
+                  WHEN jsonb_typeof(e.item->'file_id') = 'number' THEN (e.item->>'file_id')::INT8
+              END
+            WHERE m.chat_id = $1
+              AND m.deleted_at IS NULL
+              AND NOT EXISTS (
+                  SELECT 1 FROM message_hidden h
+                  WHERE h.message_id = m.id AND h.user_id = $2
+              )
+              AND ($3::INT8 IS NULL OR m.id::INT8 < $3)
+              AND (
+                  jsonb_typeof(e.item->'file_id') IS DISTINCT FROM 'number'
+                  OR f.chat_id = m.chat_id
+              )
+        ),
+        page AS (
+            SELECT DISTINCT message_id
+            FROM items
+            WHERE $4::TEXT IS NULL OR kind = $4
+            ORDER BY message_id DESC
+            LIMIT $5
+        )
+        SELECT i.*
+        FROM items i
+        JOIN page p ON p.message_id = i.message_id
+        WHERE $4::TEXT IS NULL OR i.kind = $4
+        ORDER BY i.message_id DESC, i.idx ASC
+        "#,
+    )
+    .bind(chat_id)
+    .bind(current_user_id)
+    .bind(q.before_id)
+    .bind(kind)
+    .bind(limit)
+    .bind(session_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let mut message_count = 0i64;
+    let mut last_message_id = None;
+    let mut items = Vec::with_capacity(rows.len());
+    for row in rows {
+        let message_id: i64 = row.try_get("message_id").unwrap_or_default();
+        if last_message_id != Some(message_id) {
+            message_count += 1;
+            last_message_id = Some(message_id);
+        }
+        let item: Value = row.try_get("item").unwrap_or(Value::Null);
+        let Ok(file) = serde_json::from_value::<FileMetadata>(item) else {
+            continue;
+        };
+        items.push(MediaItem {
+            message_id,
+            sender_id: row.try_get("sender_id").unwrap_or_default(),
+            created_at: row
+                .try_get::<chrono::DateTime<chrono::Utc>, _>("created_at")
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default(),
+            message_type: row
+                .try_get("message_type")
+                .unwrap_or_else(|_| "text".to_string()),
+            kind: row.try_get("kind").unwrap_or_else(|_| "file".to_string()),
+            file,
+            envelope: row.try_get("envelope").ok().flatten(),
+        });
+    }
+    let next_before_id = if message_count == limit {
+        last_message_id
+    } else {
+        None
+    };
+
+    Ok(Json(MediaResponse {
+        items,
+        next_before_id,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        MAX_QUERY_TOKENS, delete_messages_batch, forward_messages_batch, parse_media_kind,
+        parse_query_tokens,
+    };
+    use crate::models::chats::{BatchItemResult, ForwardItem};
+    use crate::test_support;
+    use axum::http::StatusCode;
+    use sqlx::PgPool;
+
+    #[test]
+    fn query_tokens_are_split_trimmed_and_deduplicated() {
+        let tokens = parse_query_tokens(" abc_1 ,def-2,abc_1,, ").expect("tokens");
+        assert_eq!(tokens, vec!["abc_1", "def-2"]);
+    }
+
+    #[test]
+    fn query_tokens_reject_non_base64url() {
+        let err = parse_query_tokens("abc,d+f=").expect_err("invalid token");
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn query_tokens_are_limited() {
+        let raw = (0..=MAX_QUERY_TOKENS)
+            .map(|i| format!("t{}", i))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert!(parse_query_tokens(&raw).is_err());
+    }
+
+    #[test]
+    fn media_kind_is_optional_and_validated() {
+        assert_eq!(parse_media_kind(None).expect("none"), None);
+        assert_eq!(parse_media_kind(Some(" ")).expect("blank"), None);
+        assert_eq!(
+            parse_media_kind(Some("Image")).expect("image"),
+            Some("image")
+        );
+        assert!(parse_media_kind(Some("sticker")).is_err());
+    }
+
+    async fn message(pool: &PgPool, chat_id: i32, sender_id: i32) -> i64 {
+        sqlx::query_scalar(
+            "INSERT INTO messages (chat_id, sender_id, message) VALUES ($1, $2, 'x') RETURNING id::INT8",
+        )
+        .bind(chat_id)
+        .bind(sender_id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn deleted_message(pool: &PgPool, chat_id: i32, sender_id: i32) -> i64 {
+        let id = message(pool, chat_id, sender_id).await;
+        sqlx::query("UPDATE messages SET deleted_at = now() WHERE id = $1")
+            .bind(id as i32)
+            .execute(pool)
+            .await
+            .unwrap();
+        id
+    }
+
+    fn outcome(results: &[BatchItemResult]) -> Vec<(i64, bool)> {
+        results.iter().map(|r| (r.message_id, r.ok)).collect()
+    }
+
+    #[tokio::test]
+    async fn delete_batch_reports_each_message() {
+        let Some(state) = test_support::state().await else {
+            return;
+        };
+        let pool = state.pool.clone();
+        let (me, peer, stranger) = (
+            test_support::user(&pool).await,
+            test_support::user(&pool).await,
+            test_support::user(&pool).await,
+        );
+        let chat_id = test_support::private_chat(&pool, me, peer).await;
+        let foreign_chat_id = test_support::private_chat(&pool, peer, stranger).await;
+
+        let mine = message(&pool, chat_id, me).await;
+        let theirs = message(&pool, chat_id, peer).await;
+        let foreign = message(&pool, foreign_chat_id, peer).await;
+        let gone = deleted_message(&pool, chat_id, me).await;
+
+        let results = delete_messages_batch(
+            &state,
+            chat_id,
+            me,
+            &[mine, theirs, foreign, gone, mine],
+            "everyone",
+        )
+        .await
+        .unwrap();
+        // Повторный id схлопывается, порядок ответа — порядок запроса
+        assert_eq!(
+            outcome(&results),
+            vec![
+                (mine, true),
+                (theirs, false),
+                (foreign, false),
+                (gone, false)
+            ]
+        );
+        assert_eq!(results[2].error.as_deref(), Some("Сообщение не найдено"));
+        assert_eq!(results[3].error.as_deref(), Some("Сообщение не найдено"));
+        let still_there: Vec<i64> = sqlx::query_scalar(
+            "SELECT id::INT8 FROM messages WHERE chat_id = $1 AND deleted_at IS NULL ORDER BY id",
+        )
+        .bind(chat_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(still_there, vec![theirs]);
+
+        // Скрыть у себя можно и чужое сообщение, но только из этого чата
+        let results = delete_messages_batch(&state, chat_id, me, &[theirs, foreign], "me")
+            .await
+            .unwrap();
+        assert_eq!(outcome(&results), vec![(theirs, true), (foreign, false)]);
+
+        let err = delete_messages_batch(&state, foreign_chat_id, me, &[foreign], "everyone")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+        let err = delete_messages_batch(&state, chat_id, me, &[theirs], "all")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn forward_batch_reports_each_message_and_checks_membership() {
+        let Some(state) = test_support::state().await else {
+            return;
+        };
+        let pool = state.pool.clone();
+        let (me, peer, stranger) = (
+            test_support::user(&pool).await,
+            test_support::user(&pool).await,
+            test_support::user(&pool).await,
+        );
+        let from_chat_id = test_support::private_chat(&pool, me, peer).await;
+        let to_chat_id = test_support::private_chat(&pool, me, stranger).await;
+        let foreign_chat_id = test_support::private_chat(&pool, peer, stranger).await;
+
+        let original = message(&pool, from_chat_id, peer).await;
+        let foreign = message(&pool, foreign_chat_id, peer).await;
+        let gone = deleted_message(&pool, from_chat_id, peer).await;
+        let items = |ids: &[i64]| {
+            ids.iter()
+                .map(|&message_id| ForwardItem {
+                    message_id,
+                    message: "y".to_string(),
+                    message_type: None,
+                    envelopes: None,
+                    metadata: None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let results = forward_messages_batch(
+            &state,
+            from_chat_id,
+            to_chat_id,
+            me,
+            items(&[original, foreign, gone]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            outcome(&results),
+            vec![(original, true), (foreign, false), (gone, false)]
+        );
+        let new_id = results[0].new_message_id.unwrap();
+        let (chat_id, source, source_sender): (i32, i32, i32) = sqlx::query_as(
+            "SELECT chat_id, forwarded_from_chat_id, forwarded_from_sender_id FROM messages WHERE id = $1",
+        )
+        .bind(new_id as i32)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            (chat_id, source, source_sender),
+            (to_chat_id, from_chat_id, peer)
+        );
+        assert!(results[1].new_message_id.is_none());
+
+        // Нельзя переслать в чужой чат и из чужого чата
+        let err = forward_messages_batch(
+            &state,
+            from_chat_id,
+            foreign_chat_id,
+            me,
+            items(&[original]),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+        let err =
+            forward_messages_batch(&state, foreign_chat_id, to_chat_id, me, items(&[foreign]))
+                .await
+                .err()
+                .unwrap();
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,76 @@
+use axum::{
+    Extension, Router,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+    routing::get,
+};
+use std::sync::Arc;
+
+use crate::AppState;
+
+// Метрики Prometheus:
+// - GET /metrics — текстовый формат экспозиции
+//
+// Доступ задаётся секцией metrics конфигурации:
+// - bind  (METRICS_BIND)  — отдельный адрес (например 127.0.0.1:9100), /metrics только там;
+// - token (METRICS_TOKEN) — требовать `Authorization: Bearer <token>`.
+// Без bind эндпоинт подключается к основному серверу только при заданном
+// token; если не задано ни то, ни другое — /metrics выключен.
+
+#[derive(Clone)]
+struct MetricsToken(Option<Arc<str>>);
+
+pub fn router(token: Option<String>) -> Router<AppState> {
+    Router::new()
+        .route("/metrics", get(metrics_handler))
+        .layer(Extension(MetricsToken(token.map(Arc::from))))
+}
+
+fn token_matches(headers: &HeaderMap, expected: &str) -> bool {
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .unwrap_or_default();
+    // Сравнение без раннего выхода по первому несовпавшему байту
+    provided.len() == expected.len()
+        && provided
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn metrics_handler(
+    State(state): State<AppState>,
+    Extension(MetricsToken(token)): Extension<MetricsToken>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if let Some(token) = token
+        && !token_matches(&headers, &token)
+    {
+        return Err((StatusCode::UNAUTHORIZED, "Неверный токен метрик".into()));
+    }
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&state),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_token_requires_exact_bearer() {
+        let mut headers = HeaderMap::new();
+        assert!(!token_matches(&headers, "s3cret"));
+
+        headers.insert(header::AUTHORIZATION, "Bearer s3cret".parse().unwrap());
+        assert!(token_matches(&headers, "s3cret"));
+        assert!(!token_matches(&headers, "s3cre"));
+        assert!(!token_matches(&headers, "s3creT"));
+    }
+}
@@ -1,18 +1,41 @@
+pub mod admin;
 pub mod auth;
 pub mod chats;
+pub mod device_links;
+pub mod devices;
+pub mod health;
+pub mod invites;
 pub mod media;
+pub mod messages;
+pub mod metrics;
+pub mod passkeys;
+pub mod privacy;
+pub mod recovery;
+pub mod security_events;
+pub mod two_factor;
 pub mod users;
 pub mod ws;
 
 use crate::AppState;
+use crate::config::Config;
 use axum::Router;
 
 // Общий роутер для модуля route (если позже появятся другие подмодули)
-pub fn router() -> Router<AppState> {
+pub fn router(config: &Config) -> Router<AppState> {
     Router::new()
         .merge(auth::router())
+        .merge(invites::router())
+        .merge(recovery::router())
+        .merge(two_factor::router())
+        .merge(passkeys::router())
+        .merge(device_links::router())
+        .merge(security_events::router())
+        .merge(devices::router())
         .merge(users::router())
+        .merge(privacy::router())
         .merge(chats::router())
-        .merge(media::router())
+        .merge(messages::router())
+        .merge(media::router(config.storage.max_upload_bytes))
         .merge(ws::router())
+        .merge(admin::router())
 }
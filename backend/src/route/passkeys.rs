@@ -0,0 +1,1012 @@
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, patch, post},
+};
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Utc};
+use ciborium::value::Value as CborValue;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+use sqlx::postgres::PgRow;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::middleware::CurrentUser;
+use crate::models::auth::{
+    LoginResponse, PasskeyLoginOptionsRequest, PasskeyLoginRequest, PasskeyOptionsResponse,
+    PasskeyRegisterOptionsRequest, PasskeyRegisterRequest, PasskeyResponse, PasskeyUpdateRequest,
+};
+use crate::route::auth::{create_login_session, user_auth_from_row, verify_password};
+use crate::route::security_events::{
+    EVENT_PASSKEY_ADDED, EVENT_PASSKEY_REMOVED, LOGIN_METHOD_PASSKEY, log_failed_login,
+    log_security_event,
+};
+
+// Время жизни вызова церемонии (секунды); то же значение уходит клиенту как timeout
+const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+const PASSKEY_NAME_MAX_CHARS: usize = 64;
+
+// COSE-алгоритмы, которые принимаем при регистрации
+const COSE_ALG_ES256: i64 = -7;
+const COSE_ALG_EDDSA: i64 = -8;
+
+// Флаги authenticatorData (WebAuthn §6.1)
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_DATA: u8 = 0x40;
+
+// Вход PRF-расширения одинаков для всех credential: результат PRF всё равно
+// уникален для пары (credential, вход), а клиенту не нужно хранить соль.
+const PRF_INPUT_LABEL: &[u8] = b"ren-passkey-prf-v1";
+
+// Подставные credential для логина без passkey (см. login_options)
+const DECOY_CREDENTIAL_LABEL: &[u8] = b"ren-passkey-decoy-v1";
+const DECOY_CREDENTIAL_LEN: usize = 32;
+
+// Вход по WebAuthn/passkey:
+// - POST /users/me/passkeys/register/options — параметры для navigator.credentials.create();
+//   требует текущий пароль, вызов выдаётся только после его проверки
+// - POST /users/me/passkeys                  — проверить attestation и сохранить credential
+// - GET /users/me/passkeys                   — список passkey пользователя
+// - PATCH/DELETE /users/me/passkeys/:id      — переименовать / загрузить pkebyprf / удалить
+// - POST /auth/passkey/options               — параметры для navigator.credentials.get()
+// - POST /auth/passkey/login                 — проверить assertion, создать сессию
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/users/me/passkeys/register/options",
+            post(register_options),
+        )
+        .route(
+            "/users/me/passkeys",
+            get(list_passkeys).post(register_passkey),
+        )
+        .route(
+            "/users/me/passkeys/:id",
+            patch(update_passkey).delete(delete_passkey),
+        )
+        .route("/auth/passkey/options", post(login_options))
+        .route("/auth/passkey/login", post(passkey_login))
+}
+
+// Credential, которым подтверждён вход: попадает в auth_sessions и LoginResponse
+pub(crate) struct PasskeyLogin {
+    pub credential_id: i32,
+    pub pkebyprf: Option<String>,
+}
+
+fn db_error(e: sqlx::Error) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Ошибка БД: {}", e),
+    )
+}
+
+fn passkey_rejected() -> (StatusCode, String) {
+    (
+        StatusCode::UNAUTHORIZED,
+        "Не удалось подтвердить passkey".into(),
+    )
+}
+
+fn b64url_encode(data: &[u8]) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(data)
+}
+
+fn b64url_decode(value: &str) -> Option<Vec<u8>> {
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(value.trim().trim_end_matches('='))
+        .ok()
+}
+
+fn prf_input() -> String {
+    b64url_encode(&Sha256::digest(PRF_INPUT_LABEL))
+}
+
+// user.id для WebAuthn: непрозрачный идентификатор без персональных данных
+fn user_handle(user_id: i32) -> Vec<u8> {
+    user_id.to_be_bytes().to_vec()
+}
+
+// Детерминированный подставной credential id: для логина без passkey (или
+// несуществующего) ответ login_options выглядит как для настоящего аккаунта
+// и не меняется между запросами
+fn decoy_credential_id(secret: &str, login: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC принимает любой ключ");
+    mac.update(DECOY_CREDENTIAL_LABEL);
+    mac.update(login.as_bytes());
+    b64url_encode(&mac.finalize().into_bytes()[..DECOY_CREDENTIAL_LEN])
+}
+
+fn new_challenge() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    b64url_encode(&bytes)
+}
+
+async fn store_challenge(
+    state: &AppState,
+    user_id: Option<i32>,
+    kind: &str,
+    challenge: &str,
+) -> Result<Uuid, (StatusCode, String)> {
+    let id = Uuid::new_v4();
+    sqlx::query(
+        r#"
+        INSERT INTO webauthn_challenges (id, user_id, kind, challenge, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .bind(kind)
+    .bind(challenge)
+    .bind(Utc::now() + chrono::Duration::seconds(CHALLENGE_TTL_SECS))
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+    Ok(id)
+}
+
+// Вызов одноразовый: помечается использованным до проверки ответа аутентификатора
+async fn consume_challenge(
+    state: &AppState,
+    challenge_id: &str,
+    kind: &str,
+) -> Result<Option<(String, Option<i32>)>, (StatusCode, String)> {
+    let Ok(id) = Uuid::parse_str(challenge_id.trim()) else {
+        return Ok(None);
+    };
+    let row = sqlx::query(
+        r#"
+        UPDATE webauthn_challenges
+        SET used_at = now()
+        WHERE id = $1 AND kind = $2 AND used_at IS NULL AND expires_at > now()
+        RETURNING challenge, user_id
+        "#,
+    )
+    .bind(id)
+    .bind(kind)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?;
+    Ok(row.map(|r| {
+        (
+            r.try_get("challenge").unwrap_or_default(),
+            r.try_get("user_id").ok().flatten(),
+        )
+    }))
+}
+
+fn normalize_name(name: Option<&str>) -> Option<String> {
+    let name = name?.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some(name.chars().take(PASSKEY_NAME_MAX_CHARS).collect())
+}
+
+fn passkey_from_row(row: &PgRow) -> Result<PasskeyResponse, (StatusCode, String)> {
+    Ok(PasskeyResponse {
+        id: row.try_get("id").map_err(db_error)?,
+        name: row.try_get("name").unwrap_or_default(),
+        created_at: row.try_get("created_at").map_err(db_error)?,
+        last_used_at: row
+            .try_get::<Option<DateTime<Utc>>, _>("last_used_at")
+            .ok()
+            .flatten(),
+        has_prf: row
+            .try_get::<Option<String>, _>("pkebyprf")
+            .ok()
+            .flatten()
+            .is_some(),
+    })
+}
+
+async fn register_options(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    Json(payload): Json<PasskeyRegisterOptionsRequest>,
+) -> Result<Json<PasskeyOptionsResponse>, (StatusCode, String)> {
+    let user = sqlx::query("SELECT login, username, nickname, password FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?
+        .ok_or((StatusCode::NOT_FOUND, "Пользователь не найден".into()))?;
+    let login: String = user.try_get("login").unwrap_or_default();
+    let hashed: String = user.try_get("password").unwrap_or_default();
+
+    // Одного access-токена мало: украденный токен не должен позволять добавить
+    // постоянный способ входа. Вызов регистрации выдаётся только после проверки
+    // пароля и одноразово привязан к пользователю (см. register_passkey).
+    let ip = addr.ip().to_string();
+    if !state.auth_rate_limiter.is_allowed(&ip, Some(&login)).await {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "Слишком много попыток. Повторите позже.".into(),
+        ));
+    }
+    if !verify_password(&hashed, &payload.password)? {
+        let (_allowed, _lockout_secs) = state
+            .auth_rate_limiter
+            .record_failure(&ip, Some(&login))
+            .await;
+        return Err((StatusCode::UNAUTHORIZED, "Неверный пароль".into()));
+    }
+    state
+        .auth_rate_limiter
+        .record_success(&ip, Some(&login))
+        .await;
+    let display_name: String = user
+        .try_get::<Option<String>, _>("nickname")
+        .ok()
+        .flatten()
+        .filter(|n| !n.trim().is_empty())
+        .unwrap_or_else(|| user.try_get("username").unwrap_or_default());
+
+    let existing: Vec<String> =
+        sqlx::query_scalar("SELECT credential_id FROM webauthn_credentials WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(&state.pool)
+            .await
+            .map_err(db_error)?;
+
+    let challenge = new_challenge();
+    let challenge_id = store_challenge(&state, Some(user_id), "register", &challenge).await?;
+
+    let public_key = json!({
+        "rp": { "id": state.config.webauthn.rp_id, "name": state.config.webauthn.rp_name },
+        "user": {
+            "id": b64url_encode(&user_handle(user_id)),
+            "name": login,
+            "displayName": display_name,
+        },
+        "challenge": challenge,
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": COSE_ALG_ES256 },
+            { "type": "public-key", "alg": COSE_ALG_EDDSA },
+        ],
+        "timeout": CHALLENGE_TTL_SECS * 1000,
+        "attestation": "none",
+        "authenticatorSelection": {
+            "residentKey": "required",
+            "userVerification": "required",
+        },
+        "excludeCredentials": existing
+            .iter()
+            .map(|id| json!({ "type": "public-key", "id": id }))
+            .collect::<Vec<_>>(),
+        "extensions": { "prf": { "eval": { "first": prf_input() } } },
+    });
+
+    Ok(Json(PasskeyOptionsResponse {
+        challenge_id: challenge_id.to_string(),
+        public_key,
+    }))
+}
+
+async fn register_passkey(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    CurrentUser {
+        id: user_id,
+        session_id,
+    }: CurrentUser,
+    Json(payload): Json<PasskeyRegisterRequest>,
+) -> Result<(StatusCode, Json<PasskeyResponse>), (StatusCode, String)> {
+    let (challenge, challenge_user) = consume_challenge(&state, &payload.challenge_id, "register")
+        .await?
+        .ok_or((
+            StatusCode::BAD_REQUEST,
+            "Недействительный или просроченный вызов".into(),
+        ))?;
+    if challenge_user != Some(user_id) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Недействительный или просроченный вызов".into(),
+        ));
+    }
+
+    let bad_request = |msg: &str| (StatusCode::BAD_REQUEST, msg.to_string());
+    let client_data = b64url_decode(&payload.credential.response.client_data_json)
+        .ok_or_else(|| bad_request("Некорректный client_data_json"))?;
+    verify_client_data(
+        &client_data,
+        "webauthn.create",
+        &challenge,
+        &state.config.webauthn.origins,
+    )
+    .map_err(bad_request)?;
+
+    let attestation = b64url_decode(&payload.credential.response.attestation_object)
+        .ok_or_else(|| bad_request("Некорректный attestation_object"))?;
+    let auth_data = parse_attestation_object(&attestation).map_err(bad_request)?;
+    let parsed = parse_authenticator_data(&auth_data).map_err(bad_request)?;
+    check_authenticator_data(&parsed, &state.config.webauthn.rp_id).map_err(bad_request)?;
+    let credential = parsed
+        .attested
+        .ok_or_else(|| bad_request("authenticatorData не содержит credential"))?;
+
+    let credential_id = b64url_encode(&credential.credential_id);
+    if b64url_decode(&payload.credential.id).as_deref() != Some(&credential.credential_id[..]) {
+        return Err(bad_request("id не совпадает с credential из attestation"));
+    }
+
+    let name = normalize_name(payload.name.as_deref()).unwrap_or_else(|| "Passkey".to_string());
+    let pkebyprf = payload
+        .pkebyprf
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty());
+
+    let row = sqlx::query(
+        r#"
+        INSERT INTO webauthn_credentials (
+            user_id, credential_id, alg, public_key, sign_count, name, pkebyprf
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id, name, created_at, last_used_at, pkebyprf
+        "#,
+    )
+    .bind(user_id)
+    .bind(&credential_id)
+    .bind(credential.alg as i32)
+    .bind(general_purpose::STANDARD.encode(&credential.public_key))
+    .bind(parsed.sign_count as i64)
+    .bind(&name)
+    .bind(pkebyprf)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| {
+        if let sqlx::Error::Database(db_err) = &e
+            && db_err.code().as_deref() == Some("23505")
+        {
+            return (
+                StatusCode::CONFLICT,
+                "Этот passkey уже зарегистрирован".into(),
+            );
+        }
+        db_error(e)
+    })?;
+
+    let passkey = passkey_from_row(&row)?;
+    log_security_event(
+        &state,
+        &headers,
+        addr,
+        user_id,
+        Some(session_id),
+        EVENT_PASSKEY_ADDED,
+        Some(json!({ "passkey_id": passkey.id, "name": passkey.name })),
+    )
+    .await;
+
+    Ok((StatusCode::CREATED, Json(passkey)))
+}
+
+async fn list_passkeys(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+) -> Result<Json<Vec<PasskeyResponse>>, (StatusCode, String)> {
+    let rows = sqlx::query(
+        r#"
+        SELECT id, name, created_at, last_used_at, pkebyprf
+        FROM webauthn_credentials
+        WHERE user_id = $1
+        ORDER BY created_at DESC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let passkeys = rows
+        .iter()
+        .map(passkey_from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Json(passkeys))
+}
+
+async fn update_passkey(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    Path(id): Path<i32>,
+    Json(payload): Json<PasskeyUpdateRequest>,
+) -> Result<Json<PasskeyResponse>, (StatusCode, String)> {
+    let name = normalize_name(payload.name.as_deref());
+    let pkebyprf = payload
+        .pkebyprf
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty());
+
+    let row = sqlx::query(
+        r#"
+        UPDATE webauthn_credentials
+        SET name = COALESCE($3, name),
+            pkebyprf = COALESCE($4, pkebyprf)
+        WHERE id = $1 AND user_id = $2
+        RETURNING id, name, created_at, last_used_at, pkebyprf
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .bind(name)
+    .bind(pkebyprf)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?
+    .ok_or((StatusCode::NOT_FOUND, "Passkey не найден".into()))?;
+
+    Ok(Json(passkey_from_row(&row)?))
+}
+
+async fn delete_passkey(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    CurrentUser {
+        id: user_id,
+        session_id,
+    }: CurrentUser,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let name: Option<String> = sqlx::query_scalar(
+        "DELETE FROM webauthn_credentials WHERE id = $1 AND user_id = $2 RETURNING name",
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?;
+    let Some(name) = name else {
+        return Err((StatusCode::NOT_FOUND, "Passkey не найден".into()));
+    };
+    log_security_event(
+        &state,
+        &headers,
+        addr,
+        user_id,
+        Some(session_id),
+        EVENT_PASSKEY_REMOVED,
+        Some(json!({ "passkey_id": id, "name": name })),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn login_options(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<PasskeyLoginOptionsRequest>,
+) -> Result<Json<PasskeyOptionsResponse>, (StatusCode, String)> {
+    let login = payload
+        .login
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty());
+    let ip = addr.ip().to_string();
+    if !state.auth_rate_limiter.is_allowed(&ip, login).await {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "Слишком много попыток входа. Повторите позже.".into(),
+        ));
+    }
+
+    // Для неизвестного логина и логина без passkey отдаём подставной credential:
+    // ответ выглядит так же, как для настоящего аккаунта, и наличие аккаунта
+    // по нему не проверить
+    let mut user_id = None;
+    let mut allow_credentials = Vec::new();
+    if let Some(login) = login {
+        let rows = sqlx::query(
+            r#"
+            SELECT u.id, c.credential_id
+            FROM users u
+            JOIN webauthn_credentials c ON c.user_id = u.id
+            WHERE u.login = $1
+            "#,
+        )
+        .bind(login)
+        .fetch_all(&state.pool)
+        .await
+        .map_err(db_error)?;
+        for row in rows {
+            user_id = row.try_get("id").ok();
+            let credential_id: String = row.try_get("credential_id").unwrap_or_default();
+            allow_credentials.push(json!({ "type": "public-key", "id": credential_id }));
+        }
+        if allow_credentials.is_empty() {
+            let credential_id = decoy_credential_id(&state.jwt_secret, login);
+            allow_credentials.push(json!({ "type": "public-key", "id": credential_id }));
+        }
+    }
+
+    let challenge = new_challenge();
+    let challenge_id = store_challenge(&state, user_id, "login", &challenge).await?;
+
+    let public_key = json!({
+        "challenge": challenge,
+        "rpId": state.config.webauthn.rp_id,
+        "timeout": CHALLENGE_TTL_SECS * 1000,
+        "userVerification": "required",
+        "allowCredentials": allow_credentials,
+        "extensions": { "prf": { "eval": { "first": prf_input() } } },
+    });
+
+    Ok(Json(PasskeyOptionsResponse {
+        challenge_id: challenge_id.to_string(),
+        public_key,
+    }))
+}
+
+async fn passkey_login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<PasskeyLoginRequest>,
+) -> Result<Json<LoginResponse>, (StatusCode, String)> {
+    let ip = addr.ip().to_string();
+    if !state.auth_rate_limiter.is_allowed(&ip, None).await {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "Слишком много попыток входа. Повторите позже.".into(),
+        ));
+    }
+
+    let Some((challenge, challenge_user)) =
+        consume_challenge(&state, &payload.challenge_id, "login").await?
+    else {
+        let (_allowed, _lockout_secs) = state.auth_rate_limiter.record_failure(&ip, None).await;
+        return Err(passkey_rejected());
+    };
+
+    let credential_id = b64url_decode(&payload.credential.id)
+        .map(|raw| b64url_encode(&raw))
+        .unwrap_or_default();
+    let row = sqlx::query(
+        r#"
+        SELECT c.id AS credential_pk, c.alg, c.public_key, c.sign_count, c.pkebyprf,
+               u.id, u.login, u.username, u.nickname, u.avatar, u.pkebymk, u.pkebyrk,
+               u.salt, u.pubk, u.kdf_params
+        FROM webauthn_credentials c
+        JOIN users u ON u.id = c.user_id
+        WHERE c.credential_id = $1
+        "#,
+    )
+    .bind(&credential_id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let Some(row) = row else {
+        let (_allowed, _lockout_secs) = state.auth_rate_limiter.record_failure(&ip, None).await;
+        return Err(passkey_rejected());
+    };
+    let user = user_auth_from_row(&row);
+
+    let verified = verify_assertion(&state, &row, &payload, &challenge, challenge_user, user.id);
+    let Some(sign_count) = verified else {
+        let (_allowed, _lockout_secs) = state
+            .auth_rate_limiter
+            .record_failure(&ip, Some(&user.login))
+            .await;
+        log_failed_login(&state, &headers, addr, user.id, LOGIN_METHOD_PASSKEY).await;
+        return Err(passkey_rejected());
+    };
+
+    let credential_pk: i32 = row.try_get("credential_pk").map_err(db_error)?;
+    sqlx::query(
+        "UPDATE webauthn_credentials SET sign_count = $2, last_used_at = now() WHERE id = $1",
+    )
+    .bind(credential_pk)
+    .bind(sign_count as i64)
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    state
+        .auth_rate_limiter
+        .record_success(&ip, Some(&user.login))
+        .await;
+
+    // Passkey с обязательной проверкой пользователя (UV) уже двухфакторный,
+    // поэтому TOTP здесь не запрашивается
+    let remember_me = payload.remember_me.unwrap_or(false);
+    let passkey = PasskeyLogin {
+        credential_id: credential_pk,
+        pkebyprf: row.try_get("pkebyprf").ok().flatten(),
+    };
+    let response = create_login_session(
+        &state,
+        &headers,
+        addr,
+        user,
+        remember_me,
+        Some(passkey),
+        LOGIN_METHOD_PASSKEY,
+    )
+    .await?;
+    Ok(Json(response))
+}
+
+// Проверка assertion; при успехе возвращает новый счётчик подписей
+fn verify_assertion(
+    state: &AppState,
+    row: &PgRow,
+    payload: &PasskeyLoginRequest,
+    challenge: &str,
+    challenge_user: Option<i32>,
+    user_id: i32,
+) -> Option<u32> {
+    // Вызов, выданный под конкретный логин, нельзя закрыть чужим credential
+    if challenge_user.is_some_and(|id| id != user_id) {
+        return None;
+    }
+    let response = &payload.credential.response;
+    if let Some(handle) = response.user_handle.as_deref().filter(|h| !h.is_empty())
+        && b64url_decode(handle)? != user_handle(user_id)
+    {
+        return None;
+    }
+
+    let client_data = b64url_decode(&response.client_data_json)?;
+    verify_client_data(
+        &client_data,
+        "webauthn.get",
+        challenge,
+        &state.config.webauthn.origins,
+    )
+    .ok()?;
+
+    let auth_data = b64url_decode(&response.authenticator_data)?;
+    let parsed = parse_authenticator_data(&auth_data).ok()?;
+    check_authenticator_data(&parsed, &state.config.webauthn.rp_id).ok()?;
+
+    let alg: i32 = row.try_get("alg").ok()?;
+    let public_key = general_purpose::STANDARD
+        .decode(row.try_get::<String, _>("public_key").ok()?)
+        .ok()?;
+    let signature = b64url_decode(&response.signature)?;
+    let mut signed = auth_data.clone();
+    signed.extend_from_slice(&Sha256::digest(&client_data));
+    if !verify_signature(alg as i64, &public_key, &signed, &signature) {
+        return None;
+    }
+
+    // Счётчик не вырос — возможен клон аутентификатора (0/0 — счётчик не поддерживается)
+    let stored: i64 = row.try_get("sign_count").unwrap_or(0);
+    if (parsed.sign_count != 0 || stored != 0) && (parsed.sign_count as i64) <= stored {
+        return None;
+    }
+    Some(parsed.sign_count)
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+fn verify_client_data(
+    raw: &[u8],
+    expected_type: &str,
+    challenge: &str,
+    origins: &[String],
+) -> Result<(), &'static str> {
+    let data: ClientData =
+        serde_json::from_slice(raw).map_err(|_| "Некорректный clientDataJSON")?;
+    if data.kind != expected_type {
+        return Err("Неверный тип церемонии");
+    }
+    if data.challenge.trim_end_matches('=') != challenge {
+        return Err("Вызов не совпадает");
+    }
+    if !origins.iter().any(|o| o == &data.origin) {
+        return Err("Недопустимый origin");
+    }
+    Ok(())
+}
+
+fn parse_attestation_object(raw: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let value: CborValue =
+        ciborium::de::from_reader(raw).map_err(|_| "Некорректный attestation_object")?;
+    // Аттестацию не проверяем (запрашиваем attestation: "none") — нужен только authData
+    value
+        .as_map()
+        .and_then(|map| {
+            map.iter()
+                .find(|(k, _)| k.as_text() == Some("authData"))
+                .and_then(|(_, v)| v.as_bytes().cloned())
+        })
+        .ok_or("attestation_object не содержит authData")
+}
+
+struct AuthenticatorData {
+    rp_id_hash: [u8; 32],
+    flags: u8,
+    sign_count: u32,
+    attested: Option<AttestedCredential>,
+}
+
+struct AttestedCredential {
+    credential_id: Vec<u8>,
+    alg: i64,
+    // ES256 — SEC1 uncompressed point, EdDSA — 32 байта
+    public_key: Vec<u8>,
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, &'static str> {
+    if data.len() < 37 {
+        return Err("authenticatorData слишком короткий");
+    }
+    let mut rp_id_hash = [0u8; 32];
+    rp_id_hash.copy_from_slice(&data[..32]);
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let attested = if flags & FLAG_ATTESTED_DATA != 0 {
+        // aaguid (16) | credentialIdLength (2) | credentialId | COSE_Key
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err("authenticatorData: обрезаны attested credential data");
+        }
+        let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let rest = &rest[18..];
+        if rest.len() < id_len {
+            return Err("authenticatorData: обрезан credentialId");
+        }
+        let (alg, public_key) = parse_cose_key(&rest[id_len..])?;
+        Some(AttestedCredential {
+            credential_id: rest[..id_len].to_vec(),
+            alg,
+            public_key,
+        })
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData {
+        rp_id_hash,
+        flags,
+        sign_count,
+        attested,
+    })
+}
+
+fn check_authenticator_data(data: &AuthenticatorData, rp_id: &str) -> Result<(), &'static str> {
+    if data.rp_id_hash[..] != Sha256::digest(rp_id.as_bytes())[..] {
+        return Err("rpIdHash не совпадает");
+    }
+    if data.flags & FLAG_USER_PRESENT == 0 || data.flags & FLAG_USER_VERIFIED == 0 {
+        return Err("Аутентификатор не подтвердил пользователя");
+    }
+    Ok(())
+}
+
+fn parse_cose_key(raw: &[u8]) -> Result<(i64, Vec<u8>), &'static str> {
+    let value: CborValue = ciborium::de::from_reader(raw).map_err(|_| "Некорректный COSE_Key")?;
+    let map = value.as_map().ok_or("Некорректный COSE_Key")?;
+    let int_param = |key: i64| -> Option<i64> {
+        map.iter()
+            .find(|(k, _)| k.as_integer().map(i128::from) == Some(key as i128))
+            .and_then(|(_, v)| v.as_integer())
+            .and_then(|v| i64::try_from(i128::from(v)).ok())
+    };
+    let bytes_param = |key: i64| -> Option<&Vec<u8>> {
+        map.iter()
+            .find(|(k, _)| k.as_integer().map(i128::from) == Some(key as i128))
+            .and_then(|(_, v)| v.as_bytes())
+    };
+
+    // kty (1), alg (3), crv (-1), x (-2), y (-3) — RFC 9053
+    let kty = int_param(1).ok_or("COSE_Key без kty")?;
+    let alg = int_param(3).ok_or("COSE_Key без alg")?;
+    match (alg, kty, int_param(-1)) {
+        (COSE_ALG_ES256, 2, Some(1)) => {
+            let x = bytes_param(-2).filter(|x| x.len() == 32);
+            let y = bytes_param(-3).filter(|y| y.len() == 32);
+            let (Some(x), Some(y)) = (x, y) else {
+                return Err("Некорректный ключ P-256");
+            };
+            let mut point = Vec::with_capacity(65);
+            point.push(0x04);
+            point.extend_from_slice(x);
+            point.extend_from_slice(y);
+            p256::ecdsa::VerifyingKey::from_sec1_bytes(&point)
+                .map_err(|_| "Некорректный ключ P-256")?;
+            Ok((alg, point))
+        }
+        (COSE_ALG_EDDSA, 1, Some(6)) => {
+            let x: [u8; 32] = bytes_param(-2)
+                .and_then(|x| x.as_slice().try_into().ok())
+                .ok_or("Некорректный ключ Ed25519")?;
+            ed25519_dalek::VerifyingKey::from_bytes(&x).map_err(|_| "Некорректный ключ Ed25519")?;
+            Ok((alg, x.to_vec()))
+        }
+        _ => Err("Неподдерживаемый алгоритм passkey"),
+    }
+}
+
+fn verify_signature(alg: i64, public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    match alg {
+        COSE_ALG_ES256 => {
+            use p256::ecdsa::signature::Verifier;
+            let Ok(key) = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key) else {
+                return false;
+            };
+            let Ok(signature) = p256::ecdsa::Signature::from_der(signature) else {
+                return false;
+            };
+            key.verify(message, &signature).is_ok()
+        }
+        COSE_ALG_EDDSA => {
+            use ed25519_dalek::Verifier;
+            let Ok(bytes) = <[u8; 32]>::try_from(public_key) else {
+                return false;
+            };
+            let Ok(key) = ed25519_dalek::VerifyingKey::from_bytes(&bytes) else {
+                return false;
+            };
+            let Ok(signature) = ed25519_dalek::Signature::from_slice(signature) else {
+                return false;
+            };
+            key.verify(message, &signature).is_ok()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RP_ID: &str = "messanger-ren.ru";
+
+    fn cbor(value: &CborValue) -> Vec<u8> {
+        let mut out = Vec::new();
+        ciborium::ser::into_writer(value, &mut out).unwrap();
+        out
+    }
+
+    fn auth_data(flags: u8, sign_count: u32, attested: Option<(&[u8], &[u8])>) -> Vec<u8> {
+        let mut data = Sha256::digest(RP_ID.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        if let Some((credential_id, cose_key)) = attested {
+            data.extend_from_slice(&[0u8; 16]);
+            data.extend_from_slice(&(credential_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(credential_id);
+            data.extend_from_slice(cose_key);
+        }
+        data
+    }
+
+    fn ed25519_cose_key(key: &ed25519_dalek::SigningKey) -> Vec<u8> {
+        cbor(&CborValue::Map(vec![
+            (1.into(), 1.into()),
+            (3.into(), COSE_ALG_EDDSA.into()),
+            ((-1).into(), 6.into()),
+            (
+                (-2).into(),
+                CborValue::Bytes(key.verifying_key().to_bytes().to_vec()),
+            ),
+        ]))
+    }
+
+    #[test]
+    fn registration_and_assertion_roundtrip_ed25519() {
+        use ed25519_dalek::Signer;
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]);
+        let flags = FLAG_USER_PRESENT | FLAG_USER_VERIFIED | FLAG_ATTESTED_DATA;
+        let reg = auth_data(flags, 0, Some((b"cred-1", &ed25519_cose_key(&key))));
+        let attestation = cbor(&CborValue::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), CborValue::Map(vec![])),
+            ("authData".into(), CborValue::Bytes(reg.clone())),
+        ]));
+
+        let parsed = parse_authenticator_data(&parse_attestation_object(&attestation).unwrap())
+            .expect("authData должен разбираться");
+        check_authenticator_data(&parsed, RP_ID).unwrap();
+        assert!(check_authenticator_data(&parsed, "evil.example").is_err());
+        let credential = parsed.attested.unwrap();
+        assert_eq!(credential.credential_id, b"cred-1");
+        assert_eq!(credential.alg, COSE_ALG_EDDSA);
+
+        let client_data =
+            br#"{"type":"webauthn.get","challenge":"abc","origin":"https://messanger-ren.ru"}"#;
+        let assertion = auth_data(FLAG_USER_PRESENT | FLAG_USER_VERIFIED, 1, None);
+        let mut signed = assertion.clone();
+        signed.extend_from_slice(&Sha256::digest(client_data));
+        let signature = key.sign(&signed).to_bytes();
+
+        assert!(verify_signature(
+            credential.alg,
+            &credential.public_key,
+            &signed,
+            &signature
+        ));
+        signed[0] ^= 1;
+        assert!(!verify_signature(
+            credential.alg,
+            &credential.public_key,
+            &signed,
+            &signature
+        ));
+    }
+
+    #[test]
+    fn es256_signature_and_cose_key() {
+        use p256::ecdsa::{SigningKey, signature::Signer};
+
+        let key = SigningKey::from_slice(&[9u8; 32]).unwrap();
+        let point = key.verifying_key().to_encoded_point(false);
+        let cose = cbor(&CborValue::Map(vec![
+            (1.into(), 2.into()),
+            (3.into(), COSE_ALG_ES256.into()),
+            ((-1).into(), 1.into()),
+            ((-2).into(), CborValue::Bytes(point.x().unwrap().to_vec())),
+            ((-3).into(), CborValue::Bytes(point.y().unwrap().to_vec())),
+        ]));
+        let (alg, public_key) = parse_cose_key(&cose).unwrap();
+        assert_eq!(alg, COSE_ALG_ES256);
+
+        let signature: p256::ecdsa::Signature = key.sign(b"payload");
+        let der = signature.to_der();
+        assert!(verify_signature(
+            alg,
+            &public_key,
+            b"payload",
+            der.as_bytes()
+        ));
+        assert!(!verify_signature(
+            alg,
+            &public_key,
+            b"other",
+            der.as_bytes()
+        ));
+    }
+
+    #[test]
+    fn client_data_checks_type_challenge_and_origin() {
+        let origins = vec!["https://messanger-ren.ru".to_string()];
+        let raw =
+            br#"{"type":"webauthn.create","challenge":"abc","origin":"https://messanger-ren.ru"}"#;
+        assert!(verify_client_data(raw, "webauthn.create", "abc", &origins).is_ok());
+        assert!(verify_client_data(raw, "webauthn.get", "abc", &origins).is_err());
+        assert!(verify_client_data(raw, "webauthn.create", "xyz", &origins).is_err());
+        assert!(verify_client_data(raw, "webauthn.create", "abc", &[]).is_err());
+    }
+
+    #[test]
+    fn authenticator_data_requires_user_verification() {
+        let parsed = parse_authenticator_data(&auth_data(FLAG_USER_PRESENT, 0, None)).unwrap();
+        assert!(check_authenticator_data(&parsed, RP_ID).is_err());
+        assert!(parse_authenticator_data(&[0u8; 10]).is_err());
+    }
+
+    #[test]
+    fn decoy_credential_is_stable_per_login() {
+        let first = decoy_credential_id("secret", "ghost");
+        assert_eq!(first, decoy_credential_id("secret", "ghost"));
+        assert_ne!(first, decoy_credential_id("secret", "ghost2"));
+        assert_ne!(first, decoy_credential_id("other", "ghost"));
+        assert_eq!(b64url_decode(&first).unwrap().len(), DECOY_CREDENTIAL_LEN);
+    }
+}
@@ -0,0 +1,471 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, put},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgPool, Row};
+use std::collections::HashSet;
+
+use crate::AppState;
+use crate::middleware::CurrentUser;
+use crate::route::ws::publish_payload_to_users;
+
+// Блокировка пользователей и настройки приватности:
+// - GET    /users/me/blocks     — заблокированные мной пользователи
+// - PUT    /users/me/blocks/:id — заблокировать (повторный вызов ничего не меняет)
+// - DELETE /users/me/blocks/:id — разблокировать
+// - GET    /users/me/privacy    — настройки приватности
+// - PUT    /users/me/privacy    — изменить: {"search_visibility": "everyone" | "contacts" | "nobody"}
+//
+// Заблокированный не может создать со мной private-чат, писать в существующий,
+// видеть моё присутствие и набор текста, добавлять меня в группы и находить в поиске.
+// Private-чат закрыт для сообщений в обе стороны, пока блокировка не снята.
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/users/me/blocks", get(list_blocks))
+        .route("/users/me/blocks/:id", put(block_user).delete(unblock_user))
+        .route("/users/me/privacy", get(get_privacy).put(update_privacy))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchVisibility {
+    // Находят все
+    #[default]
+    Everyone,
+    // Только собеседники по private-чатам
+    Contacts,
+    // Никто
+    Nobody,
+}
+
+impl SearchVisibility {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "everyone" => Some(SearchVisibility::Everyone),
+            "contacts" => Some(SearchVisibility::Contacts),
+            "nobody" => Some(SearchVisibility::Nobody),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SearchVisibility::Everyone => "everyone",
+            SearchVisibility::Contacts => "contacts",
+            SearchVisibility::Nobody => "nobody",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BlockedUserResponse {
+    id: i32,
+    username: String,
+    nickname: Option<String>,
+    avatar: Option<String>,
+    blocked_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PrivacySettings {
+    search_visibility: String,
+}
+
+fn db_error(e: sqlx::Error) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Ошибка БД: {}", e),
+    )
+}
+
+async fn list_blocks(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+) -> Result<Json<Vec<BlockedUserResponse>>, (StatusCode, String)> {
+    let rows = sqlx::query(
+        r#"
+        SELECT u.id, u.username, u.nickname, u.avatar, b.created_at
+        FROM user_blocks b
+        JOIN users u ON u.id = b.blocked_id
+        WHERE b.blocker_id = $1
+        ORDER BY b.created_at DESC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let blocked = rows
+        .iter()
+        .map(|row| BlockedUserResponse {
+            id: row.try_get("id").unwrap_or_default(),
+            username: row.try_get("username").unwrap_or_default(),
+            nickname: row.try_get("nickname").ok().flatten(),
+            avatar: row.try_get("avatar").ok().flatten(),
+            blocked_at: row
+                .try_get::<DateTime<Utc>, _>("created_at")
+                .unwrap_or_else(|_| Utc::now()),
+        })
+        .collect();
+    Ok(Json(blocked))
+}
+
+async fn block_user(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    Path(target_id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if target_id == user_id {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Нельзя заблокировать самого себя".into(),
+        ));
+    }
+    let result = sqlx::query(
+        r#"
+        INSERT INTO user_blocks (blocker_id, blocked_id)
+        SELECT $1, id FROM users WHERE id = $2
+        ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(user_id)
+    .bind(target_id)
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    if result.rows_affected() > 0 {
+        // Мои устройства синхронизируют список, заблокированный видит меня offline
+        let payload = json!({ "type": "user_blocked", "user_id": target_id }).to_string();
+        publish_payload_to_users(&state, &[user_id], payload);
+        let presence = json!({
+            "type": "presence",
+            "user_id": user_id,
+            "status": "offline",
+        })
+        .to_string();
+        publish_payload_to_users(&state, &[target_id], presence);
+    } else if !user_exists(&state.pool, target_id).await? {
+        return Err((StatusCode::NOT_FOUND, "Пользователь не найден".into()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unblock_user(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    Path(target_id): Path<i32>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let result = sqlx::query("DELETE FROM user_blocks WHERE blocker_id = $1 AND blocked_id = $2")
+        .bind(user_id)
+        .bind(target_id)
+        .execute(&state.pool)
+        .await
+        .map_err(db_error)?;
+    if result.rows_affected() > 0 {
+        let payload = json!({ "type": "user_unblocked", "user_id": target_id }).to_string();
+        publish_payload_to_users(&state, &[user_id], payload);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_privacy(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+) -> Result<Json<PrivacySettings>, (StatusCode, String)> {
+    let search_visibility: Option<String> =
+        sqlx::query_scalar("SELECT search_visibility FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(db_error)?;
+    let Some(search_visibility) = search_visibility else {
+        return Err((StatusCode::NOT_FOUND, "Пользователь не найден".into()));
+    };
+    Ok(Json(PrivacySettings { search_visibility }))
+}
+
+async fn update_privacy(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    Json(body): Json<PrivacySettings>,
+) -> Result<Json<PrivacySettings>, (StatusCode, String)> {
+    let visibility = SearchVisibility::parse(&body.search_visibility).ok_or((
+        StatusCode::BAD_REQUEST,
+        "search_visibility должен быть одним из: everyone, contacts, nobody".into(),
+    ))?;
+    sqlx::query("UPDATE users SET search_visibility = $2 WHERE id = $1")
+        .bind(user_id)
+        .bind(visibility.as_str())
+        .execute(&state.pool)
+        .await
+        .map_err(db_error)?;
+    Ok(Json(PrivacySettings {
+        search_visibility: visibility.as_str().to_string(),
+    }))
+}
+
+async fn user_exists(pool: &PgPool, user_id: i32) -> Result<bool, (StatusCode, String)> {
+    let found: Option<i32> = sqlx::query_scalar("SELECT id FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?;
+    Ok(found.is_some())
+}
+
+// Кто из targets заблокировал user_id (добавлять его в группы и чаты им нельзя)
+pub(crate) async fn blocked_by(
+    pool: &PgPool,
+    user_id: i32,
+    targets: &[i32],
+) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT blocker_id FROM user_blocks WHERE blocked_id = $1 AND blocker_id = ANY($2)",
+    )
+    .bind(user_id)
+    .bind(targets)
+    .fetch_all(pool)
+    .await
+}
+
+// Пользователи из contacts, с которыми у user_id блокировка в любую сторону:
+// присутствие между ними не пересылается
+pub(crate) async fn blocked_contacts(
+    pool: &PgPool,
+    user_id: i32,
+    contacts: &HashSet<i32>,
+) -> Result<HashSet<i32>, sqlx::Error> {
+    if contacts.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let contacts: Vec<i32> = contacts.iter().copied().collect();
+    let blocked: Vec<i32> = sqlx::query_scalar(
+        r#"
+        SELECT blocked_id FROM user_blocks WHERE blocker_id = $1 AND blocked_id = ANY($2)
+        UNION
+        SELECT blocker_id FROM user_blocks WHERE blocked_id = $1 AND blocker_id = ANY($2)
+        "#,
+    )
+    .bind(user_id)
+    .bind(&contacts)
+    .fetch_all(pool)
+    .await?;
+    Ok(blocked.into_iter().collect())
+}
+
+// Кому из участников чата пересылать набор текста user_id: None — всем
+// (блокировок с участниками нет), иначе — список без тех, с кем у user_id
+// блокировка в любую сторону
+pub(crate) async fn typing_recipients(
+    pool: &PgPool,
+    chat_id: i32,
+    user_id: i32,
+) -> Result<Option<Vec<i32>>, sqlx::Error> {
+    let blocked: Vec<i32> = sqlx::query_scalar(
+        r#"
+        SELECT p.user_id
+        FROM chat_participants p
+        JOIN user_blocks b
+          ON (b.blocker_id = p.user_id AND b.blocked_id = $2)
+          OR (b.blocker_id = $2 AND b.blocked_id = p.user_id)
+        WHERE p.chat_id = $1 AND p.user_id <> $2
+        "#,
+    )
+    .bind(chat_id)
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    if blocked.is_empty() {
+        return Ok(None);
+    }
+    let recipients: Vec<i32> = sqlx::query_scalar(
+        "SELECT user_id FROM chat_participants WHERE chat_id = $1 AND user_id <> $2 AND user_id <> ALL($3)",
+    )
+    .bind(chat_id)
+    .bind(user_id)
+    .bind(&blocked)
+    .fetch_all(pool)
+    .await?;
+    Ok(Some(recipients))
+}
+
+// Private-чат, где между собеседниками есть блокировка в любую сторону
+pub(crate) async fn private_chat_blocked(
+    pool: &PgPool,
+    chat_id: i32,
+    user_id: i32,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM chats c
+            JOIN chat_participants peer ON peer.chat_id = c.id AND peer.user_id <> $2
+            JOIN user_blocks b
+              ON (b.blocker_id = peer.user_id AND b.blocked_id = $2)
+              OR (b.blocker_id = $2 AND b.blocked_id = peer.user_id)
+            WHERE c.id = $1 AND c.kind = 'private'
+        )
+        "#,
+    )
+    .bind(chat_id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use tokio::sync::broadcast;
+
+    #[test]
+    fn search_visibility_round_trips() {
+        for visibility in [
+            SearchVisibility::Everyone,
+            SearchVisibility::Contacts,
+            SearchVisibility::Nobody,
+        ] {
+            assert_eq!(
+                SearchVisibility::parse(visibility.as_str()),
+                Some(visibility)
+            );
+        }
+        assert_eq!(
+            SearchVisibility::parse(" Nobody "),
+            Some(SearchVisibility::Nobody)
+        );
+        assert_eq!(SearchVisibility::parse("friends"), None);
+    }
+
+    #[tokio::test]
+    async fn block_and_unblock_update_every_check() {
+        let Some(state) = test_support::state().await else {
+            return;
+        };
+        let pool = state.pool.clone();
+        let (me, peer) = (
+            test_support::user(&pool).await,
+            test_support::user(&pool).await,
+        );
+        let chat_id = test_support::private_chat(&pool, me, peer).await;
+        let (tx, mut my_events) = broadcast::channel(16);
+        state.user_hub.insert(me, tx);
+
+        let status = block_user(State(state.clone()), test_support::current(me), Path(peer))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(my_events.try_recv().unwrap().contains("user_blocked"));
+        // Повторная блокировка ничего не меняет и событий не шлёт
+        block_user(State(state.clone()), test_support::current(me), Path(peer))
+            .await
+            .unwrap();
+        assert!(my_events.try_recv().is_err());
+
+        let blocks = list_blocks(State(state.clone()), test_support::current(me))
+            .await
+            .unwrap();
+        assert_eq!(
+            blocks.0.iter().map(|b| b.id).collect::<Vec<_>>(),
+            vec![peer]
+        );
+        assert_eq!(blocked_by(&pool, peer, &[me]).await.unwrap(), vec![me]);
+        assert!(blocked_by(&pool, me, &[peer]).await.unwrap().is_empty());
+        // Присутствие и private-чат закрыты в обе стороны
+        let contacts = HashSet::from([peer]);
+        assert!(
+            blocked_contacts(&pool, me, &contacts)
+                .await
+                .unwrap()
+                .contains(&peer)
+        );
+        let contacts = HashSet::from([me]);
+        assert!(
+            blocked_contacts(&pool, peer, &contacts)
+                .await
+                .unwrap()
+                .contains(&me)
+        );
+        assert!(private_chat_blocked(&pool, chat_id, me).await.unwrap());
+        assert!(private_chat_blocked(&pool, chat_id, peer).await.unwrap());
+
+        unblock_user(State(state.clone()), test_support::current(me), Path(peer))
+            .await
+            .unwrap();
+        assert!(my_events.try_recv().unwrap().contains("user_unblocked"));
+        assert!(blocked_by(&pool, peer, &[me]).await.unwrap().is_empty());
+        assert!(!private_chat_blocked(&pool, chat_id, peer).await.unwrap());
+
+        let err = block_user(State(state.clone()), test_support::current(me), Path(me))
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+        let err = block_user(
+            State(state.clone()),
+            test_support::current(me),
+            Path(i32::MAX),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn typing_skips_blocked_group_members() {
+        let Some(state) = test_support::state().await else {
+            return;
+        };
+        let pool = state.pool.clone();
+        let (me, blocker, other) = (
+            test_support::user(&pool).await,
+            test_support::user(&pool).await,
+            test_support::user(&pool).await,
+        );
+        let chat_id: i32 = sqlx::query_scalar(
+            "INSERT INTO chats (kind, title) VALUES ('group', 't') RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        for user_id in [me, blocker, other] {
+            sqlx::query("INSERT INTO chat_participants (chat_id, user_id) VALUES ($1, $2)")
+                .bind(chat_id)
+                .bind(user_id)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(typing_recipients(&pool, chat_id, me).await.unwrap(), None);
+
+        block_user(
+            State(state.clone()),
+            test_support::current(blocker),
+            Path(me),
+        )
+        .await
+        .unwrap();
+        // Набор текста не ходит между заблокированными ни в одну сторону
+        assert_eq!(
+            typing_recipients(&pool, chat_id, me).await.unwrap(),
+            Some(vec![other])
+        );
+        assert_eq!(
+            typing_recipients(&pool, chat_id, blocker).await.unwrap(),
+            Some(vec![other])
+        );
+        assert_eq!(
+            typing_recipients(&pool, chat_id, other).await.unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use axum::{
+    Json, Router,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    routing::{post, put},
+};
+use base64::{Engine as _, engine::general_purpose};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+use serde_json::json;
+use sqlx::Row;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::middleware::CurrentUser;
+use crate::models::auth::{
+    KdfParams, RecoveryChallengeRequest, RecoveryChallengeResponse, RecoveryResetRequest,
+    RecoveryVerifyRequest, RecoveryVerifyResponse, SetRecoveryKeyRequest,
+};
+use crate::route::auth::{
+    generate_refresh_token, hash_password, hash_refresh_token, resolve_kdf_params_update,
+    revoke_user_sessions, verify_password,
+};
+use crate::route::devices::publish_device_keys_changed;
+use crate::route::security_events::{EVENT_ACCOUNT_RECOVERED, log_security_event};
+use crate::route::ws::publish_session_revoked;
+
+// Время жизни вызова и токена сброса (секунды)
+const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+const RESET_TOKEN_TTL_SECS: i64 = 10 * 60;
+
+// Восстановление аккаунта по ключу восстановления:
+// - POST /auth/recovery/challenge — выдать одноразовый вызов для логина
+// - POST /auth/recovery/verify    — проверить подпись вызова, вернуть pkebyrk и токен сброса
+// - POST /auth/recovery/reset     — новый пароль + pkebymk/salt, отзыв всех сессий
+// - PUT /users/me/recovery-key    — установить ключ проверки для существующего аккаунта
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/auth/recovery/challenge", post(recovery_challenge))
+        .route("/auth/recovery/verify", post(recovery_verify))
+        .route("/auth/recovery/reset", post(recovery_reset))
+        .route("/users/me/recovery-key", put(set_recovery_key))
+}
+
+fn db_error(e: sqlx::Error) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Ошибка БД: {}", e),
+    )
+}
+
+fn too_many_attempts() -> (StatusCode, String) {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        "Слишком много попыток восстановления. Повторите позже.".into(),
+    )
+}
+
+// Счётчики восстановления ведутся отдельно от входа, чтобы перебор здесь
+// не блокировал обычный логин (и наоборот).
+fn limiter_account(login: &str) -> String {
+    format!("recovery:{}", login.trim())
+}
+
+// Строка, которую клиент подписывает ключом, выведенным из ключа восстановления
+fn challenge_message(challenge_id: &str, challenge: &str) -> String {
+    format!("ren-recovery:{}:{}", challenge_id, challenge)
+}
+
+pub(crate) fn parse_recovery_pubk(b64: &str) -> Result<VerifyingKey, (StatusCode, String)> {
+    let invalid = || {
+        (
+            StatusCode::BAD_REQUEST,
+            "recovery_pubk: ожидается Ed25519 ключ (Base64, 32 байта)".to_string(),
+        )
+    };
+    let bytes: [u8; 32] = general_purpose::STANDARD
+        .decode(b64.trim())
+        .map_err(|_| invalid())?
+        .try_into()
+        .map_err(|_| invalid())?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| invalid())
+}
+
+fn verify_challenge_signature(
+    recovery_pubk: &str,
+    challenge_id: &str,
+    challenge: &str,
+    signature_b64: &str,
+) -> bool {
+    let Ok(key) = parse_recovery_pubk(recovery_pubk) else {
+        return false;
+    };
+    let Some(signature) = general_purpose::STANDARD
+        .decode(signature_b64.trim())
+        .ok()
+        .and_then(|b| Signature::from_slice(&b).ok())
+    else {
+        return false;
+    };
+    key.verify(
+        challenge_message(challenge_id, challenge).as_bytes(),
+        &signature,
+    )
+    .is_ok()
+}
+
+async fn recovery_challenge(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<RecoveryChallengeRequest>,
+) -> Result<Json<RecoveryChallengeResponse>, (StatusCode, String)> {
+    let login = payload.login.trim().to_string();
+    if login.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "login обязателен".into()));
+    }
+
+    // Каждый выданный вызов расходует попытку: восстановление — редкая операция,
+    // и лимит по IP/логину должен быть строже, чем у входа.
+    let ip = addr.ip().to_string();
+    let account = limiter_account(&login);
+    if !state
+        .auth_rate_limiter
+        .is_allowed(&ip, Some(&account))
+        .await
+    {
+        return Err(too_many_attempts());
+    }
+    let (allowed, _lockout_secs) = state
+        .auth_rate_limiter
+        .record_failure(&ip, Some(&account))
+        .await;
+    if !allowed {
+        return Err(too_many_attempts());
+    }
+
+    // Для неизвестного логина или аккаунта без recovery_pubk вызов тоже выдаётся
+    // (с user_id = NULL), чтобы ответ не раскрывал существование аккаунта.
+    let user_id: Option<i32> =
+        sqlx::query_scalar("SELECT id FROM users WHERE login = $1 AND recovery_pubk IS NOT NULL")
+            .bind(&login)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(db_error)?;
+
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let challenge = general_purpose::STANDARD.encode(bytes);
+    let challenge_id = Uuid::new_v4();
+
+    sqlx::query(
+        r#"
+        INSERT INTO recovery_challenges (id, user_id, challenge, expires_at)
+        VALUES ($1, $2, $3, now() + make_interval(secs => $4))
+        "#,
+    )
+    .bind(challenge_id)
+    .bind(user_id)
+    .bind(&challenge)
+    .bind(CHALLENGE_TTL_SECS as f64)
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    Ok(Json(RecoveryChallengeResponse {
+        challenge_id: challenge_id.to_string(),
+        challenge,
+        expires_in: CHALLENGE_TTL_SECS,
+    }))
+}
+
+async fn recovery_verify(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<RecoveryVerifyRequest>,
+) -> Result<Json<RecoveryVerifyResponse>, (StatusCode, String)> {
+    let ip = addr.ip().to_string();
+    if !state.auth_rate_limiter.is_allowed(&ip, None).await {
+        return Err(too_many_attempts());
+    }
+
+    let rejected = || {
+        (
+            StatusCode::UNAUTHORIZED,
+            "Не удалось подтвердить ключ восстановления".to_string(),
+        )
+    };
+    let Ok(challenge_id) = Uuid::parse_str(payload.challenge_id.trim()) else {
+        state.auth_rate_limiter.record_failure(&ip, None).await;
+        return Err(rejected());
+    };
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    let row = sqlx::query(
+        r#"
+        SELECT c.challenge, u.login, u.recovery_pubk, u.pkebyrk
+        FROM recovery_challenges c
+        LEFT JOIN users u ON u.id = c.user_id
+        WHERE c.id = $1
+          AND c.expires_at > now()
+          AND c.attempted_at IS NULL
+        FOR UPDATE OF c
+        "#,
+    )
+    .bind(challenge_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    let Some(row) = row else {
+        drop(tx);
+        state.auth_rate_limiter.record_failure(&ip, None).await;
+        return Err(rejected());
+    };
+
+    // Вызов одноразовый: любая попытка проверки его погашает
+    sqlx::query("UPDATE recovery_challenges SET attempted_at = now() WHERE id = $1")
+        .bind(challenge_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    let challenge: String = row.try_get("challenge").unwrap_or_default();
+    let login: Option<String> = row.try_get("login").ok().flatten();
+    let recovery_pubk: Option<String> = row.try_get("recovery_pubk").ok().flatten();
+    let pkebyrk: Option<String> = row.try_get("pkebyrk").ok().flatten();
+    let account = login.as_deref().map(limiter_account);
+
+    let verified = recovery_pubk.as_deref().is_some_and(|key| {
+        verify_challenge_signature(key, &payload.challenge_id, &challenge, &payload.signature)
+    });
+    let (true, Some(pkebyrk)) = (verified, pkebyrk) else {
+        tx.commit().await.map_err(db_error)?;
+        state
+            .auth_rate_limiter
+            .record_failure(&ip, account.as_deref())
+            .await;
+        return Err(rejected());
+    };
+
+    let reset_token = generate_refresh_token();
+    sqlx::query(
+        r#"
+        UPDATE recovery_challenges
+        SET reset_token_hash = $2,
+            reset_expires_at = now() + make_interval(secs => $3)
+        WHERE id = $1
+        "#,
+    )
+    .bind(challenge_id)
+    .bind(hash_refresh_token(&state.jwt_secret, &reset_token))
+    .bind(RESET_TOKEN_TTL_SECS as f64)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    tx.commit().await.map_err(db_error)?;
+
+    Ok(Json(RecoveryVerifyResponse {
+        pkebyrk,
+        reset_token,
+        expires_in: RESET_TOKEN_TTL_SECS,
+    }))
+}
+
+async fn recovery_reset(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<RecoveryResetRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if payload.new_password.len() < 6 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Некорректные данные (минимум: пароль >= 6 символов)".into(),
+        ));
+    }
+    if payload.pkebymk.trim().is_empty() || payload.salt.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "pkebymk и salt обязательны".into()));
+    }
+    let ip = addr.ip().to_string();
+    if !state.auth_rate_limiter.is_allowed(&ip, None).await {
+        return Err(too_many_attempts());
+    }
+
+    let password_hash = hash_password(&payload.new_password)?;
+    let token_hash = hash_refresh_token(&state.jwt_secret, payload.reset_token.trim());
+
+    let mut tx = state.pool.begin().await.map_err(db_error)?;
+
+    let row = sqlx::query(
+        r#"
+        SELECT c.id, c.user_id, u.login, u.kdf_params
+        FROM recovery_challenges c
+        JOIN users u ON u.id = c.user_id
+        WHERE c.reset_token_hash = $1
+          AND c.reset_expires_at > now()
+          AND c.used_at IS NULL
+        FOR UPDATE OF c
+        "#,
+    )
+    .bind(&token_hash)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    let Some(row) = row else {
+        drop(tx);
+        state.auth_rate_limiter.record_failure(&ip, None).await;
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Токен сброса недействителен или истёк".into(),
+        ));
+    };
+    let user_id: i32 = row.try_get("user_id").unwrap_or_default();
+    let login: String = row.try_get("login").unwrap_or_default();
+    let current = row
+        .try_get::<sqlx::types::Json<KdfParams>, _>("kdf_params")
+        .map(|p| p.0)
+        .unwrap_or_else(|_| KdfParams::legacy_pbkdf2());
+    let kdf_params = resolve_kdf_params_update(payload.kdf_params, current)?;
+
+    sqlx::query(
+        "UPDATE users SET password = $2, pkebymk = $3, salt = $4, kdf_params = $5 WHERE id = $1",
+    )
+    .bind(user_id)
+    .bind(&password_hash)
+    .bind(&payload.pkebymk)
+    .bind(&payload.salt)
+    .bind(sqlx::types::Json(&kdf_params))
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    // Погашаем этот и все прочие незавершённые вызовы пользователя
+    sqlx::query(
+        "UPDATE recovery_challenges SET used_at = now() WHERE user_id = $1 AND used_at IS NULL",
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    let revoked = revoke_user_sessions(&mut tx, user_id, None).await?;
+
+    tx.commit().await.map_err(db_error)?;
+
+    state
+        .auth_rate_limiter
+        .record_success(&ip, Some(&limiter_account(&login)))
+        .await;
+    publish_session_revoked(&state, user_id, &revoked, EVENT_ACCOUNT_RECOVERED);
+    publish_device_keys_changed(&state, user_id).await;
+    log_security_event(
+        &state,
+        &headers,
+        addr,
+        user_id,
+        None,
+        EVENT_ACCOUNT_RECOVERED,
+        Some(json!({ "revoked_session_ids": revoked })),
+    )
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_recovery_key(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    Json(payload): Json<SetRecoveryKeyRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    parse_recovery_pubk(&payload.recovery_pubk)?;
+
+    let row = sqlx::query("SELECT login, password FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(db_error)?
+        .ok_or((StatusCode::NOT_FOUND, "Пользователь не найден".into()))?;
+    let login: String = row.try_get("login").unwrap_or_default();
+    let hashed: String = row.try_get("password").unwrap_or_default();
+
+    // Подмена ключа восстановления = захват аккаунта, поэтому требуем пароль
+    let ip = addr.ip().to_string();
+    if !state.auth_rate_limiter.is_allowed(&ip, Some(&login)).await {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "Слишком много попыток. Повторите позже.".into(),
+        ));
+    }
+    if !verify_password(&hashed, &payload.password)? {
+        let (_allowed, _lockout_secs) = state
+            .auth_rate_limiter
+            .record_failure(&ip, Some(&login))
+            .await;
+        return Err((StatusCode::UNAUTHORIZED, "Неверный пароль".into()));
+    }
+    state
+        .auth_rate_limiter
+        .record_success(&ip, Some(&login))
+        .await;
+
+    sqlx::query("UPDATE users SET recovery_pubk = $2 WHERE id = $1")
+        .bind(user_id)
+        .bind(payload.recovery_pubk.trim())
+        .execute(&state.pool)
+        .await
+        .map_err(db_error)?;
+
+    // Незавершённые восстановления со старым ключом больше недействительны
+    sqlx::query(
+        "UPDATE recovery_challenges SET used_at = now() WHERE user_id = $1 AND used_at IS NULL",
+    )
+    .bind(user_id)
+    .execute(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{challenge_message, parse_recovery_pubk, verify_challenge_signature};
+    use base64::{Engine as _, engine::general_purpose};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signed(key: &SigningKey, challenge_id: &str, challenge: &str) -> String {
+        let signature = key.sign(challenge_message(challenge_id, challenge).as_bytes());
+        general_purpose::STANDARD.encode(signature.to_bytes())
+    }
+
+    #[test]
+    fn challenge_signature_roundtrip() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let pubk = general_purpose::STANDARD.encode(key.verifying_key().as_bytes());
+        let signature = signed(&key, "id-1", "Y2hhbGxlbmdl");
+
+        assert!(verify_challenge_signature(
+            &pubk,
+            "id-1",
+            "Y2hhbGxlbmdl",
+            &signature
+        ));
+        // Подпись привязана к конкретному вызову
+        assert!(!verify_challenge_signature(
+            &pubk,
+            "id-2",
+            "Y2hhbGxlbmdl",
+            &signature
+        ));
+        assert!(!verify_challenge_signature(
+            &pubk, "id-1", "b3RoZXI=", &signature
+        ));
+    }
+
+    #[test]
+    fn challenge_signature_rejects_foreign_key_and_garbage() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let other = SigningKey::from_bytes(&[9u8; 32]);
+        let other_pubk = general_purpose::STANDARD.encode(other.verifying_key().as_bytes());
+        let signature = signed(&key, "id-1", "c");
+
+        assert!(!verify_challenge_signature(
+            &other_pubk,
+            "id-1",
+            "c",
+            &signature
+        ));
+        assert!(!verify_challenge_signature(
+            &other_pubk,
+            "id-1",
+            "c",
+            "not-base64!"
+        ));
+        assert!(parse_recovery_pubk("AAAA").is_err());
+    }
+}
@@ -0,0 +1,398 @@
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    routing::get,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::{PgPool, Row, postgres::PgRow};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::middleware::CurrentUser;
+use crate::route::auth::{extract_ip, resolve_city};
+use crate::route::ws::publish_payload_to_users;
+
+// Журнал событий безопасности аккаунта (таблица security_events):
+// - GET /users/me/security-events — события текущего пользователя, новые сначала
+pub fn router() -> Router<AppState> {
+    Router::new().route("/users/me/security-events", get(list_security_events))
+}
+
+pub const EVENT_LOGIN: &str = "login";
+pub const EVENT_LOGIN_FAILED: &str = "login_failed";
+pub const EVENT_LOGOUT: &str = "logout";
+pub const EVENT_SESSION_REVOKED: &str = "session_revoked";
+pub const EVENT_PASSWORD_CHANGED: &str = "password_changed";
+pub const EVENT_KDF_UPGRADED: &str = "kdf_upgraded";
+pub const EVENT_ACCOUNT_RECOVERED: &str = "account_recovered";
+pub const EVENT_DEVICE_KEY_REGISTERED: &str = "device_key_registered";
+pub const EVENT_PASSKEY_ADDED: &str = "passkey_added";
+pub const EVENT_PASSKEY_REMOVED: &str = "passkey_removed";
+pub const EVENT_TOTP_ENABLED: &str = "totp_enabled";
+pub const EVENT_TOTP_DISABLED: &str = "totp_disabled";
+pub const EVENT_BACKUP_CODES_REGENERATED: &str = "backup_codes_regenerated";
+// Заменённый refresh-токен предъявлен повторно — сессия отозвана
+pub const EVENT_REFRESH_TOKEN_REUSE: &str = "refresh_token_reuse";
+
+// Способ входа (details.method у login / login_failed)
+pub const LOGIN_METHOD_PASSWORD: &str = "password";
+pub const LOGIN_METHOD_TOTP: &str = "totp";
+pub const LOGIN_METHOD_PASSKEY: &str = "passkey";
+pub const LOGIN_METHOD_DEVICE_LINK: &str = "device_link";
+
+const UNKNOWN_CITY: &str = "Unknown";
+
+// Неудачные входы одного аккаунта с одного IP тем же способом в пределах окна
+// сливаются в одну запись (details.count, details.last_at): перебор пароля
+// не раздувает журнал и не вызывает определение города на каждую попытку
+const LOGIN_FAILED_MERGE_WINDOW_SECS: i64 = 15 * 60;
+
+#[derive(Deserialize)]
+struct SecurityEventsQuery {
+    before_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct SecurityEventResponse {
+    id: i64,
+    kind: String,
+    session_id: Option<String>,
+    ip_address: Option<String>,
+    city: Option<String>,
+    details: Option<Value>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct SecurityEventsResponse {
+    events: Vec<SecurityEventResponse>,
+    next_before_id: Option<i64>,
+}
+
+fn db_error(e: sqlx::Error) -> (StatusCode, String) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Ошибка БД: {}", e),
+    )
+}
+
+fn event_from_row(row: &PgRow) -> SecurityEventResponse {
+    SecurityEventResponse {
+        id: row.try_get("id").unwrap_or_default(),
+        kind: row.try_get("kind").unwrap_or_default(),
+        session_id: row
+            .try_get::<Option<Uuid>, _>("session_id")
+            .ok()
+            .flatten()
+            .map(|id| id.to_string()),
+        ip_address: row.try_get("ip_address").ok().flatten(),
+        city: row.try_get("city").ok().flatten(),
+        details: row.try_get("details").ok().flatten(),
+        created_at: row
+            .try_get::<DateTime<Utc>, _>("created_at")
+            .unwrap_or_else(|_| Utc::now()),
+    }
+}
+
+async fn list_security_events(
+    State(state): State<AppState>,
+    CurrentUser { id: user_id, .. }: CurrentUser,
+    Query(q): Query<SecurityEventsQuery>,
+) -> Result<Json<SecurityEventsResponse>, (StatusCode, String)> {
+    let limit = q.limit.unwrap_or(50).clamp(1, 100);
+
+    let rows = sqlx::query(
+        r#"
+        SELECT id, kind, session_id, ip_address, city, details, created_at
+        FROM security_events
+        WHERE user_id = $1
+          AND ($2::INT8 IS NULL OR id < $2)
+        ORDER BY id DESC
+        LIMIT $3
+        "#,
+    )
+    .bind(user_id)
+    .bind(q.before_id)
+    .bind(limit)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(db_error)?;
+
+    let events: Vec<SecurityEventResponse> = rows.iter().map(event_from_row).collect();
+    let next_before_id = if events.len() as i64 == limit {
+        events.last().map(|e| e.id)
+    } else {
+        None
+    };
+
+    Ok(Json(SecurityEventsResponse {
+        events,
+        next_before_id,
+    }))
+}
+
+// Запись события; ip/city — как в auth_sessions (extract_ip / resolve_city)
+pub(crate) async fn record_security_event(
+    pool: &PgPool,
+    user_id: i32,
+    session_id: Option<Uuid>,
+    kind: &str,
+    ip_address: &str,
+    city: &str,
+    details: Option<Value>,
+) -> Result<SecurityEventResponse, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        INSERT INTO security_events (user_id, session_id, kind, ip_address, city, details)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, kind, session_id, ip_address, city, details, created_at
+        "#,
+    )
+    .bind(user_id)
+    .bind(session_id)
+    .bind(kind)
+    .bind(ip_address)
+    .bind(city)
+    .bind(details)
+    .fetch_one(pool)
+    .await?;
+    Ok(event_from_row(&row))
+}
+
+// Запись события из обработчика запроса. Само действие к этому моменту уже
+// выполнено, поэтому ошибка журнала только выводится в лог.
+pub(crate) async fn log_security_event(
+    state: &AppState,
+    headers: &HeaderMap,
+    addr: SocketAddr,
+    user_id: i32,
+    session_id: Option<Uuid>,
+    kind: &str,
+    details: Option<Value>,
+) {
+    let ip_address = extract_ip(state, headers, addr);
+    let city = resolve_city(state, headers, &ip_address).await;
+    if let Err(e) = record_security_event(
+        &state.pool,
+        user_id,
+        session_id,
+        kind,
+        &ip_address,
+        &city,
+        details,
+    )
+    .await
+    {
+        tracing::error!(kind, error = %e, "Ошибка записи события безопасности");
+    }
+}
+
+// Неудачный вход (login_failed). Повтор в окне LOGIN_FAILED_MERGE_WINDOW_SECS
+// только увеличивает счётчик уже существующей записи.
+pub(crate) async fn log_failed_login(
+    state: &AppState,
+    headers: &HeaderMap,
+    addr: SocketAddr,
+    user_id: i32,
+    method: &str,
+) {
+    let ip_address = extract_ip(state, headers, addr);
+    match merge_failed_login(&state.pool, user_id, &ip_address, method).await {
+        Ok(true) => return,
+        Ok(false) => {}
+        Err(e) => {
+            tracing::error!(kind = EVENT_LOGIN_FAILED, error = %e, "Ошибка записи события безопасности");
+            return;
+        }
+    }
+    let city = resolve_city(state, headers, &ip_address).await;
+    if let Err(e) = record_security_event(
+        &state.pool,
+        user_id,
+        None,
+        EVENT_LOGIN_FAILED,
+        &ip_address,
+        &city,
+        Some(json!({ "method": method, "count": 1, "last_at": Utc::now() })),
+    )
+    .await
+    {
+        tracing::error!(kind = EVENT_LOGIN_FAILED, error = %e, "Ошибка записи события безопасности");
+    }
+}
+
+// Добавляет попытку к свежей записи login_failed; false — такой записи нет
+async fn merge_failed_login(
+    pool: &PgPool,
+    user_id: i32,
+    ip_address: &str,
+    method: &str,
+) -> Result<bool, sqlx::Error> {
+    let merged = sqlx::query(
+        r#"
+        UPDATE security_events
+        SET details = details || jsonb_build_object(
+            'count', COALESCE((details->>'count')::INT, 1) + 1,
+            'last_at', now()
+        )
+        WHERE id = (
+            SELECT id
+            FROM security_events
+            WHERE user_id = $1
+              AND kind = $2
+              AND ip_address = $3
+              AND details->>'method' = $4
+              AND created_at > now() - make_interval(secs => $5)
+            ORDER BY id DESC
+            LIMIT 1
+        )
+        "#,
+    )
+    .bind(user_id)
+    .bind(EVENT_LOGIN_FAILED)
+    .bind(ip_address)
+    .bind(method)
+    .bind(LOGIN_FAILED_MERGE_WINDOW_SECS as f64)
+    .execute(pool)
+    .await?;
+    Ok(merged.rows_affected() > 0)
+}
+
+// Успешный вход. Если город определён и раньше входов из него не было,
+// все сессии пользователя получают WS-событие security_event.
+pub(crate) async fn log_login(
+    state: &AppState,
+    user_id: i32,
+    session_id: Uuid,
+    ip_address: &str,
+    city: &str,
+    method: &str,
+) {
+    let new_location = match is_new_login_location(&state.pool, user_id, city).await {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::warn!(error = %e, "Ошибка проверки места входа");
+            false
+        }
+    };
+
+    let event = match record_security_event(
+        &state.pool,
+        user_id,
+        Some(session_id),
+        EVENT_LOGIN,
+        ip_address,
+        city,
+        Some(json!({ "method": method, "new_location": new_location })),
+    )
+    .await
+    {
+        Ok(event) => event,
+        Err(e) => {
+            tracing::error!(kind = EVENT_LOGIN, error = %e, "Ошибка записи события безопасности");
+            return;
+        }
+    };
+
+    if new_location {
+        let payload = json!({
+            "type": "security_event",
+            "event": event,
+        })
+        .to_string();
+        publish_payload_to_users(state, &[user_id], payload);
+    }
+}
+
+async fn is_new_login_location(
+    pool: &PgPool,
+    user_id: i32,
+    city: &str,
+) -> Result<bool, sqlx::Error> {
+    if city.trim().is_empty() || city.eq_ignore_ascii_case(UNKNOWN_CITY) {
+        return Ok(false);
+    }
+    // Первый вход в аккаунт новым местом не считается
+    let row = sqlx::query(
+        r#"
+        SELECT
+            COUNT(*) AS total,
+            COUNT(*) FILTER (WHERE lower(city) = lower($2)) AS same_city
+        FROM security_events
+        WHERE user_id = $1 AND kind = $3
+        "#,
+    )
+    .bind(user_id)
+    .bind(city)
+    .bind(EVENT_LOGIN)
+    .fetch_one(pool)
+    .await?;
+    let total: i64 = row.try_get("total").unwrap_or_default();
+    let same_city: i64 = row.try_get("same_city").unwrap_or_default();
+    Ok(total > 0 && same_city == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    async fn failed_logins(pool: &PgPool, user_id: i32) -> Vec<(String, Value)> {
+        sqlx::query(
+            "SELECT ip_address, details FROM security_events WHERE user_id = $1 AND kind = $2",
+        )
+        .bind(user_id)
+        .bind(EVENT_LOGIN_FAILED)
+        .fetch_all(pool)
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| (row.get("ip_address"), row.get("details")))
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn failed_logins_merge_per_ip_and_method() {
+        let Some(state) = test_support::state().await else {
+            return;
+        };
+        let user_id = test_support::user(&state.pool).await;
+        let headers = HeaderMap::new();
+        let first: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+
+        for _ in 0..3 {
+            log_failed_login(&state, &headers, first, user_id, LOGIN_METHOD_PASSWORD).await;
+        }
+        log_failed_login(&state, &headers, first, user_id, LOGIN_METHOD_TOTP).await;
+        log_failed_login(&state, &headers, second, user_id, LOGIN_METHOD_PASSWORD).await;
+
+        let events = failed_logins(&state.pool, user_id).await;
+        assert_eq!(events.len(), 3);
+        let count = |ip: &str, method: &str| {
+            events
+                .iter()
+                .find(|(i, d)| i == ip && d["method"] == method)
+                .map(|(_, d)| d["count"].as_i64().unwrap())
+        };
+        assert_eq!(count("10.0.0.1", LOGIN_METHOD_PASSWORD), Some(3));
+        assert_eq!(count("10.0.0.1", LOGIN_METHOD_TOTP), Some(1));
+        assert_eq!(count("10.0.0.2", LOGIN_METHOD_PASSWORD), Some(1));
+
+        // За пределами окна попытка начинает новую запись
+        sqlx::query(
+            "UPDATE security_events SET created_at = now() - interval '1 day' WHERE user_id = $1",
+        )
+        .bind(user_id)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+        log_failed_login(&state, &headers, first, user_id, LOGIN_METHOD_PASSWORD).await;
+        assert_eq!(failed_logins(&state.pool, user_id).await.len(), 4);
+    }
+}
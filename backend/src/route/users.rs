@@ -6,9 +6,10 @@ use axum::{
     routing::{get, patch},
 };
 use serde::{Deserialize, Serialize};
-use sqlx::Row;
+use sqlx::{PgPool, Row};
 
 use crate::AppState;
+use crate::config::StorageConfig;
 use crate::middleware::CurrentUser; // экстрактор текущего пользователя
 use crate::models::auth::UserResponse; // используем общую модель пользователя
 use crate::route::ws::publish_profile_updated_for_user;
@@ -58,6 +59,8 @@ async fn search_users(
     let id_q: Option<i32> = q.parse::<i32>().ok();
     let like = format!("%{}%", q);
 
+    // Не показываем заблокировавших меня и скрытых настройкой search_visibility
+    // (contacts — только собеседникам по private-чатам)
     let rows = sqlx::query(
         r#"
         SELECT id, login, username, nickname, avatar
@@ -67,6 +70,22 @@ async fn search_users(
             ($1::int IS NOT NULL AND id = $1::int)
             OR username ILIKE $2
           )
+          AND NOT EXISTS (
+            SELECT 1 FROM user_blocks b WHERE b.blocker_id = users.id AND b.blocked_id = $3
+          )
+          AND (
+            search_visibility = 'everyone'
+            OR (
+              search_visibility = 'contacts'
+              AND EXISTS (
+                SELECT 1
+                FROM chats c
+                JOIN chat_participants p1 ON p1.chat_id = c.id AND p1.user_id = users.id
+                JOIN chat_participants p2 ON p2.chat_id = c.id AND p2.user_id = $3
+                WHERE c.kind = 'private'
+              )
+            )
+          )
         ORDER BY
           CASE WHEN ($1::int IS NOT NULL AND id = $1::int) THEN 0 ELSE 1 END,
           username ASC
@@ -331,10 +350,9 @@ async fn update_avatar(
         // Удаляем аватар (устанавливаем в NULL)
         None
     } else if let Some(data) = avatar_data {
-        // Лимит на размер аватара (защита от загрузки огромных файлов в память)
-        // 5MB должно быть достаточно для большинства аватаров.
-        const MAX_AVATAR_BYTES: usize = 5 * 1024 * 1024;
-        if data.len() > MAX_AVATAR_BYTES {
+        // Лимит на размер аватара (защита от загрузки огромных файлов в память),
+        // storage.max_avatar_bytes в конфигурации
+        if data.len() > state.config.storage.max_avatar_bytes {
             return Err((
                 StatusCode::BAD_REQUEST,
                 "Слишком большой файл аватара".into(),
@@ -342,8 +360,8 @@ async fn update_avatar(
         }
 
         // Создаем директорию для аватаров, если её нет
-        let avatars_dir = Path::new("uploads/avatars");
-        fs::create_dir_all(avatars_dir).await.map_err(|e| {
+        let avatars_dir = state.config.storage.path("avatars");
+        fs::create_dir_all(&avatars_dir).await.map_err(|e| {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Не удалось создать директорию для аватаров: {}", e),
@@ -364,7 +382,7 @@ async fn update_avatar(
         };
 
         // Генерируем путь с использованием id пользователя
-        let new_path = format!("uploads/avatars/user_{}.{}", id, extension);
+        let new_path = avatars_dir.join(format!("user_{}.{}", id, extension));
 
         // Сохраняем файл
         let mut file = fs::File::create(&new_path).await.map_err(|e| {
@@ -385,6 +403,11 @@ async fn update_avatar(
                 format!("Не удалось синхронизировать файл аватара: {}", e),
             )
         })?;
+        state
+            .metrics
+            .upload_bytes
+            .with_label_values(&["avatar"])
+            .inc_by(data.len() as u64);
 
         Some(format!("avatars/user_{}.{}", id, extension))
     } else {
@@ -395,7 +418,7 @@ async fn update_avatar(
     // Удаляем старый файл, если он был и мы его меняем
     if let Some(ref old_path) = old_avatar_path {
         if new_avatar_path.as_ref() != Some(old_path) {
-            let full_old_path = Path::new("uploads").join(old_path);
+            let full_old_path = state.config.storage.path(old_path);
             if full_old_path.exists() {
                 let _ = fs::remove_file(&full_old_path).await; // Игнорируем ошибки удаления
             }
@@ -439,10 +462,7 @@ async fn delete_me(
     State(state): State<AppState>,
     CurrentUser { id, .. }: CurrentUser,
 ) -> Result<StatusCode, (StatusCode, String)> {
-    // Получаем путь к аватару перед удалением пользователя
-    let avatar_row = sqlx::query("SELECT avatar FROM users WHERE id = $1")
-        .bind(id)
-        .fetch_optional(&state.pool)
+    delete_user(&state.pool, &state.config.storage, id)
         .await
         .map_err(|e| {
             (
@@ -451,23 +471,36 @@ async fn delete_me(
             )
         })?;
 
-    // Удаляем текущего пользователя
-    sqlx::query("DELETE FROM users WHERE id = $1")
+    // Сессии удалены каскадом вместе с пользователем — закрываем их websocket'ы
+    state.sessions.revoke_user(id, "account_deleted");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Удаляет пользователя (данные в БД — каскадом) и файл его аватара.
+// Возвращает false, если пользователя не было. Живые websocket-соединения
+// закрывает вызывающий (SessionRegistry::revoke_user). Используется и из CLI.
+pub(crate) async fn delete_user(
+    pool: &PgPool,
+    storage: &StorageConfig,
+    id: i32,
+) -> Result<bool, sqlx::Error> {
+    // Получаем путь к аватару перед удалением пользователя
+    let avatar_row = sqlx::query("SELECT avatar FROM users WHERE id = $1")
         .bind(id)
-        .execute(&state.pool)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Ошибка БД: {}", e),
-            )
-        })?;
+        .fetch_optional(pool)
+        .await?;
+
+    let result = sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await?;
 
     // Удаляем файл аватара, если он был
     if let Some(row) = avatar_row {
         if let Ok(avatar_path) = row.try_get::<Option<String>, _>("avatar") {
             if let Some(path) = avatar_path {
-                let full_path = Path::new("uploads").join(&path);
+                let full_path = storage.path(&path);
                 if full_path.exists() {
                     let _ = fs::remove_file(&full_path).await; // Игнорируем ошибки
                 }
@@ -475,7 +508,7 @@ async fn delete_me(
         }
     }
 
-    Ok(StatusCode::NO_CONTENT)
+    Ok(result.rows_affected() > 0)
 }
 
 // Модель для ответа с публичным ключом
@@ -561,6 +594,7 @@ async fn get_public_key(
 
 // Хендлер для получения файла аватара
 async fn get_avatar(
+    State(state): State<AppState>,
     PathExtractor(path): PathExtractor<String>,
 ) -> Result<Response, (StatusCode, String)> {
     // Защита от path traversal: разрешаем только "нормальные" компоненты пути.
@@ -581,7 +615,7 @@ async fn get_avatar(
         return Err((StatusCode::BAD_REQUEST, "Некорректный путь".into()));
     }
 
-    let file_path = Path::new("uploads").join(rel);
+    let file_path = state.config.storage.path(rel);
 
     let content = fs::read(&file_path)
         .await
@@ -606,4 +640,69 @@ async fn get_avatar(
                 format!("Ошибка создания ответа: {}", e),
             )
         })?)
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    async fn found(state: &AppState, me: i32, target: i32) -> bool {
+        let Json(users) = search_users(
+            State(state.clone()),
+            test_support::current(me),
+            Query(SearchUsersQuery {
+                q: target.to_string(),
+                limit: None,
+            }),
+        )
+        .await
+        .unwrap();
+        users.iter().any(|u| u.id == target)
+    }
+
+    async fn set_visibility(pool: &sqlx::PgPool, user_id: i32, visibility: &str) {
+        sqlx::query("UPDATE users SET search_visibility = $2 WHERE id = $1")
+            .bind(user_id)
+            .bind(visibility)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn search_respects_visibility_and_blocks() {
+        let Some(state) = test_support::state().await else {
+            return;
+        };
+        let pool = state.pool.clone();
+        let (me, target, stranger) = (
+            test_support::user(&pool).await,
+            test_support::user(&pool).await,
+            test_support::user(&pool).await,
+        );
+        test_support::private_chat(&pool, me, target).await;
+
+        assert!(found(&state, me, target).await);
+        assert!(found(&state, stranger, target).await);
+
+        set_visibility(&pool, target, "contacts").await;
+        assert!(found(&state, me, target).await);
+        assert!(!found(&state, stranger, target).await);
+
+        set_visibility(&pool, target, "nobody").await;
+        assert!(!found(&state, me, target).await);
+
+        // Заблокировавший меня не находится даже при открытом поиске
+        set_visibility(&pool, target, "everyone").await;
+        sqlx::query("INSERT INTO user_blocks (blocker_id, blocked_id) VALUES ($1, $2)")
+            .bind(target)
+            .bind(me)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(!found(&state, me, target).await);
+        assert!(found(&state, stranger, target).await);
+    }
 }
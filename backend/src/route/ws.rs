@@ -1,25 +1,42 @@
 use axum::{
     Router,
-    extract::State,
-    extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+    extract::ws::{CloseFrame, Message as WsMessage, WebSocket, WebSocketUpgrade},
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
     response::IntoResponse,
     routing::get,
 };
 use futures_util::{SinkExt, StreamExt};
+use rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_json::json;
 use sqlx::Row;
 use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::{
     sync::{broadcast, mpsc},
     task::JoinHandle,
 };
+use tracing::Instrument;
+use uuid::Uuid;
 
 use crate::AppState;
-use crate::middleware::{CurrentUser, ensure_can_send_message, ensure_member};
+use crate::metrics::Metrics;
+use crate::middleware::rate_limit::rate_limit_subject;
+use crate::middleware::{
+    CurrentUser, ensure_can_delete_for_everyone, ensure_can_send_message, ensure_member,
+};
 use crate::models::auth::UserResponse;
-use crate::models::chats::{FileMetadata, Message};
+use crate::models::chats::{BatchItemResult, FileMetadata, ForwardItem, Message};
+use crate::route::auth::extract_ip;
+use crate::route::devices::filter_device_envelopes;
+use crate::route::messages::{
+    delete_messages_batch, forward_messages_batch, remove_search_tokens, save_search_tokens,
+};
+use crate::route::privacy::{blocked_contacts, typing_recipients};
 
 pub fn router() -> Router<AppState> {
     Router::new().route("/ws", get(ws_handler))
@@ -28,11 +45,42 @@ pub fn router() -> Router<AppState> {
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
-    CurrentUser { id: user_id, .. }: CurrentUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    CurrentUser {
+        id: user_id,
+        session_id,
+    }: CurrentUser,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state, user_id))
+    let ip = extract_ip(&state, &headers, addr);
+    // Соединение живёт дольше HTTP-запроса апгрейда: свой спан, события — дочерние
+    let span = tracing::info_span!(
+        "ws_connection",
+        user_id,
+        session_id = %session_id,
+        client_ip = %ip,
+    );
+    // При остановке сервер дожидается завершения обработчика (см. shutdown)
+    let shutdown = state.shutdown.clone();
+    ws.on_upgrade(move |socket| {
+        shutdown
+            .track_socket(handle_socket(socket, state, user_id, session_id, ip).instrument(span))
+    })
 }
 
+// Код закрытия websocket при отзыве сессии (диапазон 4000–4999 — для приложений);
+// причина закрытия — та же, что в событии session_revoked.
+const SESSION_REVOKED_CLOSE_CODE: u16 = 4001;
+// Код закрытия при остановке сервера: 1012 (Service Restart)
+const SERVER_SHUTDOWN_CLOSE_CODE: u16 = 1012;
+// Пока сокеты закрываются, они ещё пересылают offline-события друг друга
+const SHUTDOWN_PRESENCE_GRACE: Duration = Duration::from_millis(500);
+// Сколько writer дописывает очередь исходящих после close-фрейма
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+// Разброс подсказки переподключения, чтобы клиенты не вернулись все разом
+const RECONNECT_AFTER_MS_MIN: u64 = 1_000;
+const RECONNECT_AFTER_MS_MAX: u64 = 5_000;
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ClientEvent {
@@ -53,6 +101,7 @@ enum ClientEvent {
         metadata: Option<Vec<FileMetadata>>, // метаданные файлов
         reply_to_message_id: Option<i64>,
         client_message_id: Option<String>,   // P1-6: Client-provided UUID for idempotency
+        search_tokens: Option<Vec<String>>,  // слепой индекс отправителя (опционально)
     },
     VoiceMessage {
         chat_id: i32,
@@ -62,6 +111,7 @@ enum ClientEvent {
         metadata: Option<Vec<FileMetadata>>,
         reply_to_message_id: Option<i64>,
         client_message_id: Option<String>,   // P1-6: Client-provided UUID for idempotency
+        search_tokens: Option<Vec<String>>,  // слепой индекс отправителя (опционально)
     },
     VideoMessage {
         chat_id: i32,
@@ -71,6 +121,7 @@ enum ClientEvent {
         metadata: Option<Vec<FileMetadata>>,
         reply_to_message_id: Option<i64>,
         client_message_id: Option<String>,   // P1-6: Client-provided UUID for idempotency
+        search_tokens: Option<Vec<String>>,  // слепой индекс отправителя (опционально)
     },
     EditMessage {
         chat_id: i32,
@@ -79,10 +130,12 @@ enum ClientEvent {
         message_type: Option<String>,
         envelopes: Option<Value>,
         metadata: Option<Vec<FileMetadata>>,
+        search_tokens: Option<Vec<String>>, // заменяет слепой индекс автора
     },
     DeleteMessage {
         chat_id: i32,
         message_id: i64,
+        scope: Option<String>, // 'everyone' (по умолчанию) | 'me'
     },
     ForwardMessage {
         from_chat_id: i32,
@@ -94,6 +147,17 @@ enum ClientEvent {
         metadata: Option<Vec<FileMetadata>>,
         client_message_id: Option<String>,   // P1-6: Client-provided UUID for idempotency
     },
+    // Пакетные операции: одна транзакция и одно событие на весь пакет
+    DeleteMessages {
+        chat_id: i32,
+        message_ids: Vec<i64>,
+        scope: Option<String>, // 'everyone' (по умолчанию) | 'me'
+    },
+    ForwardMessages {
+        from_chat_id: i32,
+        to_chat_id: i32,
+        items: Vec<ForwardItem>,
+    },
     Typing {
         chat_id: i32,
         is_typing: bool,
@@ -120,6 +184,7 @@ enum ServerEvent<'a> {
         message_id: i64,
         deleted_at: String,
         deleted_by: i64,
+        scope: &'a str, // 'everyone' | 'me'
     },
     Typing {
         chat_id: i32,
@@ -133,6 +198,46 @@ enum ServerEvent<'a> {
     ProfileUpdated {
         user: UserResponse,
     },
+    // Результаты пакетной операции по каждому сообщению (только инициатору)
+    BatchResult {
+        op: &'a str, // 'delete' | 'forward'
+        chat_id: i32,
+        results: Vec<BatchItemResult>,
+    },
+    // Событие отброшено лимитом частоты; повторить не раньше чем через retry_after секунд
+    RateLimited {
+        event: &'a str,
+        retry_after: u64,
+    },
+    // Сервер останавливается; переподключиться через reconnect_after_ms
+    ServerShutdown {
+        reconnect_after_ms: u64,
+    },
+}
+
+fn reconnect_after_ms() -> u64 {
+    RECONNECT_AFTER_MS_MIN
+        + OsRng.next_u64() % (RECONNECT_AFTER_MS_MAX - RECONNECT_AFTER_MS_MIN + 1)
+}
+
+// Тип клиентского события и его чат — для политики rate limit и спана до полного разбора
+#[derive(Deserialize)]
+struct ClientEventType {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    chat_id: Option<Value>,
+    #[serde(default)]
+    to_chat_id: Option<Value>,
+}
+
+impl ClientEventType {
+    fn chat_id(&self) -> Option<i64> {
+        self.chat_id
+            .as_ref()
+            .or(self.to_chat_id.as_ref())
+            .and_then(Value::as_i64)
+    }
 }
 
 #[derive(Serialize)]
@@ -421,7 +526,101 @@ pub fn publish_message_delivered(
     publish_payload_to_users(state, recipients, payload);
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState, user_id: i32) {
+pub fn publish_messages_deleted(
+    state: &AppState,
+    recipients: &[i32],
+    chat_id: i32,
+    message_ids: &[i64],
+    deleted_at: &str,
+    deleted_by: i32,
+    scope: &str,
+) {
+    let payload = json!({
+        "type": "messages_deleted",
+        "chat_id": chat_id,
+        "message_ids": message_ids,
+        "deleted_at": deleted_at,
+        "deleted_by": deleted_by,
+        "scope": scope
+    })
+    .to_string();
+    publish_payload_to_users(state, recipients, payload);
+}
+
+pub fn publish_messages_forwarded(
+    state: &AppState,
+    recipients: &[i32],
+    chat_id: i32,
+    from_chat_id: i32,
+    messages: &[Message],
+    forwarded_by: i32,
+) {
+    let payload = json!({
+        "type": "messages_forwarded",
+        "chat_id": chat_id,
+        "from_chat_id": from_chat_id,
+        "messages": messages,
+        "forwarded_by": forwarded_by
+    })
+    .to_string();
+    publish_payload_to_users(state, recipients, payload);
+}
+
+// Уведомляет устройства пользователя об отзыве их сессий: клиент, чей session_id
+// есть в списке, должен завершить сеанс и очистить локальные данные.
+// Websocket-соединения отозванных сессий закрываются сразу.
+pub fn publish_session_revoked(
+    state: &AppState,
+    user_id: i32,
+    session_ids: &[String],
+    reason: &str,
+) {
+    if session_ids.is_empty() {
+        return;
+    }
+    let payload = json!({
+        "type": "session_revoked",
+        "session_ids": session_ids,
+        "reason": reason
+    })
+    .to_string();
+    publish_payload_to_users(state, &[user_id], payload);
+    state.sessions.revoke(
+        session_ids.iter().filter_map(|id| Uuid::parse_str(id).ok()),
+        reason,
+    );
+}
+
+// Пересылает события broadcast-канала в сокет. Отставший подписчик
+// (переполнен буфер канала) теряет часть событий, но не отключается.
+fn spawn_forwarder(
+    mut rx: broadcast::Receiver<String>,
+    out_tx: mpsc::UnboundedSender<WsMessage>,
+    metrics: Arc<Metrics>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(msg) => {
+                    let _ = out_tx.send(WsMessage::Text(msg));
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    metrics.ws_broadcast_lagged.inc();
+                    metrics.ws_broadcast_skipped.inc_by(skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    user_id: i32,
+    session_id: Uuid,
+    ip: String,
+) {
     // Канал для записи в websocket из разных задач
     let (out_tx, mut out_rx) = mpsc::unbounded_channel::<WsMessage>();
 
@@ -431,12 +630,20 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: i32) {
     // Задача writer: отправляет всё, что приходит в out_rx, в сокет
     let writer = tokio::spawn(async move {
         while let Some(msg) = out_rx.recv().await {
-            if ws_sender.send(msg).await.is_err() {
+            let is_close = matches!(msg, WsMessage::Close(_));
+            if ws_sender.send(msg).await.is_err() || is_close {
                 break;
             }
         }
     });
 
+    // Сокет привязан к сессии: при её отзыве (logout, удаление сессии, смена пароля)
+    // реестр присылает причину, и соединение закрывается.
+    let (socket_id, mut revoked_rx) = state.sessions.register_socket(session_id, user_id);
+    let mut revoked = false;
+    let mut shutting_down = false;
+    state.metrics.ws_connections.inc();
+
     let mut subs = Subscriptions {
         joined: HashSet::new(),
         forwarders: HashMap::new(),
@@ -489,201 +696,404 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: i32) {
     // Подписываем каждое соединение на личный канал пользователя сразу после апгрейда.
     ensure_user_channel(&state, user_id);
     let tx = state.user_hub.get(&user_id).unwrap().clone();
-    let mut rx = tx.subscribe();
+    let rx = tx.subscribe();
     let out_tx_clone = out_tx.clone();
-    subs.user_forwarder = Some(tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            let _ = out_tx_clone.send(WsMessage::Text(msg));
-        }
-    }));
+    subs.user_forwarder = Some(spawn_forwarder(rx, out_tx_clone, state.metrics.clone()));
 
     // Обработка входящих сообщений клиента
-    while let Some(Ok(msg)) = ws_receiver.next().await {
+    loop {
+        let next = tokio::select! {
+            reason = &mut revoked_rx => {
+                let reason = reason.unwrap_or_else(|_| "session_revoked".to_string());
+                let _ = out_tx.send(WsMessage::Close(Some(CloseFrame {
+                    code: SESSION_REVOKED_CLOSE_CODE,
+                    reason: reason.into(),
+                })));
+                revoked = true;
+                break;
+            }
+            _ = state.shutdown.triggered() => {
+                if let Ok(evt) = serde_json::to_string(&ServerEvent::ServerShutdown {
+                    reconnect_after_ms: reconnect_after_ms(),
+                }) {
+                    let _ = out_tx.send(WsMessage::Text(evt));
+                }
+                shutting_down = true;
+                break;
+            }
+            next = ws_receiver.next() => next,
+        };
+        let Some(Ok(msg)) = next else {
+            break;
+        };
         match msg {
             WsMessage::Text(text) => {
-                let parsed: Result<ClientEvent, _> = serde_json::from_str(&text);
-                match parsed {
-                    Ok(ClientEvent::Init { contacts }) => {
-                        let next_contacts: HashSet<i32> = contacts.into_iter().collect();
-                        let old_contacts = std::mem::replace(&mut subs.contacts, next_contacts);
-
-                        // Отправляем снимок online/offline по текущим контактам инициатору.
-                        for cid in &subs.contacts {
-                            let status = if is_user_online(&state, *cid) {
-                                "online"
-                            } else {
-                                "offline"
-                            };
-                            if let Ok(evt) = serde_json::to_string(&ServerEvent::Presence {
-                                user_id: *cid,
-                                status,
-                            }) {
-                                let _ = out_tx.send(WsMessage::Text(evt));
-                            }
+                let event = serde_json::from_str::<ClientEventType>(&text).ok();
+                let event_type = event.as_ref().map(|e| e.kind.clone()).unwrap_or_default();
+                // Спан на событие клиента: тип и chat_id, без содержимого сообщения
+                let event_span = tracing::info_span!(
+                    "ws_event",
+                    event = %event_type,
+                    chat_id = event.as_ref().and_then(ClientEventType::chat_id),
+                );
+                async {
+                    let policy = state.rate_limiter.ws_policy(&event_type);
+                    let subject =
+                        rate_limit_subject(policy.key, &ip, Some(user_id), Some(session_id));
+                    let decision = state.rate_limiter.check(&policy, &subject).await;
+                    if let Some(retry_after) = decision.retry_after {
+                        state
+                            .metrics
+                            .rate_limit_rejections
+                            .with_label_values(&[policy.name])
+                            .inc();
+                        if let Ok(evt) = serde_json::to_string(&ServerEvent::RateLimited {
+                            event: &event_type,
+                            retry_after: retry_after.as_secs_f64().ceil() as u64,
+                        }) {
+                            let _ = out_tx.send(WsMessage::Text(evt));
                         }
+                        return;
+                    }
 
-                        // Оповещаем контакты о нашем online:
-                        // 1) при первом онлайн сокете — всех старых/новых контактов;
-                        // 2) при обновлении списка — только новых.
-                        let contacts_to_notify = if should_announce_online {
-                            subs.contacts
-                                .union(&old_contacts)
-                                .copied()
-                                .collect::<HashSet<_>>()
-                        } else {
-                            subs.contacts
-                                .difference(&old_contacts)
-                                .copied()
-                                .collect::<HashSet<_>>()
-                        };
-
-                        if !contacts_to_notify.is_empty() {
-                            let presence_evt = match serde_json::to_string(&ServerEvent::Presence {
-                                user_id,
-                                status: "online",
-                            }) {
-                                Ok(s) => s,
+                    let parsed: Result<ClientEvent, _> = serde_json::from_str(&text);
+                    match parsed {
+                        Ok(ClientEvent::Init { contacts }) => {
+                            let mut next_contacts: HashSet<i32> = contacts.into_iter().collect();
+                            // С заблокированными (в любую сторону) присутствием не обмениваемся.
+                            // Проверяем и старый список: блокировка могла появиться после
+                            // прошлого init, а старым контактам ниже тоже уходит online.
+                            let candidates: HashSet<i32> =
+                                next_contacts.union(&subs.contacts).copied().collect();
+                            match blocked_contacts(&state.pool, user_id, &candidates).await {
+                                Ok(blocked) => {
+                                    next_contacts.retain(|cid| !blocked.contains(cid));
+                                    subs.contacts.retain(|cid| !blocked.contains(cid));
+                                }
                                 Err(_) => {
-                                    let _ = out_tx.send(WsMessage::Text(
-                                            serde_json::to_string(&ServerEvent::Error {
-                                                error: "Ошибка сериализации",
-                                            })
-                                            .unwrap_or_else(|_| {
-                                                "{\"type\":\"error\",\"error\":\"Ошибка сериализации\"}"
-                                                    .to_string()
-                                            }),
-                                        ));
-                                    continue;
+                                    if let Ok(evt) = serde_json::to_string(&ServerEvent::Error {
+                                        error: "Ошибка БД",
+                                    }) {
+                                        let _ = out_tx.send(WsMessage::Text(evt));
+                                    }
+                                    return;
+                                }
+                            }
+                            let old_contacts = std::mem::replace(&mut subs.contacts, next_contacts);
+
+                            // Отправляем снимок online/offline по текущим контактам инициатору.
+                            for cid in &subs.contacts {
+                                let status = if is_user_online(&state, *cid) {
+                                    "online"
+                                } else {
+                                    "offline"
+                                };
+                                if let Ok(evt) = serde_json::to_string(&ServerEvent::Presence {
+                                    user_id: *cid,
+                                    status,
+                                }) {
+                                    let _ = out_tx.send(WsMessage::Text(evt));
                                 }
+                            }
+
+                            // Оповещаем контакты о нашем online:
+                            // 1) при первом онлайн сокете — всех старых/новых контактов;
+                            // 2) при обновлении списка — только новых.
+                            let contacts_to_notify = if should_announce_online {
+                                subs.contacts
+                                    .union(&old_contacts)
+                                    .copied()
+                                    .collect::<HashSet<_>>()
+                            } else {
+                                subs.contacts
+                                    .difference(&old_contacts)
+                                    .copied()
+                                    .collect::<HashSet<_>>()
                             };
 
-                            for cid in contacts_to_notify {
-                                ensure_user_channel(&state, cid);
-                                publish_user(&state, cid, presence_evt.clone());
+                            if !contacts_to_notify.is_empty() {
+                                let presence_evt = match serde_json::to_string(&ServerEvent::Presence {
+                                    user_id,
+                                    status: "online",
+                                }) {
+                                    Ok(s) => s,
+                                    Err(_) => {
+                                        let _ = out_tx.send(WsMessage::Text(
+                                                serde_json::to_string(&ServerEvent::Error {
+                                                    error: "Ошибка сериализации",
+                                                })
+                                                .unwrap_or_else(|_| {
+                                                    "{\"type\":\"error\",\"error\":\"Ошибка сериализации\"}"
+                                                        .to_string()
+                                                }),
+                                            ));
+                                        return;
+                                    }
+                                };
+
+                                for cid in contacts_to_notify {
+                                    ensure_user_channel(&state, cid);
+                                    publish_user(&state, cid, presence_evt.clone());
+                                }
+                                should_announce_online = false;
                             }
-                            should_announce_online = false;
-                        }
 
-                        let ok_msg = serde_json::to_string(&ServerEvent::Ok)
-                            .unwrap_or_else(|_| "{\"type\":\"ok\"}".to_string());
-                        let _ = out_tx.send(WsMessage::Text(ok_msg));
-                    }
-                    Ok(ClientEvent::JoinChat { chat_id }) => {
-                        if let Err(e) = ensure_member(&state, chat_id, user_id).await {
-                            let err_msg =
-                                serde_json::to_string(&ServerEvent::Error { error: &e.1 })
-                                    .unwrap_or_else(|_| {
-                                        format!(
-                                            "{{\"type\":\"error\",\"error\":{}}}",
-                                            serde_json::to_string(&e.1)
-                                                .unwrap_or_else(|_| "\"Ошибка\"".to_string())
-                                        )
-                                    });
-                            let _ = out_tx.send(WsMessage::Text(err_msg));
-                            continue;
+                            let ok_msg = serde_json::to_string(&ServerEvent::Ok)
+                                .unwrap_or_else(|_| "{\"type\":\"ok\"}".to_string());
+                            let _ = out_tx.send(WsMessage::Text(ok_msg));
                         }
-                        // Получаем или создаём broadcaster для чата
-                        let tx = match state.ws_hub.get(&chat_id) {
-                            Some(existing) => existing.clone(),
-                            None => {
-                                let (tx, _rx) = broadcast::channel::<String>(200);
-                                state.ws_hub.insert(chat_id, tx);
-                                state.ws_hub.get(&chat_id).unwrap().clone()
-                            }
-                        };
-                        // Подписываемся и создаём форвардер в out_tx
-                        let mut rx = tx.subscribe();
-                        let out_tx_clone = out_tx.clone();
-                        let handle = tokio::spawn(async move {
-                            while let Ok(msg) = rx.recv().await {
-                                let _ = out_tx_clone.send(WsMessage::Text(msg));
+                        Ok(ClientEvent::JoinChat { chat_id }) => {
+                            if let Err(e) = ensure_member(&state, chat_id, user_id).await {
+                                let err_msg =
+                                    serde_json::to_string(&ServerEvent::Error { error: &e.1 })
+                                        .unwrap_or_else(|_| {
+                                            format!(
+                                                "{{\"type\":\"error\",\"error\":{}}}",
+                                                serde_json::to_string(&e.1)
+                                                    .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                            )
+                                        });
+                                let _ = out_tx.send(WsMessage::Text(err_msg));
+                                return;
                             }
-                        });
-                        subs.joined.insert(chat_id);
-                        subs.forwarders.insert(chat_id, handle);
-                        let ok_msg = serde_json::to_string(&ServerEvent::Ok)
-                            .unwrap_or_else(|_| "{\"type\":\"ok\"}".to_string());
-                        let _ = out_tx.send(WsMessage::Text(ok_msg));
-                    }
-                    Ok(ClientEvent::LeaveChat { chat_id }) => {
-                        if subs.joined.remove(&chat_id) {
-                            if let Some(h) = subs.forwarders.remove(&chat_id) {
-                                h.abort();
+                            // Получаем или создаём broadcaster для чата
+                            let tx = match state.ws_hub.get(&chat_id) {
+                                Some(existing) => existing.clone(),
+                                None => {
+                                    let (tx, _rx) = broadcast::channel::<String>(200);
+                                    state.ws_hub.insert(chat_id, tx);
+                                    state.ws_hub.get(&chat_id).unwrap().clone()
+                                }
+                            };
+                            // Подписываемся и создаём форвардер в out_tx
+                            let rx = tx.subscribe();
+                            let handle = spawn_forwarder(rx, out_tx.clone(), state.metrics.clone());
+                            subs.joined.insert(chat_id);
+                            subs.forwarders.insert(chat_id, handle);
+                            let ok_msg = serde_json::to_string(&ServerEvent::Ok)
+                                .unwrap_or_else(|_| "{\"type\":\"ok\"}".to_string());
+                            let _ = out_tx.send(WsMessage::Text(ok_msg));
+                        }
+                        Ok(ClientEvent::LeaveChat { chat_id }) => {
+                            if subs.joined.remove(&chat_id) {
+                                if let Some(h) = subs.forwarders.remove(&chat_id) {
+                                    h.abort();
+                                }
                             }
+                            let ok_msg = serde_json::to_string(&ServerEvent::Ok)
+                                .unwrap_or_else(|_| "{\"type\":\"ok\"}".to_string());
+                            let _ = out_tx.send(WsMessage::Text(ok_msg));
                         }
-                        let ok_msg = serde_json::to_string(&ServerEvent::Ok)
-                            .unwrap_or_else(|_| "{\"type\":\"ok\"}".to_string());
-                        let _ = out_tx.send(WsMessage::Text(ok_msg));
-                    }
-                    Ok(ClientEvent::Typing { chat_id, is_typing }) => {
-                        if subs.joined.contains(&chat_id) {
-                            if let Ok(evt) = serde_json::to_string(&ServerEvent::Typing {
+                        Ok(ClientEvent::Typing { chat_id, is_typing }) => {
+                            if !subs.joined.contains(&chat_id) {
+                                return;
+                            }
+                            // Участникам с блокировкой в любую сторону (в private-чате это
+                            // единственный собеседник, в группе — кто угодно) набор текста
+                            // не пересылается: тогда рассылаем остальным по личным каналам
+                            // вместо общего канала чата
+                            let Ok(recipients) =
+                                typing_recipients(&state.pool, chat_id, user_id).await
+                            else {
+                                return;
+                            };
+                            let Ok(evt) = serde_json::to_string(&ServerEvent::Typing {
                                 chat_id,
                                 user_id,
                                 is_typing,
-                            }) {
-                                publish(&state, chat_id, evt);
+                            }) else {
+                                return;
+                            };
+                            match recipients {
+                                None => publish(&state, chat_id, evt),
+                                Some(recipients) => {
+                                    for rid in recipients {
+                                        publish_user(&state, rid, evt.clone());
+                                    }
+                                }
                             }
                         }
-                    }
-                    Ok(ClientEvent::SendMessage {
-                        chat_id,
-                        message,
-                        message_type,
-                        envelopes,
-                        metadata,
-                        reply_to_message_id,
-                        client_message_id,
-                    })
-                    | Ok(ClientEvent::VoiceMessage {
-                        chat_id,
-                        message,
-                        message_type,
-                        envelopes,
-                        metadata,
-                        reply_to_message_id,
-                        client_message_id,
-                    })
-                    | Ok(ClientEvent::VideoMessage {
-                        chat_id,
-                        message,
-                        message_type,
-                        envelopes,
-                        metadata,
-                        reply_to_message_id,
-                        client_message_id,
-                    }) => {
-                        if let Err(e) = ensure_can_send_message(&state, chat_id, user_id).await {
-                            let err_msg =
-                                serde_json::to_string(&ServerEvent::Error { error: &e.1 })
-                                    .unwrap_or_else(|_| {
-                                        format!(
-                                            "{{\"type\":\"error\",\"error\":{}}}",
-                                            serde_json::to_string(&e.1)
-                                                .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                        Ok(ClientEvent::SendMessage {
+                            chat_id,
+                            message,
+                            message_type,
+                            envelopes,
+                            metadata,
+                            reply_to_message_id,
+                            client_message_id,
+                            search_tokens,
+                        })
+                        | Ok(ClientEvent::VoiceMessage {
+                            chat_id,
+                            message,
+                            message_type,
+                            envelopes,
+                            metadata,
+                            reply_to_message_id,
+                            client_message_id,
+                            search_tokens,
+                        })
+                        | Ok(ClientEvent::VideoMessage {
+                            chat_id,
+                            message,
+                            message_type,
+                            envelopes,
+                            metadata,
+                            reply_to_message_id,
+                            client_message_id,
+                            search_tokens,
+                        }) => {
+                            if let Err(e) = ensure_can_send_message(&state, chat_id, user_id).await {
+                                let err_msg =
+                                    serde_json::to_string(&ServerEvent::Error { error: &e.1 })
+                                        .unwrap_or_else(|_| {
+                                            format!(
+                                                "{{\"type\":\"error\",\"error\":{}}}",
+                                                serde_json::to_string(&e.1)
+                                                    .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                            )
+                                        });
+                                let _ = out_tx.send(WsMessage::Text(err_msg));
+                                return;
+                            }
+
+                            let envelopes = match filter_device_envelopes(&state, chat_id, envelopes)
+                                .await
+                            {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    let err_msg =
+                                        serde_json::to_string(&ServerEvent::Error { error: &e.1 })
+                                            .unwrap_or_else(|_| {
+                                                format!(
+                                                    "{{\"type\":\"error\",\"error\":{}}}",
+                                                    serde_json::to_string(&e.1)
+                                                        .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                                )
+                                            });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
+                                }
+                            };
+
+                            let msg_type = message_type.unwrap_or_else(|| "text".to_string());
+                            let has_files = metadata.as_ref().map(|m| !m.is_empty());
+
+                            // Сериализуем envelopes и metadata в JSON
+                            let envelopes_json =
+                                envelopes.map(|v| serde_json::to_value(v).ok()).flatten();
+                            let metadata_json = metadata
+                                .as_ref()
+                                .map(|m| serde_json::to_value(m).ok())
+                                .flatten();
+
+                            // P1-6: Check for idempotency - if client_message_id is provided,
+                            // check if we already have this message and return it instead of creating duplicate
+                            if let Some(client_msg_id) = &client_message_id {
+                                let existing = sqlx::query(
+                                    r#"
+                                    SELECT
+                                        id::INT8 AS id,
+                                        chat_id::INT8 AS chat_id,
+                                        sender_id::INT8 AS sender_id,
+                                        message,
+                                        message_type,
+                                        created_at,
+                                        edited_at,
+                                        reply_to_message_id::INT8 AS reply_to_message_id,
+                                        forwarded_from_message_id::INT8 AS forwarded_from_message_id,
+                                        forwarded_from_chat_id::INT8 AS forwarded_from_chat_id,
+                                        forwarded_from_sender_id::INT8 AS forwarded_from_sender_id,
+                                        deleted_at,
+                                        deleted_by::INT8 AS deleted_by,
+                                        is_read,
+                                        is_delivered,
+                                        envelopes,
+                                        metadata
+                                    FROM messages
+                                    WHERE chat_id = $1 AND sender_id = $2 AND client_message_id = $3
+                                    LIMIT 1
+                                    "#,
+                                )
+                                .bind(chat_id)
+                                .bind(user_id)
+                                .bind(client_msg_id)
+                                .fetch_optional(&state.pool)
+                                .await;
+
+                                if let Ok(Some(row)) = existing {
+                                    // P1-6: Idempotency - return existing message instead of duplicate
+                                    let envelopes_value: Option<Value> =
+                                        row.try_get("envelopes").ok().flatten();
+                                    let metadata_value: Option<Value> = row.try_get("metadata").ok().flatten();
+                                    let metadata_vec: Option<Vec<FileMetadata>> =
+                                        metadata_value.and_then(|v| serde_json::from_value(v).ok());
+
+                                    let msg = OutMessage {
+                                        id: row.try_get("id").unwrap_or_default(),
+                                        chat_id: row.try_get("chat_id").unwrap_or_default(),
+                                        sender_id: row.try_get("sender_id").unwrap_or_default(),
+                                        message: row.try_get("message").unwrap_or_default(),
+                                        message_type: row.try_get("message_type").unwrap_or_else(|_| "text".to_string()),
+                                        created_at: row.try_get::<chrono::DateTime<chrono::Utc>, _>("created_at").map(|t| t.to_rfc3339()).unwrap_or_default(),
+                                        edited_at: row.try_get::<chrono::DateTime<chrono::Utc>, _>("edited_at").ok().map(|t| t.to_rfc3339()),
+                                        reply_to_message_id: row.try_get("reply_to_message_id").ok(),
+                                        forwarded_from_message_id: row.try_get("forwarded_from_message_id").ok(),
+                                        forwarded_from_chat_id: row.try_get("forwarded_from_chat_id").ok(),
+                                        forwarded_from_sender_id: row.try_get("forwarded_from_sender_id").ok(),
+                                        deleted_at: row.try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at").ok().map(|t| t.to_rfc3339()),
+                                        deleted_by: row.try_get("deleted_by").ok(),
+                                        is_read: row.try_get("is_read").unwrap_or(false),
+                                        is_delivered: row.try_get("is_delivered").unwrap_or(false),
+                                        has_files,
+                                        metadata: metadata_vec,
+                                        envelopes: envelopes_value,
+                                        status: Some("sent".to_string()),
+                                    };
+
+                                    let evt_message_new = serde_json::to_string(&ServerEvent::MessageNew {
+                                        chat_id,
+                                        message: msg,
+                                    })
+                                    .ok();
+
+                                    if let Some(evt) = &evt_message_new {
+                                        // Send to all participants
+                                        let rows = sqlx::query(
+                                            r#"SELECT user_id FROM chat_participants WHERE chat_id = $1"#,
                                         )
-                                    });
-                            let _ = out_tx.send(WsMessage::Text(err_msg));
-                            continue;
-                        }
+                                        .bind(chat_id)
+                                        .fetch_all(&state.pool)
+                                        .await;
+
+                                        if let Ok(participants) = rows {
+                                            for r in participants {
+                                                let uid: i32 = r.try_get("user_id").unwrap_or_default();
+                                                if uid <= 0 {
+                                                    continue;
+                                                }
+                                                if !is_user_online(&state, uid) {
+                                                    continue;
+                                                }
+                                                ensure_user_channel(&state, uid);
+                                                publish_user(&state, uid, evt.clone());
+                                            }
+                                        }
+                                    }
 
-                        let msg_type = message_type.unwrap_or_else(|| "text".to_string());
-                        let has_files = metadata.as_ref().map(|m| !m.is_empty());
-
-                        // Сериализуем envelopes и metadata в JSON
-                        let envelopes_json =
-                            envelopes.map(|v| serde_json::to_value(v).ok()).flatten();
-                        let metadata_json = metadata
-                            .as_ref()
-                            .map(|m| serde_json::to_value(m).ok())
-                            .flatten();
-
-                        // P1-6: Check for idempotency - if client_message_id is provided,
-                        // check if we already have this message and return it instead of creating duplicate
-                        if let Some(client_msg_id) = &client_message_id {
-                            let existing = sqlx::query(
+                                    // Send OK to sender
+                                    let ok_msg = serde_json::to_string(&ServerEvent::Ok).unwrap_or_else(|_| "{\"type\":\"ok\"}".to_string());
+                                    let _ = out_tx.send(WsMessage::Text(ok_msg));
+                                    return;
+                                }
+                            }
+
+                            // Сохраняем сообщение в БД
+                            let row = match sqlx::query(
                                 r#"
-                                SELECT
+                                INSERT INTO messages (chat_id, sender_id, message, message_type, envelopes, metadata, reply_to_message_id, client_message_id)
+                                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                                RETURNING
                                     id::INT8 AS id,
                                     chat_id::INT8 AS chat_id,
                                     sender_id::INT8 AS sender_id,
@@ -701,295 +1111,402 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: i32) {
                                     is_delivered,
                                     envelopes,
                                     metadata
-                                FROM messages
-                                WHERE chat_id = $1 AND sender_id = $2 AND client_message_id = $3
-                                LIMIT 1
                                 "#,
                             )
                             .bind(chat_id)
                             .bind(user_id)
-                            .bind(client_msg_id)
-                            .fetch_optional(&state.pool)
+                            .bind(&message)
+                            .bind(&msg_type)
+                            .bind(&envelopes_json)
+                            .bind(&metadata_json)
+                            .bind(reply_to_message_id.map(|v| v as i32))
+                            .bind(client_message_id.as_ref().map(|s| s.as_str()))
+                            .fetch_one(&state.pool)
+                            .await {
+                                Ok(r) => {
+                                    state.metrics.message_sent(&msg_type);
+                                    r
+                                }
+                                Err(e) => {
+                                    let err_txt = format!("Ошибка БД: {}", e);
+                                    let err_msg = serde_json::to_string(&ServerEvent::Error { error: &err_txt })
+                                        .unwrap_or_else(|_| format!("{{\"type\":\"error\",\"error\":{}}}", serde_json::to_string(&err_txt).unwrap_or_else(|_| "\"Ошибка\"".to_string())));
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
+                                }
+                            };
+
+                            // Десериализуем envelopes и metadata обратно
+                            let envelopes_value: Option<Value> =
+                                row.try_get("envelopes").ok().flatten();
+                            let metadata_value: Option<Value> = row.try_get("metadata").ok().flatten();
+                            let metadata_vec: Option<Vec<FileMetadata>> =
+                                metadata_value.and_then(|v| serde_json::from_value(v).ok());
+
+                            let msg = OutMessage {
+                                id: row.try_get("id").unwrap_or_default(),
+                                chat_id: row.try_get("chat_id").unwrap_or_default(),
+                                sender_id: row.try_get("sender_id").unwrap_or_default(),
+                                message: row.try_get("message").unwrap_or_default(),
+                                message_type: row
+                                    .try_get("message_type")
+                                    .unwrap_or_else(|_| "text".to_string()),
+                                created_at: row
+                                    .try_get::<chrono::DateTime<chrono::Utc>, _>("created_at")
+                                    .map(|t| t.to_rfc3339())
+                                    .unwrap_or_default(),
+                                edited_at: row
+                                    .try_get::<chrono::DateTime<chrono::Utc>, _>("edited_at")
+                                    .ok()
+                                    .map(|t| t.to_rfc3339()),
+                                reply_to_message_id: row.try_get("reply_to_message_id").ok(),
+                                forwarded_from_message_id: row
+                                    .try_get("forwarded_from_message_id")
+                                    .ok(),
+                                forwarded_from_chat_id: row.try_get("forwarded_from_chat_id").ok(),
+                                forwarded_from_sender_id: row.try_get("forwarded_from_sender_id").ok(),
+                                deleted_at: row
+                                    .try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at")
+                                    .ok()
+                                    .map(|t| t.to_rfc3339()),
+                                deleted_by: row.try_get("deleted_by").ok(),
+                                is_read: row.try_get("is_read").unwrap_or(false),
+                                is_delivered: row.try_get("is_delivered").unwrap_or(false),
+                                has_files,
+                                metadata: metadata_vec,
+                                envelopes: envelopes_value,
+                                status: Some("sent".to_string()),
+                            };
+
+                            // Слепой индекс не влияет на доставку: ошибки сохранения игнорируем.
+                            if let Some(tokens) = &search_tokens {
+                                let _ =
+                                    save_search_tokens(&state, user_id, chat_id, msg.id, tokens).await;
+                            }
+
+                            // Полная синхронизация идёт через личные user-каналы:
+                            // все онлайн-устройства всех участников получают message_new.
+                            let evt_message_new = serde_json::to_string(&ServerEvent::MessageNew {
+                                chat_id,
+                                message: msg.clone(),
+                            })
+                            .ok();
+
+                            let rows = sqlx::query(
+                                r#"SELECT user_id FROM chat_participants WHERE chat_id = $1"#,
+                            )
+                            .bind(chat_id)
+                            .fetch_all(&state.pool)
                             .await;
 
-                            if let Ok(Some(row)) = existing {
-                                // P1-6: Idempotency - return existing message instead of duplicate
-                                let envelopes_value: Option<Value> =
-                                    row.try_get("envelopes").ok().flatten();
-                                let metadata_value: Option<Value> = row.try_get("metadata").ok().flatten();
-                                let metadata_vec: Option<Vec<FileMetadata>> =
-                                    metadata_value.and_then(|v| serde_json::from_value(v).ok());
-
-                                let msg = OutMessage {
-                                    id: row.try_get("id").unwrap_or_default(),
-                                    chat_id: row.try_get("chat_id").unwrap_or_default(),
-                                    sender_id: row.try_get("sender_id").unwrap_or_default(),
-                                    message: row.try_get("message").unwrap_or_default(),
-                                    message_type: row.try_get("message_type").unwrap_or_else(|_| "text".to_string()),
-                                    created_at: row.try_get::<chrono::DateTime<chrono::Utc>, _>("created_at").map(|t| t.to_rfc3339()).unwrap_or_default(),
-                                    edited_at: row.try_get::<chrono::DateTime<chrono::Utc>, _>("edited_at").ok().map(|t| t.to_rfc3339()),
-                                    reply_to_message_id: row.try_get("reply_to_message_id").ok(),
-                                    forwarded_from_message_id: row.try_get("forwarded_from_message_id").ok(),
-                                    forwarded_from_chat_id: row.try_get("forwarded_from_chat_id").ok(),
-                                    forwarded_from_sender_id: row.try_get("forwarded_from_sender_id").ok(),
-                                    deleted_at: row.try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at").ok().map(|t| t.to_rfc3339()),
-                                    deleted_by: row.try_get("deleted_by").ok(),
-                                    is_read: row.try_get("is_read").unwrap_or(false),
-                                    is_delivered: row.try_get("is_delivered").unwrap_or(false),
-                                    has_files,
-                                    metadata: metadata_vec,
-                                    envelopes: envelopes_value,
-                                    status: Some("sent".to_string()),
-                                };
+                            if let Ok(participants) = rows {
+                                for r in participants {
+                                    let uid: i32 = r.try_get("user_id").unwrap_or_default();
+                                    if uid <= 0 {
+                                        continue;
+                                    }
 
-                                let evt_message_new = serde_json::to_string(&ServerEvent::MessageNew {
-                                    chat_id,
-                                    message: msg,
-                                })
-                                .ok();
-
-                                if let Some(evt) = &evt_message_new {
-                                    // Send to all participants
-                                    let rows = sqlx::query(
-                                        r#"SELECT user_id FROM chat_participants WHERE chat_id = $1"#,
-                                    )
-                                    .bind(chat_id)
-                                    .fetch_all(&state.pool)
-                                    .await;
-
-                                    if let Ok(participants) = rows {
-                                        for r in participants {
-                                            let uid: i32 = r.try_get("user_id").unwrap_or_default();
-                                            if uid <= 0 {
-                                                continue;
-                                            }
-                                            if !is_user_online(&state, uid) {
-                                                continue;
-                                            }
-                                            ensure_user_channel(&state, uid);
-                                            publish_user(&state, uid, evt.clone());
-                                        }
+                                    if !is_user_online(&state, uid) {
+                                        continue;
                                     }
-                                }
 
-                                // Send OK to sender
-                                let ok_msg = serde_json::to_string(&ServerEvent::Ok).unwrap_or_else(|_| "{\"type\":\"ok\"}".to_string());
-                                let _ = out_tx.send(WsMessage::Text(ok_msg));
-                                continue;
-                            }
-                        }
+                                    ensure_user_channel(&state, uid);
 
-                        // Сохраняем сообщение в БД
-                        let row = match sqlx::query(
-                            r#"
-                            INSERT INTO messages (chat_id, sender_id, message, message_type, envelopes, metadata, reply_to_message_id, client_message_id)
-                            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-                            RETURNING
-                                id::INT8 AS id,
-                                chat_id::INT8 AS chat_id,
-                                sender_id::INT8 AS sender_id,
-                                message,
-                                message_type,
-                                created_at,
-                                edited_at,
-                                reply_to_message_id::INT8 AS reply_to_message_id,
-                                forwarded_from_message_id::INT8 AS forwarded_from_message_id,
-                                forwarded_from_chat_id::INT8 AS forwarded_from_chat_id,
-                                forwarded_from_sender_id::INT8 AS forwarded_from_sender_id,
-                                deleted_at,
-                                deleted_by::INT8 AS deleted_by,
-                                is_read,
-                                is_delivered,
-                                envelopes,
-                                metadata
-                            "#,
-                        )
-                        .bind(chat_id)
-                        .bind(user_id)
-                        .bind(&message)
-                        .bind(&msg_type)
-                        .bind(&envelopes_json)
-                        .bind(&metadata_json)
-                        .bind(reply_to_message_id.map(|v| v as i32))
-                        .bind(client_message_id.as_ref().map(|s| s.as_str()))
-                        .fetch_one(&state.pool)
-                        .await {
-                            Ok(r) => r,
-                            Err(e) => {
-                                let err_txt = format!("Ошибка БД: {}", e);
-                                let err_msg = serde_json::to_string(&ServerEvent::Error { error: &err_txt })
-                                    .unwrap_or_else(|_| format!("{{\"type\":\"error\",\"error\":{}}}", serde_json::to_string(&err_txt).unwrap_or_else(|_| "\"Ошибка\"".to_string())));
-                                let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
+                                    if let Some(evt) = &evt_message_new {
+                                        publish_user(&state, uid, evt.clone());
+                                    }
+                                }
                             }
-                        };
-
-                        // Десериализуем envelopes и metadata обратно
-                        let envelopes_value: Option<Value> =
-                            row.try_get("envelopes").ok().flatten();
-                        let metadata_value: Option<Value> = row.try_get("metadata").ok().flatten();
-                        let metadata_vec: Option<Vec<FileMetadata>> =
-                            metadata_value.and_then(|v| serde_json::from_value(v).ok());
-
-                        let msg = OutMessage {
-                            id: row.try_get("id").unwrap_or_default(),
-                            chat_id: row.try_get("chat_id").unwrap_or_default(),
-                            sender_id: row.try_get("sender_id").unwrap_or_default(),
-                            message: row.try_get("message").unwrap_or_default(),
-                            message_type: row
-                                .try_get("message_type")
-                                .unwrap_or_else(|_| "text".to_string()),
-                            created_at: row
-                                .try_get::<chrono::DateTime<chrono::Utc>, _>("created_at")
-                                .map(|t| t.to_rfc3339())
-                                .unwrap_or_default(),
-                            edited_at: row
-                                .try_get::<chrono::DateTime<chrono::Utc>, _>("edited_at")
-                                .ok()
-                                .map(|t| t.to_rfc3339()),
-                            reply_to_message_id: row.try_get("reply_to_message_id").ok(),
-                            forwarded_from_message_id: row
-                                .try_get("forwarded_from_message_id")
-                                .ok(),
-                            forwarded_from_chat_id: row.try_get("forwarded_from_chat_id").ok(),
-                            forwarded_from_sender_id: row.try_get("forwarded_from_sender_id").ok(),
-                            deleted_at: row
-                                .try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at")
-                                .ok()
-                                .map(|t| t.to_rfc3339()),
-                            deleted_by: row.try_get("deleted_by").ok(),
-                            is_read: row.try_get("is_read").unwrap_or(false),
-                            is_delivered: row.try_get("is_delivered").unwrap_or(false),
-                            has_files,
-                            metadata: metadata_vec,
-                            envelopes: envelopes_value,
-                            status: Some("sent".to_string()),
-                        };
-                        // Полная синхронизация идёт через личные user-каналы:
-                        // все онлайн-устройства всех участников получают message_new.
-                        let evt_message_new = serde_json::to_string(&ServerEvent::MessageNew {
+                        }
+                        Ok(ClientEvent::EditMessage {
                             chat_id,
-                            message: msg.clone(),
-                        })
-                        .ok();
-
-                        let rows = sqlx::query(
-                            r#"SELECT user_id FROM chat_participants WHERE chat_id = $1"#,
-                        )
-                        .bind(chat_id)
-                        .fetch_all(&state.pool)
-                        .await;
-
-                        if let Ok(participants) = rows {
-                            for r in participants {
-                                let uid: i32 = r.try_get("user_id").unwrap_or_default();
-                                if uid <= 0 {
-                                    continue;
+                            message_id,
+                            message,
+                            message_type,
+                            envelopes,
+                            metadata,
+                            search_tokens,
+                        }) => {
+                            if !subs.joined.contains(&chat_id) {
+                                if let Err(e) = ensure_member(&state, chat_id, user_id).await {
+                                    let err_msg =
+                                        serde_json::to_string(&ServerEvent::Error { error: &e.1 })
+                                            .unwrap_or_else(|_| {
+                                                format!(
+                                                    "{{\"type\":\"error\",\"error\":{}}}",
+                                                    serde_json::to_string(&e.1)
+                                                        .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                                )
+                                            });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
                                 }
+                            }
 
-                                if !is_user_online(&state, uid) {
-                                    continue;
+                            let envelopes = match filter_device_envelopes(&state, chat_id, envelopes)
+                                .await
+                            {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    let err_msg =
+                                        serde_json::to_string(&ServerEvent::Error { error: &e.1 })
+                                            .unwrap_or_else(|_| {
+                                                format!(
+                                                    "{{\"type\":\"error\",\"error\":{}}}",
+                                                    serde_json::to_string(&e.1)
+                                                        .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                                )
+                                            });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
                                 }
+                            };
 
-                                ensure_user_channel(&state, uid);
+                            // Только автор может редактировать. Нельзя редактировать удалённое.
+                            let envelopes_value = envelopes.clone();
+                            let metadata_json = match metadata {
+                                Some(ref v) => serde_json::to_value(v).ok(),
+                                None => None,
+                            };
+                            let has_files = metadata.as_ref().map(|v| !v.is_empty()).unwrap_or(false);
 
-                                if let Some(evt) = &evt_message_new {
-                                    publish_user(&state, uid, evt.clone());
+                            let updated = sqlx::query(
+                                r#"
+                                UPDATE messages
+                                SET message = $1,
+                                    message_type = $2,
+                                    envelopes = $3,
+                                    metadata = $4,
+                                    edited_at = now()
+                                WHERE id = $5
+                                  AND chat_id = $6
+                                  AND sender_id = $7
+                                  AND deleted_at IS NULL
+                                RETURNING
+                                    id::INT8 AS id,
+                                    chat_id::INT8 AS chat_id,
+                                    sender_id::INT8 AS sender_id,
+                                    message,
+                                    message_type,
+                                    created_at,
+                                    edited_at,
+                                    reply_to_message_id::INT8 AS reply_to_message_id,
+                                    forwarded_from_message_id::INT8 AS forwarded_from_message_id,
+                                    forwarded_from_chat_id::INT8 AS forwarded_from_chat_id,
+                                    forwarded_from_sender_id::INT8 AS forwarded_from_sender_id,
+                                    deleted_at,
+                                    deleted_by::INT8 AS deleted_by,
+                                    is_read,
+                                    is_delivered,
+                                    envelopes,
+                                    metadata
+                                "#,
+                            )
+                            .bind(&message)
+                            .bind(&message_type)
+                            .bind(&envelopes_value)
+                            .bind(&metadata_json)
+                            .bind(message_id)
+                            .bind(chat_id)
+                            .bind(user_id)
+                            .fetch_optional(&state.pool)
+                            .await;
+
+                            let row = match updated {
+                                Ok(Some(r)) => r,
+                                Ok(None) => {
+                                    let err_msg = serde_json::to_string(&ServerEvent::Error {
+                                        error: "Сообщение не найдено",
+                                    })
+                                    .unwrap_or_else(|_| {
+                                        "{\"type\":\"error\",\"error\":\"Сообщение не найдено\"}"
+                                            .to_string()
+                                    });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
                                 }
+                                Err(e) => {
+                                    let err_txt = format!("Ошибка БД: {}", e);
+                                    let err_msg =
+                                        serde_json::to_string(&ServerEvent::Error { error: &err_txt })
+                                            .unwrap_or_else(|_| {
+                                                format!(
+                                                    "{{\"type\":\"error\",\"error\":{}}}",
+                                                    serde_json::to_string(&err_txt)
+                                                        .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                                )
+                                            });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
+                                }
+                            };
+
+                            let envelopes_value: Option<Value> =
+                                row.try_get("envelopes").ok().flatten();
+                            let metadata_value: Option<Value> = row.try_get("metadata").ok().flatten();
+                            let metadata_vec: Option<Vec<FileMetadata>> =
+                                metadata_value.and_then(|v| serde_json::from_value(v).ok());
+
+                            let msg = OutMessage {
+                                id: row.try_get("id").unwrap_or_default(),
+                                chat_id: row.try_get("chat_id").unwrap_or_default(),
+                                sender_id: row.try_get("sender_id").unwrap_or_default(),
+                                message: row.try_get("message").unwrap_or_default(),
+                                message_type: row
+                                    .try_get("message_type")
+                                    .unwrap_or_else(|_| "text".to_string()),
+                                created_at: row
+                                    .try_get::<chrono::DateTime<chrono::Utc>, _>("created_at")
+                                    .map(|t| t.to_rfc3339())
+                                    .unwrap_or_default(),
+                                edited_at: row
+                                    .try_get::<chrono::DateTime<chrono::Utc>, _>("edited_at")
+                                    .ok()
+                                    .map(|t| t.to_rfc3339()),
+                                is_read: row.try_get("is_read").unwrap_or(false),
+                                is_delivered: row.try_get("is_delivered").unwrap_or(false),
+                                reply_to_message_id: row.try_get("reply_to_message_id").ok(),
+                                forwarded_from_message_id: row
+                                    .try_get("forwarded_from_message_id")
+                                    .ok(),
+                                forwarded_from_chat_id: row.try_get("forwarded_from_chat_id").ok(),
+                                forwarded_from_sender_id: row.try_get("forwarded_from_sender_id").ok(),
+                                deleted_at: row
+                                    .try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at")
+                                    .ok()
+                                    .map(|t| t.to_rfc3339()),
+                                deleted_by: row.try_get("deleted_by").ok(),
+                                has_files: Some(has_files),
+                                metadata: metadata_vec,
+                                envelopes: envelopes_value,
+                                status: Some("sent".to_string()),
+                            };
+
+                            // Старый индекс больше не соответствует тексту: получатели переиндексируют
+                            // сообщение по message_updated, автор присылает новые токены сразу.
+                            let _ = remove_search_tokens(&state, &[message_id]).await;
+                            if let Some(tokens) = &search_tokens {
+                                let _ =
+                                    save_search_tokens(&state, user_id, chat_id, message_id, tokens)
+                                        .await;
                             }
-                        }
-                    }
-                    Ok(ClientEvent::EditMessage {
-                        chat_id,
-                        message_id,
-                        message,
-                        message_type,
-                        envelopes,
-                        metadata,
-                    }) => {
-                        if !subs.joined.contains(&chat_id) {
-                            if let Err(e) = ensure_member(&state, chat_id, user_id).await {
-                                let err_msg =
-                                    serde_json::to_string(&ServerEvent::Error { error: &e.1 })
-                                        .unwrap_or_else(|_| {
-                                            format!(
-                                                "{{\"type\":\"error\",\"error\":{}}}",
-                                                serde_json::to_string(&e.1)
-                                                    .unwrap_or_else(|_| "\"Ошибка\"".to_string())
-                                            )
-                                        });
-                                let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
-                            }
-                        }
 
-                        // Только автор может редактировать. Нельзя редактировать удалённое.
-                        let envelopes_value = envelopes.clone();
-                        let metadata_json = match metadata {
-                            Some(ref v) => serde_json::to_value(v).ok(),
-                            None => None,
-                        };
-                        let has_files = metadata.as_ref().map(|v| !v.is_empty()).unwrap_or(false);
-
-                        let updated = sqlx::query(
-                            r#"
-                            UPDATE messages
-                            SET message = $1,
-                                message_type = $2,
-                                envelopes = $3,
-                                metadata = $4,
-                                edited_at = now()
-                            WHERE id = $5
-                              AND chat_id = $6
-                              AND sender_id = $7
-                              AND deleted_at IS NULL
-                            RETURNING
-                                id::INT8 AS id,
-                                chat_id::INT8 AS chat_id,
-                                sender_id::INT8 AS sender_id,
-                                message,
-                                message_type,
-                                created_at,
-                                edited_at,
-                                reply_to_message_id::INT8 AS reply_to_message_id,
-                                forwarded_from_message_id::INT8 AS forwarded_from_message_id,
-                                forwarded_from_chat_id::INT8 AS forwarded_from_chat_id,
-                                forwarded_from_sender_id::INT8 AS forwarded_from_sender_id,
-                                deleted_at,
-                                deleted_by::INT8 AS deleted_by,
-                                is_read,
-                                is_delivered,
-                                envelopes,
-                                metadata
-                            "#,
-                        )
-                        .bind(&message)
-                        .bind(&message_type)
-                        .bind(&envelopes_value)
-                        .bind(&metadata_json)
-                        .bind(message_id)
-                        .bind(chat_id)
-                        .bind(user_id)
-                        .fetch_optional(&state.pool)
-                        .await;
-
-                        let row = match updated {
-                            Ok(Some(r)) => r,
-                            Ok(None) => {
-                                let err_msg = serde_json::to_string(&ServerEvent::Error {
-                                    error: "Сообщение не найдено",
-                                })
-                                .unwrap_or_else(|_| {
-                                    "{\"type\":\"error\",\"error\":\"Сообщение не найдено\"}"
-                                        .to_string()
+                            let evt = match serde_json::to_string(&ServerEvent::MessageUpdated {
+                                chat_id,
+                                message: msg,
+                            }) {
+                                Ok(s) => s,
+                                Err(_) => {
+                                    let err_msg = serde_json::to_string(&ServerEvent::Error {
+                                        error: "Ошибка сериализации",
+                                    })
+                                    .unwrap_or_else(|_| {
+                                        "{\"type\":\"error\",\"error\":\"Ошибка сериализации\"}"
+                                            .to_string()
+                                    });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
+                                }
+                            };
+
+                            let participants = sqlx::query(
+                                r#"
+                                SELECT user_id
+                                FROM chat_participants
+                                WHERE chat_id = $1
+                                "#,
+                            )
+                            .bind(chat_id)
+                            .fetch_all(&state.pool)
+                            .await;
+
+                            if let Ok(rows) = participants {
+                                for r in rows {
+                                    let uid: i32 = r.try_get("user_id").unwrap_or_default();
+                                    if uid <= 0 {
+                                        continue;
+                                    }
+                                    if !is_user_online(&state, uid) {
+                                        continue;
+                                    }
+                                    ensure_user_channel(&state, uid);
+                                    publish_user(&state, uid, evt.clone());
+                                }
+                            }
+                        }
+                        Ok(ClientEvent::DeleteMessage {
+                            chat_id,
+                            message_id,
+                            scope,
+                        }) => {
+                            if !subs.joined.contains(&chat_id) {
+                                if let Err(e) = ensure_member(&state, chat_id, user_id).await {
+                                    let err_msg =
+                                        serde_json::to_string(&ServerEvent::Error { error: &e.1 })
+                                            .unwrap_or_else(|_| {
+                                                format!(
+                                                    "{{\"type\":\"error\",\"error\":{}}}",
+                                                    serde_json::to_string(&e.1)
+                                                        .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                                )
+                                            });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
+                                }
+                            }
+
+                            let scope = scope.unwrap_or_else(|| "everyone".to_string());
+                            if scope != "everyone" && scope != "me" {
+                                let err_msg = serde_json::to_string(&ServerEvent::Error {
+                                    error: "Некорректный scope (ожидается everyone или me)",
+                                })
+                                .unwrap_or_else(|_| {
+                                    "{\"type\":\"error\",\"error\":\"Некорректный scope\"}".to_string()
                                 });
                                 let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
+                                return;
                             }
-                            Err(e) => {
-                                let err_txt = format!("Ошибка БД: {}", e);
-                                let err_msg =
-                                    serde_json::to_string(&ServerEvent::Error { error: &err_txt })
+
+                            // "Удалить у меня": скрываем сообщение только для текущего пользователя
+                            // и оповещаем только его собственные устройства.
+                            if scope == "me" {
+                                let hidden = sqlx::query(
+                                    r#"
+                                    INSERT INTO message_hidden (user_id, message_id)
+                                    SELECT $3, m.id
+                                    FROM messages m
+                                    WHERE m.id = $1 AND m.chat_id = $2
+                                    ON CONFLICT (user_id, message_id) DO UPDATE SET hidden_at = message_hidden.hidden_at
+                                    RETURNING hidden_at
+                                    "#,
+                                )
+                                .bind(message_id as i32)
+                                .bind(chat_id)
+                                .bind(user_id)
+                                .fetch_optional(&state.pool)
+                                .await;
+
+                                let hidden_at = match hidden {
+                                    Ok(Some(r)) => r
+                                        .try_get::<chrono::DateTime<chrono::Utc>, _>("hidden_at")
+                                        .map(|t| t.to_rfc3339())
+                                        .unwrap_or_default(),
+                                    Ok(None) => {
+                                        let err_msg = serde_json::to_string(&ServerEvent::Error {
+                                            error: "Сообщение не найдено",
+                                        })
+                                        .unwrap_or_else(|_| {
+                                            "{\"type\":\"error\",\"error\":\"Сообщение не найдено\"}"
+                                                .to_string()
+                                        });
+                                        let _ = out_tx.send(WsMessage::Text(err_msg));
+                                        return;
+                                    }
+                                    Err(e) => {
+                                        let err_txt = format!("Ошибка БД: {}", e);
+                                        let err_msg = serde_json::to_string(&ServerEvent::Error {
+                                            error: &err_txt,
+                                        })
                                         .unwrap_or_else(|_| {
                                             format!(
                                                 "{{\"type\":\"error\",\"error\":{}}}",
@@ -997,101 +1514,29 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: i32) {
                                                     .unwrap_or_else(|_| "\"Ошибка\"".to_string())
                                             )
                                         });
-                                let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
-                            }
-                        };
-
-                        let envelopes_value: Option<Value> =
-                            row.try_get("envelopes").ok().flatten();
-                        let metadata_value: Option<Value> = row.try_get("metadata").ok().flatten();
-                        let metadata_vec: Option<Vec<FileMetadata>> =
-                            metadata_value.and_then(|v| serde_json::from_value(v).ok());
-
-                        let msg = OutMessage {
-                            id: row.try_get("id").unwrap_or_default(),
-                            chat_id: row.try_get("chat_id").unwrap_or_default(),
-                            sender_id: row.try_get("sender_id").unwrap_or_default(),
-                            message: row.try_get("message").unwrap_or_default(),
-                            message_type: row
-                                .try_get("message_type")
-                                .unwrap_or_else(|_| "text".to_string()),
-                            created_at: row
-                                .try_get::<chrono::DateTime<chrono::Utc>, _>("created_at")
-                                .map(|t| t.to_rfc3339())
-                                .unwrap_or_default(),
-                            edited_at: row
-                                .try_get::<chrono::DateTime<chrono::Utc>, _>("edited_at")
-                                .ok()
-                                .map(|t| t.to_rfc3339()),
-                            is_read: row.try_get("is_read").unwrap_or(false),
-                            is_delivered: row.try_get("is_delivered").unwrap_or(false),
-                            reply_to_message_id: row.try_get("reply_to_message_id").ok(),
-                            forwarded_from_message_id: row
-                                .try_get("forwarded_from_message_id")
-                                .ok(),
-                            forwarded_from_chat_id: row.try_get("forwarded_from_chat_id").ok(),
-                            forwarded_from_sender_id: row.try_get("forwarded_from_sender_id").ok(),
-                            deleted_at: row
-                                .try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at")
-                                .ok()
-                                .map(|t| t.to_rfc3339()),
-                            deleted_by: row.try_get("deleted_by").ok(),
-                            has_files: Some(has_files),
-                            metadata: metadata_vec,
-                            envelopes: envelopes_value,
-                            status: Some("sent".to_string()),
-                        };
-
-                        let evt = match serde_json::to_string(&ServerEvent::MessageUpdated {
-                            chat_id,
-                            message: msg,
-                        }) {
-                            Ok(s) => s,
-                            Err(_) => {
-                                let err_msg = serde_json::to_string(&ServerEvent::Error {
-                                    error: "Ошибка сериализации",
-                                })
-                                .unwrap_or_else(|_| {
-                                    "{\"type\":\"error\",\"error\":\"Ошибка сериализации\"}"
-                                        .to_string()
-                                });
-                                let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
-                            }
-                        };
-
-                        let participants = sqlx::query(
-                            r#"
-                            SELECT user_id
-                            FROM chat_participants
-                            WHERE chat_id = $1
-                            "#,
-                        )
-                        .bind(chat_id)
-                        .fetch_all(&state.pool)
-                        .await;
-
-                        if let Ok(rows) = participants {
-                            for r in rows {
-                                let uid: i32 = r.try_get("user_id").unwrap_or_default();
-                                if uid <= 0 {
-                                    continue;
-                                }
-                                if !is_user_online(&state, uid) {
-                                    continue;
+                                        let _ = out_tx.send(WsMessage::Text(err_msg));
+                                        return;
+                                    }
+                                };
+
+                                if let Ok(evt) = serde_json::to_string(&ServerEvent::MessageDeleted {
+                                    chat_id,
+                                    message_id,
+                                    deleted_at: hidden_at,
+                                    deleted_by: user_id as i64,
+                                    scope: "me",
+                                }) {
+                                    ensure_user_channel(&state, user_id);
+                                    publish_user(&state, user_id, evt);
                                 }
-                                ensure_user_channel(&state, uid);
-                                publish_user(&state, uid, evt.clone());
+                                return;
                             }
-                        }
-                    }
-                    Ok(ClientEvent::DeleteMessage {
-                        chat_id,
-                        message_id,
-                    }) => {
-                        if !subs.joined.contains(&chat_id) {
-                            if let Err(e) = ensure_member(&state, chat_id, user_id).await {
+
+                            // Автор — в пределах окна; admin/owner в group/channel — чужие сообщения.
+                            if let Err(e) =
+                                ensure_can_delete_for_everyone(&state, chat_id, message_id, user_id)
+                                    .await
+                            {
                                 let err_msg =
                                     serde_json::to_string(&ServerEvent::Error { error: &e.1 })
                                         .unwrap_or_else(|_| {
@@ -1102,30 +1547,49 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: i32) {
                                             )
                                         });
                                 let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
+                                return;
                             }
-                        }
 
-                        // P0-1: Only message author or chat admin/owner can delete
-                        // Check ownership and permissions before allowing deletion
-                        let msg_check = sqlx::query(
-                            r#"
-                            SELECT m.sender_id, COALESCE(cp.role, 'member') AS role
-                            FROM messages m
-                            JOIN chat_participants cp ON cp.chat_id = m.chat_id
-                            WHERE m.id = $1 AND m.chat_id = $2 AND cp.user_id = $3
-                            LIMIT 1
-                            "#,
-                        )
-                        .bind(message_id)
-                        .bind(chat_id)
-                        .bind(user_id)
-                        .fetch_optional(&state.pool)
-                        .await;
-
-                        let msg_row = match msg_check {
-                            Ok(Some(r)) => r,
-                            Ok(None) => {
+                            // Удаление у всех: вычищаем шифротекст, конверты и метаданные файлов,
+                            // чтобы на сервере не оставалось содержимого удалённого сообщения.
+                            let updated = sqlx::query(
+                                r#"
+                                UPDATE messages
+                                SET deleted_at = now(),
+                                    deleted_by = $3,
+                                    message = NULL,
+                                    body = NULL,
+                                    envelopes = NULL,
+                                    metadata = NULL
+                                WHERE id = $1 AND chat_id = $2 AND deleted_at IS NULL
+                                RETURNING deleted_at
+                                "#,
+                            )
+                            .bind(message_id as i32)
+                            .bind(chat_id)
+                            .bind(user_id)
+                            .fetch_optional(&state.pool)
+                            .await;
+
+                            let row = match updated {
+                                Ok(r) => r,
+                                Err(e) => {
+                                    let err_txt = format!("Ошибка БД: {}", e);
+                                    let err_msg =
+                                        serde_json::to_string(&ServerEvent::Error { error: &err_txt })
+                                            .unwrap_or_else(|_| {
+                                                format!(
+                                                    "{{\"type\":\"error\",\"error\":{}}}",
+                                                    serde_json::to_string(&err_txt)
+                                                        .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                                )
+                                            });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
+                                }
+                            };
+
+                            let Some(row) = row else {
                                 let err_msg = serde_json::to_string(&ServerEvent::Error {
                                     error: "Сообщение не найдено",
                                 })
@@ -1134,375 +1598,379 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: i32) {
                                         .to_string()
                                 });
                                 let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
+                                return;
+                            };
+
+                            let deleted_at = row
+                                .try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at")
+                                .map(|t| t.to_rfc3339())
+                                .unwrap_or_default();
+                            let _ = remove_search_tokens(&state, &[message_id]).await;
+
+                            let evt = match serde_json::to_string(&ServerEvent::MessageDeleted {
+                                chat_id,
+                                message_id,
+                                deleted_at,
+                                deleted_by: user_id as i64,
+                                scope: "everyone",
+                            }) {
+                                Ok(s) => s,
+                                Err(_) => {
+                                    let err_msg = serde_json::to_string(&ServerEvent::Error {
+                                        error: "Ошибка сериализации",
+                                    })
+                                    .unwrap_or_else(|_| {
+                                        "{\"type\":\"error\",\"error\":\"Ошибка сериализации\"}"
+                                            .to_string()
+                                    });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
+                                }
+                            };
+
+                            // Realtime всем онлайн-участникам через личные каналы.
+                            let participants = sqlx::query(
+                                r#"
+                                SELECT user_id
+                                FROM chat_participants
+                                WHERE chat_id = $1
+                                "#,
+                            )
+                            .bind(chat_id)
+                            .fetch_all(&state.pool)
+                            .await;
+
+                            if let Ok(rows) = participants {
+                                for r in rows {
+                                    let uid: i32 = r.try_get("user_id").unwrap_or_default();
+                                    if uid <= 0 {
+                                        continue;
+                                    }
+                                    if !is_user_online(&state, uid) {
+                                        continue;
+                                    }
+                                    ensure_user_channel(&state, uid);
+                                    publish_user(&state, uid, evt.clone());
+                                }
                             }
-                            Err(e) => {
-                                let err_txt = format!("Ошибка БД: {}", e);
+                        }
+                        Ok(ClientEvent::ForwardMessage {
+                            from_chat_id,
+                            message_id,
+                            to_chat_id,
+                            message,
+                            message_type,
+                            envelopes,
+                            metadata,
+                            client_message_id: _, // Ignore for forward
+                        }) => {
+                            // Должен быть участником и исходного, и целевого чата
+                            if let Err(e) = ensure_member(&state, from_chat_id, user_id).await {
                                 let err_msg =
-                                    serde_json::to_string(&ServerEvent::Error { error: &err_txt })
+                                    serde_json::to_string(&ServerEvent::Error { error: &e.1 })
                                         .unwrap_or_else(|_| {
                                             format!(
                                                 "{{\"type\":\"error\",\"error\":{}}}",
-                                                serde_json::to_string(&err_txt)
+                                                serde_json::to_string(&e.1)
                                                     .unwrap_or_else(|_| "\"Ошибка\"".to_string())
                                             )
                                         });
                                 let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
+                                return;
                             }
-                        };
-
-                        let sender_id: i32 = msg_row.try_get("sender_id").unwrap_or_default();
-                        let role: String = msg_row.try_get("role").unwrap_or_else(|_| "member".to_string());
-
-                        // Only sender, admin, or owner can delete
-                        if sender_id != user_id && role != "admin" && role != "owner" {
-                            let err_msg = serde_json::to_string(&ServerEvent::Error {
-                                error: "Недостаточно прав: только автор сообщения или admin/owner могут удалять сообщения",
-                            })
-                            .unwrap_or_else(|_| {
-                                "{\"type\":\"error\",\"error\":\"Недостаточно прав: только автор сообщения или admin/owner могут удалять сообщения\"}"
-                                    .to_string()
-                            });
-                            let _ = out_tx.send(WsMessage::Text(err_msg));
-                            continue;
-                        }
-
-                        let updated = sqlx::query(
-                            r#"
-                            UPDATE messages
-                            SET deleted_at = now(), deleted_by = $3
-                            WHERE id = $1 AND chat_id = $2 AND sender_id = $4
-                            RETURNING deleted_at
-                            "#,
-                        )
-                        .bind(message_id as i32)
-                        .bind(chat_id)
-                        .bind(user_id)
-                        .bind(sender_id)
-                        .fetch_optional(&state.pool)
-                        .await;
-
-                        let row = match updated {
-                            Ok(r) => r,
-                            Err(e) => {
-                                let err_txt = format!("Ошибка БД: {}", e);
+                            if let Err(e) = ensure_can_send_message(&state, to_chat_id, user_id).await {
                                 let err_msg =
-                                    serde_json::to_string(&ServerEvent::Error { error: &err_txt })
+                                    serde_json::to_string(&ServerEvent::Error { error: &e.1 })
                                         .unwrap_or_else(|_| {
                                             format!(
                                                 "{{\"type\":\"error\",\"error\":{}}}",
-                                                serde_json::to_string(&err_txt)
+                                                serde_json::to_string(&e.1)
                                                     .unwrap_or_else(|_| "\"Ошибка\"".to_string())
                                             )
                                         });
                                 let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
+                                return;
                             }
-                        };
 
-                        let Some(row) = row else {
-                            let err_msg = serde_json::to_string(&ServerEvent::Error {
-                                error: "Сообщение не найдено",
-                            })
-                            .unwrap_or_else(|_| {
-                                "{\"type\":\"error\",\"error\":\"Сообщение не найдено\"}"
-                                    .to_string()
-                            });
-                            let _ = out_tx.send(WsMessage::Text(err_msg));
-                            continue;
-                        };
+                            // Получаем автора исходного сообщения
+                            let src = sqlx::query(
+                                r#"
+                                SELECT sender_id::INT8 AS sender_id
+                                FROM messages
+                                WHERE id = $1 AND chat_id = $2
+                                LIMIT 1
+                                "#,
+                            )
+                            .bind(message_id as i32)
+                            .bind(from_chat_id)
+                            .fetch_optional(&state.pool)
+                            .await;
 
-                        let deleted_at = row
-                            .try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at")
-                            .map(|t| t.to_rfc3339())
-                            .unwrap_or_default();
+                            let src = match src {
+                                Ok(r) => r,
+                                Err(e) => {
+                                    let err_txt = format!("Ошибка БД: {}", e);
+                                    let err_msg =
+                                        serde_json::to_string(&ServerEvent::Error { error: &err_txt })
+                                            .unwrap_or_else(|_| {
+                                                format!(
+                                                    "{{\"type\":\"error\",\"error\":{}}}",
+                                                    serde_json::to_string(&err_txt)
+                                                        .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                                )
+                                            });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
+                                }
+                            };
 
-                        let evt = match serde_json::to_string(&ServerEvent::MessageDeleted {
-                            chat_id,
-                            message_id,
-                            deleted_at,
-                            deleted_by: user_id as i64,
-                        }) {
-                            Ok(s) => s,
-                            Err(_) => {
+                            let Some(src_row) = src else {
                                 let err_msg = serde_json::to_string(&ServerEvent::Error {
-                                    error: "Ошибка сериализации",
+                                    error: "Исходное сообщение не найдено",
                                 })
                                 .unwrap_or_else(|_| {
-                                    "{\"type\":\"error\",\"error\":\"Ошибка сериализации\"}"
+                                    "{\"type\":\"error\",\"error\":\"Исходное сообщение не найдено\"}"
                                         .to_string()
                                 });
                                 let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
-                            }
-                        };
-
-                        // Realtime всем онлайн-участникам через личные каналы.
-                        let participants = sqlx::query(
-                            r#"
-                            SELECT user_id
-                            FROM chat_participants
-                            WHERE chat_id = $1
-                            "#,
-                        )
-                        .bind(chat_id)
-                        .fetch_all(&state.pool)
-                        .await;
-
-                        if let Ok(rows) = participants {
-                            for r in rows {
-                                let uid: i32 = r.try_get("user_id").unwrap_or_default();
-                                if uid <= 0 {
-                                    continue;
+                                return;
+                            };
+
+                            let original_sender_id: i64 =
+                                src_row.try_get("sender_id").unwrap_or_default();
+
+                            let envelopes = match filter_device_envelopes(&state, to_chat_id, envelopes)
+                                .await
+                            {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    let err_msg =
+                                        serde_json::to_string(&ServerEvent::Error { error: &e.1 })
+                                            .unwrap_or_else(|_| {
+                                                format!(
+                                                    "{{\"type\":\"error\",\"error\":{}}}",
+                                                    serde_json::to_string(&e.1)
+                                                        .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                                )
+                                            });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
+                                }
+                            };
+
+                            let msg_type = message_type.unwrap_or_else(|| "text".to_string());
+                            let has_files = metadata.as_ref().map(|m| !m.is_empty());
+                            let envelopes_json =
+                                envelopes.map(|v| serde_json::to_value(v).ok()).flatten();
+                            let metadata_json = metadata
+                                .as_ref()
+                                .map(|m| serde_json::to_value(m).ok())
+                                .flatten();
+
+                            let row = match sqlx::query(
+                                r#"
+                                INSERT INTO messages (
+                                    chat_id,
+                                    sender_id,
+                                    message,
+                                    message_type,
+                                    envelopes,
+                                    metadata,
+                                    forwarded_from_message_id,
+                                    forwarded_from_chat_id,
+                                    forwarded_from_sender_id
+                                )
+                                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                                RETURNING
+                                    id::INT8 AS id,
+                                    chat_id::INT8 AS chat_id,
+                                    sender_id::INT8 AS sender_id,
+                                    message,
+                                    message_type,
+                                    created_at,
+                                    edited_at,
+                                    reply_to_message_id::INT8 AS reply_to_message_id,
+                                    forwarded_from_message_id::INT8 AS forwarded_from_message_id,
+                                    forwarded_from_chat_id::INT8 AS forwarded_from_chat_id,
+                                    forwarded_from_sender_id::INT8 AS forwarded_from_sender_id,
+                                    deleted_at,
+                                    deleted_by::INT8 AS deleted_by,
+                                    is_read,
+                                    is_delivered,
+                                    envelopes,
+                                    metadata
+                                "#,
+                            )
+                            .bind(to_chat_id)
+                            .bind(user_id)
+                            .bind(&message)
+                            .bind(&msg_type)
+                            .bind(&envelopes_json)
+                            .bind(&metadata_json)
+                            .bind(message_id as i32)
+                            .bind(from_chat_id)
+                            .bind(original_sender_id as i32)
+                            .fetch_one(&state.pool)
+                            .await
+                            {
+                                Ok(r) => {
+                                    state.metrics.message_sent(&msg_type);
+                                    r
                                 }
-                                if !is_user_online(&state, uid) {
-                                    continue;
+                                Err(e) => {
+                                    let err_txt = format!("Ошибка БД: {}", e);
+                                    let err_msg =
+                                        serde_json::to_string(&ServerEvent::Error { error: &err_txt })
+                                            .unwrap_or_else(|_| {
+                                                format!(
+                                                    "{{\"type\":\"error\",\"error\":{}}}",
+                                                    serde_json::to_string(&err_txt)
+                                                        .unwrap_or_else(|_| "\"Ошибка\"".to_string())
+                                                )
+                                            });
+                                    let _ = out_tx.send(WsMessage::Text(err_msg));
+                                    return;
+                                }
+                            };
+
+                            let envelopes_value: Option<Value> =
+                                row.try_get("envelopes").ok().flatten();
+                            let metadata_value: Option<Value> = row.try_get("metadata").ok().flatten();
+                            let metadata_vec: Option<Vec<FileMetadata>> =
+                                metadata_value.and_then(|v| serde_json::from_value(v).ok());
+
+                            let msg = OutMessage {
+                                id: row.try_get("id").unwrap_or_default(),
+                                chat_id: row.try_get("chat_id").unwrap_or_default(),
+                                sender_id: row.try_get("sender_id").unwrap_or_default(),
+                                message: row.try_get("message").unwrap_or_default(),
+                                message_type: row
+                                    .try_get("message_type")
+                                    .unwrap_or_else(|_| "text".to_string()),
+                                created_at: row
+                                    .try_get::<chrono::DateTime<chrono::Utc>, _>("created_at")
+                                    .map(|t| t.to_rfc3339())
+                                    .unwrap_or_default(),
+                                edited_at: row
+                                    .try_get::<chrono::DateTime<chrono::Utc>, _>("edited_at")
+                                    .ok()
+                                    .map(|t| t.to_rfc3339()),
+                                reply_to_message_id: row.try_get("reply_to_message_id").ok(),
+                                forwarded_from_message_id: row
+                                    .try_get("forwarded_from_message_id")
+                                    .ok(),
+                                forwarded_from_chat_id: row.try_get("forwarded_from_chat_id").ok(),
+                                forwarded_from_sender_id: row.try_get("forwarded_from_sender_id").ok(),
+                                deleted_at: row
+                                    .try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at")
+                                    .ok()
+                                    .map(|t| t.to_rfc3339()),
+                                deleted_by: row.try_get("deleted_by").ok(),
+                                is_read: row.try_get("is_read").unwrap_or(false),
+                                is_delivered: row.try_get("is_delivered").unwrap_or(false),
+                                has_files,
+                                metadata: metadata_vec,
+                                envelopes: envelopes_value,
+                                status: Some("sent".to_string()),
+                            };
+
+                            if let Ok(evt) = serde_json::to_string(&ServerEvent::MessageNew {
+                                chat_id: to_chat_id,
+                                message: msg,
+                            }) {
+                                let participants = sqlx::query(
+                                    r#"
+                                    SELECT user_id
+                                    FROM chat_participants
+                                    WHERE chat_id = $1
+                                    "#,
+                                )
+                                .bind(to_chat_id)
+                                .fetch_all(&state.pool)
+                                .await;
+
+                                if let Ok(rows) = participants {
+                                    for r in rows {
+                                        let uid: i32 = r.try_get("user_id").unwrap_or_default();
+                                        if uid <= 0 {
+                                            continue;
+                                        }
+                                        if !is_user_online(&state, uid) {
+                                            continue;
+                                        }
+                                        ensure_user_channel(&state, uid);
+                                        publish_user(&state, uid, evt.clone());
+                                    }
                                 }
-                                ensure_user_channel(&state, uid);
-                                publish_user(&state, uid, evt.clone());
                             }
                         }
-                    }
-                    Ok(ClientEvent::ForwardMessage {
-                        from_chat_id,
-                        message_id,
-                        to_chat_id,
-                        message,
-                        message_type,
-                        envelopes,
-                        metadata,
-                        client_message_id: _, // Ignore for forward
-                    }) => {
-                        // Должен быть участником и исходного, и целевого чата
-                        if let Err(e) = ensure_member(&state, from_chat_id, user_id).await {
-                            let err_msg =
-                                serde_json::to_string(&ServerEvent::Error { error: &e.1 })
-                                    .unwrap_or_else(|_| {
-                                        format!(
-                                            "{{\"type\":\"error\",\"error\":{}}}",
-                                            serde_json::to_string(&e.1)
-                                                .unwrap_or_else(|_| "\"Ошибка\"".to_string())
-                                        )
-                                    });
-                            let _ = out_tx.send(WsMessage::Text(err_msg));
-                            continue;
+                        Ok(ClientEvent::DeleteMessages {
+                            chat_id,
+                            message_ids,
+                            scope,
+                        }) => {
+                            let scope = scope.unwrap_or_else(|| "everyone".to_string());
+                            let evt = match delete_messages_batch(
+                                &state,
+                                chat_id,
+                                user_id,
+                                &message_ids,
+                                &scope,
+                            )
+                            .await
+                            {
+                                Ok(results) => serde_json::to_string(&ServerEvent::BatchResult {
+                                    op: "delete",
+                                    chat_id,
+                                    results,
+                                }),
+                                Err(e) => serde_json::to_string(&ServerEvent::Error { error: &e.1 }),
+                            };
+                            let evt = evt.unwrap_or_else(|_| {
+                                "{\"type\":\"error\",\"error\":\"Ошибка сериализации\"}".to_string()
+                            });
+                            let _ = out_tx.send(WsMessage::Text(evt));
                         }
-                        if let Err(e) = ensure_can_send_message(&state, to_chat_id, user_id).await {
-                            let err_msg =
-                                serde_json::to_string(&ServerEvent::Error { error: &e.1 })
-                                    .unwrap_or_else(|_| {
-                                        format!(
-                                            "{{\"type\":\"error\",\"error\":{}}}",
-                                            serde_json::to_string(&e.1)
-                                                .unwrap_or_else(|_| "\"Ошибка\"".to_string())
-                                        )
-                                    });
-                            let _ = out_tx.send(WsMessage::Text(err_msg));
-                            continue;
+                        Ok(ClientEvent::ForwardMessages {
+                            from_chat_id,
+                            to_chat_id,
+                            items,
+                        }) => {
+                            let evt = match forward_messages_batch(
+                                &state,
+                                from_chat_id,
+                                to_chat_id,
+                                user_id,
+                                items,
+                            )
+                            .await
+                            {
+                                Ok(results) => serde_json::to_string(&ServerEvent::BatchResult {
+                                    op: "forward",
+                                    chat_id: to_chat_id,
+                                    results,
+                                }),
+                                Err(e) => serde_json::to_string(&ServerEvent::Error { error: &e.1 }),
+                            };
+                            let evt = evt.unwrap_or_else(|_| {
+                                "{\"type\":\"error\",\"error\":\"Ошибка сериализации\"}".to_string()
+                            });
+                            let _ = out_tx.send(WsMessage::Text(evt));
                         }
-
-                        // Получаем автора исходного сообщения
-                        let src = sqlx::query(
-                            r#"
-                            SELECT sender_id::INT8 AS sender_id
-                            FROM messages
-                            WHERE id = $1 AND chat_id = $2
-                            LIMIT 1
-                            "#,
-                        )
-                        .bind(message_id as i32)
-                        .bind(from_chat_id)
-                        .fetch_optional(&state.pool)
-                        .await;
-
-                        let src = match src {
-                            Ok(r) => r,
-                            Err(e) => {
-                                let err_txt = format!("Ошибка БД: {}", e);
-                                let err_msg =
-                                    serde_json::to_string(&ServerEvent::Error { error: &err_txt })
-                                        .unwrap_or_else(|_| {
-                                            format!(
-                                                "{{\"type\":\"error\",\"error\":{}}}",
-                                                serde_json::to_string(&err_txt)
-                                                    .unwrap_or_else(|_| "\"Ошибка\"".to_string())
-                                            )
-                                        });
-                                let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
-                            }
-                        };
-
-                        let Some(src_row) = src else {
+                        Err(_) => {
                             let err_msg = serde_json::to_string(&ServerEvent::Error {
-                                error: "Исходное сообщение не найдено",
+                                error: "Некорректный формат сообщения",
                             })
                             .unwrap_or_else(|_| {
-                                "{\"type\":\"error\",\"error\":\"Исходное сообщение не найдено\"}"
+                                "{\"type\":\"error\",\"error\":\"Некорректный формат сообщения\"}"
                                     .to_string()
                             });
                             let _ = out_tx.send(WsMessage::Text(err_msg));
-                            continue;
-                        };
-
-                        let original_sender_id: i64 =
-                            src_row.try_get("sender_id").unwrap_or_default();
-
-                        let msg_type = message_type.unwrap_or_else(|| "text".to_string());
-                        let has_files = metadata.as_ref().map(|m| !m.is_empty());
-                        let envelopes_json =
-                            envelopes.map(|v| serde_json::to_value(v).ok()).flatten();
-                        let metadata_json = metadata
-                            .as_ref()
-                            .map(|m| serde_json::to_value(m).ok())
-                            .flatten();
-
-                        let row = match sqlx::query(
-                            r#"
-                            INSERT INTO messages (
-                                chat_id,
-                                sender_id,
-                                message,
-                                message_type,
-                                envelopes,
-                                metadata,
-                                forwarded_from_message_id,
-                                forwarded_from_chat_id,
-                                forwarded_from_sender_id
-                            )
-                            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
-                            RETURNING
-                                id::INT8 AS id,
-                                chat_id::INT8 AS chat_id,
-                                sender_id::INT8 AS sender_id,
-                                message,
-                                message_type,
-                                created_at,
-                                edited_at,
-                                reply_to_message_id::INT8 AS reply_to_message_id,
-                                forwarded_from_message_id::INT8 AS forwarded_from_message_id,
-                                forwarded_from_chat_id::INT8 AS forwarded_from_chat_id,
-                                forwarded_from_sender_id::INT8 AS forwarded_from_sender_id,
-                                deleted_at,
-                                deleted_by::INT8 AS deleted_by,
-                                is_read,
-                                is_delivered,
-                                envelopes,
-                                metadata
-                            "#,
-                        )
-                        .bind(to_chat_id)
-                        .bind(user_id)
-                        .bind(&message)
-                        .bind(&msg_type)
-                        .bind(&envelopes_json)
-                        .bind(&metadata_json)
-                        .bind(message_id as i32)
-                        .bind(from_chat_id)
-                        .bind(original_sender_id as i32)
-                        .fetch_one(&state.pool)
-                        .await
-                        {
-                            Ok(r) => r,
-                            Err(e) => {
-                                let err_txt = format!("Ошибка БД: {}", e);
-                                let err_msg =
-                                    serde_json::to_string(&ServerEvent::Error { error: &err_txt })
-                                        .unwrap_or_else(|_| {
-                                            format!(
-                                                "{{\"type\":\"error\",\"error\":{}}}",
-                                                serde_json::to_string(&err_txt)
-                                                    .unwrap_or_else(|_| "\"Ошибка\"".to_string())
-                                            )
-                                        });
-                                let _ = out_tx.send(WsMessage::Text(err_msg));
-                                continue;
-                            }
-                        };
-
-                        let envelopes_value: Option<Value> =
-                            row.try_get("envelopes").ok().flatten();
-                        let metadata_value: Option<Value> = row.try_get("metadata").ok().flatten();
-                        let metadata_vec: Option<Vec<FileMetadata>> =
-                            metadata_value.and_then(|v| serde_json::from_value(v).ok());
-
-                        let msg = OutMessage {
-                            id: row.try_get("id").unwrap_or_default(),
-                            chat_id: row.try_get("chat_id").unwrap_or_default(),
-                            sender_id: row.try_get("sender_id").unwrap_or_default(),
-                            message: row.try_get("message").unwrap_or_default(),
-                            message_type: row
-                                .try_get("message_type")
-                                .unwrap_or_else(|_| "text".to_string()),
-                            created_at: row
-                                .try_get::<chrono::DateTime<chrono::Utc>, _>("created_at")
-                                .map(|t| t.to_rfc3339())
-                                .unwrap_or_default(),
-                            edited_at: row
-                                .try_get::<chrono::DateTime<chrono::Utc>, _>("edited_at")
-                                .ok()
-                                .map(|t| t.to_rfc3339()),
-                            reply_to_message_id: row.try_get("reply_to_message_id").ok(),
-                            forwarded_from_message_id: row
-                                .try_get("forwarded_from_message_id")
-                                .ok(),
-                            forwarded_from_chat_id: row.try_get("forwarded_from_chat_id").ok(),
-                            forwarded_from_sender_id: row.try_get("forwarded_from_sender_id").ok(),
-                            deleted_at: row
-                                .try_get::<chrono::DateTime<chrono::Utc>, _>("deleted_at")
-                                .ok()
-                                .map(|t| t.to_rfc3339()),
-                            deleted_by: row.try_get("deleted_by").ok(),
-                            is_read: row.try_get("is_read").unwrap_or(false),
-                            is_delivered: row.try_get("is_delivered").unwrap_or(false),
-                            has_files,
-                            metadata: metadata_vec,
-                            envelopes: envelopes_value,
-                            status: Some("sent".to_string()),
-                        };
-
-                        if let Ok(evt) = serde_json::to_string(&ServerEvent::MessageNew {
-                            chat_id: to_chat_id,
-                            message: msg,
-                        }) {
-                            let participants = sqlx::query(
-                                r#"
-                                SELECT user_id
-                                FROM chat_participants
-                                WHERE chat_id = $1
-                                "#,
-                            )
-                            .bind(to_chat_id)
-                            .fetch_all(&state.pool)
-                            .await;
-
-                            if let Ok(rows) = participants {
-                                for r in rows {
-                                    let uid: i32 = r.try_get("user_id").unwrap_or_default();
-                                    if uid <= 0 {
-                                        continue;
-                                    }
-                                    if !is_user_online(&state, uid) {
-                                        continue;
-                                    }
-                                    ensure_user_channel(&state, uid);
-                                    publish_user(&state, uid, evt.clone());
-                                }
-                            }
                         }
                     }
-                    Err(_) => {
-                        let err_msg = serde_json::to_string(&ServerEvent::Error {
-                            error: "Некорректный формат сообщения",
-                        })
-                        .unwrap_or_else(|_| {
-                            "{\"type\":\"error\",\"error\":\"Некорректный формат сообщения\"}"
-                                .to_string()
-                        });
-                        let _ = out_tx.send(WsMessage::Text(err_msg));
-                    }
                 }
+                .instrument(event_span)
+                .await;
             }
             WsMessage::Close(_) => {
                 break;
@@ -1514,15 +1982,10 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: i32) {
         }
     }
 
-    // Закрытие: отписываемся от всех каналов и шлём offline глобально
-    for (_chat_id, handle) in subs.forwarders.drain() {
-        handle.abort();
-    }
-    subs.joined.clear();
-    if let Some(h) = subs.user_forwarder.take() {
-        h.abort();
-    }
+    state.sessions.unregister_socket(session_id, socket_id);
+    state.metrics.ws_connections.dec();
 
+    // Закрытие: шлём offline глобально и отписываемся от всех каналов
     // Снимаем одно активное соединение; offline отправляем только когда сокетов больше не осталось.
     // Важно: нельзя вызывать remove, пока удерживается guard от get_mut (DashMap может взаимно заблокироваться).
     let became_offline = match state.online_connections.get_mut(&user_id) {
@@ -1548,7 +2011,14 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: i32) {
             Ok(s) => s,
             Err(_) => "{\"type\":\"presence\",\"user_id\":0,\"status\":\"offline\"}".to_string(),
         };
+        // Блокировка могла появиться после init
+        let blocked = blocked_contacts(&state.pool, user_id, &subs.contacts)
+            .await
+            .unwrap_or_default();
         for cid in subs.contacts.drain() {
+            if blocked.contains(&cid) {
+                continue;
+            }
             ensure_user_channel(&state, cid);
             if let Some(entry) = state.user_hub.get(&cid) {
                 let _ = entry.send(presence_evt.clone());
@@ -1556,5 +2026,32 @@ async fn handle_socket(socket: WebSocket, state: AppState, user_id: i32) {
         }
     }
 
-    let _ = writer.abort();
+    if shutting_down {
+        // Остальные сокеты закрываются одновременно: даём форвардерам
+        // доставить их offline-события, прежде чем отписаться
+        tokio::time::sleep(SHUTDOWN_PRESENCE_GRACE).await;
+    }
+    for (_chat_id, handle) in subs.forwarders.drain() {
+        handle.abort();
+    }
+    subs.joined.clear();
+    if let Some(h) = subs.user_forwarder.take() {
+        h.abort();
+    }
+
+    if revoked {
+        // Даём writer'у отправить close-фрейм с причиной отзыва
+        drop(out_tx);
+        let _ = tokio::time::timeout(Duration::from_secs(1), writer).await;
+    } else if shutting_down {
+        // Writer дописывает очередь исходящих и закрывает соединение
+        let _ = out_tx.send(WsMessage::Close(Some(CloseFrame {
+            code: SERVER_SHUTDOWN_CLOSE_CODE,
+            reason: "server_shutdown".into(),
+        })));
+        drop(out_tx);
+        let _ = tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, writer).await;
+    } else {
+        let _ = writer.abort();
+    }
 }
@@ -0,0 +1,135 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::{TaskTracker, task_tracker::TrackedFuture};
+
+// Плавная остановка сервера (SIGTERM / SIGINT):
+// 0. /health/ready начинает отвечать 503, но сервер ещё server.shutdown_ready_grace_secs
+//    обслуживает запросы, пока балансировщик не снимет инстанс;
+// 1. axum перестаёт принимать соединения и дожидается текущих HTTP-запросов
+//    (в том числе загрузок файлов);
+// 2. каждый websocket получает server_shutdown с подсказкой переподключения,
+//    дописывает очередь исходящих и закрывается;
+// 3. по истечении server.shutdown_timeout_secs оставшиеся задачи прерываются,
+//    пул БД закрывается.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    // Инстанс снимается с балансировки (ready = 503), соединения ещё принимаются
+    draining: CancellationToken,
+    token: CancellationToken,
+    // Задачи websocket-соединений: после апгрейда axum их уже не отслеживает
+    sockets: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn begin_drain(&self) {
+        self.draining.cancel();
+    }
+
+    pub fn trigger(&self) {
+        self.draining.cancel();
+        self.token.cancel();
+    }
+
+    /// Остановка начата (в том числе период снятия с балансировки)
+    pub fn is_draining(&self) -> bool {
+        self.draining.is_cancelled()
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Завершается, когда начата остановка
+    pub async fn triggered(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Оборачивает обработчик websocket: остановка ждёт его завершения
+    pub fn track_socket<F: Future>(&self, future: F) -> TrackedFuture<F> {
+        self.sockets.track_future(future)
+    }
+
+    /// Ждёт завершения всех websocket-задач не дольше `timeout`.
+    /// Возвращает false, если не уложились.
+    pub async fn wait_sockets(&self, timeout: Duration) -> bool {
+        self.sockets.close();
+        tokio::time::timeout(timeout, self.sockets.wait())
+            .await
+            .is_ok()
+    }
+
+    pub fn live_sockets(&self) -> usize {
+        self.sockets.len()
+    }
+}
+
+/// Ждёт SIGINT (Ctrl+C) или SIGTERM
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "Не удалось подписаться на SIGINT");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Не удалось подписаться на SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("SIGINT: останавливаем сервер"),
+        _ = terminate => tracing::info!("SIGTERM: останавливаем сервер"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_tracked_sockets_after_trigger() {
+        let shutdown = Shutdown::new();
+        let socket = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(shutdown.track_socket({
+                let shutdown = shutdown.clone();
+                async move { shutdown.triggered().await }
+            }))
+        };
+        tokio::task::yield_now().await;
+        assert_eq!(shutdown.live_sockets(), 1);
+        assert!(!shutdown.wait_sockets(Duration::from_millis(50)).await);
+
+        shutdown.trigger();
+        assert!(shutdown.wait_sockets(Duration::from_secs(1)).await);
+        assert_eq!(shutdown.live_sockets(), 0);
+        socket.await.unwrap();
+    }
+
+    #[test]
+    fn drain_precedes_trigger() {
+        let shutdown = Shutdown::new();
+        shutdown.begin_drain();
+        assert!(shutdown.is_draining());
+        assert!(!shutdown.is_triggered());
+
+        shutdown.trigger();
+        assert!(shutdown.is_triggered());
+    }
+}
@@ -0,0 +1,111 @@
+use opentelemetry::KeyValue;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::{Resource, runtime, trace::TracerProvider};
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{EnvFilter, Registry, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{Layer, fmt};
+
+// Логи и трассировка через tracing:
+// - формат вывода — log.format в конфигурации (LOG_FORMAT): pretty | json
+// - RUST_LOG — фильтр уровней, по умолчанию "info"
+// - OTEL_EXPORTER_OTLP_ENDPOINT (или OTEL_EXPORTER_OTLP_TRACES_ENDPOINT) — включает
+//   экспорт спанов по OTLP/HTTP (protobuf) на {endpoint}/v1/traces
+// - OTEL_SERVICE_NAME — имя сервиса в трассах, по умолчанию "ren-backend"
+//
+// В спаны попадают только идентификаторы (user_id, session_id, chat_id),
+// содержимое сообщений и тела запросов не логируются.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "pretty" | "text" => Some(LogFormat::Pretty),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+// Держит провайдер трассировки; при drop отправляет накопленные спаны
+pub struct TelemetryGuard {
+    provider: Option<TracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Ошибка остановки экспортёра трасс: {}", e);
+        }
+    }
+}
+
+fn otlp_endpoint_configured() -> bool {
+    [
+        "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        "OTEL_EXPORTER_OTLP_ENDPOINT",
+    ]
+    .iter()
+    .any(|var| std::env::var(var).is_ok_and(|v| !v.trim().is_empty()))
+}
+
+fn otlp_provider() -> TracerProvider {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .expect("Не удалось создать OTLP-экспортёр");
+    let service_name =
+        std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "ren-backend".to_string());
+    TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", service_name)]))
+        .build()
+}
+
+/// Устанавливает глобальный subscriber. Вызывать внутри рантайма Tokio:
+/// пакетный экспортёр OTLP запускает в нём фоновую задачу.
+pub fn init(format: LogFormat) -> TelemetryGuard {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let fmt_layer = match format {
+        LogFormat::Pretty => fmt::layer().with_target(false).boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+
+    let provider = otlp_endpoint_configured().then(otlp_provider);
+    let otel_layer = provider
+        .as_ref()
+        .map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("ren-backend")));
+
+    Registry::default()
+        .with(filter)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .init();
+
+    TelemetryGuard { provider }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_log_format() {
+        assert_eq!(LogFormat::parse(""), Some(LogFormat::Pretty));
+        assert_eq!(LogFormat::parse("JSON"), Some(LogFormat::Json));
+        assert_eq!(LogFormat::parse("xml"), None);
+    }
+}
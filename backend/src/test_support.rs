@@ -0,0 +1,103 @@
+use dashmap::DashMap;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::middleware::{self, CurrentUser};
+use crate::{AppState, MIGRATOR, config, metrics, shutdown};
+
+// Состояние приложения для тестов обработчиков на настоящей базе. Такие тесты
+// запускаются, только если задан TEST_DATABASE_URL
+// (например, postgres://postgres@localhost/ren_test); иначе state() вернёт None.
+pub(crate) async fn state() -> Option<AppState> {
+    let url = std::env::var("TEST_DATABASE_URL").ok()?;
+    let pool = PgPool::connect(&url).await.unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+
+    let config = config::Config::default();
+    let store: Arc<dyn middleware::CounterStore> = Arc::new(middleware::MemoryCounterStore::new());
+    Some(AppState {
+        pool,
+        jwt_secret: "test-secret".to_string(),
+        ws_hub: Arc::new(DashMap::new()),
+        user_hub: Arc::new(DashMap::new()),
+        online_connections: Arc::new(DashMap::new()),
+        rate_limiter: middleware::RateLimiter::new(
+            config.rate_limit.limiter_config(),
+            store.clone(),
+        ),
+        auth_rate_limiter: middleware::AuthRateLimiter::new(
+            config.rate_limit.auth_limiter_config(),
+            store,
+        ),
+        sessions: middleware::SessionRegistry::new(),
+        metrics: Arc::new(metrics::Metrics::new()),
+        config: Arc::new(config),
+        shutdown: shutdown::Shutdown::new(),
+    })
+}
+
+// Новый пользователь с уникальным логином; возвращает его id
+pub(crate) async fn user(pool: &PgPool) -> i32 {
+    let login = format!("test_{}", Uuid::new_v4().simple());
+    sqlx::query_scalar(
+        r#"
+        INSERT INTO users (login, username, password, pkebymk, pkebyrk, pubk, salt)
+        VALUES ($1, $1, 'x', 'x', 'x', 'x', 'x')
+        RETURNING id
+        "#,
+    )
+    .bind(&login)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+// Private-чат двух пользователей; возвращает id чата
+pub(crate) async fn private_chat(pool: &PgPool, first: i32, second: i32) -> i32 {
+    let chat_id: i32 = sqlx::query_scalar(
+        "INSERT INTO chats (kind, user_a, user_b) VALUES ('private', $1, $2) RETURNING id",
+    )
+    .bind(first.min(second))
+    .bind(first.max(second))
+    .fetch_one(pool)
+    .await
+    .unwrap();
+    for user_id in [first, second] {
+        sqlx::query("INSERT INTO chat_participants (chat_id, user_id) VALUES ($1, $2)")
+            .bind(chat_id)
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+    chat_id
+}
+
+// Активная сессия пользователя (для обработчиков, проверяющих session_id)
+pub(crate) async fn session(pool: &PgPool, user_id: i32) -> CurrentUser {
+    let session_id = Uuid::new_v4();
+    sqlx::query(
+        r#"
+        INSERT INTO auth_sessions (id, user_id, refresh_token_hash, expires_at)
+        VALUES ($1, $2, $3, now() + interval '1 day')
+        "#,
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .bind(session_id.to_string())
+    .execute(pool)
+    .await
+    .unwrap();
+    CurrentUser {
+        id: user_id,
+        session_id,
+    }
+}
+
+pub(crate) fn current(id: i32) -> CurrentUser {
+    CurrentUser {
+        id,
+        session_id: Uuid::new_v4(),
+    }
+}